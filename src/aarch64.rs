@@ -0,0 +1,366 @@
+//! AArch64 assembly for Linux, making system calls itself like
+//! [`crate::x86`] so that building needs only an assembler and a linker.
+//! Behaves like [`crate::gen::QbeGenerator`]'s output: 32 bit cells, `,` at
+//! end of input leaves the cell alone, I/O retried on `EINTR`, and moving
+//! off the tape exits with status 1.
+//!
+//! `x19` holds the cell pointer, `x20` the start of the tape and `x21` its
+//! size in bytes, all kept across the runtime routines.
+
+use std::fmt::Write;
+
+use crate::{ast, backend::CodeGen, target::Target, CompileOptions, Error, Newline};
+
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_EXIT: u32 = 93;
+const EINTR: u32 = 4;
+
+// keeps the tape within reach of `adrp`
+const MAX_TAPE_LEN: usize = 1 << 28;
+
+const READ_ERR: &[u8] = b"bfc: read error\n";
+const WRITE_ERR: &[u8] = b"bfc: write error\n";
+
+pub struct Aarch64Generator {
+    label_counter: usize,
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    debug_file: Option<String>,
+    text: String,
+}
+
+impl Default for Aarch64Generator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for Aarch64Generator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl Aarch64Generator {
+    pub fn new() -> Self {
+        Aarch64Generator {
+            label_counter: 0,
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            debug_file: None,
+            text: String::new(),
+        }
+    }
+
+    /// Fails for targets other than Linux on AArch64, the system calls are
+    /// its own, and for tapes too large to address relative to the code.
+    pub fn with_options(opts: &CompileOptions) -> Result<Self, Error> {
+        if opts.target.is_some_and(|t| t != Target::Arm64) {
+            return Err(Error::Unsupported(
+                "AArch64 code is only generated for arm64",
+            ));
+        }
+        if opts.tape_len > MAX_TAPE_LEN {
+            return Err(Error::Unsupported(
+                "AArch64 code supports tapes of up to 2^28 cells",
+            ));
+        }
+        Ok(Aarch64Generator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            debug_file: opts.debug_file.clone(),
+            ..Self::new()
+        })
+    }
+
+    pub fn gen(&mut self, prog: &ast::Prog) -> String {
+        if let Some(file) = &self.debug_file {
+            let file = file.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(self.text, "\t.file 1 \"{}\"", file).unwrap();
+        }
+        self.text.push_str("\t.text\n\t.globl _start\n_start:\n");
+        self.generate_address("x20", "bf_tape");
+        self.instr("mov x19, x20");
+        self.generate_imm("x21", self.tape_len as u64 * 4);
+        self.generate_block(prog);
+        self.instr("mov x0, #0");
+        self.instr("b bf_exit");
+
+        self.generate_runtime();
+        writeln!(
+            self.text,
+            "\n\t.bss\n\t.balign 16\nbf_tape:\n\t.zero {}\nbf_buf:\n\t.zero 1",
+            self.tape_len * 4
+        )
+        .unwrap();
+        if self.io_strict {
+            writeln!(
+                self.text,
+                "\n\t.section .rodata\nbf_read_err:\n\t.ascii \"{}\"\nbf_write_err:\n\t.ascii \"{}\"",
+                READ_ERR.escape_ascii(),
+                WRITE_ERR.escape_ascii()
+            )
+            .unwrap();
+        }
+        std::mem::take(&mut self.text)
+    }
+
+    fn generate_block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.generate_statement(stat)
+        }
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) {
+        if self.debug_file.is_some() {
+            let span = stat.attr.span;
+            self.instr(&format!(".loc 1 {} {}", span.line, span.col));
+        }
+
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move("sub", *n as u64),
+            ast::Statement::MoveR(n) => self.generate_move("add", *n as u64),
+            ast::Statement::Add(n) => self.generate_add("add", *n as u32),
+            ast::Statement::Sub(n) => self.generate_add("sub", *n as u32),
+            ast::Statement::Set(0) => self.instr("str wzr, [x19]"),
+            ast::Statement::Set(n) => {
+                self.generate_imm("w9", *n as u64);
+                self.instr("str w9, [x19]");
+            }
+            ast::Statement::MulAdd { offset, factor } => {
+                // moves only touch x9, the product waits in w11
+                self.instr("ldr w11, [x19]");
+                self.generate_imm("w10", *factor as u64);
+                self.instr("mul w11, w11, w10");
+                let n = offset.unsigned_abs() as u64;
+                let (there, back) = if *offset < 0 {
+                    ("sub", "add")
+                } else {
+                    ("add", "sub")
+                };
+                self.generate_move(there, n);
+                self.instr("ldr w9, [x19]");
+                self.instr("add w9, w9, w11");
+                self.instr("str w9, [x19]");
+                self.generate_move(back, n);
+            }
+            ast::Statement::Read => {
+                let skip = self.generate_label("eof");
+                self.instr("bl bf_getc");
+                self.instr(&format!("tbnz w0, #31, {}", skip));
+                self.instr("str w0, [x19]");
+                self.label(&skip);
+            }
+            ast::Statement::Write => {
+                self.instr("ldr w0, [x19]");
+                if self.crlf {
+                    let plain = self.generate_label("char");
+                    self.instr("and w9, w0, #0xff");
+                    self.instr("cmp w9, #10");
+                    self.instr(&format!("b.ne {}", plain));
+                    self.instr("mov w0, #13");
+                    self.instr("bl bf_putc");
+                    self.instr("mov w0, #10");
+                    self.label(&plain);
+                }
+                self.instr("bl bf_putc");
+            }
+            ast::Statement::Scan(stride) => {
+                let start = self.generate_label("scan");
+                let end = self.generate_label("scanned");
+                self.instr("ldr w9, [x19]");
+                self.instr(&format!("cbz w9, {}", end));
+                self.label(&start);
+                let op = if *stride < 0 { "sub" } else { "add" };
+                self.generate_move(op, stride.unsigned_abs() as u64);
+                self.instr("ldr w9, [x19]");
+                self.instr(&format!("cbnz w9, {}", start));
+                self.label(&end);
+            }
+            ast::Statement::Loop(body) => {
+                let start = self.generate_label("loop");
+                let end = self.generate_label("end");
+                self.instr("ldr w9, [x19]");
+                self.instr(&format!("cbz w9, {}", end));
+                self.label(&start);
+                self.generate_block(body);
+                self.instr("ldr w9, [x19]");
+                self.instr(&format!("cbnz w9, {}", start));
+                self.label(&end);
+            }
+            ast::Statement::If(body) => {
+                let end = self.generate_label("fi");
+                self.instr("ldr w9, [x19]");
+                self.instr(&format!("cbz w9, {}", end));
+                self.generate_block(body);
+                self.label(&end);
+            }
+            // there is no data to copy from, see `spell_out_fill`
+            ast::Statement::Fill(values) => self.generate_block(&ast::spell_out_fill(values)),
+            // nor any to write from, see `spell_out_print`
+            ast::Statement::Print(bytes) => self.generate_block(&ast::spell_out_print(bytes)),
+        }
+    }
+
+    /// `op` is `add` or `sub`.
+    fn generate_add(&mut self, op: &str, n: u32) {
+        self.instr("ldr w9, [x19]");
+        if n < 4096 {
+            self.instr(&format!("{} w9, w9, #{}", op, n));
+        } else {
+            self.generate_imm("w10", n as u64);
+            self.instr(&format!("{} w9, w9, w10", op));
+        }
+        self.instr("str w9, [x19]");
+    }
+
+    /// Moves the pointer by `n` cells, `op` being `add` or `sub`, and exits
+    /// with status 1 when it ends up off the tape.
+    fn generate_move(&mut self, op: &str, n: u64) {
+        let bytes = n.wrapping_mul(4);
+        if bytes < 4096 {
+            self.instr(&format!("{} x19, x19, #{}", op, bytes));
+        } else {
+            self.generate_imm("x9", bytes);
+            self.instr(&format!("{} x19, x19, x9", op));
+        }
+        self.instr("sub x9, x19, x20");
+        self.instr("cmp x9, x21");
+        self.instr("b.hs bf_halt");
+    }
+
+    /// `bf_putc` writes the byte in `w0`, `bf_getc` returns the next byte of
+    /// input in `w0`, or -1 at its end.
+    fn generate_runtime(&mut self) {
+        self.label("bf_halt");
+        self.instr("mov x0, #1");
+        self.label("bf_exit");
+        self.instr(&format!("mov x8, #{}", SYS_EXIT));
+        self.instr("svc #0");
+
+        self.label("bf_putc");
+        self.generate_address("x1", "bf_buf");
+        self.instr("strb w0, [x1]");
+        self.label(".Lputc_retry");
+        self.generate_syscall(SYS_WRITE, 1);
+        self.instr("cmp x0, #1");
+        self.instr("b.eq .Lputc_done");
+        // nothing written, try again
+        self.instr("cbz x0, .Lputc_retry");
+        self.instr(&format!("cmn x0, #{}", EINTR));
+        self.instr("b.eq .Lputc_retry");
+        if self.io_strict {
+            self.generate_io_error("bf_write_err", WRITE_ERR);
+        }
+        self.label(".Lputc_done");
+        self.instr("ret");
+
+        self.label("bf_getc");
+        self.generate_address("x1", "bf_buf");
+        self.label(".Lgetc_retry");
+        self.generate_syscall(SYS_READ, 0);
+        self.instr("cmp x0, #1");
+        self.instr("b.ne .Lgetc_short");
+        self.instr("ldrb w0, [x1]");
+        if self.crlf {
+            self.instr("cmp w0, #13");
+            self.instr("b.eq .Lgetc_retry");
+        }
+        self.instr("ret");
+        self.label(".Lgetc_short");
+        self.instr("cbz x0, .Lgetc_eof");
+        self.instr(&format!("cmn x0, #{}", EINTR));
+        self.instr("b.eq .Lgetc_retry");
+        if self.io_strict {
+            self.generate_io_error("bf_read_err", READ_ERR);
+        }
+        self.label(".Lgetc_eof");
+        self.instr("mov w0, #-1");
+        self.instr("ret");
+    }
+
+    /// One byte through the buffer `x1` points at, the result left in `x0`.
+    fn generate_syscall(&mut self, nr: u32, fd: u32) {
+        self.instr(&format!("mov x0, #{}", fd));
+        self.instr("mov x2, #1");
+        self.instr(&format!("mov x8, #{}", nr));
+        self.instr("svc #0");
+    }
+
+    fn generate_io_error(&mut self, name: &str, msg: &[u8]) {
+        self.instr("mov x0, #2");
+        self.generate_address("x1", name);
+        self.instr(&format!("mov x2, #{}", msg.len()));
+        self.instr(&format!("mov x8, #{}", SYS_WRITE));
+        self.instr("svc #0");
+        self.instr("b bf_halt");
+    }
+
+    fn generate_address(&mut self, reg: &str, sym: &str) {
+        self.instr(&format!("adrp {}, {}", reg, sym));
+        self.instr(&format!("add {0}, {0}, :lo12:{1}", reg, sym));
+    }
+
+    /// Loads `value` into `reg` 16 bits at a time.
+    fn generate_imm(&mut self, reg: &str, value: u64) {
+        self.instr(&format!("movz {}, #{}", reg, value & 0xffff));
+        let width = if reg.starts_with('w') { 32 } else { 64 };
+        for shift in (16..width).step_by(16) {
+            let chunk = (value >> shift) & 0xffff;
+            if chunk != 0 {
+                self.instr(&format!("movk {}, #{}, lsl #{}", reg, chunk, shift));
+            }
+        }
+    }
+
+    fn instr(&mut self, instr: &str) {
+        writeln!(self.text, "\t{}", instr).unwrap();
+    }
+
+    fn label(&mut self, label: &str) {
+        writeln!(self.text, "{}:", label).unwrap();
+    }
+
+    fn generate_label(&mut self, prefix: &str) -> String {
+        let c = self.label_counter;
+        self.label_counter += 1;
+        format!(".L{}{}", prefix, c)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn multiplies_keep_the_product_in_w11() {
+        let opts = CompileOptions {
+            opt_level: crate::OptLevel::O2,
+            ..Default::default()
+        };
+        let prog = crate::optimize(",[->-<]>.", &opts).unwrap();
+        let asm = Aarch64Generator::new().gen(&prog);
+        assert!(asm.contains("\tbl bf_getc\n\ttbnz w0, #31, .Leof0\n\tstr w0, [x19]\n.Leof0:\n"));
+        assert!(asm.contains(
+            "\tldr w11, [x19]\n\tmovz w10, #65535\n\tmovk w10, #65535, lsl #16\n\tmul w11, w11, w10\n"
+        ));
+        assert!(asm.contains("\tldr w9, [x19]\n\tadd w9, w9, w11\n\tstr w9, [x19]\n"));
+        assert!(asm.contains("\tstr wzr, [x19]\n"));
+    }
+
+    #[test]
+    fn large_immediates() {
+        let mut gen = Aarch64Generator::new();
+        gen.generate_add("sub", 0x1_0005);
+        assert_eq!(
+            gen.text,
+            "\tldr w9, [x19]\n\tmovz w10, #5\n\tmovk w10, #1, lsl #16\n\tsub w9, w9, w10\n\tstr w9, [x19]\n"
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! Objects compiled with Cranelift: the program is lowered as [`crate::jit`]
+//! lowers it, next to a `main` and the runtime functions it calls written
+//! in IR on top of libc's `read` and `write`, and written out as an object
+//! for the host that the system linker turns into an executable, no `qbe`
+//! or `cc` involved in compiling. Only built with the `aot` feature.
+//!
+//! The executable behaves as the others do: 32 bit cells on a tape in the
+//! data segment, line endings translated as `newline` says, and with
+//! `io_strict` I/O errors reported on stderr and ending the run with
+//! status 1.
+
+use cranelift_codegen::{
+    ir::{
+        condcodes::IntCC, types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, StackSlotData,
+        StackSlotKind, Value,
+    },
+    settings::{self, Configurable},
+    Context,
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_module::{
+    default_libcall_names, DataDescription, DataId, FuncId, Linkage, Module, ModuleError,
+};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+use crate::{
+    ast,
+    jit::{Lowering, CELL_SIZE, EOF, IO_ERROR},
+    target::Target,
+    CompileOptions, Error, Newline,
+};
+
+/// What `errno` holds after a call interrupted by a signal, on Linux and
+/// macOS alike.
+const EINTR: i64 = 4;
+
+/// Compiles `prog` to an object defining `main`, for the host.
+pub fn object(prog: &ast::Prog, opts: &CompileOptions) -> Result<Vec<u8>, Error> {
+    let host = Target::host();
+    if opts.target.is_some() && opts.target != host {
+        return Err(Error::Unsupported(
+            "Cranelift objects are only built for the host",
+        ));
+    }
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").map_err(aot_error)?;
+    flags.set("is_pic", "true").map_err(aot_error)?;
+    let isa = cranelift_native::builder()
+        .map_err(|e| Error::Aot(e.to_string()))?
+        .finish(settings::Flags::new(flags))
+        .map_err(aot_error)?;
+    let builder = ObjectBuilder::new(isa, "bf", default_libcall_names()).map_err(aot_error)?;
+    let mut module = ObjectModule::new(builder);
+
+    let tape_size = opts
+        .tape_len
+        .checked_mul(CELL_SIZE as usize)
+        .ok_or(Error::Unsupported("the tape does not fit in memory"))?;
+    let errno_location = host.map_or("__errno_location", |t| t.errno_location());
+    let runtime = Runtime::declare(&mut module, errno_location).map_err(aot_error)?;
+    runtime.define(&mut module, opts).map_err(aot_error)?;
+    let bf_main = Lowering::declare(&mut module, prog, opts).map_err(aot_error)?;
+    define_main(&mut module, bf_main, tape_size).map_err(aot_error)?;
+    module.finish().emit().map_err(Error::Object)
+}
+
+fn aot_error(e: impl std::fmt::Display) -> Error {
+    Error::Aot(e.to_string())
+}
+
+/// Defines `main`, running `bf_main` on a zeroed tape of `tape_size`
+/// bytes.
+fn define_main(
+    module: &mut ObjectModule,
+    bf_main: FuncId,
+    tape_size: usize,
+) -> Result<(), Box<ModuleError>> {
+    let ptr_ty = module.target_config().pointer_type();
+    let tape = module.declare_data("bf_tape", Linkage::Local, true, false)?;
+    let mut data = DataDescription::new();
+    data.define_zeroinit(tape_size);
+    data.set_align(CELL_SIZE as u64);
+    module.define_data(tape, &data)?;
+
+    let mut sig = module.make_signature();
+    sig.returns.push(AbiParam::new(types::I32));
+    let main = module.declare_function("main", Linkage::Export, &sig)?;
+    let mut ctx = module.make_context();
+    ctx.func.signature = sig;
+    let mut fctx = FunctionBuilderContext::new();
+    let mut b = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+    let bf_main = module.declare_func_in_func(bf_main, b.func);
+    let tape = module.declare_data_in_func(tape, b.func);
+
+    let entry = b.create_block();
+    b.switch_to_block(entry);
+    let tape = b.ins().symbol_value(ptr_ty, tape);
+    // the runtime functions take no state, see `Runtime`
+    let io = b.ins().iconst(ptr_ty, 0);
+    let call = b.ins().call(bf_main, &[tape, io]);
+    let status = b.inst_results(call)[0];
+    b.ins().return_(&[status]);
+    b.seal_all_blocks();
+    b.finalize();
+    module.define_function(main, &mut ctx)?;
+    module.clear_context(&mut ctx);
+    Ok(())
+}
+
+/// The functions `bf_main` calls for I/O, taking the `Io` pointer
+/// [`crate::jit`] hands them and ignoring it, and the libc functions they
+/// call in turn.
+struct Runtime {
+    getc: FuncId,
+    putc: FuncId,
+    read: FuncId,
+    write: FuncId,
+    errno_location: FuncId,
+    /// Messages written to stderr with `io_strict`.
+    read_error: DataId,
+    write_error: DataId,
+}
+
+impl Runtime {
+    fn declare(module: &mut ObjectModule, errno_location: &str) -> Result<Self, Box<ModuleError>> {
+        let ptr_ty = module.target_config().pointer_type();
+        let mut getc_sig = module.make_signature();
+        getc_sig.params.push(AbiParam::new(ptr_ty));
+        getc_sig.returns.push(AbiParam::new(types::I32));
+        let mut putc_sig = getc_sig.clone();
+        putc_sig.params.push(AbiParam::new(types::I32));
+        // `ssize_t read(int, void *, size_t)`, and `write` alike
+        let mut io_sig = module.make_signature();
+        io_sig.params.extend([
+            AbiParam::new(types::I32),
+            AbiParam::new(ptr_ty),
+            AbiParam::new(ptr_ty),
+        ]);
+        io_sig.returns.push(AbiParam::new(ptr_ty));
+        let mut errno_sig = module.make_signature();
+        errno_sig.returns.push(AbiParam::new(ptr_ty));
+
+        let mut message = |name: &str, text: &[u8]| -> Result<DataId, Box<ModuleError>> {
+            let id = module.declare_data(name, Linkage::Local, false, false)?;
+            let mut data = DataDescription::new();
+            data.define(text.into());
+            module.define_data(id, &data)?;
+            Ok(id)
+        };
+        let read_error = message("bf_read_error", b"bfc: read error\n")?;
+        let write_error = message("bf_write_error", b"bfc: write error\n")?;
+
+        Ok(Runtime {
+            getc: module.declare_function("bf_getc", Linkage::Local, &getc_sig)?,
+            putc: module.declare_function("bf_putc", Linkage::Local, &putc_sig)?,
+            read: module.declare_function("read", Linkage::Import, &io_sig)?,
+            write: module.declare_function("write", Linkage::Import, &io_sig)?,
+            errno_location: module.declare_function(errno_location, Linkage::Import, &errno_sig)?,
+            read_error,
+            write_error,
+        })
+    }
+
+    fn define(
+        &self,
+        module: &mut ObjectModule,
+        opts: &CompileOptions,
+    ) -> Result<(), Box<ModuleError>> {
+        let crlf = opts.newline.resolve() == Newline::Crlf;
+        let mut ctx = module.make_context();
+        let mut fctx = FunctionBuilderContext::new();
+
+        ctx.func.signature = module
+            .declarations()
+            .get_function_decl(self.getc)
+            .signature
+            .clone();
+        let mut calls = Calls::new(self, module, &mut ctx, &mut fctx, opts.io_strict);
+        calls.getc(crlf);
+        calls.b.seal_all_blocks();
+        calls.b.finalize();
+        module.define_function(self.getc, &mut ctx)?;
+        module.clear_context(&mut ctx);
+
+        ctx.func.signature = module
+            .declarations()
+            .get_function_decl(self.putc)
+            .signature
+            .clone();
+        let mut calls = Calls::new(self, module, &mut ctx, &mut fctx, opts.io_strict);
+        calls.putc(crlf);
+        calls.b.seal_all_blocks();
+        calls.b.finalize();
+        module.define_function(self.putc, &mut ctx)?;
+        module.clear_context(&mut ctx);
+        Ok(())
+    }
+}
+
+/// Builds the body of one runtime function.
+struct Calls<'a> {
+    b: FunctionBuilder<'a>,
+    ptr_ty: types::Type,
+    io_strict: bool,
+    read: FuncRef,
+    write: FuncRef,
+    errno_location: FuncRef,
+    read_error: (Value, usize),
+    write_error: (Value, usize),
+    /// A byte of stack to read into and write from.
+    byte: Value,
+}
+
+impl<'a> Calls<'a> {
+    fn new(
+        runtime: &Runtime,
+        module: &mut ObjectModule,
+        ctx: &'a mut Context,
+        fctx: &'a mut FunctionBuilderContext,
+        io_strict: bool,
+    ) -> Self {
+        let ptr_ty = module.target_config().pointer_type();
+        let mut b = FunctionBuilder::new(&mut ctx.func, fctx);
+        let read = module.declare_func_in_func(runtime.read, b.func);
+        let write = module.declare_func_in_func(runtime.write, b.func);
+        let errno_location = module.declare_func_in_func(runtime.errno_location, b.func);
+        let read_error = module.declare_data_in_func(runtime.read_error, b.func);
+        let write_error = module.declare_data_in_func(runtime.write_error, b.func);
+        let slot = b.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 1, 0));
+
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let read_error = b.ins().symbol_value(ptr_ty, read_error);
+        let write_error = b.ins().symbol_value(ptr_ty, write_error);
+        let byte = b.ins().stack_addr(ptr_ty, slot, 0);
+        Calls {
+            b,
+            ptr_ty,
+            io_strict,
+            read,
+            write,
+            errno_location,
+            read_error: (read_error, b"bfc: read error\n".len()),
+            write_error: (write_error, b"bfc: write error\n".len()),
+            byte,
+        }
+    }
+
+    /// `bf_getc`, returning the next byte of input, `EOF` or `IO_ERROR`,
+    /// skipping carriage returns with `crlf`.
+    fn getc(&mut self, crlf: bool) {
+        let again = self.b.create_block();
+        let got = self.b.create_block();
+        let eof = self.b.create_block();
+        let failed = self.b.create_block();
+        self.b.ins().jump(again, &[]);
+
+        self.b.switch_to_block(again);
+        let fd = self.b.ins().iconst(types::I32, 0);
+        let len = self.b.ins().iconst(self.ptr_ty, 1);
+        let call = self.b.ins().call(self.read, &[fd, self.byte, len]);
+        let n = self.b.inst_results(call)[0];
+        let read = self.b.ins().icmp_imm(IntCC::SignedGreaterThan, n, 0);
+        let not_read = self.b.create_block();
+        self.b.ins().brif(read, got, &[], not_read, &[]);
+        self.b.switch_to_block(not_read);
+        self.b.ins().brif(n, failed, &[], eof, &[]);
+
+        self.b.switch_to_block(got);
+        let c = self
+            .b
+            .ins()
+            .uload8(types::I32, MemFlags::trusted(), self.byte, 0);
+        if crlf {
+            let cr = self.b.ins().icmp_imm(IntCC::Equal, c, b'\r' as i64);
+            let keep = self.b.create_block();
+            self.b.ins().brif(cr, again, &[], keep, &[]);
+            self.b.switch_to_block(keep);
+        }
+        self.b.ins().return_(&[c]);
+
+        self.b.switch_to_block(eof);
+        self.ret(EOF);
+
+        self.b.switch_to_block(failed);
+        self.retry_or_fail(again, self.read_error, eof);
+    }
+
+    /// `bf_putc`, writing the low byte of its argument, after a carriage
+    /// return for a newline with `crlf`, returning 0 or `IO_ERROR`.
+    fn putc(&mut self, crlf: bool) {
+        let entry = self.b.current_block().expect("the entry block");
+        let c = self.b.block_params(entry)[1];
+        if crlf {
+            let nl = self.b.ins().icmp_imm(IntCC::Equal, c, b'\n' as i64);
+            let cr = self.b.create_block();
+            let rest = self.b.create_block();
+            self.b.ins().brif(nl, cr, &[], rest, &[]);
+            self.b.switch_to_block(cr);
+            let r = self.b.ins().iconst(types::I32, b'\r' as i64);
+            self.put(r);
+            self.b.ins().jump(rest, &[]);
+            self.b.switch_to_block(rest);
+        }
+        self.put(c);
+        self.ret(0);
+    }
+
+    /// Writes the byte `c`, returning `IO_ERROR` when that fails with
+    /// `io_strict`.
+    fn put(&mut self, c: Value) {
+        let again = self.b.create_block();
+        let failed = self.b.create_block();
+        let done = self.b.create_block();
+        self.b.ins().istore8(MemFlags::trusted(), c, self.byte, 0);
+        self.b.ins().jump(again, &[]);
+
+        self.b.switch_to_block(again);
+        let fd = self.b.ins().iconst(types::I32, 1);
+        let len = self.b.ins().iconst(self.ptr_ty, 1);
+        let call = self.b.ins().call(self.write, &[fd, self.byte, len]);
+        let n = self.b.inst_results(call)[0];
+        let written = self.b.ins().icmp_imm(IntCC::SignedGreaterThan, n, 0);
+        self.b.ins().brif(written, done, &[], failed, &[]);
+
+        self.b.switch_to_block(failed);
+        self.retry_or_fail(again, self.write_error, done);
+        self.b.switch_to_block(done);
+    }
+
+    /// After a failed call, goes back to `again` when a signal interrupted
+    /// it, and otherwise reports `error` and returns `IO_ERROR` with
+    /// `io_strict` or goes on to `ignore`.
+    fn retry_or_fail(&mut self, again: Block, error: (Value, usize), ignore: Block) {
+        let call = self.b.ins().call(self.errno_location, &[]);
+        let errno = self.b.inst_results(call)[0];
+        let errno = self.b.ins().load(types::I32, MemFlags::trusted(), errno, 0);
+        let interrupted = self.b.ins().icmp_imm(IntCC::Equal, errno, EINTR);
+        let fail = self.b.create_block();
+        self.b.ins().brif(interrupted, again, &[], fail, &[]);
+
+        self.b.switch_to_block(fail);
+        if !self.io_strict {
+            self.b.ins().jump(ignore, &[]);
+            return;
+        }
+        let (msg, len) = error;
+        let fd = self.b.ins().iconst(types::I32, 2);
+        let len = self.b.ins().iconst(self.ptr_ty, len as i64);
+        self.b.ins().call(self.write, &[fd, msg, len]);
+        self.ret(IO_ERROR);
+    }
+
+    fn ret(&mut self, value: i64) {
+        let value = self.b.ins().iconst(types::I32, value);
+        self.b.ins().return_(&[value]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, process::Command};
+
+    use super::*;
+
+    #[test]
+    fn build_objects_cc_links() {
+        let dir = std::env::temp_dir().join(format!("bfc-aot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let run = |src: &str, input: &[u8], opts: &CompileOptions| {
+            let prog = crate::parse(src).unwrap();
+            let obj = dir.join("prog.o");
+            let exe = dir.join("prog");
+            std::fs::write(&obj, object(&prog, opts).unwrap()).unwrap();
+            let linked = Command::new("cc").arg(&obj).arg("-o").arg(&exe).status();
+            // without a C toolchain there is nothing to link with
+            if !linked.is_ok_and(|s| s.success()) {
+                return None;
+            }
+            let mut child = Command::new(&exe)
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(input).unwrap();
+            let out = child.wait_with_output().unwrap();
+            Some((out.status.code(), out.stdout))
+        };
+
+        let opts = CompileOptions::default();
+        if let Some(found) = run(",[.[-],]", b"hi", &opts) {
+            assert_eq!(found, (Some(0), b"hi".to_vec()));
+            assert_eq!(run("+++,.", b"", &opts), Some((Some(0), vec![3])));
+            assert_eq!(run("<", b"", &opts).unwrap().0, Some(1));
+            let crlf = CompileOptions {
+                newline: Newline::Crlf,
+                ..Default::default()
+            };
+            assert_eq!(
+                run(",.,.", b"\r\n", &crlf),
+                Some((Some(0), b"\r\n\r\n".to_vec()))
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+//! What a program built with unchecked bounds has to hold to, see
+//! [`CompileOptions::unchecked`]. Checked builds end a program moving off
+//! the tape with status 1, unchecked ones read and write whatever memory
+//! the pointer lands on. [`assumptions`] lists the places where that may
+//! happen, as far as the pointer can be followed without running the
+//! program, so that whoever builds it knows what they are vouching for.
+
+use std::fmt;
+
+use crate::{
+    ast::{NodeBlock, Prog, Statement},
+    lex::Span,
+    CompileOptions,
+};
+
+/// Something the program has to do for its unchecked build to behave like
+/// the checked one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assumption {
+    /// The statement it is about, `None` for the program as a whole.
+    pub span: Option<Span>,
+    pub what: String,
+}
+
+impl fmt::Display for Assumption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}:{}: {}", span.line, span.col, self.what),
+            None => write!(f, "{}", self.what),
+        }
+    }
+}
+
+/// The assumptions an unchecked build of `prog`, as optimized, rests on:
+/// that every loop moving the pointer by a varying amount keeps it on the
+/// tape, and that moves taking a pointer whose position is known off the
+/// tape never run. Empty when the pointer provably stays on it.
+pub fn assumptions(prog: &Prog, opts: &CompileOptions) -> Vec<Assumption> {
+    let mut out = Vec::new();
+    if opts.shared_tape {
+        out.push(Assumption {
+            span: None,
+            what: "the program must start on a cell leaving room on the tape for its moves"
+                .to_string(),
+        });
+    }
+    // on a shared tape the program starts wherever it was left
+    let start = (!opts.shared_tape).then_some(0);
+    walk(prog, start, opts.tape_len as i64, &mut out);
+    out
+}
+
+/// Follows the pointer through `block` from `pos`, when known.
+fn walk(block: &NodeBlock, mut pos: Option<i64>, tape_len: i64, out: &mut Vec<Assumption>) {
+    let off_tape = |span: Span, cell: i64| Assumption {
+        span: Some(span),
+        what: format!(
+            "this statement must never run, it reaches cell {} of a tape of {}",
+            cell, tape_len
+        ),
+    };
+    for stat in &block.stats {
+        let span = stat.attr.span;
+        match &stat.stat {
+            Statement::MoveL(n) | Statement::MoveR(n) => {
+                let by = i64::try_from(*n).unwrap_or(i64::MAX);
+                let by = if let Statement::MoveL(_) = stat.stat {
+                    -by
+                } else {
+                    by
+                };
+                pos = pos.and_then(|p| p.checked_add(by));
+                if let Some(p) = pos.filter(|p| !(0..tape_len).contains(p)) {
+                    out.push(off_tape(span, p));
+                }
+            }
+            Statement::MulAdd { offset, .. } => {
+                if let Some(p) = pos
+                    .and_then(|p| p.checked_add(*offset as i64))
+                    .filter(|p| !(0..tape_len).contains(p))
+                {
+                    out.push(off_tape(span, p));
+                }
+            }
+            Statement::Loop(body) | Statement::If(body) if stat.attr.metrics.balanced => {
+                walk(body, pos, tape_len, out);
+            }
+            Statement::Loop(_) | Statement::If(_) | Statement::Scan(_) => {
+                out.push(Assumption {
+                    span: Some(span),
+                    what: "this loop must keep the pointer on the tape, it moves it a \
+                           different amount each time round"
+                        .to_string(),
+                });
+                if let Statement::Loop(body) | Statement::If(body) = &stat.stat {
+                    walk(body, None, tape_len, out);
+                }
+                pos = None;
+            }
+            Statement::Add(_)
+            | Statement::Sub(_)
+            | Statement::Set(_)
+            | Statement::Fill(_)
+            | Statement::Print(_)
+            | Statement::Read
+            | Statement::Write => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unbalanced_loops_and_known_moves() {
+        let opts = CompileOptions {
+            tape_len: 4,
+            ..Default::default()
+        };
+        let prog = crate::parse("+[->+<]>>>\n[>]<<<<<").unwrap();
+        let found: Vec<_> = assumptions(&prog, &opts)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            found,
+            ["2:1: this loop must keep the pointer on the tape, it moves it a different amount each time round"]
+        );
+        let prog = crate::parse("+[->+<]>>>>").unwrap();
+        assert_eq!(
+            assumptions(&prog, &opts)[0].to_string(),
+            "1:8: this statement must never run, it reaches cell 4 of a tape of 4"
+        );
+        assert!(assumptions(&crate::parse("+[->+<]>>>").unwrap(), &opts).is_empty());
+    }
+}
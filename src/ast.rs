@@ -1,31 +1,45 @@
-use core::slice;
 use std::{
     fmt::{self, Display},
-    iter, usize,
+    iter,
 };
 
-use crate::lex::Token;
-
-type ParserResult<I, O, E> = Result<(I, O), ParserError<E>>;
+use crate::{
+    combinator::{
+        all_consuming, alt, cut, many, map, preceded, satisfy, terminated, token, PResult,
+        ParseError, TokenError,
+    },
+    lex::{Gap, Span, Token},
+};
 
-#[derive(Debug)]
-enum ParserError<E> {
-    Err(E),
-    Failure(E),
-}
+/// Deepest loop nesting accepted by [`parse`]. Parsing, code generation
+/// and even dropping the tree recurse once per level, so without a bound a
+/// hostile input could exhaust the stack.
+pub const MAX_NESTING: usize = 256;
 
 #[derive(Debug)]
 pub enum Error {
     UnexpectedToken(Token),
     EndOfInput,
+    TooDeep,
 }
 
-fn take_one_of(input: Tokens, of: Token) -> ParserResult<Tokens, Token, Error> {
-    let i = input.first().ok_or(ParserError::Err(Error::EndOfInput))?;
-    if i == &of {
-        Ok((&input[1..], of))
-    } else {
-        Err(ParserError::Err(Error::UnexpectedToken(i.clone())))
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnexpectedToken(t) => write!(f, "unexpected token {:?}", t),
+            Error::EndOfInput => write!(f, "unexpected end of input"),
+            Error::TooDeep => write!(f, "loops nested deeper than {}", MAX_NESTING),
+        }
+    }
+}
+
+impl TokenError<Token> for Error {
+    fn unexpected(token: &Token) -> Self {
+        Error::UnexpectedToken(token.clone())
+    }
+
+    fn end_of_input() -> Self {
+        Error::EndOfInput
     }
 }
 
@@ -35,7 +49,7 @@ trait Node
 where
     Self: Sized,
 {
-    fn parse(input: Tokens) -> ParserResult<Tokens, Self, Error>;
+    fn parse(input: Tokens) -> PResult<Token, Self, Error>;
     fn print(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result;
 }
 
@@ -48,35 +62,60 @@ impl Display for Ast {
     }
 }
 
-#[derive(Debug, Default, PartialEq)]
-pub struct Attr {}
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Attr {
+    /// Where the node starts, for loop bodies where their `]` is.
+    pub span: Span,
+    /// Comments around the node, only kept by [`crate::parse_with_trivia`].
+    pub trivia: Trivia,
+    /// The shape of the node, see [`attach_metrics`].
+    pub metrics: Metrics,
+    /// Whether the node is a loop the optimization passes have to leave as
+    /// it is, body included, see [`attach_no_opt`].
+    pub no_opt: bool,
+}
 
-#[derive(Debug, PartialEq)]
+/// Facts about a node and everything in it, worked out once for the whole
+/// tree by [`attach_metrics`] so that passes and tools can look them up.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// Loops around the node.
+    pub depth: usize,
+    /// Statements in the node, counting itself and those nested in loops.
+    /// For blocks, only the statements in them.
+    pub size: usize,
+    /// Whether the node reads or writes.
+    pub io: bool,
+    /// Whether the pointer always ends up where it was before the node:
+    /// loops and blocks whose moves cancel out, those of nested loops
+    /// included, and statements that do not move it.
+    pub balanced: bool,
+}
+
+/// Source text around a node that is not code, verbatim.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Trivia {
+    /// Text before the node. For blocks, which start with their first
+    /// statement, always empty.
+    pub leading: String,
+    /// Text after the node up to the end of its last line. For blocks, the
+    /// text between their last statement and the end of the block.
+    pub trailing: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct NodeBlock {
     pub attr: Attr,
     pub stats: Vec<NodeStatement>,
 }
 
 impl Node for NodeBlock {
-    fn parse(input: Tokens) -> ParserResult<Tokens, Self, Error> {
-        let mut input = input;
-        let mut node = Self {
+    fn parse(input: Tokens) -> PResult<Token, Self, Error> {
+        let stats = many(NodeStatement::parse);
+        map(stats, |stats| NodeBlock {
             attr: Attr::default(),
-            stats: Vec::new(),
-        };
-
-        loop {
-            match NodeStatement::parse(input) {
-                Err(ParserError::Failure(f)) => return ParserResult::Err(ParserError::Failure(f)),
-                Err(ParserError::Err(_)) => break,
-                Ok((rest, stat)) => {
-                    input = rest;
-                    node.stats.push(stat);
-                }
-            };
-        }
-
-        ParserResult::Ok((input, node))
+            stats,
+        })(input)
     }
 
     fn print(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
@@ -87,72 +126,32 @@ impl Node for NodeBlock {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct NodeStatement {
     pub attr: Attr,
     pub stat: Statement,
 }
 
 impl Node for NodeStatement {
-    fn parse(input: Tokens) -> ParserResult<Tokens, Self, Error> {
-        let t = input.first().ok_or(ParserError::Err(Error::EndOfInput))?;
-        match t {
-            Token::MoveL(count) => ParserResult::Ok((
-                &input[1..],
-                NodeStatement {
-                    attr: Attr::default(),
-                    stat: Statement::MoveL(*count),
-                },
-            )),
-            Token::MoveR(count) => ParserResult::Ok((
-                &input[1..],
-                NodeStatement {
-                    attr: Attr::default(),
-                    stat: Statement::MoveR(*count),
-                },
-            )),
-            Token::Read => ParserResult::Ok((
-                &input[1..],
-                NodeStatement {
-                    attr: Attr::default(),
-                    stat: Statement::Read,
-                },
-            )),
-            Token::Write => ParserResult::Ok((
-                &input[1..],
-                NodeStatement {
-                    attr: Attr::default(),
-                    stat: Statement::Write,
-                },
-            )),
-            Token::Inc(count) => ParserResult::Ok((
-                &input[1..],
-                NodeStatement {
-                    attr: Attr::default(),
-                    stat: Statement::Add(*count),
-                },
-            )),
-            Token::Dec(count) => ParserResult::Ok((
-                &input[1..],
-                NodeStatement {
-                    attr: Attr::default(),
-                    stat: Statement::Sub(*count),
-                },
-            )),
-            Token::JmpZero => {
-                let (input, block) = NodeBlock::parse(&input[1..])?;
-                let (input, _) = take_one_of(input, Token::JmpNoZero)?;
-
-                ParserResult::Ok((
-                    input,
-                    NodeStatement {
-                        attr: Attr::default(),
-                        stat: Statement::Loop(Box::new(block)),
-                    },
-                ))
-            }
-            _ => ParserResult::Err(ParserError::Err(Error::UnexpectedToken(t.clone()))),
-        }
+    fn parse(input: Tokens) -> PResult<Token, Self, Error> {
+        let simple = satisfy(|t: &Token| match t {
+            Token::MoveL(count) => Some(Statement::MoveL(*count)),
+            Token::MoveR(count) => Some(Statement::MoveR(*count)),
+            Token::Read => Some(Statement::Read),
+            Token::Write => Some(Statement::Write),
+            Token::Inc(count) => Some(Statement::Add(*count)),
+            Token::Dec(count) => Some(Statement::Sub(*count)),
+            Token::JmpZero | Token::JmpNoZero => None,
+        });
+        // past its `[`, a loop has to be closed
+        let body = cut(terminated(NodeBlock::parse, token(Token::JmpNoZero)));
+        let lp = map(preceded(token(Token::JmpZero), body), |block| {
+            Statement::Loop(Box::new(block))
+        });
+        map(alt(simple, lp), |stat| NodeStatement {
+            attr: Attr::default(),
+            stat,
+        })(input)
     }
 
     fn print(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
@@ -161,12 +160,16 @@ impl Node for NodeStatement {
                 writeln!(f, "{:indent$}Loop", "", indent = depth * 2)?;
                 block.print(f, depth + 1)
             }
-            _ => writeln!(f, "{:indent$}{:?}", "", self.stat, indent = depth * 2),
+            Statement::If(block) => {
+                writeln!(f, "{:indent$}If", "", indent = depth * 2)?;
+                block.print(f, depth + 1)
+            }
+            _ => writeln!(f, "{:indent$}{}", "", self.stat, indent = depth * 2),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
     MoveL(usize),
     MoveR(usize),
@@ -175,26 +178,285 @@ pub enum Statement {
     Read,
     Write,
     Loop(Box<NodeBlock>),
+    /// Stores a value in the cell, never parsed but put in by passes, such
+    /// as for `[-]`.
+    Set(u32),
+    /// Adds the cell times `factor` to the cell `offset` away, never parsed
+    /// but put in by passes for multiply loops. The pointer stays put, but
+    /// the program ends with status 1 when the other cell is off the tape,
+    /// as it would moving there.
+    MulAdd {
+        offset: isize,
+        factor: u32,
+    },
+    /// Moves the pointer `stride` cells at a time, right being positive,
+    /// until it is on a zero cell, never parsed but put in by passes for
+    /// loops such as `[>]` and `[<<]`. Each move is checked like a
+    /// [`Statement::MoveR`] or [`Statement::MoveL`] would be.
+    Scan(isize),
+    /// Runs the block when the cell is not zero, never parsed but put in by
+    /// passes for loops whose body always leaves the cell zero, which
+    /// would go round at most once.
+    If(Box<NodeBlock>),
+    /// Stores the values in the cells from the current one on, the pointer
+    /// staying put, never parsed but put in by passes for tape contents
+    /// worked out while compiling. Passes only put it where the cells are
+    /// known to be on the tape, so it is not bounds checked.
+    Fill(Vec<u32>),
+    /// Writes the bytes as a [`Statement::Write`] of each in turn would,
+    /// leaving the last of them in the cell, never parsed but put in by
+    /// passes for output worked out while compiling. Never empty.
+    Print(Vec<u8>),
+}
+
+/// One line per statement in the tree printer. Spelled out rather than
+/// derived from `Debug` so the output stays put for snapshot tests as
+/// statements grow fields.
+impl Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Statement::MoveL(n) => write!(f, "MoveL({})", n),
+            Statement::MoveR(n) => write!(f, "MoveR({})", n),
+            Statement::Add(n) => write!(f, "Add({})", n),
+            Statement::Sub(n) => write!(f, "Sub({})", n),
+            Statement::Read => write!(f, "Read"),
+            Statement::Write => write!(f, "Write"),
+            Statement::Loop(_) => write!(f, "Loop"),
+            Statement::If(_) => write!(f, "If"),
+            Statement::Fill(values) => {
+                let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "Fill({})", values.join(", "))
+            }
+            Statement::Print(bytes) => write!(f, "Print(\"{}\")", bytes.escape_ascii()),
+            Statement::Set(n) => write!(f, "Set({})", n),
+            Statement::MulAdd { offset, factor } => write!(f, "MulAdd({}, {})", offset, factor),
+            Statement::Scan(stride) => write!(f, "Scan({})", stride),
+        }
+    }
+}
+
+/// A problem with the source, located at the token causing it.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line, self.span.col, self.message)
+    }
+}
+
+/// Finds every bracket that does not pair up and every loop nested too deep
+/// without building a tree. Brackets are the only thing that can make a
+/// lexed program fail to parse, so no diagnostics means [`parse`] succeeds.
+pub fn check(tokens: &[(Token, Span)]) -> Vec<Diagnostic> {
+    let mut open = Vec::new();
+    let mut diags = Vec::new();
+    for (token, span) in tokens {
+        match token {
+            Token::JmpZero => {
+                open.push(*span);
+                if open.len() == MAX_NESTING + 1 {
+                    diags.push(Diagnostic {
+                        span: *span,
+                        message: Error::TooDeep.to_string(),
+                    });
+                }
+            }
+            Token::JmpNoZero if open.pop().is_none() => diags.push(Diagnostic {
+                span: *span,
+                message: "unmatched `]`".to_string(),
+            }),
+            _ => {}
+        }
+    }
+    diags.extend(open.into_iter().map(|span| Diagnostic {
+        span,
+        message: "unclosed `[`".to_string(),
+    }));
+    diags.sort_by_key(|d| (d.span.line, d.span.col));
+    diags
+}
+
+fn check_nesting(input: Tokens) -> Result<(), Error> {
+    let mut depth = 0usize;
+    for t in input {
+        match t {
+            Token::JmpZero => depth += 1,
+            Token::JmpNoZero => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if depth > MAX_NESTING {
+            return Err(Error::TooDeep);
+        }
+    }
+    Ok(())
 }
 
 pub fn parse(input: Tokens) -> Result<Ast, Error> {
-    match NodeBlock::parse(input) {
-        Err(ParserError::Err(e)) => Err(e),
-        Err(ParserError::Failure(e)) => Err(e),
-        Ok((rest, ast)) => {
-            if !rest.is_empty() {
-                Err(Error::EndOfInput)
-            } else {
-                Ok(ast)
+    check_nesting(input)?;
+    all_consuming(NodeBlock::parse)(input)
+        .map(|(_, ast)| ast)
+        .map_err(ParseError::into_inner)
+}
+
+/// Records where every statement came from, given the spans of the tokens
+/// the tree was parsed from (see [`crate::lex::lex_spanned`]). Each
+/// statement owns one token, loops a second one for their closing bracket,
+/// which becomes the span of the loop's body.
+pub fn attach_spans(ast: &mut Ast, spans: &[Span]) {
+    fn walk<'a>(block: &mut NodeBlock, spans: &mut impl Iterator<Item = &'a Span>) {
+        for stat in &mut block.stats {
+            stat.attr.span = spans.next().copied().unwrap_or_default();
+            if let Statement::Loop(body) = &mut stat.stat {
+                walk(body, spans);
+                body.attr.span = spans.next().copied().unwrap_or_default();
             }
         }
     }
+
+    walk(ast, &mut spans.iter());
+}
+
+/// Marks the loops starting at `spans` as ones the passes leave alone,
+/// given where those with a [`crate::lex::NO_OPT`] pragma before them start
+/// (see [`crate::lex::no_opt_loops`]). Spans have to be attached first.
+pub fn attach_no_opt(ast: &mut Ast, spans: &[Span]) {
+    for stat in &mut ast.stats {
+        if let Statement::Loop(body) = &mut stat.stat {
+            stat.attr.no_opt = spans.contains(&stat.attr.span);
+            attach_no_opt(body, spans);
+        }
+    }
+}
+
+/// Fills in the [`Metrics`] of every node in the tree, in one walk. The
+/// parser does it for [`crate::parse`], and so does anything changing the
+/// tree afterwards, so that they stay true.
+pub fn attach_metrics(ast: &mut Ast) {
+    fn walk(block: &mut NodeBlock, depth: usize) {
+        let mut metrics = Metrics {
+            depth,
+            balanced: true,
+            ..Default::default()
+        };
+        let mut shift = 0i64;
+        for stat in &mut block.stats {
+            let mut own = Metrics {
+                depth,
+                size: 1,
+                io: false,
+                balanced: true,
+            };
+            match &mut stat.stat {
+                Statement::MoveL(n) => {
+                    shift -= *n as i64;
+                    own.balanced = false;
+                }
+                Statement::MoveR(n) => {
+                    shift += *n as i64;
+                    own.balanced = false;
+                }
+                Statement::Scan(_) => {
+                    own.balanced = false;
+                    metrics.balanced = false;
+                }
+                Statement::Add(_)
+                | Statement::Sub(_)
+                | Statement::Set(_)
+                | Statement::MulAdd { .. }
+                | Statement::Fill(_) => {}
+                Statement::Read | Statement::Write | Statement::Print(_) => own.io = true,
+                Statement::Loop(body) | Statement::If(body) => {
+                    walk(body, depth + 1);
+                    own.size += body.attr.metrics.size;
+                    own.io = body.attr.metrics.io;
+                    own.balanced = body.attr.metrics.balanced;
+                    metrics.balanced &= own.balanced;
+                }
+            }
+            stat.attr.metrics = own;
+            metrics.size += own.size;
+            metrics.io |= own.io;
+        }
+        metrics.balanced &= shift == 0;
+        block.attr.metrics = metrics;
+    }
+
+    walk(ast, 0);
+}
+
+/// The statements a [`Statement::Fill`] of `values` comes to, for backends
+/// with no way of copying data onto the tape: a `Set` of each cell, moving
+/// right from one to the next, and a move back to the first.
+pub fn spell_out_fill(values: &[u32]) -> NodeBlock {
+    let stat = |stat| NodeStatement {
+        attr: Attr::default(),
+        stat,
+    };
+    let mut stats = Vec::with_capacity(values.len() * 2);
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            stats.push(stat(Statement::MoveR(1)));
+        }
+        stats.push(stat(Statement::Set(*value)));
+    }
+    if values.len() > 1 {
+        stats.push(stat(Statement::MoveL(values.len() - 1)));
+    }
+    NodeBlock {
+        attr: Attr::default(),
+        stats,
+    }
+}
+
+/// The statements a [`Statement::Print`] of `bytes` comes to, for backends
+/// with no way of writing data: a `Set` of the cell to each byte and a
+/// `Write` of it.
+pub fn spell_out_print(bytes: &[u8]) -> NodeBlock {
+    let stat = |stat| NodeStatement {
+        attr: Attr::default(),
+        stat,
+    };
+    let stats = bytes
+        .iter()
+        .flat_map(|byte| [stat(Statement::Set(*byte as u32)), stat(Statement::Write)])
+        .collect();
+    NodeBlock {
+        attr: Attr::default(),
+        stats,
+    }
+}
+
+/// Hands the gaps from [`crate::lex::lex_trivia`] out to the nodes of the
+/// tree parsed from the same tokens, see [`Trivia`].
+pub fn attach_trivia(ast: &mut Ast, gaps: Vec<Gap>) {
+    fn walk(block: &mut NodeBlock, gaps: &mut iter::Peekable<std::vec::IntoIter<Gap>>) {
+        for stat in &mut block.stats {
+            stat.attr.trivia.leading = gaps.next().unwrap_or_default().leading;
+            if let Statement::Loop(body) = &mut stat.stat {
+                walk(body, gaps);
+                // the gap before `]`, its trailing part went to the last
+                // statement of the body
+                body.attr.trivia.trailing = gaps.next().unwrap_or_default().leading;
+            }
+            if let Some(gap) = gaps.peek_mut() {
+                stat.attr.trivia.trailing = std::mem::take(&mut gap.trailing);
+            }
+        }
+    }
+
+    let mut gaps = gaps.into_iter().peekable();
+    walk(ast, &mut gaps);
+    ast.attr.trivia.trailing = gaps.next().unwrap_or_default().leading;
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::lex::Token;
+    use crate::lex::{Span, Token};
 
     #[test]
     fn parse_statement() {
@@ -209,6 +471,104 @@ mod test {
         assert_eq!(ast.stat, Statement::MoveR(1));
     }
 
+    #[test]
+    fn print_tree() {
+        let ast = parse(&crate::lex::lex("+++[>,.<-]".to_string())).unwrap();
+        assert_eq!(
+            ast.to_string(),
+            "Block\n  Add(3)\n  Loop\n    Block\n      MoveR(1)\n      Read\n      Write\n      MoveL(1)\n      Sub(1)\n"
+        );
+    }
+
+    #[test]
+    fn attach_comments() {
+        let src = "init\n+ one\n[ body\n- dec\nend ] two\n eof";
+        let (tokens, gaps) = crate::lex::lex_trivia(src);
+        let tokens: Vec<_> = tokens.into_iter().map(|(t, _)| t).collect();
+        let mut ast = parse(&tokens).unwrap();
+        attach_trivia(&mut ast, gaps);
+
+        fn trivia(stat: &NodeStatement) -> (&str, &str) {
+            (&stat.attr.trivia.leading, &stat.attr.trivia.trailing)
+        }
+        assert_eq!(trivia(&ast.stats[0]), ("init\n", " one"));
+        assert_eq!(trivia(&ast.stats[1]), ("\n", " two"));
+        let Statement::Loop(body) = &ast.stats[1].stat else {
+            panic!("expected a loop");
+        };
+        assert_eq!(trivia(&body.stats[0]), (" body\n", " dec"));
+        assert_eq!(body.attr.trivia.trailing, "\nend ");
+        assert_eq!(ast.attr.trivia.trailing, "\n eof");
+    }
+
+    #[test]
+    fn check_brackets() {
+        let tokens = crate::lex::lex_spanned("]+[\n[-]\n[");
+        let diags: Vec<_> = check(&tokens).iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diags,
+            [
+                "1:1: unmatched `]`",
+                "1:3: unclosed `[`",
+                "3:1: unclosed `[`"
+            ]
+        );
+        assert!(check(&crate::lex::lex_spanned("+[-[>]<]")).is_empty());
+    }
+
+    #[test]
+    fn parse_too_deep() {
+        let mut input = vec![Token::JmpZero; MAX_NESTING + 1];
+        input.extend(vec![Token::JmpNoZero; MAX_NESTING + 1]);
+        assert!(matches!(parse(&input), Err(Error::TooDeep)));
+
+        let input = [&input[1..MAX_NESTING + 1], &input[MAX_NESTING + 2..]].concat();
+        assert!(parse(&input).is_ok());
+    }
+
+    #[test]
+    fn attach_statement_spans() {
+        let tokens = crate::lex::lex_spanned("+[\n>]\n.");
+        let (tokens, spans): (Vec<_>, Vec<_>) = tokens.into_iter().unzip();
+        let mut ast = parse(&tokens).unwrap();
+        attach_spans(&mut ast, &spans);
+        assert_eq!(ast.stats[1].attr.span, Span { line: 1, col: 2 });
+        assert_eq!(ast.stats[2].attr.span, Span { line: 3, col: 1 });
+        if let Statement::Loop(body) = &ast.stats[1].stat {
+            assert_eq!(body.stats[0].attr.span, Span { line: 2, col: 1 });
+            assert_eq!(body.attr.span, Span { line: 2, col: 2 });
+        }
+    }
+
+    #[test]
+    fn attach_node_metrics() {
+        let ast = crate::parse("+[>[-]<]>[[.]>]").unwrap();
+        assert_eq!(
+            ast.attr.metrics,
+            Metrics {
+                depth: 0,
+                size: 11,
+                io: true,
+                balanced: false,
+            }
+        );
+        assert_eq!(
+            ast.stats[1].attr.metrics,
+            Metrics {
+                depth: 0,
+                size: 5,
+                io: false,
+                balanced: true,
+            }
+        );
+        assert!(!ast.stats[3].attr.metrics.balanced);
+        assert!(ast.stats[3].attr.metrics.io);
+        if let Statement::Loop(body) = &ast.stats[3].stat {
+            assert_eq!(body.stats[0].attr.metrics.depth, 1);
+            assert!(body.stats[0].attr.metrics.balanced);
+        }
+    }
+
     #[test]
     fn parse_block_1() {
         let input = vec![Token::MoveL(1), Token::MoveR(1)];
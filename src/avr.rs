@@ -0,0 +1,377 @@
+//! AVR assembly for Arduino boards, reading and writing the first UART
+//! rather than making system calls. The output is meant for `avr-gcc`: it is
+//! preprocessed for the register names of `<avr/io.h>`, which are those of
+//! the ATmega328P of the Uno and Nano, and links against avr-libc's startup
+//! code, which clears the tape and calls `main`.
+//!
+//! SRAM is measured in kilobytes, so cells are bytes here rather than the
+//! 32 bits of the other backends, and the tape has to be sized to fit with
+//! `--tape-len`. The UART runs at `BAUD`, 9600 unless defined otherwise, for
+//! a clock of `F_CPU`, 16 MHz unless defined otherwise; both can be set with
+//! `-D` through `--link-arg`. Input never ends, `,` waits for the next byte.
+//! There is nothing to return to either, so the program ends, normally or
+//! by moving off the tape, by disabling interrupts and spinning.
+//!
+//! `X` holds the cell pointer. `r24` carries bytes to and from the runtime
+//! routines, which only touch `r24`, `r25` and `Z`. Cells being bytes, scans
+//! for a zero cell to the right are avr-libc's `memchr`.
+
+use std::fmt::Write;
+
+use crate::{ast, backend::CodeGen, CompileOptions, Error, Newline};
+
+/// Largest tape `X` can address.
+const MAX_TAPE_LEN: usize = 0xffff;
+
+const READ_ERR: &str = "bfc: read error\\n";
+
+pub struct AvrGenerator {
+    label_counter: usize,
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    debug_file: Option<String>,
+    text: String,
+}
+
+impl Default for AvrGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for AvrGenerator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl AvrGenerator {
+    pub fn new() -> Self {
+        AvrGenerator {
+            label_counter: 0,
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            debug_file: None,
+            text: String::new(),
+        }
+    }
+
+    /// Fails for tapes larger than the address space. The target is left
+    /// alone, those name the machines QBE knows.
+    pub fn with_options(opts: &CompileOptions) -> Result<Self, Error> {
+        if opts.tape_len > MAX_TAPE_LEN {
+            return Err(Error::Unsupported(
+                "AVR code supports tapes of up to 65535 cells",
+            ));
+        }
+        Ok(AvrGenerator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            debug_file: opts.debug_file.clone(),
+            ..Self::new()
+        })
+    }
+
+    pub fn gen(&mut self, prog: &ast::Prog) -> String {
+        self.text.push_str("#include <avr/io.h>\n\n");
+        self.text
+            .push_str("#ifndef F_CPU\n#define F_CPU 16000000\n#endif\n");
+        self.text
+            .push_str("#ifndef BAUD\n#define BAUD 9600\n#endif\n\n");
+        if let Some(file) = &self.debug_file {
+            let file = file.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(self.text, "\t.file 1 \"{}\"", file).unwrap();
+        }
+        // the startup code only clears .bss for those asking
+        self.text
+            .push_str("\t.global __do_clear_bss\n\t.text\n\t.global main\nmain:\n");
+        self.instr("ldi r24, lo8(F_CPU / 16 / BAUD - 1)");
+        self.instr("sts _SFR_MEM_ADDR(UBRR0L), r24");
+        self.instr("ldi r24, hi8(F_CPU / 16 / BAUD - 1)");
+        self.instr("sts _SFR_MEM_ADDR(UBRR0H), r24");
+        self.instr("ldi r24, (1 << RXEN0) | (1 << TXEN0)");
+        self.instr("sts _SFR_MEM_ADDR(UCSR0B), r24");
+        // 8 data bits, no parity, one stop bit
+        self.instr("ldi r24, (1 << UCSZ01) | (1 << UCSZ00)");
+        self.instr("sts _SFR_MEM_ADDR(UCSR0C), r24");
+        self.instr("ldi r26, lo8(bf_tape)");
+        self.instr("ldi r27, hi8(bf_tape)");
+        self.generate_block(prog);
+        self.instr("jmp bf_exit");
+
+        self.generate_runtime();
+        writeln!(
+            self.text,
+            "\n\t.section .bss\nbf_tape:\n\t.zero {}",
+            self.tape_len
+        )
+        .unwrap();
+        if self.io_strict {
+            writeln!(
+                self.text,
+                "\n\t.section .progmem.data\nbf_read_err:\n\t.asciz \"{}\"",
+                READ_ERR
+            )
+            .unwrap();
+        }
+        std::mem::take(&mut self.text)
+    }
+
+    fn generate_block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.generate_statement(stat)
+        }
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) {
+        if self.debug_file.is_some() {
+            let span = stat.attr.span;
+            self.instr(&format!(".loc 1 {} {}", span.line, span.col));
+        }
+
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move("-", *n),
+            ast::Statement::MoveR(n) => self.generate_move("+", *n),
+            ast::Statement::Add(n) => {
+                self.instr("ld r24, X");
+                // there is no add immediate, subtract the negation
+                self.instr(&format!("subi r24, lo8(-({}))", *n as u8));
+                self.instr("st X, r24");
+            }
+            ast::Statement::Sub(n) => {
+                self.instr("ld r24, X");
+                self.instr(&format!("subi r24, lo8({})", *n as u8));
+                self.instr("st X, r24");
+            }
+            ast::Statement::Set(n) => {
+                self.instr(&format!("ldi r24, {}", *n as u8));
+                self.instr("st X, r24");
+            }
+            ast::Statement::MulAdd { offset, factor } => {
+                // `mul` leaves the product in r1:r0, and avr-gcc counts on
+                // r1 being zero; moves leave r22 alone
+                self.instr("ld r22, X");
+                self.instr(&format!("ldi r23, {}", *factor as u8));
+                self.instr("mul r22, r23");
+                self.instr("mov r22, r0");
+                self.instr("clr r1");
+                let n = offset.unsigned_abs();
+                let (there, back) = if *offset < 0 { ("-", "+") } else { ("+", "-") };
+                self.generate_move(there, n);
+                self.instr("ld r24, X");
+                self.instr("add r24, r22");
+                self.instr("st X, r24");
+                self.generate_move(back, n);
+            }
+            ast::Statement::Read => {
+                self.instr("call bf_getc");
+                self.instr("st X, r24");
+            }
+            ast::Statement::Write => {
+                self.instr("ld r24, X");
+                if self.crlf {
+                    let plain = self.generate_label("char");
+                    self.instr("cpi r24, 10");
+                    self.instr(&format!("brne {}", plain));
+                    self.instr("ldi r24, 13");
+                    self.instr("call bf_putc");
+                    self.instr("ldi r24, 10");
+                    self.label(&plain);
+                }
+                self.instr("call bf_putc");
+            }
+            ast::Statement::Scan(1) => {
+                // memchr(X, 0, cells from X to the end of the tape), which
+                // is NULL when the scan would move off it
+                self.instr("movw r24, r26");
+                self.instr("ldi r22, 0");
+                self.instr("ldi r23, 0");
+                self.instr(&format!("ldi r20, lo8(bf_tape + {})", self.tape_len));
+                self.instr(&format!("ldi r21, hi8(bf_tape + {})", self.tape_len));
+                self.instr("sub r20, r26");
+                self.instr("sbc r21, r27");
+                self.instr("call memchr");
+                let found = self.generate_label("found");
+                self.instr("sbiw r24, 0");
+                self.instr(&format!("brne {}", found));
+                self.instr("jmp bf_exit");
+                self.label(&found);
+                self.instr("movw r26, r24");
+            }
+            ast::Statement::Scan(stride) => {
+                let step = self.generate_label("scan");
+                let end = self.generate_label("scanned");
+                self.instr("ld r24, X");
+                self.instr("tst r24");
+                self.instr(&format!("brne {}", step));
+                self.instr(&format!("jmp {}", end));
+                self.label(&step);
+                let op = if *stride < 0 { "-" } else { "+" };
+                self.generate_move(op, stride.unsigned_abs());
+                self.instr("ld r24, X");
+                self.instr("tst r24");
+                self.instr(&format!("breq {}", end));
+                self.instr(&format!("jmp {}", step));
+                self.label(&end);
+            }
+            ast::Statement::Loop(body) => {
+                // branches reach 64 words, so each skips over a `jmp`
+                let start = self.generate_label("loop");
+                let end = self.generate_label("end");
+                self.instr("ld r24, X");
+                self.instr("tst r24");
+                self.instr(&format!("brne {}", start));
+                self.instr(&format!("jmp {}", end));
+                self.label(&start);
+                self.generate_block(body);
+                self.instr("ld r24, X");
+                self.instr("tst r24");
+                self.instr(&format!("breq {}", end));
+                self.instr(&format!("jmp {}", start));
+                self.label(&end);
+            }
+            ast::Statement::If(body) => {
+                let then = self.generate_label("then");
+                let end = self.generate_label("fi");
+                self.instr("ld r24, X");
+                self.instr("tst r24");
+                self.instr(&format!("brne {}", then));
+                self.instr(&format!("jmp {}", end));
+                self.label(&then);
+                self.generate_block(body);
+                self.label(&end);
+            }
+            // there is no data to copy from, see `spell_out_fill`
+            ast::Statement::Fill(values) => self.generate_block(&ast::spell_out_fill(values)),
+            // nor any to write from, see `spell_out_print`
+            ast::Statement::Print(bytes) => self.generate_block(&ast::spell_out_print(bytes)),
+        }
+    }
+
+    /// Moves the pointer by `n` cells, `op` being `+` or `-`, and stops the
+    /// program when it ends up off the tape.
+    fn generate_move(&mut self, op: &str, n: usize) {
+        if n >= self.tape_len {
+            self.instr("jmp bf_exit");
+            return;
+        }
+        let n = match op {
+            "+" => format!("-{}", n),
+            _ => n.to_string(),
+        };
+        self.instr(&format!("subi r26, lo8({})", n));
+        self.instr(&format!("sbci r27, hi8({})", n));
+
+        // unsigned, so moving left of the tape wraps around past its end
+        let ok = self.generate_label("ok");
+        self.instr("movw r24, r26");
+        self.instr("subi r24, lo8(bf_tape)");
+        self.instr("sbci r25, hi8(bf_tape)");
+        self.instr(&format!("cpi r24, lo8({})", self.tape_len));
+        self.instr(&format!("ldi r30, hi8({})", self.tape_len));
+        self.instr("cpc r25, r30");
+        self.instr(&format!("brlo {}", ok));
+        self.instr("jmp bf_exit");
+        self.label(&ok);
+    }
+
+    /// `bf_putc` writes the byte in `r24` once the UART can take it,
+    /// `bf_getc` waits for the next byte and returns it in `r24`.
+    fn generate_runtime(&mut self) {
+        self.label("bf_exit");
+        self.instr("cli");
+        self.label(".Lhang");
+        self.instr("rjmp .Lhang");
+
+        self.label("bf_putc");
+        self.instr("lds r25, _SFR_MEM_ADDR(UCSR0A)");
+        self.instr("sbrs r25, UDRE0");
+        self.instr("rjmp bf_putc");
+        self.instr("sts _SFR_MEM_ADDR(UDR0), r24");
+        self.instr("ret");
+
+        self.label("bf_getc");
+        self.instr("lds r25, _SFR_MEM_ADDR(UCSR0A)");
+        self.instr("sbrs r25, RXC0");
+        self.instr("rjmp bf_getc");
+        if self.io_strict {
+            // the flags describe the byte about to be read
+            self.instr("andi r25, (1 << FE0) | (1 << DOR0) | (1 << UPE0)");
+            self.instr("brne .Lgetc_err");
+        }
+        self.instr("lds r24, _SFR_MEM_ADDR(UDR0)");
+        if self.crlf {
+            self.instr("cpi r24, 13");
+            self.instr("breq bf_getc");
+        }
+        self.instr("ret");
+        if self.io_strict {
+            self.label(".Lgetc_err");
+            // clears the error along with the byte
+            self.instr("lds r24, _SFR_MEM_ADDR(UDR0)");
+            self.instr("ldi r30, lo8(bf_read_err)");
+            self.instr("ldi r31, hi8(bf_read_err)");
+            self.label(".Lgetc_msg");
+            self.instr("lpm r24, Z+");
+            self.instr("tst r24");
+            self.instr("breq bf_exit");
+            self.instr("rcall bf_putc");
+            self.instr("rjmp .Lgetc_msg");
+        }
+    }
+
+    fn instr(&mut self, instr: &str) {
+        writeln!(self.text, "\t{}", instr).unwrap();
+    }
+
+    fn label(&mut self, label: &str) {
+        writeln!(self.text, "{}:", label).unwrap();
+    }
+
+    fn generate_label(&mut self, prefix: &str) -> String {
+        let c = self.label_counter;
+        self.label_counter += 1;
+        format!(".L{}{}", prefix, c)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn byte_cells() {
+        let opts = CompileOptions {
+            opt_level: crate::OptLevel::O2,
+            ..Default::default()
+        };
+        let prog = crate::optimize(",[->-<]>.", &opts).unwrap();
+        let asm = AvrGenerator::new().gen(&prog);
+        // avr-gcc counts on r1 being zero
+        assert!(
+            asm.contains("\tld r22, X\n\tldi r23, 255\n\tmul r22, r23\n\tmov r22, r0\n\tclr r1\n")
+        );
+        let asm = AvrGenerator::new().gen(&crate::parse(&"+".repeat(257)).unwrap());
+        assert!(asm.contains("\tld r24, X\n\tsubi r24, lo8(-(1))\n\tst X, r24\n"));
+    }
+
+    #[test]
+    fn scans_right_are_memchr() {
+        let opts = CompileOptions {
+            opt_level: crate::OptLevel::O2,
+            tape_len: 100,
+            ..Default::default()
+        };
+        let prog = crate::optimize("+[>]<[<]", &opts).unwrap();
+        let asm = AvrGenerator::with_options(&opts).unwrap().gen(&prog);
+        assert!(asm.contains("\tldi r20, lo8(bf_tape + 100)\n"));
+        assert!(asm.contains("\tcall memchr\n\tsbiw r24, 0\n"));
+        assert_eq!(asm.matches("call memchr").count(), 1);
+        assert!(asm.contains(".Lscan"));
+    }
+}
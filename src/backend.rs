@@ -0,0 +1,152 @@
+//! The interface every code generator implements, so that the pipeline in
+//! [`crate::compile_timed`] and the `--backend` selection treat them alike.
+//! Each generator is built from [`CompileOptions`] by its `with_options`,
+//! which is where a target or option it cannot handle is turned down, and
+//! then turns optimized syntax trees into its output.
+
+use crate::{
+    aarch64, ast, avr, c, gen, js, llvm, lua, python, riscv, x86, Backend, CompileOptions, Error,
+};
+
+pub trait CodeGen {
+    /// What the generated code comes as, source text for most backends.
+    type Output;
+
+    /// Generates the code running `prog`.
+    fn generate(&mut self, prog: &ast::Prog) -> Result<Self::Output, Error>;
+}
+
+/// The generator `opts.backend` names, for those producing text.
+pub fn generator(opts: &CompileOptions) -> Result<Box<dyn CodeGen<Output = String>>, Error> {
+    Ok(match opts.backend {
+        Backend::Qbe => Box::new(gen::QbeGenerator::with_options(opts)?),
+        Backend::Llvm => Box::new(llvm::LlvmGenerator::with_options(opts)),
+        Backend::X86_64Asm => Box::new(x86::X86Generator::with_options(opts)?),
+        Backend::Aarch64Asm => Box::new(aarch64::Aarch64Generator::with_options(opts)?),
+        Backend::Rv64Asm => Box::new(riscv::Rv64Generator::with_options(opts)?),
+        Backend::AvrAsm => Box::new(avr::AvrGenerator::with_options(opts)?),
+        Backend::C => Box::new(c::CGenerator::with_options(opts)),
+        Backend::Js => Box::new(js::JsGenerator::with_options(opts)),
+        Backend::Python => Box::new(python::PythonGenerator::with_options(opts)),
+        Backend::Lua => Box::new(lua::LuaGenerator::with_options(opts)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::Write,
+        path::Path,
+        process::{Command, Stdio},
+    };
+
+    use super::*;
+    use crate::{interp, OptLevel};
+
+    const BACKENDS: [Backend; 10] = [
+        Backend::Qbe,
+        Backend::Llvm,
+        Backend::X86_64Asm,
+        Backend::Aarch64Asm,
+        Backend::Rv64Asm,
+        Backend::AvrAsm,
+        Backend::C,
+        Backend::Js,
+        Backend::Python,
+        Backend::Lua,
+    ];
+
+    /// Moves both ways, nested loops, input up to its end, multiplying by a
+    /// negative factor and moving off the tape, with the input for each.
+    const PROGRAMS: [(&str, &[u8]); 4] = [
+        (",[.[-],]", b"hi\n"),
+        ("++++++[>++++++++<-]>+.>+[->>+<<]>>+.", b""),
+        (",[->-<]>.", b"\x02"),
+        ("+++[>+++[>+<-]<-]>>.<<<", b""),
+    ];
+
+    /// Runs `cmd` with `input` on stdin, `None` when it cannot be started.
+    fn run(cmd: &mut Command, input: &[u8]) -> Option<(Option<i32>, Vec<u8>)> {
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let out = child.wait_with_output().unwrap();
+        Some((out.status.code(), out.stdout))
+    }
+
+    /// Runs what `backend` generated, `None` when nothing here can run it.
+    fn execute(
+        backend: Backend,
+        code: &str,
+        dir: &Path,
+        input: &[u8],
+    ) -> Option<(Option<i32>, Vec<u8>)> {
+        let write = |ext: &str| {
+            let path = dir.join(format!("prog.{}", ext));
+            fs::write(&path, code).unwrap();
+            path
+        };
+        let exe = dir.join("prog");
+        let build = |ext: &str, extra: &[&str]| {
+            let built = Command::new("cc")
+                .args(extra)
+                .arg(write(ext))
+                .arg("-o")
+                .arg(&exe)
+                .stderr(Stdio::null())
+                .status();
+            built.is_ok_and(|s| s.success()).then_some(())
+        };
+        match backend {
+            Backend::C => build("c", &["-w"])?,
+            Backend::X86_64Asm if cfg!(all(target_arch = "x86_64", target_os = "linux")) => {
+                build("s", &["-nostdlib"])?
+            }
+            Backend::Js => return run(Command::new("node").arg(write("mjs")), input),
+            Backend::Python => return run(Command::new("python3").arg(write("py")), input),
+            Backend::Lua => return run(Command::new("lua").arg(write("lua")), input),
+            _ => return None,
+        }
+        run(&mut Command::new(&exe), input)
+    }
+
+    #[test]
+    fn backends_agree_with_the_interpreter() {
+        let dir = std::env::temp_dir().join(format!("bfc-backends-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (src, input) in PROGRAMS {
+            let expected = interp::run(&crate::parse(src).unwrap(), input, Default::default());
+            let status = match expected.status {
+                interp::Status::Finished => 0,
+                _ => 1,
+            };
+            for backend in BACKENDS {
+                for opt_level in [OptLevel::O0, OptLevel::O2] {
+                    let opts = CompileOptions {
+                        backend,
+                        opt_level,
+                        ..Default::default()
+                    };
+                    let prog = crate::optimize(src, &opts).unwrap();
+                    let code = generator(&opts).unwrap().generate(&prog).unwrap();
+                    if let Some(found) = execute(backend, &code, &dir, input) {
+                        assert_eq!(
+                            found,
+                            (Some(status), expected.output.clone()),
+                            "{} at {:?}: {}",
+                            backend,
+                            opt_level,
+                            src
+                        );
+                    }
+                }
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,341 @@
+//! A compact serialized form of the optimized tree, written to `.bfo` files
+//! for `bfc run` to start from without parsing and optimizing again, and
+//! for other tools to read.
+//!
+//! A file starts with [`MAGIC`], a version byte and the tape length the
+//! program was built for. Then comes its code, one opcode byte per
+//! statement, those with a count followed by it as an unsigned LEB128
+//! number. Loops are bracketed by [`LOOP`] and [`END`], like in the source.
+//! [`MULADD`] has two, its offset zigzag encoded first so that negative ones
+//! stay short, and [`SCAN`] has its stride zigzag encoded too. Version 2
+//! added [`SET`], version 3 [`MULADD`] and version 4 [`SCAN`], files of
+//! older versions are read all the same.
+
+use std::fmt::{self, Write};
+
+use crate::ast::{self, Attr, NodeBlock, NodeStatement, Statement};
+
+pub const MAGIC: [u8; 4] = *b"\0bfo";
+pub const VERSION: u8 = 4;
+
+pub const ADD: u8 = 0x01;
+pub const SUB: u8 = 0x02;
+pub const LEFT: u8 = 0x03;
+pub const RIGHT: u8 = 0x04;
+pub const READ: u8 = 0x05;
+pub const WRITE: u8 = 0x06;
+pub const LOOP: u8 = 0x07;
+pub const END: u8 = 0x08;
+pub const SET: u8 = 0x09;
+pub const MULADD: u8 = 0x0a;
+pub const SCAN: u8 = 0x0b;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The file does not start with [`MAGIC`].
+    NotBytecode,
+    Version(u8),
+    /// The file ends in the middle of something.
+    Truncated,
+    /// An unknown opcode, at the offset into the code.
+    Opcode {
+        at: usize,
+        op: u8,
+    },
+    /// A count that does not fit, at the offset into the code.
+    Count(usize),
+    /// An [`END`] without a loop, or a loop without one.
+    Unbalanced,
+    /// Loops nested deeper than [`ast::MAX_NESTING`].
+    TooDeep,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotBytecode => write!(f, "not a bytecode file"),
+            Error::Version(v) => write!(f, "bytecode version {} is not supported", v),
+            Error::Truncated => write!(f, "the file is cut short"),
+            Error::Opcode { at, op } => write!(f, "unknown opcode {:#04x} at {:#06x}", op, at),
+            Error::Count(at) => write!(f, "count out of range at {:#06x}", at),
+            Error::Unbalanced => write!(f, "unbalanced loops"),
+            Error::TooDeep => write!(f, "loops nested deeper than {}", ast::MAX_NESTING),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A program read back from bytecode, without source spans.
+#[derive(Debug, PartialEq)]
+pub struct Module {
+    pub tape_len: usize,
+    pub prog: ast::Prog,
+}
+
+/// Whether `bytes` look like bytecode rather than source.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// The bytecode of `prog`, built for a tape of `tape_len` cells.
+pub fn encode(prog: &ast::Prog, tape_len: usize) -> Vec<u8> {
+    fn block(out: &mut Vec<u8>, node: &NodeBlock) {
+        for stat in &node.stats {
+            match &stat.stat {
+                Statement::Add(n) => op(out, ADD, *n),
+                Statement::Sub(n) => op(out, SUB, *n),
+                Statement::MoveL(n) => op(out, LEFT, *n),
+                Statement::MoveR(n) => op(out, RIGHT, *n),
+                Statement::Read => out.push(READ),
+                Statement::Write => out.push(WRITE),
+                // there is no conditional, an `If` goes round once as the
+                // loop it came from
+                Statement::Loop(body) | Statement::If(body) => {
+                    out.push(LOOP);
+                    block(out, body);
+                    out.push(END);
+                }
+                // nor a way of copying data onto the tape
+                Statement::Fill(values) => block(out, &ast::spell_out_fill(values)),
+                Statement::Print(bytes) => block(out, &ast::spell_out_print(bytes)),
+                Statement::Set(n) => op(out, SET, *n as usize),
+                Statement::MulAdd { offset, factor } => {
+                    op(out, MULADD, zigzag(*offset));
+                    leb128(out, *factor as usize);
+                }
+                Statement::Scan(stride) => op(out, SCAN, zigzag(*stride)),
+            }
+        }
+    }
+
+    fn op(out: &mut Vec<u8>, op: u8, n: usize) {
+        out.push(op);
+        leb128(out, n);
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    leb128(&mut out, tape_len);
+    block(&mut out, prog);
+    out
+}
+
+/// `n` as an unsigned number, small for small magnitudes of either sign.
+fn zigzag(n: isize) -> usize {
+    ((n << 1) ^ (n >> (isize::BITS - 1))) as usize
+}
+
+fn leb128(out: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Reads bytecode a byte at a time, keeping track of where it is.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self.bytes.get(self.at).ok_or(Error::Truncated)?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn count(&mut self) -> Result<usize, Error> {
+        let start = self.at;
+        let mut n = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as usize;
+            if bits << shift >> shift != bits {
+                return Err(Error::Count(start));
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(Error::Count(start))
+    }
+
+    /// A zigzag encoded offset.
+    fn offset(&mut self) -> Result<isize, Error> {
+        let n = self.count()?;
+        Ok((n >> 1) as isize ^ -((n & 1) as isize))
+    }
+
+    /// A count that has to fit in a cell.
+    fn cell(&mut self) -> Result<u32, Error> {
+        let at = self.at;
+        u32::try_from(self.count()?).map_err(|_| Error::Count(at))
+    }
+
+    fn at_end(&self) -> bool {
+        self.at == self.bytes.len()
+    }
+
+    /// Reads the header, returning a reader of the code, the version and
+    /// the tape length.
+    fn header(bytes: &[u8]) -> Result<(Reader<'_>, u8, usize), Error> {
+        if !is_bytecode(bytes) {
+            return Err(Error::NotBytecode);
+        }
+        let mut reader = Reader {
+            bytes: &bytes[MAGIC.len()..],
+            at: 0,
+        };
+        let version = match reader.byte()? {
+            v @ 1..=VERSION => v,
+            v => return Err(Error::Version(v)),
+        };
+        let tape_len = reader.count()?;
+        let code = &reader.bytes[reader.at..];
+        Ok((Reader { bytes: code, at: 0 }, version, tape_len))
+    }
+}
+
+/// Reads a program back from its bytecode.
+pub fn decode(bytes: &[u8]) -> Result<Module, Error> {
+    let (mut reader, _, tape_len) = Reader::header(bytes)?;
+    // the blocks of the loops around what is being read, innermost last
+    let mut blocks = vec![NodeBlock {
+        attr: Attr::default(),
+        stats: Vec::new(),
+    }];
+    while !reader.at_end() {
+        let at = reader.at;
+        let stat = match reader.byte()? {
+            ADD => Statement::Add(reader.count()?),
+            SUB => Statement::Sub(reader.count()?),
+            LEFT => Statement::MoveL(reader.count()?),
+            RIGHT => Statement::MoveR(reader.count()?),
+            READ => Statement::Read,
+            WRITE => Statement::Write,
+            SET => Statement::Set(reader.cell()?),
+            MULADD => Statement::MulAdd {
+                offset: reader.offset()?,
+                factor: reader.cell()?,
+            },
+            SCAN => Statement::Scan(reader.offset()?),
+            LOOP if blocks.len() > ast::MAX_NESTING => return Err(Error::TooDeep),
+            LOOP => {
+                blocks.push(NodeBlock {
+                    attr: Attr::default(),
+                    stats: Vec::new(),
+                });
+                continue;
+            }
+            END if blocks.len() == 1 => return Err(Error::Unbalanced),
+            END => Statement::Loop(Box::new(blocks.pop().unwrap())),
+            op => return Err(Error::Opcode { at, op }),
+        };
+        blocks.last_mut().unwrap().stats.push(NodeStatement {
+            attr: Attr::default(),
+            stat,
+        });
+    }
+    let prog = blocks.pop().unwrap();
+    if !blocks.is_empty() {
+        return Err(Error::Unbalanced);
+    }
+    Ok(Module { tape_len, prog })
+}
+
+/// Bytecode as text, one instruction per line after its offset into the
+/// code, loop bodies indented.
+pub fn disassemble(bytes: &[u8]) -> Result<String, Error> {
+    let (mut reader, version, tape_len) = Reader::header(bytes)?;
+    let mut out = String::new();
+    writeln!(out, "; bytecode version {}, tape-len {}", version, tape_len).unwrap();
+    let mut depth = 0usize;
+    while !reader.at_end() {
+        let at = reader.at;
+        let op = reader.byte()?;
+        let (name, args) = match op {
+            ADD => ("add", vec![reader.count()? as i128]),
+            SUB => ("sub", vec![reader.count()? as i128]),
+            LEFT => ("left", vec![reader.count()? as i128]),
+            RIGHT => ("right", vec![reader.count()? as i128]),
+            READ => ("read", vec![]),
+            WRITE => ("write", vec![]),
+            SET => ("set", vec![reader.cell()? as i128]),
+            MULADD => (
+                "muladd",
+                vec![reader.offset()? as i128, reader.cell()? as i128],
+            ),
+            SCAN => ("scan", vec![reader.offset()? as i128]),
+            LOOP => ("loop", vec![]),
+            END => ("end", vec![]),
+            op => return Err(Error::Opcode { at, op }),
+        };
+        if op == END {
+            depth = depth.checked_sub(1).ok_or(Error::Unbalanced)?;
+        }
+        write!(
+            out,
+            "{:04x}  {:indent$}{}",
+            at,
+            "",
+            name,
+            indent = depth * 2
+        )
+        .unwrap();
+        for arg in args {
+            write!(out, " {}", arg).unwrap();
+        }
+        out.push('\n');
+        if op == LOOP {
+            depth += 1;
+        }
+    }
+    if depth != 0 {
+        return Err(Error::Unbalanced);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip_and_disassemble() {
+        let prog = crate::parse(&format!(",[>{}<-]>.", "+".repeat(200))).unwrap();
+        let bytes = encode(&prog, 300);
+        assert_eq!(bytes[..7], [0, b'b', b'f', b'o', VERSION, 0xac, 0x02]);
+        let module = decode(&bytes).unwrap();
+        assert_eq!(module.tape_len, 300);
+        assert_eq!(encode(&module.prog, 300), bytes);
+        assert_eq!(
+            disassemble(&bytes).unwrap(),
+            "; bytecode version 4, tape-len 300\n\
+             0000  read\n\
+             0001  loop\n\
+             0002    right 1\n\
+             0004    add 200\n\
+             0007    left 1\n\
+             0009    sub 1\n\
+             000b  end\n\
+             000c  right 1\n\
+             000e  write\n"
+        );
+
+        assert_eq!(decode(b"+-"), Err(Error::NotBytecode));
+        let mut open = bytes.clone();
+        open.truncate(bytes.len() - 4);
+        assert_eq!(decode(&open), Err(Error::Unbalanced));
+        let mut bad = bytes;
+        bad.push(0xee);
+        assert_eq!(decode(&bad), Err(Error::Opcode { at: 0x0f, op: 0xee }));
+    }
+}
@@ -0,0 +1,289 @@
+//! Portable C, for platforms neither QBE nor the assembly backends know
+//! about: any C99 compiler and its standard library will do. The runtime
+//! behaviour matches [`crate::gen::QbeGenerator`]: 32 bit cells, `,` at end
+//! of input leaves the cell alone, and moving off the tape exits with status
+//! 1. I/O goes through `stdio`, retried however the C library does it.
+
+use std::fmt::Write;
+
+use crate::{ast, backend::CodeGen, CompileOptions, Error, Newline};
+
+const READ_ERR: &str = "bfc: read error\\n";
+const WRITE_ERR: &str = "bfc: write error\\n";
+/// Cells either side of the tape memcheck reports accesses to.
+const REDZONE: usize = 16;
+
+pub struct CGenerator {
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    debug_file: Option<String>,
+    memcheck: bool,
+    unchecked: bool,
+    indent: usize,
+    body: String,
+}
+
+impl Default for CGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for CGenerator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl CGenerator {
+    pub fn new() -> Self {
+        CGenerator {
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            debug_file: None,
+            memcheck: false,
+            unchecked: false,
+            indent: 1,
+            body: String::new(),
+        }
+    }
+
+    pub fn with_options(opts: &CompileOptions) -> Self {
+        CGenerator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            debug_file: opts.debug_file.clone(),
+            memcheck: opts.memcheck,
+            unchecked: opts.unchecked,
+            ..Self::new()
+        }
+    }
+
+    pub fn gen(&mut self, prog: &ast::Prog) -> String {
+        self.generate_block(prog);
+
+        let mut out = String::new();
+        out.push_str("#include <stdint.h>\n#include <stdio.h>\n#include <stdlib.h>\n");
+        // the bytes are the program's own, no newline translation
+        out.push_str("#ifdef _WIN32\n#include <fcntl.h>\n#include <io.h>\n#endif\n\n");
+        writeln!(out, "#define TAPE_LEN {}\n", self.tape_len).unwrap();
+        if self.memcheck {
+            self.generate_memcheck_decls(&mut out);
+        } else {
+            out.push_str("static uint32_t tape[TAPE_LEN];\n\n");
+        }
+
+        out.push_str("static void bf_putc(uint32_t c)\n{\n");
+        if self.crlf {
+            out.push_str("\tif ((c & 0xff) == '\\n')\n\t\tbf_putc('\\r');\n");
+        }
+        if self.io_strict {
+            writeln!(
+                out,
+                "\tif (putchar((int)(c & 0xff)) == EOF) {{\n\t\tfputs(\"{}\", stderr);\n\t\texit(1);\n\t}}",
+                WRITE_ERR
+            )
+            .unwrap();
+        } else {
+            out.push_str("\tputchar((int)(c & 0xff));\n");
+        }
+        out.push_str("}\n\n");
+
+        out.push_str("static void bf_getc(uint32_t *cell)\n{\n\tint c = getchar();\n");
+        if self.crlf {
+            out.push_str("\twhile (c == '\\r')\n\t\tc = getchar();\n");
+        }
+        if self.io_strict {
+            writeln!(
+                out,
+                "\tif (c == EOF && ferror(stdin)) {{\n\t\tfputs(\"{}\", stderr);\n\t\texit(1);\n\t}}",
+                READ_ERR
+            )
+            .unwrap();
+        }
+        out.push_str("\tif (c != EOF)\n\t\t*cell = (uint32_t)c;\n}\n\n");
+
+        out.push_str("int main(void)\n{\n\tsize_t p = 0;\n\n");
+        out.push_str("#ifdef _WIN32\n\t_setmode(_fileno(stdin), _O_BINARY);\n");
+        out.push_str("\t_setmode(_fileno(stdout), _O_BINARY);\n#endif\n");
+        if self.memcheck {
+            self.generate_memcheck_alloc(&mut out);
+        }
+        out.push_str(&std::mem::take(&mut self.body));
+        if self.io_strict {
+            writeln!(
+                out,
+                "\tif (fflush(stdout) == EOF) {{\n\t\tfputs(\"{}\", stderr);\n\t\treturn 1;\n\t}}",
+                WRITE_ERR
+            )
+            .unwrap();
+        }
+        out.push_str("\treturn 0;\n}\n");
+        out
+    }
+
+    /// The tape as a pointer into a heap block with `REDZONE` cells either
+    /// side, and no-op stand-ins for the client requests when memcheck's
+    /// header is missing.
+    fn generate_memcheck_decls(&self, out: &mut String) {
+        out.push_str("#if defined(__has_include)\n#if __has_include(<valgrind/memcheck.h>)\n");
+        out.push_str("#include <valgrind/memcheck.h>\n#endif\n#endif\n");
+        out.push_str("#ifndef VALGRIND_MAKE_MEM_NOACCESS\n");
+        out.push_str("#define VALGRIND_MAKE_MEM_NOACCESS(addr, len) ((void)0)\n");
+        out.push_str("#define VALGRIND_CREATE_BLOCK(addr, len, desc) ((void)0)\n#endif\n\n");
+        writeln!(out, "#define REDZONE {}\n", REDZONE).unwrap();
+        out.push_str("static uint32_t *tape;\n\n");
+    }
+
+    fn generate_memcheck_alloc(&self, out: &mut String) {
+        out.push_str("\tuint32_t *mem = calloc(TAPE_LEN + 2 * REDZONE, sizeof *mem);\n");
+        out.push_str("\tif (!mem)\n\t\treturn 1;\n\ttape = mem + REDZONE;\n");
+        out.push_str("\tVALGRIND_MAKE_MEM_NOACCESS(mem, REDZONE * sizeof *mem);\n");
+        out.push_str("\tVALGRIND_MAKE_MEM_NOACCESS(tape + TAPE_LEN, REDZONE * sizeof *mem);\n");
+        out.push_str("\tVALGRIND_CREATE_BLOCK(tape, TAPE_LEN * sizeof *tape, \"bf tape\");\n");
+    }
+
+    fn generate_block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.generate_statement(stat)
+        }
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) {
+        if let Some(file) = &self.debug_file {
+            let file = file.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(self.body, "#line {} \"{}\"", stat.attr.span.line, file).unwrap();
+        }
+
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move("-=", *n),
+            ast::Statement::MoveR(n) => self.generate_move("+=", *n),
+            ast::Statement::Add(n) => self.line(&format!("tape[p] += {}u;", *n as u32)),
+            ast::Statement::Sub(n) => self.line(&format!("tape[p] -= {}u;", *n as u32)),
+            ast::Statement::Set(n) => self.line(&format!("tape[p] = {}u;", n)),
+            ast::Statement::MulAdd { offset, factor } => {
+                let (there, back) = if *offset < 0 {
+                    ("-=", "+")
+                } else {
+                    ("+=", "-")
+                };
+                let n = offset.unsigned_abs();
+                self.generate_move(there, n);
+                self.line(&format!("tape[p] += tape[p {} {}] * {}u;", back, n, factor));
+                self.generate_move(if *offset < 0 { "+=" } else { "-=" }, n);
+            }
+            ast::Statement::Scan(stride) => {
+                self.line("while (tape[p]) {");
+                self.indent += 1;
+                let op = if *stride < 0 { "-=" } else { "+=" };
+                self.generate_move(op, stride.unsigned_abs());
+                self.indent -= 1;
+                self.line("}");
+            }
+            ast::Statement::Read => self.line("bf_getc(&tape[p]);"),
+            ast::Statement::Write => self.line("bf_putc(tape[p]);"),
+            ast::Statement::Loop(body) => {
+                self.line("while (tape[p]) {");
+                self.indent += 1;
+                self.generate_block(body);
+                self.indent -= 1;
+                self.line("}");
+            }
+            ast::Statement::If(body) => {
+                self.line("if (tape[p]) {");
+                self.indent += 1;
+                self.generate_block(body);
+                self.indent -= 1;
+                self.line("}");
+            }
+            ast::Statement::Fill(values) => {
+                let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+                self.line("{");
+                self.indent += 1;
+                let fill = format!("static const uint32_t fill[] = {{{}}};", values.join(", "));
+                self.line(&fill);
+                self.line(&format!("for (size_t i = 0; i < {}; i++)", values.len()));
+                self.line("\ttape[p + i] = fill[i];");
+                self.indent -= 1;
+                self.line("}");
+            }
+            ast::Statement::Print(bytes) => {
+                let chars: Vec<_> = bytes.iter().map(|b| b.to_string()).collect();
+                self.line("{");
+                self.indent += 1;
+                let print = format!("static const uint8_t print[] = {{{}}};", chars.join(", "));
+                self.line(&print);
+                self.line(&format!("for (size_t i = 0; i < {}; i++)", bytes.len()));
+                self.line("\tbf_putc(print[i]);");
+                self.line(&format!("tape[p] = {};", bytes[bytes.len() - 1]));
+                self.indent -= 1;
+                self.line("}");
+            }
+        }
+    }
+
+    /// Moves the pointer by `n` cells, `op` being `+=` or `-=`, and exits
+    /// with status 1 when it ends up off the tape, unless bounds go
+    /// unchecked. Moving left wraps around to a large index.
+    fn generate_move(&mut self, op: &str, n: usize) {
+        if n >= self.tape_len {
+            self.line("exit(1);");
+            return;
+        }
+        self.line(&format!("p {} {};", op, n));
+        if !self.unchecked {
+            self.line("if (p >= TAPE_LEN)");
+            self.line("\texit(1);");
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.body.push('\t');
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn moves_check_one_bound() {
+        let opts = CompileOptions {
+            tape_len: 2,
+            ..Default::default()
+        };
+        let prog = crate::parse("<>>").unwrap();
+        let c = CGenerator::with_options(&opts).gen(&prog);
+        // moving left of the tape wraps around past its end
+        assert!(c.contains("\tp -= 1;\n\tif (p >= TAPE_LEN)\n\t\texit(1);\n"));
+        // off the tape wherever it starts
+        assert!(c.contains("\texit(1);\n\treturn 0;\n"));
+
+        let opts = CompileOptions {
+            unchecked: true,
+            ..opts
+        };
+        let c = CGenerator::with_options(&opts).gen(&prog);
+        assert!(c.contains("\tp -= 1;\n\texit(1);\n"));
+    }
+
+    #[test]
+    fn memcheck_tape_has_redzones() {
+        let opts = CompileOptions {
+            memcheck: true,
+            ..Default::default()
+        };
+        let c = CGenerator::with_options(&opts).gen(&crate::parse("+").unwrap());
+        assert!(c.contains("static uint32_t *tape;\n"));
+        assert!(c.contains("\ttape = mem + REDZONE;\n"));
+    }
+}
@@ -0,0 +1,189 @@
+//! Parser combinators over token slices, what [`crate::ast`] parses
+//! programs with and what frontends for extended dialects can build on.
+//!
+//! A parser takes the input and returns what is left of it along with its
+//! output. It fails in one of two ways: [`ParseError::Backtrack`] when the
+//! input is not what it parses, leaving [`alt`] and [`many`] to try
+//! something else, or [`ParseError::Cut`] once it has seen enough to be
+//! sure the input was meant for it, which nothing recovers from. [`cut`]
+//! turns the first into the second, typically after an opening bracket:
+//! what follows has to be a loop body and its closing bracket, and an
+//! error inside it is the error to report rather than a reason to stop
+//! the enclosing block early.
+
+pub type PResult<'a, T, O, E> = Result<(&'a [T], O), ParseError<E>>;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError<E> {
+    /// The input does not match, alternatives may be tried.
+    Backtrack(E),
+    /// The input matched far enough to commit to this parser.
+    Cut(E),
+}
+
+impl<E> ParseError<E> {
+    pub fn into_inner(self) -> E {
+        match self {
+            ParseError::Backtrack(e) | ParseError::Cut(e) => e,
+        }
+    }
+}
+
+/// The errors the combinators report about the input on their own.
+pub trait TokenError<T> {
+    fn unexpected(token: &T) -> Self;
+    fn end_of_input() -> Self;
+}
+
+/// One token, turned into output by `f` unless it returns `None`.
+pub fn satisfy<'a, T: 'a, O, E: TokenError<T>>(
+    f: impl Fn(&'a T) -> Option<O>,
+) -> impl Fn(&'a [T]) -> PResult<'a, T, O, E> {
+    move |input| {
+        let first = input
+            .first()
+            .ok_or(ParseError::Backtrack(E::end_of_input()))?;
+        match f(first) {
+            Some(out) => Ok((&input[1..], out)),
+            None => Err(ParseError::Backtrack(E::unexpected(first))),
+        }
+    }
+}
+
+/// One token equal to `expected`.
+pub fn token<'a, T: PartialEq + 'a, E: TokenError<T>>(
+    expected: T,
+) -> impl Fn(&'a [T]) -> PResult<'a, T, &'a T, E> {
+    satisfy(move |t| (*t == expected).then_some(t))
+}
+
+/// `first`, or `second` where `first` backtracks.
+pub fn alt<'a, T: 'a, O, E>(
+    first: impl Fn(&'a [T]) -> PResult<'a, T, O, E>,
+    second: impl Fn(&'a [T]) -> PResult<'a, T, O, E>,
+) -> impl Fn(&'a [T]) -> PResult<'a, T, O, E> {
+    move |input| match first(input) {
+        Err(ParseError::Backtrack(_)) => second(input),
+        res => res,
+    }
+}
+
+/// `p` as many times as it matches, possibly none. Stops where `p`
+/// backtracks or stops consuming input, and fails only where it cuts.
+pub fn many<'a, T: 'a, O, E>(
+    p: impl Fn(&'a [T]) -> PResult<'a, T, O, E>,
+) -> impl Fn(&'a [T]) -> PResult<'a, T, Vec<O>, E> {
+    move |mut input| {
+        let mut out = Vec::new();
+        loop {
+            match p(input) {
+                Ok((rest, o)) if rest.len() < input.len() => {
+                    out.push(o);
+                    input = rest;
+                }
+                Ok(_) | Err(ParseError::Backtrack(_)) => return Ok((input, out)),
+                Err(cut) => return Err(cut),
+            }
+        }
+    }
+}
+
+/// `p`, committing to it: where it backtracks, the whole parse fails.
+pub fn cut<'a, T: 'a, O, E>(
+    p: impl Fn(&'a [T]) -> PResult<'a, T, O, E>,
+) -> impl Fn(&'a [T]) -> PResult<'a, T, O, E> {
+    move |input| p(input).map_err(|e| ParseError::Cut(e.into_inner()))
+}
+
+/// `first` then `second`, keeping the output of `second`.
+pub fn preceded<'a, T: 'a, O1, O2, E>(
+    first: impl Fn(&'a [T]) -> PResult<'a, T, O1, E>,
+    second: impl Fn(&'a [T]) -> PResult<'a, T, O2, E>,
+) -> impl Fn(&'a [T]) -> PResult<'a, T, O2, E> {
+    move |input| {
+        let (input, _) = first(input)?;
+        second(input)
+    }
+}
+
+/// `first` then `second`, keeping the output of `first`.
+pub fn terminated<'a, T: 'a, O1, O2, E>(
+    first: impl Fn(&'a [T]) -> PResult<'a, T, O1, E>,
+    second: impl Fn(&'a [T]) -> PResult<'a, T, O2, E>,
+) -> impl Fn(&'a [T]) -> PResult<'a, T, O1, E> {
+    move |input| {
+        let (input, out) = first(input)?;
+        let (input, _) = second(input)?;
+        Ok((input, out))
+    }
+}
+
+/// `p` with its output passed through `f`.
+pub fn map<'a, T: 'a, O1, O2, E>(
+    p: impl Fn(&'a [T]) -> PResult<'a, T, O1, E>,
+    f: impl Fn(O1) -> O2,
+) -> impl Fn(&'a [T]) -> PResult<'a, T, O2, E> {
+    move |input| p(input).map(|(rest, out)| (rest, f(out)))
+}
+
+/// `p`, which has to consume all of the input.
+pub fn all_consuming<'a, T: 'a, O, E: TokenError<T>>(
+    p: impl Fn(&'a [T]) -> PResult<'a, T, O, E>,
+) -> impl Fn(&'a [T]) -> PResult<'a, T, O, E> {
+    move |input| match p(input)? {
+        (rest, out) if rest.is_empty() => Ok((rest, out)),
+        (rest, _) => Err(ParseError::Backtrack(E::unexpected(&rest[0]))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Error {
+        Unexpected(char),
+        End,
+    }
+
+    impl TokenError<char> for Error {
+        fn unexpected(token: &char) -> Self {
+            Error::Unexpected(*token)
+        }
+
+        fn end_of_input() -> Self {
+            Error::End
+        }
+    }
+
+    /// Nested parentheses, counting the pairs.
+    fn pairs(input: &[char]) -> PResult<'_, char, usize, Error> {
+        let group = preceded(token('('), cut(terminated(pairs, token(')'))));
+        map(many(map(group, |inner| inner + 1)), |counts| {
+            counts.into_iter().sum()
+        })(input)
+    }
+
+    #[test]
+    fn cut_commits() {
+        let [nested, unclosed, unopened, x] =
+            ["(())()", "()(()", "())", "x1"].map(|s| s.chars().collect::<Vec<_>>());
+        assert_eq!(all_consuming(pairs)(&nested), Ok((&[][..], 3)));
+        // the unclosed group is the error, not the text after the pairs
+        assert_eq!(
+            all_consuming(pairs)(&unclosed),
+            Err(ParseError::Cut(Error::End))
+        );
+        assert_eq!(
+            all_consuming(pairs)(&unopened),
+            Err(ParseError::Backtrack(Error::Unexpected(')')))
+        );
+        let digit = satisfy(|c: &char| c.to_digit(10));
+        let either = alt(digit, map(token('x'), |_| 10));
+        assert_eq!(either(&x), Ok((&x[1..], 10)));
+        assert_eq!(
+            either(&unopened[2..]),
+            Err(ParseError::Backtrack(Error::Unexpected(')')))
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! Project configuration. Options are resolved in this order, later
+//! sources overriding earlier ones:
+//!
+//! 1. built in defaults
+//! 2. `bfc.toml`
+//! 3. `BFC_*` environment variables, see [`Config::from_env`]
+//! 4. command line flags
+//! 5. pragmas in the program, see [`Config::from_pragmas`]
+//!
+//! [`resolve`] does the layering, remembering where each value came from.
+//! Cells are as wide as the backend makes them, 8 bits with `avr-asm` and
+//! 32 with every other one. `cell-width` only says which width a project
+//! expects, applying a configuration fails when the backend chosen has
+//! cells of another width.
+//!
+//! A program compiled `--hardened` cannot set `output` or the options
+//! hardening settles, see [`Resolved::check_hardened`].
+
+use serde::Deserialize;
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{lex::PRAGMA, target::Target, Backend, CompileOptions, Newline, OptLevel};
+
+/// Name of the project configuration file looked up next to the sources.
+pub const FILE_NAME: &str = "bfc.toml";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(&'static str, String),
+    /// A pragma on the given line of a program.
+    Pragma(PathBuf, usize, String),
+    /// A cell width the backend does not build cells of.
+    CellWidth(u32, Backend),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Parse(path, e) => write!(f, "{}: {}", path.display(), e.message()),
+            Error::Env(var, e) => write!(f, "{}: {}", var, e),
+            Error::Pragma(path, line, e) => write!(f, "{}:{}: {}", path.display(), line, e),
+            Error::CellWidth(bits, backend) => write!(
+                f,
+                "cell-width = {}: the {} backend's cells are {} bits wide",
+                bits,
+                backend,
+                backend.cell_bits()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Project defaults from a `bfc.toml`. Every key is optional, anything
+/// left out keeps the built in default and command line flags override
+/// whatever is set here.
+///
+/// ```toml
+/// tape-len = 65536
+/// cell-width = 32
+/// max-stack = 131072
+/// newline = "crlf"
+/// opt-level = "s"
+/// target = "arm64"
+/// io-strict = true
+/// output = "build/prog"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub tape_len: Option<usize>,
+    /// Bits in a cell, which has to be what the backend's are.
+    pub cell_width: Option<u32>,
+    pub max_stack: Option<usize>,
+    pub io_strict: Option<bool>,
+    pub keep_all_io: Option<bool>,
+    pub split_loops: Option<bool>,
+    pub unroll_budget: Option<usize>,
+    pub eval_fuel: Option<u64>,
+    pub newline: Option<Newline>,
+    pub opt_level: Option<OptLevel>,
+    pub target: Option<Target>,
+    pub backend: Option<Backend>,
+    /// Output path, relative paths are resolved against the directory
+    /// holding the configuration file.
+    pub output: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn parse(src: &str, path: &Path) -> Result<Config, Error> {
+        let mut config: Config =
+            toml::from_str(src).map_err(|e| Error::Parse(path.to_path_buf(), e))?;
+        if let (Some(out), Some(dir)) = (&config.output, path.parent()) {
+            config.output = Some(dir.join(out));
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let src = fs::read_to_string(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+        Config::parse(&src, path)
+    }
+
+    /// Loads the `bfc.toml` sitting next to `src`, if there is one.
+    pub fn discover(src: &Path) -> Result<Option<Config>, Error> {
+        discover_path(src)
+            .map(|path| Config::load(&path))
+            .transpose()
+    }
+
+    /// Reads the pragmas of a program, lines starting with `#bfc ` followed
+    /// by a key and its value as they would be written in `bfc.toml`:
+    ///
+    /// ```text
+    /// #bfc tape-len = 65536
+    /// #bfc newline = "crlf"
+    /// ```
+    ///
+    /// The lexer skips these lines. Returns the options each one sets with
+    /// its line number, `path` being the program's.
+    pub fn from_pragmas(src: &str, path: &Path) -> Result<Vec<(usize, Config)>, Error> {
+        src.lines()
+            .enumerate()
+            .filter_map(|(i, line)| Some((i + 1, line.strip_prefix(PRAGMA)?)))
+            .map(|(line, pragma)| match Config::parse(pragma, path) {
+                Ok(config) => Ok((line, config)),
+                Err(Error::Parse(path, e)) => {
+                    Err(Error::Pragma(path, line, e.message().to_string()))
+                }
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    /// Reads overrides from `BFC_TAPE_LEN`, `BFC_CELL_WIDTH`,
+    /// `BFC_MAX_STACK`, `BFC_IO_STRICT`, `BFC_KEEP_ALL_IO`,
+    /// `BFC_SPLIT_LOOPS`, `BFC_UNROLL_BUDGET`, `BFC_EVAL_FUEL`,
+    /// `BFC_NEWLINE`, `BFC_OPT_LEVEL`, `BFC_TARGET`, `BFC_BACKEND` and
+    /// `BFC_OUTPUT`.
+    pub fn from_env() -> Result<Config, Error> {
+        Config::from_vars(|var| env::var(var).ok())
+    }
+
+    pub fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Config, Error> {
+        fn parse<T: FromStr>(
+            lookup: &impl Fn(&str) -> Option<String>,
+            var: &'static str,
+        ) -> Result<Option<T>, Error>
+        where
+            T::Err: fmt::Display,
+        {
+            lookup(var)
+                .map(|val| {
+                    val.parse()
+                        .map_err(|e: T::Err| Error::Env(var, e.to_string()))
+                })
+                .transpose()
+        }
+
+        fn flag(
+            lookup: &impl Fn(&str) -> Option<String>,
+            var: &'static str,
+        ) -> Result<Option<bool>, Error> {
+            match lookup(var).as_deref() {
+                None => Ok(None),
+                Some("1" | "true" | "yes" | "on") => Ok(Some(true)),
+                Some("" | "0" | "false" | "no" | "off") => Ok(Some(false)),
+                Some(val) => Err(Error::Env(
+                    var,
+                    format!("expected a boolean, found `{}`", val),
+                )),
+            }
+        }
+
+        Ok(Config {
+            tape_len: parse(&lookup, "BFC_TAPE_LEN")?,
+            cell_width: parse(&lookup, "BFC_CELL_WIDTH")?,
+            max_stack: parse(&lookup, "BFC_MAX_STACK")?,
+            io_strict: flag(&lookup, "BFC_IO_STRICT")?,
+            keep_all_io: flag(&lookup, "BFC_KEEP_ALL_IO")?,
+            split_loops: flag(&lookup, "BFC_SPLIT_LOOPS")?,
+            unroll_budget: parse(&lookup, "BFC_UNROLL_BUDGET")?,
+            eval_fuel: parse(&lookup, "BFC_EVAL_FUEL")?,
+            newline: parse(&lookup, "BFC_NEWLINE")?,
+            opt_level: parse(&lookup, "BFC_OPT_LEVEL")?,
+            target: parse(&lookup, "BFC_TARGET")?,
+            backend: parse(&lookup, "BFC_BACKEND")?,
+            output: lookup("BFC_OUTPUT").map(PathBuf::from),
+        })
+    }
+
+    /// Layers `over` on top of `self`, keys set in both take `over`'s value.
+    pub fn merge(self, over: Config) -> Config {
+        Config {
+            tape_len: over.tape_len.or(self.tape_len),
+            cell_width: over.cell_width.or(self.cell_width),
+            max_stack: over.max_stack.or(self.max_stack),
+            io_strict: over.io_strict.or(self.io_strict),
+            keep_all_io: over.keep_all_io.or(self.keep_all_io),
+            split_loops: over.split_loops.or(self.split_loops),
+            unroll_budget: over.unroll_budget.or(self.unroll_budget),
+            eval_fuel: over.eval_fuel.or(self.eval_fuel),
+            newline: over.newline.or(self.newline),
+            opt_level: over.opt_level.or(self.opt_level),
+            target: over.target.or(self.target),
+            backend: over.backend.or(self.backend),
+            output: over.output.or(self.output),
+        }
+    }
+
+    /// Every option as it was applied to `opts`, with `output`.
+    pub fn of(opts: &CompileOptions, output: Option<PathBuf>) -> Config {
+        Config {
+            tape_len: Some(opts.tape_len),
+            cell_width: Some(opts.backend.cell_bits()),
+            max_stack: opts.max_stack,
+            io_strict: Some(opts.io_strict),
+            keep_all_io: Some(opts.keep_all_io),
+            split_loops: Some(opts.split_loops),
+            unroll_budget: Some(opts.unroll_budget),
+            eval_fuel: Some(opts.eval_fuel),
+            newline: Some(opts.newline),
+            opt_level: Some(opts.opt_level),
+            target: opts.target,
+            backend: Some(opts.backend),
+            output,
+        }
+    }
+
+    /// Every key with its value as it would be written in `bfc.toml`, `None`
+    /// for keys left unset.
+    pub fn entries(&self) -> [(&'static str, Option<String>); 13] {
+        fn plain(val: Option<impl fmt::Display>) -> Option<String> {
+            val.map(|val| val.to_string())
+        }
+        fn string(val: Option<impl fmt::Display>) -> Option<String> {
+            val.map(|val| toml::Value::String(val.to_string()).to_string())
+        }
+
+        [
+            ("tape-len", plain(self.tape_len)),
+            ("cell-width", plain(self.cell_width)),
+            ("max-stack", plain(self.max_stack)),
+            ("io-strict", plain(self.io_strict)),
+            ("keep-all-io", plain(self.keep_all_io)),
+            ("split-loops", plain(self.split_loops)),
+            ("unroll-budget", plain(self.unroll_budget)),
+            ("eval-fuel", plain(self.eval_fuel)),
+            ("newline", string(self.newline)),
+            ("opt-level", string(self.opt_level)),
+            ("target", string(self.target)),
+            ("backend", string(self.backend)),
+            ("output", string(self.output.as_ref().map(|p| p.display()))),
+        ]
+    }
+
+    /// Sets the options given in `opts`, failing when `cell-width` is not
+    /// the width of the backend's cells.
+    pub fn apply(&self, opts: &mut CompileOptions) -> Result<(), Error> {
+        if let Some(tape_len) = self.tape_len {
+            opts.tape_len = tape_len;
+        }
+        if let Some(max_stack) = self.max_stack {
+            opts.max_stack = Some(max_stack);
+        }
+        if let Some(io_strict) = self.io_strict {
+            opts.io_strict = io_strict;
+        }
+        if let Some(keep_all_io) = self.keep_all_io {
+            opts.keep_all_io = keep_all_io;
+        }
+        if let Some(split_loops) = self.split_loops {
+            opts.split_loops = split_loops;
+        }
+        if let Some(unroll_budget) = self.unroll_budget {
+            opts.unroll_budget = unroll_budget;
+        }
+        if let Some(eval_fuel) = self.eval_fuel {
+            opts.eval_fuel = eval_fuel;
+        }
+        if let Some(newline) = self.newline {
+            opts.newline = newline;
+        }
+        if let Some(opt_level) = self.opt_level {
+            opts.opt_level = opt_level;
+        }
+        if let Some(target) = self.target {
+            opts.target = Some(target);
+        }
+        if let Some(backend) = self.backend {
+            opts.backend = backend;
+        }
+        match self.cell_width {
+            Some(bits) if bits != opts.backend.cell_bits() => {
+                Err(Error::CellWidth(bits, opts.backend))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn discover_path(src: &Path) -> Option<PathBuf> {
+    let dir = src.parent().unwrap_or(Path::new(""));
+    Some(dir.join(FILE_NAME)).filter(|path| path.is_file())
+}
+
+/// Where an option got its value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    File(PathBuf),
+    Env,
+    Cli,
+    /// The pragma on the given line of a program.
+    Pragma(PathBuf, usize),
+}
+
+/// Options layered from several sources, later layers overriding earlier
+/// ones.
+#[derive(Debug, Default)]
+pub struct Resolved {
+    pub config: Config,
+    origins: Vec<(&'static str, Origin)>,
+}
+
+impl Resolved {
+    pub fn layer(&mut self, origin: Origin, config: Config) {
+        for (key, val) in config.entries() {
+            if val.is_some() {
+                self.origins.retain(|(k, _)| *k != key);
+                self.origins.push((key, origin.clone()));
+            }
+        }
+        self.config = std::mem::take(&mut self.config).merge(config);
+    }
+
+    /// Which layer set `key`, [`Origin::Default`] if none did.
+    pub fn origin(&self, key: &str) -> Origin {
+        self.origins
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map_or(Origin::Default, |(_, origin)| origin.clone())
+    }
+
+    /// Where `key` came from, for people: the file, variable or line that
+    /// set it.
+    pub fn describe_origin(&self, key: &str) -> String {
+        match self.origin(key) {
+            Origin::Default => "default".to_string(),
+            Origin::File(path) => path.display().to_string(),
+            Origin::Env => format!("BFC_{}", key.to_uppercase().replace('-', "_")),
+            Origin::Cli => "command line".to_string(),
+            Origin::Pragma(path, line) => format!("{}:{}", path.display(), line),
+        }
+    }
+
+    /// Fails on a pragma setting `output` or an option hardening settles,
+    /// neither of which a program compiled `--hardened` gets a say in.
+    pub fn check_hardened(&self) -> Result<(), Error> {
+        for key in HARDENED_KEYS {
+            if let Origin::Pragma(path, line) = self.origin(key) {
+                return Err(Error::Pragma(
+                    path,
+                    line,
+                    format!("`{}` cannot be set by a program compiled --hardened", key),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keys only the person compiling a program may set when hardening it.
+const HARDENED_KEYS: [&str; 3] = ["output", "io-strict", "max-stack"];
+
+/// Layers the options for the program `src` read from `path`: `file`, or
+/// the `bfc.toml` next to the program, then the environment, then `cli` and
+/// last the program's pragmas.
+pub fn resolve(
+    file: Option<&Path>,
+    path: &Path,
+    src: &str,
+    cli: Config,
+) -> Result<Resolved, Error> {
+    let mut resolved = Resolved::default();
+    if let Some(file) = file.map(Path::to_path_buf).or_else(|| discover_path(path)) {
+        let config = Config::load(&file)?;
+        resolved.layer(Origin::File(file), config);
+    }
+    resolved.layer(Origin::Env, Config::from_env()?);
+    resolved.layer(Origin::Cli, cli);
+    for (line, config) in Config::from_pragmas(src, path)? {
+        resolved.layer(Origin::Pragma(path.to_path_buf(), line), config);
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let src = "tape-len = 100\nnewline = \"crlf\"\noutput = \"out/prog\"\n";
+        let config = Config::parse(src, Path::new("proj/bfc.toml")).unwrap();
+        assert_eq!(config.output, Some(PathBuf::from("proj/out/prog")));
+
+        let mut opts = CompileOptions::default();
+        config.apply(&mut opts).unwrap();
+        assert_eq!(opts.tape_len, 100);
+        assert_eq!(opts.newline, Newline::Crlf);
+        assert!(!opts.io_strict);
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let file =
+            Config::parse("tape-len = 100\nio-strict = true", Path::new("bfc.toml")).unwrap();
+        let env = Config::from_vars(|var| match var {
+            "BFC_TAPE_LEN" => Some("200".to_string()),
+            "BFC_OPT_LEVEL" => Some("s".to_string()),
+            _ => None,
+        })
+        .unwrap();
+
+        let mut opts = CompileOptions::default();
+        file.merge(env).apply(&mut opts).unwrap();
+        assert_eq!(opts.tape_len, 200);
+        assert_eq!(opts.opt_level, OptLevel::Size);
+        assert!(opts.io_strict);
+
+        let env = Config::from_vars(|var| (var == "BFC_IO_STRICT").then(|| "maybe".to_string()));
+        assert!(env.is_err());
+    }
+
+    #[test]
+    fn pragmas_override_everything() {
+        let path = Path::new("prog.bf");
+        let src = "#bfc tape-len = 10\n+.\n#bfc newline = \"crlf\"\n";
+        let cli = Config {
+            tape_len: Some(20),
+            io_strict: Some(true),
+            ..Default::default()
+        };
+        let resolved = resolve(None, path, src, cli).unwrap();
+        assert_eq!(resolved.config.tape_len, Some(10));
+        assert_eq!(resolved.origin("tape-len"), Origin::Pragma(path.into(), 1));
+        assert_eq!(resolved.origin("newline"), Origin::Pragma(path.into(), 3));
+        assert_eq!(resolved.origin("io-strict"), Origin::Cli);
+        assert_eq!(resolved.describe_origin("opt-level"), "default");
+
+        let err = Config::from_pragmas("+\n#bfc tape-size = 1", path).unwrap_err();
+        assert!(matches!(err, Error::Pragma(_, 2, _)));
+    }
+
+    #[test]
+    fn hardened_programs_keep_out_of_output() {
+        let path = Path::new("prog.bf");
+        let src = "#bfc tape-len = 10\n+.\n#bfc output = \"/etc/passwd\"\n";
+        let resolved = resolve(None, path, src, Config::default()).unwrap();
+        let err = resolved.check_hardened().unwrap_err();
+        assert!(matches!(err, Error::Pragma(_, 3, _)));
+
+        let cli = Config {
+            output: Some(PathBuf::from("prog")),
+            io_strict: Some(true),
+            ..Default::default()
+        };
+        let resolved = resolve(None, path, "#bfc tape-len = 10\n+.\n", cli).unwrap();
+        assert!(resolved.check_hardened().is_ok());
+    }
+
+    #[test]
+    fn cell_width_is_the_backends() {
+        let path = Path::new("bfc.toml");
+        let mut opts = CompileOptions::default();
+        let config = Config::parse("cell-width = 32", path).unwrap();
+        assert!(config.apply(&mut opts).is_ok());
+
+        let config = Config::parse("cell-width = 8\nbackend = \"avr-asm\"", path).unwrap();
+        assert!(config.apply(&mut opts).is_ok());
+        assert_eq!(opts.backend, Backend::AvrAsm);
+
+        let config = Config::parse("cell-width = 16", path).unwrap();
+        let err = config.apply(&mut opts).unwrap_err();
+        assert!(matches!(err, Error::CellWidth(16, Backend::AvrAsm)));
+        assert_eq!(
+            err.to_string(),
+            "cell-width = 16: the avr-asm backend's cells are 8 bits wide"
+        );
+    }
+
+    #[test]
+    fn reject_unknown_keys() {
+        let path = Path::new("bfc.toml");
+        assert!(Config::parse("tape-size = 1", path).is_err());
+        assert!(Config::parse("newline = \"lf\"", path).is_err());
+    }
+}
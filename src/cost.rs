@@ -0,0 +1,110 @@
+use std::ops::{Add, Mul};
+
+use crate::{ast::Statement, OptLevel};
+
+/// Rough static estimate of what a piece of generated code costs: `size` in
+/// emitted instructions and `cycles` in time per execution.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cost {
+    pub size: usize,
+    pub cycles: usize,
+}
+
+impl Cost {
+    pub fn new(size: usize, cycles: usize) -> Self {
+        Cost { size, cycles }
+    }
+
+    /// A single figure to compare alternatives by, leaning towards whatever
+    /// `level` cares about most.
+    pub fn weigh(self, level: OptLevel) -> usize {
+        match level {
+            OptLevel::Size => self.size * 8 + self.cycles,
+            _ => self.size + self.cycles * 8,
+        }
+    }
+}
+
+impl Add for Cost {
+    type Output = Cost;
+
+    fn add(self, rhs: Cost) -> Cost {
+        Cost::new(self.size + rhs.size, self.cycles + rhs.cycles)
+    }
+}
+
+impl Mul<usize> for Cost {
+    type Output = Cost;
+
+    fn mul(self, n: usize) -> Cost {
+        Cost::new(self.size * n, self.cycles * n)
+    }
+}
+
+/// Per backend costs that passes and code generators consult before
+/// trading code size against speed.
+pub trait CostModel {
+    /// Cost of one statement, not counting the body of loops.
+    fn statement(&self, stat: &Statement) -> Cost;
+    /// Overhead of calling into an outlined helper instead of inlining it.
+    fn call(&self) -> Cost;
+}
+
+pub struct QbeCost;
+
+impl CostModel for QbeCost {
+    fn statement(&self, stat: &Statement) -> Cost {
+        match stat {
+            // load, op, store
+            Statement::Add(_) | Statement::Sub(_) => Cost::new(3, 3),
+            // pointer update and bounds check
+            Statement::MoveL(_) | Statement::MoveR(_) => Cost::new(6, 3),
+            // the syscall dominates, surrounded by the EINTR retry logic
+            Statement::Read | Statement::Write => Cost::new(14, 100),
+            // condition test on entry and on every back edge
+            Statement::Loop(_) => Cost::new(4, 2),
+            // the test on entry alone
+            Statement::If(_) => Cost::new(2, 1),
+            // a call copying the cells
+            Statement::Fill(values) => Cost::new(4, values.len()),
+            // a syscall writing a data definition out, its retry logic
+            // going on until all of it is
+            Statement::Print(bytes) => Cost::new(20, 100 + bytes.len()),
+            // a store
+            Statement::Set(_) => Cost::new(1, 1),
+            // a load and multiply, then a checked move to the other cell,
+            // an add there and a checked move back
+            Statement::MulAdd { .. } => Cost::new(17, 10),
+            // the loop and move it replaces, every cell along the way
+            Statement::Scan(_) => Cost::new(10, 5),
+        }
+    }
+
+    fn call(&self) -> Cost {
+        Cost::new(1, 4)
+    }
+}
+
+/// Whether `uses` inline copies of code costing `inline` are better off as
+/// calls to a single outlined copy.
+pub fn prefer_outlined(model: &impl CostModel, level: OptLevel, inline: Cost, uses: usize) -> bool {
+    let inlined = inline * uses;
+    let outlined = Cost::new(
+        inline.size + model.call().size * uses,
+        (inline.cycles + model.call().cycles) * uses,
+    );
+    outlined.weigh(level) < inlined.weigh(level)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn outline_only_for_size() {
+        let io = QbeCost.statement(&Statement::Write);
+        assert!(!prefer_outlined(&QbeCost, OptLevel::O0, io, 50));
+        assert!(!prefer_outlined(&QbeCost, OptLevel::Size, io, 1));
+        assert!(prefer_outlined(&QbeCost, OptLevel::Size, io, 2));
+    }
+}
@@ -0,0 +1,757 @@
+use std::{
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::{self, Command, ExitStatus, Stdio},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    image::{self, Format},
+    target::Target,
+    timing::Timings,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Spawn(String, io::Error),
+    /// A tool that failed, with the command line it was run with and what
+    /// it wrote to stderr.
+    Tool {
+        phase: &'static str,
+        tool: String,
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// A tool killed after running for longer than
+    /// [`BuildOptions::timeout`].
+    Timeout {
+        tool: String,
+        command: String,
+        after: Duration,
+    },
+    Image(PathBuf, image::Error),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Spawn(tool, e) if e.kind() == io::ErrorKind::NotFound => {
+                write!(f, "could not run `{}`: {}, is it installed?", tool, e)
+            }
+            Error::Spawn(tool, e) => write!(f, "could not run `{}`: {}", tool, e),
+            Error::Tool {
+                phase,
+                tool,
+                command,
+                status,
+                stderr,
+            } => {
+                writeln!(f, "`{}` failed with {}", tool, status)?;
+                writeln!(f, "  command: {}", command)?;
+                for line in stderr.lines() {
+                    writeln!(f, "  | {}", line)?;
+                }
+                match *phase {
+                    "qbe" => write!(f, "  `--emit qbe` writes the IL it was given"),
+                    _ => write!(f, "  `--save-temps` keeps the files it was given"),
+                }
+            }
+            Error::Timeout {
+                tool,
+                command,
+                after,
+            } => write!(
+                f,
+                "`{}` was stopped after {:.1}s\n  command: {}",
+                tool,
+                after.as_secs_f64(),
+                command
+            ),
+            Error::Image(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Unsupported(what) => write!(f, "{}", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    pub qbe: String,
+    /// C compiler assembling and linking the output of `qbe`.
+    pub cc: String,
+    /// Extra arguments to `cc`, ahead of the output and input files.
+    pub cc_args: Vec<String>,
+    /// Linker `cc` should use, passed as `-fuse-ld`.
+    pub linker: Option<String>,
+    /// Extra arguments to `cc`, after the assembly so libraries resolve
+    /// against it.
+    pub link_args: Vec<String>,
+    /// Linker script for the final link, passed as `-T`.
+    pub linker_script: Option<PathBuf>,
+    /// Position independent executable or not, `None` leaves it to `cc`.
+    pub pie: Option<bool>,
+    /// Strip symbols from the executable.
+    pub strip: bool,
+    /// What the linked executable is turned into, a flat image for
+    /// flashing bare-metal boards or left alone.
+    pub format: Format,
+    /// Where a flat image starts, see [`image::convert`].
+    pub load_address: Option<u64>,
+    /// Link statically, so the executable runs without any shared library.
+    pub static_link: bool,
+    /// Passed to `qbe -t`, `None` uses its default.
+    pub target: Option<Target>,
+    /// Keep the QBE IL and assembly next to the output as `<output>.ssa`
+    /// and `<output>.s` instead of in throwaway temporary files.
+    pub save_temps: bool,
+    /// Keep every intermediate file in this directory, named after the
+    /// output: the IL, assembly or source `cc` is given and the object it
+    /// is compiled to before linking, see [`Intermediates`].
+    pub keep_intermediates: Option<PathBuf>,
+    /// Print every command before running it.
+    pub verbose: bool,
+    /// How long `qbe` and `cc` may run before they are killed, `None` for
+    /// as long as they take.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            qbe: "qbe".to_string(),
+            cc: "cc".to_string(),
+            cc_args: Vec::new(),
+            linker: None,
+            link_args: Vec::new(),
+            linker_script: None,
+            pie: None,
+            strip: false,
+            format: Format::Elf,
+            load_address: None,
+            static_link: false,
+            target: None,
+            save_temps: false,
+            keep_intermediates: None,
+            verbose: false,
+            timeout: None,
+        }
+    }
+}
+
+impl BuildOptions {
+    /// Options building for `target`. Anything but the host needs a cross
+    /// compiler: `<triple>-gcc` for Linux targets, `clang` told the triple
+    /// for Apple ones.
+    pub fn for_target(target: Option<Target>) -> Self {
+        let mut opts = BuildOptions {
+            target,
+            ..Default::default()
+        };
+        match target {
+            Some(t) if Some(t) != Target::host() && t.is_apple() => {
+                opts.cc = "clang".to_string();
+                opts.cc_args.push(format!("--target={}", t.triple()));
+            }
+            Some(t) if Some(t) != Target::host() => opts.cc = format!("{}-gcc", t.triple()),
+            _ => {}
+        }
+        opts
+    }
+
+    /// Options building LLVM IR for `target`, which takes `clang` rather
+    /// than whatever `cc` is, told the triple when cross compiling.
+    pub fn for_llvm(target: Option<Target>) -> Self {
+        let mut opts = BuildOptions {
+            cc: "clang".to_string(),
+            target,
+            ..Default::default()
+        };
+        if let Some(t) = target.filter(|t| Some(*t) != Target::host()) {
+            opts.cc_args.push(format!("--target={}", t.triple()));
+        }
+        opts
+    }
+
+    /// Options assembling and linking the output of an assembly backend
+    /// into a static executable, it has no use for shared libraries. macOS
+    /// allows no static executables and its code calls into `libSystem`.
+    pub fn for_asm(target: Option<Target>) -> Self {
+        BuildOptions {
+            static_link: !target.is_some_and(Target::is_apple),
+            ..BuildOptions::for_target(target)
+        }
+    }
+
+    /// Options building the output of the AVR backend with `avr-gcc` for
+    /// the ATmega328P, other boards take `--cc avr-gcc` and their own
+    /// `-mmcu` through `--link-arg`.
+    pub fn for_avr() -> Self {
+        BuildOptions {
+            cc: "avr-gcc".to_string(),
+            cc_args: vec!["-mmcu=atmega328p".to_string()],
+            ..Default::default()
+        }
+    }
+}
+
+/// Named bundles of toolchain settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Fully static executables against musl, through `musl-gcc` on the
+    /// host and `<arch>-linux-musl-gcc` when cross compiling.
+    Musl,
+}
+
+impl Preset {
+    pub fn apply(self, opts: &mut BuildOptions) {
+        match self {
+            Preset::Musl => {
+                opts.cc = match opts.target {
+                    Some(t) if Some(t) != Target::host() => {
+                        format!("{}-gcc", t.triple().replace("-gnu", "-musl"))
+                    }
+                    _ => "musl-gcc".to_string(),
+                };
+                opts.cc_args.clear();
+                opts.static_link = true;
+            }
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "musl" => Ok(Preset::Musl),
+            _ => Err(format!("expected `musl`, found `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Preset::Musl => write!(f, "musl"),
+        }
+    }
+}
+
+/// Turns QBE IL into an executable at `output` by running `qbe` and `cc`.
+pub fn build(
+    il: &str,
+    output: &Path,
+    opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    check_target(opts)?;
+
+    let files = Intermediates::new(output, opts)?;
+    let (ssa, asm) = (files.path("ssa"), files.path("s"));
+    run_qbe(il, &ssa, &asm, opts, timings)?;
+    run_cc(&asm, &[], output, files.object().as_deref(), opts, timings)
+}
+
+/// Turns LLVM IR into an executable at `output` by handing it to `cc`,
+/// which has to understand it, as clang does.
+pub fn build_llvm(
+    ir: &str,
+    output: &Path,
+    opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    build_source(ir, "ll", &[], output, opts, timings)
+}
+
+/// Compiles and links C source into an executable at `output` with `cc`.
+pub fn build_c(
+    src: &str,
+    output: &Path,
+    opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    build_source(src, "c", &[], output, opts, timings)
+}
+
+/// Assembles and links the output of an assembly backend into an
+/// executable at `output` with `cc`. It brings its own `_start` and system
+/// calls, so libc is left out, except on macOS where it is a `_main` calling
+/// `libSystem`.
+pub fn build_asm(
+    asm: &str,
+    output: &Path,
+    opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    let extra: &[&str] = if opts.target.is_some_and(Target::is_apple) {
+        &[]
+    } else {
+        &["-nostdlib"]
+    };
+    build_source(asm, "s", extra, output, opts, timings)
+}
+
+/// Assembles and links the output of the AVR backend into an ELF
+/// executable at `output` with `cc`, which preprocesses it for the register
+/// names of `<avr/io.h>` and adds avr-libc's startup code.
+pub fn build_avr(
+    asm: &str,
+    output: &Path,
+    opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    build_source(asm, "S", &[], output, opts, timings)
+}
+
+/// Writes `code` to an intermediate file with extension `ext` and runs `cc`
+/// on it with `extra` arguments.
+fn build_source(
+    code: &str,
+    ext: &str,
+    extra: &[&str],
+    output: &Path,
+    opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    check_target(opts)?;
+
+    let files = Intermediates::new(output, opts)?;
+    let path = files.path(ext);
+    fs::write(&path, code).map_err(|e| Error::Io(path.clone(), e))?;
+    run_cc(
+        &path,
+        extra,
+        output,
+        files.object().as_deref(),
+        opts,
+        timings,
+    )
+}
+
+/// Where a build keeps its intermediate files. They go in a directory of
+/// their own, removed along with them once the build is done, unless
+/// [`BuildOptions::keep_intermediates`] or [`BuildOptions::save_temps`]
+/// asks to keep them: then they are named after the output, `prog.ssa`,
+/// `prog.s` and `prog.o` for `prog`, in the chosen directory or next to
+/// it.
+pub struct Intermediates {
+    dir: PathBuf,
+    stem: String,
+    keep: bool,
+    object: bool,
+}
+
+impl Intermediates {
+    pub fn new(output: &Path, opts: &BuildOptions) -> Result<Self, Error> {
+        let stem = output
+            .file_name()
+            .map_or("bfc".into(), |name| name.to_string_lossy().into_owned());
+        let (dir, keep) = match &opts.keep_intermediates {
+            Some(dir) => (dir.clone(), true),
+            None if opts.save_temps => {
+                let parent = output.parent().unwrap_or(Path::new(""));
+                (parent.to_path_buf(), true)
+            }
+            None => (temp_base(), false),
+        };
+        fs::create_dir_all(&dir).map_err(|e| Error::Io(dir.clone(), e))?;
+        Ok(Intermediates {
+            // `save_temps` never kept objects
+            object: opts.keep_intermediates.is_some(),
+            dir,
+            stem,
+            keep,
+        })
+    }
+
+    /// The intermediate file with extension `ext`.
+    pub fn path(&self, ext: &str) -> PathBuf {
+        with_suffix(&self.dir.join(&self.stem), ext)
+    }
+
+    /// Where to compile to before linking, when the object is kept.
+    pub fn object(&self) -> Option<PathBuf> {
+        self.object.then(|| self.path("o"))
+    }
+}
+
+impl Drop for Intermediates {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Rejects link options the target's toolchain has no equivalent for.
+fn check_target(opts: &BuildOptions) -> Result<(), Error> {
+    if opts.target.is_some_and(Target::is_windows) {
+        return Err(Error::Unsupported(
+            "Windows executables are linked by hand, build an object with `--emit obj`",
+        ));
+    }
+    if opts.static_link && opts.target.is_some_and(Target::is_apple) {
+        return Err(Error::Unsupported(
+            "Apple targets do not support static executables",
+        ));
+    }
+    if opts.pie == Some(false) && opts.target.is_some_and(Target::is_apple) {
+        return Err(Error::Unsupported(
+            "Apple targets require position independent executables",
+        ));
+    }
+    if opts.linker_script.is_some() && opts.target.is_some_and(Target::is_apple) {
+        return Err(Error::Unsupported(
+            "the Apple linker does not support linker scripts",
+        ));
+    }
+    Ok(())
+}
+
+/// Runs `qbe` alone, returning the assembly it produced for `il`.
+pub fn assemble(il: &str, opts: &BuildOptions, timings: &mut Timings) -> Result<String, Error> {
+    let files = Intermediates::new(Path::new("bfc"), &BuildOptions::default())?;
+    let (ssa, asm) = (files.path("ssa"), files.path("s"));
+    run_qbe(il, &ssa, &asm, opts, timings)?;
+    fs::read_to_string(&asm).map_err(|e| Error::Io(asm.clone(), e))
+}
+
+/// Interleaves assembly with the lines of `src` its `.loc` directives point
+/// at, as comments. The IL must have been generated with
+/// [`crate::CompileOptions::debug_file`] set.
+pub fn annotate_asm(asm: &str, src: &str) -> String {
+    let src: Vec<&str> = src.lines().collect();
+    let mut last = None;
+    let mut out = String::new();
+    for line in asm.lines() {
+        let loc = line
+            .trim_start()
+            .strip_prefix(".loc ")
+            .and_then(|args| args.split_whitespace().nth(1))
+            .and_then(|n| n.parse::<usize>().ok());
+        if let Some(n) = loc.filter(|n| last != Some(*n)) {
+            if let Some(text) = n.checked_sub(1).and_then(|i| src.get(i)) {
+                out.push_str(&format!("# {}: {}\n", n, text.trim_end()));
+            }
+            last = Some(n);
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+fn run_qbe(
+    il: &str,
+    ssa: &Path,
+    asm: &Path,
+    opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    fs::write(ssa, il).map_err(|e| Error::Io(ssa.to_path_buf(), e))?;
+
+    let mut qbe = Command::new(&opts.qbe);
+    if let Some(target) = opts.target {
+        qbe.arg("-t").arg(target.qbe_name());
+    }
+    qbe.arg("-o").arg(asm).arg(ssa);
+    run("qbe", qbe, opts, timings)
+}
+
+/// Compiles or assembles `input` and links it into `output`, `extra`
+/// arguments going ahead of the user's. With an `object`, it is compiled to
+/// that first and linked in a second run.
+fn run_cc(
+    input: &Path,
+    extra: &[&str],
+    output: &Path,
+    object: Option<&Path>,
+    opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    let base = || {
+        let mut cc = Command::new(&opts.cc);
+        cc.args(extra).args(&opts.cc_args);
+        cc
+    };
+    let input = match object {
+        Some(object) => {
+            let mut cc = base();
+            compile_args(&mut cc, opts);
+            cc.arg("-c").arg("-o").arg(object).arg(input);
+            run("cc", cc, opts, timings)?;
+            object
+        }
+        None => input,
+    };
+
+    let mut cc = base();
+    if object.is_none() {
+        compile_args(&mut cc, opts);
+    }
+    link_args(&mut cc, opts);
+    cc.arg("-o").arg(output).arg(input);
+    cc.args(&opts.link_args);
+    run("cc", cc, opts, timings)?;
+    write_image(output, opts)
+}
+
+/// Arguments to `cc` for compiling: C and LLVM IR are compiled to match the
+/// executable, assembly is the same either way.
+fn compile_args(cc: &mut Command, opts: &BuildOptions) {
+    match opts.pie {
+        Some(true) => cc.arg("-fPIE"),
+        Some(false) => cc.arg("-fno-pie"),
+        None => cc,
+    };
+}
+
+fn link_args(cc: &mut Command, opts: &BuildOptions) {
+    match opts.pie {
+        Some(true) if opts.static_link => cc.arg("-static-pie"),
+        Some(true) => cc.arg("-pie"),
+        Some(false) => cc.arg("-no-pie"),
+        None => cc,
+    };
+    if opts.static_link && opts.pie != Some(true) {
+        cc.arg("-static");
+    }
+    if opts.strip {
+        cc.arg("-s");
+    }
+    if let Some(linker) = &opts.linker {
+        cc.arg(format!("-fuse-ld={}", linker));
+    }
+    if let Some(script) = &opts.linker_script {
+        cc.arg("-T").arg(script);
+    }
+}
+
+/// Replaces the executable at `output` with an image in `opts.format`.
+fn write_image(output: &Path, opts: &BuildOptions) -> Result<(), Error> {
+    if opts.format == Format::Elf {
+        return Ok(());
+    }
+    let elf = fs::read(output).map_err(|e| Error::Io(output.to_path_buf(), e))?;
+    let image = image::convert(&elf, opts.format, opts.load_address)
+        .map_err(|e| Error::Image(output.to_path_buf(), e))?;
+    fs::write(output, image).map_err(|e| Error::Io(output.to_path_buf(), e))
+}
+
+/// Runs `cmd` for `phase`, passing on what it writes to stderr when it
+/// succeeds and reporting it along with the command line when it fails.
+fn run(
+    phase: &'static str,
+    mut cmd: Command,
+    opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    let tool = cmd.get_program().to_string_lossy().into_owned();
+    let command = command_line(&cmd);
+    if opts.verbose {
+        eprintln!("bfc: running {}", command);
+    }
+
+    let start = Instant::now();
+    let res = wait(&mut cmd, opts.timeout);
+    timings.add(phase, start.elapsed());
+    match res.map_err(|e| Error::Spawn(tool.clone(), e))? {
+        Some((status, stderr)) if status.success() => {
+            eprint!("{}", stderr);
+            Ok(())
+        }
+        Some((status, stderr)) => Err(Error::Tool {
+            phase,
+            tool,
+            command,
+            status,
+            stderr,
+        }),
+        None => Err(Error::Timeout {
+            tool,
+            command,
+            after: start.elapsed(),
+        }),
+    }
+}
+
+/// Runs `cmd` with its stderr captured, killing it once it has run for
+/// `timeout`, in which case there is no status.
+fn wait(cmd: &mut Command, timeout: Option<Duration>) -> io::Result<Option<(ExitStatus, String)>> {
+    let mut child = cmd.stderr(Stdio::piped()).spawn()?;
+    let mut pipe = child.stderr.take().expect("stderr is piped");
+    // drained while waiting, a tool filling the pipe would block forever
+    let reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    });
+
+    let status = match timeout {
+        None => Some(child.wait()?),
+        Some(limit) => {
+            let start = Instant::now();
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break Some(status);
+                }
+                if start.elapsed() >= limit {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    break None;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    };
+    let stderr = reader.join().unwrap_or_default();
+    Ok(status.map(|status| (status, String::from_utf8_lossy(&stderr).into_owned())))
+}
+
+/// `cmd` as it could be typed into a shell, arguments with spaces or quotes
+/// in them quoted.
+fn command_line(cmd: &Command) -> String {
+    let quote = |arg: &std::ffi::OsStr| {
+        let arg = arg.to_string_lossy();
+        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "'\"\\$".contains(c)) {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        } else {
+            arg.into_owned()
+        }
+    };
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn with_suffix(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+fn temp_base() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("bfc-{}-{}", process::id(), n))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn annotate_with_source_lines() {
+        let asm = "main:\n\t.loc 1 1\n\tmovl $0, %eax\n\t.loc 1 1\n\t.loc 1 2\n\tret\n";
+        let out = annotate_asm(asm, "+++\n.\n");
+        assert_eq!(
+            out,
+            "main:\n# 1: +++\n\t.loc 1 1\n\tmovl $0, %eax\n\t.loc 1 1\n# 2: .\n\t.loc 1 2\n\tret\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_failing_tools() {
+        let opts = BuildOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo 'bad IL' >&2; exit 3"]);
+        let err = run("qbe", cmd, &opts, &mut Timings::default()).unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.starts_with("`sh` failed with exit status: 3\n"),
+            "{}",
+            msg
+        );
+        assert!(msg.contains("  command: sh -c 'echo '\\''bad IL'\\'' >&2; exit 3'\n"));
+        assert!(msg.contains("  | bad IL\n  `--emit qbe`"));
+
+        let mut cmd = Command::new("sleep");
+        cmd.arg("5");
+        let err = run("cc", cmd, &opts, &mut Timings::default()).unwrap_err();
+        assert!(matches!(err, Error::Timeout { .. }), "{}", err);
+    }
+
+    #[test]
+    fn intermediates_named_after_output() {
+        let dir = temp_base();
+        let opts = BuildOptions {
+            keep_intermediates: Some(dir.clone()),
+            ..Default::default()
+        };
+        let files = Intermediates::new(Path::new("out/prog"), &opts).unwrap();
+        assert_eq!(files.path("ssa"), dir.join("prog.ssa"));
+        assert_eq!(files.object(), Some(dir.join("prog.o")));
+        drop(files);
+        assert!(dir.is_dir());
+        fs::remove_dir(&dir).unwrap();
+
+        let files = Intermediates::new(Path::new("prog"), &BuildOptions::default()).unwrap();
+        let ssa = files.path("ssa");
+        fs::write(&ssa, "").unwrap();
+        assert_eq!(files.object(), None);
+        drop(files);
+        assert!(!ssa.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn cross_compiler_for_foreign_targets() {
+        let host = Target::host();
+        assert_eq!(BuildOptions::for_target(host).cc, "cc");
+        assert_eq!(BuildOptions::for_target(None).cc, "cc");
+        if host != Some(Target::Rv64) {
+            let opts = BuildOptions::for_target(Some(Target::Rv64));
+            assert_eq!(opts.cc, "riscv64-linux-gnu-gcc");
+        }
+        if !host.is_some_and(|t| t.is_apple()) {
+            let opts = BuildOptions::for_target(Some(Target::Arm64Apple));
+            assert_eq!(opts.cc, "clang");
+            assert_eq!(opts.cc_args, ["--target=arm64-apple-darwin"]);
+        }
+
+        let opts = BuildOptions::for_llvm(host);
+        assert_eq!(opts.cc, "clang");
+        assert!(opts.cc_args.is_empty());
+        if host != Some(Target::Rv64) {
+            let opts = BuildOptions::for_llvm(Some(Target::Rv64));
+            assert_eq!(opts.cc_args, ["--target=riscv64-linux-gnu"]);
+        }
+    }
+
+    #[test]
+    fn musl_preset() {
+        let mut opts = BuildOptions::for_target(Target::host());
+        Preset::Musl.apply(&mut opts);
+        assert_eq!(opts.cc, "musl-gcc");
+        assert!(opts.static_link);
+
+        if Target::host() != Some(Target::Arm64) {
+            let mut opts = BuildOptions::for_target(Some(Target::Arm64));
+            Preset::Musl.apply(&mut opts);
+            assert_eq!(opts.cc, "aarch64-linux-musl-gcc");
+        }
+    }
+
+    #[test]
+    fn temps_next_to_output() {
+        let out = Path::new("dir/hello");
+        assert_eq!(with_suffix(out, "ssa"), Path::new("dir/hello.ssa"));
+        assert_eq!(with_suffix(out, "s"), Path::new("dir/hello.s"));
+    }
+}
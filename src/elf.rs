@@ -0,0 +1,119 @@
+//! Static Linux x86-64 executables written out by the compiler itself, no
+//! assembler or linker involved: an ELF header, a read-only executable
+//! segment with the code and messages, and a zero-filled segment for the
+//! tape. The code only addresses things relative to itself, so it runs
+//! wherever it is loaded and position independent executables need no
+//! dynamic relocations.
+
+use crate::x86::{self, Code, Sym};
+
+// where the executable segment of a fixed address executable is mapped,
+// the conventional spot
+const BASE: u64 = 0x40_0000;
+const PAGE: u64 = 0x1000;
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Lays out `code` as an executable with `tape_size` bytes of zeroed tape,
+/// position independent with `pie`.
+pub fn executable(code: &Code, tape_size: usize, pie: bool) -> Vec<u8> {
+    let base = if pie { 0 } else { BASE };
+    let text_off = EHDR_SIZE + 2 * PHDR_SIZE;
+    let text_addr = base + text_off;
+    let read_err = text_addr + code.text.len() as u64;
+    let write_err = read_err + x86::READ_ERR.len() as u64;
+    let file_size = write_err - base + x86::WRITE_ERR.len() as u64;
+    let tape = (base + file_size).next_multiple_of(PAGE);
+    // the byte I/O goes through sits right after the tape
+    let bss_size = tape_size as u64 + 1;
+
+    let mut text = code.text.clone();
+    for &(at, sym) in &code.relocs {
+        let addr = match sym {
+            Sym::Tape => tape,
+            Sym::Buf => tape + tape_size as u64,
+            Sym::ReadErr => read_err,
+            Sym::WriteErr => write_err,
+            Sym::Import(_) | Sym::Func(_) => unreachable!("Linux code imports nothing"),
+        };
+        let rel = addr.wrapping_sub(text_addr + at as u64 + 4) as i32;
+        text[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    let mut out = Vec::with_capacity(file_size as usize);
+    // e_ident: 64 bit, little endian, version 1, System V ABI
+    out.extend(b"\x7fELF\x02\x01\x01\x00");
+    out.extend([0; 8]);
+    out.extend(if pie { ET_DYN } else { ET_EXEC }.to_le_bytes());
+    out.extend(62u16.to_le_bytes()); // EM_X86_64
+    out.extend(1u32.to_le_bytes());
+    out.extend((text_addr + code.entry as u64).to_le_bytes());
+    out.extend(EHDR_SIZE.to_le_bytes()); // program headers right after
+    out.extend(0u64.to_le_bytes()); // no section headers
+    out.extend(0u32.to_le_bytes());
+    out.extend((EHDR_SIZE as u16).to_le_bytes());
+    out.extend((PHDR_SIZE as u16).to_le_bytes());
+    out.extend(2u16.to_le_bytes());
+    out.extend([0; 6]); // section header size, count and name index
+
+    program_header(&mut out, PF_R | PF_X, 0, base, file_size, file_size);
+    program_header(&mut out, PF_R | PF_W, 0, tape, 0, bss_size);
+
+    out.extend(text);
+    out.extend(x86::READ_ERR);
+    out.extend(x86::WRITE_ERR);
+    out
+}
+
+fn program_header(out: &mut Vec<u8>, flags: u32, offset: u64, addr: u64, file: u64, mem: u64) {
+    out.extend(PT_LOAD.to_le_bytes());
+    out.extend(flags.to_le_bytes());
+    out.extend(offset.to_le_bytes());
+    out.extend(addr.to_le_bytes());
+    out.extend(addr.to_le_bytes());
+    out.extend(file.to_le_bytes());
+    out.extend(mem.to_le_bytes());
+    out.extend(PAGE.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn headers_and_layout() {
+        let prog = crate::parse("+.").unwrap();
+        let mut gen = x86::X86Generator::new();
+        let code = gen.encode(&prog);
+        let exe = executable(&code, gen.tape_size(), false);
+        let entry = u64::from_le_bytes(exe[24..32].try_into().unwrap());
+        assert_eq!(entry, BASE + 176);
+        assert_eq!(exe.len(), 176 + code.text.len() + 33);
+        // the tape segment starts on the page after the code
+        let tape = u64::from_le_bytes(exe[64 + 56 + 16..64 + 56 + 24].try_into().unwrap());
+        assert_eq!(tape, BASE + PAGE);
+        // leaq bf_tape(%rip), %r12 points there
+        let rel = i32::from_le_bytes(exe[179..183].try_into().unwrap());
+        assert_eq!(entry as i64 + 7 + rel as i64, tape as i64);
+    }
+
+    #[test]
+    fn position_independent() {
+        let prog = crate::parse("+.").unwrap();
+        let mut gen = x86::X86Generator::new();
+        let code = gen.encode(&prog);
+        let exe = executable(&code, gen.tape_size(), true);
+        assert_eq!(u16::from_le_bytes([exe[16], exe[17]]), ET_DYN);
+        let entry = u64::from_le_bytes(exe[24..32].try_into().unwrap());
+        assert_eq!(entry, 176);
+        // the same code as at a fixed address, nothing to relocate
+        assert_eq!(exe[176..], executable(&code, gen.tape_size(), false)[176..]);
+    }
+}
@@ -0,0 +1,212 @@
+//! Brute-force testing of the backends: every program up to some length
+//! with balanced brackets is run under [`interp`] on a few inputs, and a
+//! backend's code for it has to do the same. Most lowering and optimizer
+//! bugs show up on some program of a handful of statements, and there are
+//! few enough of those to try them all.
+//!
+//! The interpreter runs the tree as parsed, so the optimizer is checked
+//! along with the backend. Inputs a program does not finish on under the
+//! interpreter are left out.
+
+use std::fmt;
+
+use crate::{
+    interp::{self, Limits},
+    validate::Run,
+};
+
+/// The commands programs are made of, in the order they are enumerated.
+pub const ALPHABET: [u8; 8] = *b"+-<>,.[]";
+
+/// Inputs every program is run on: none, so that `,` hits end of input
+/// straight away, and two bytes, one of them past what a signed byte holds.
+pub const INPUTS: [&[u8]; 2] = [b"", b"\x02\xff"];
+
+/// Every program of at most `max_len` commands with balanced brackets,
+/// shortest first.
+pub fn programs(max_len: usize) -> Programs {
+    Programs {
+        max_len,
+        digits: Vec::new(),
+        done: false,
+    }
+}
+
+pub struct Programs {
+    max_len: usize,
+    /// Index into [`ALPHABET`] of each command of the next candidate.
+    digits: Vec<usize>,
+    done: bool,
+}
+
+impl Programs {
+    fn advance(&mut self) {
+        for digit in self.digits.iter_mut().rev() {
+            *digit += 1;
+            if *digit < ALPHABET.len() {
+                return;
+            }
+            *digit = 0;
+        }
+        // every program of this length was tried, all digits are back at 0
+        if self.digits.len() == self.max_len {
+            self.done = true;
+        } else {
+            self.digits.push(0);
+        }
+    }
+}
+
+impl Iterator for Programs {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        while !self.done {
+            let src: String = self.digits.iter().map(|&d| ALPHABET[d] as char).collect();
+            self.advance();
+            if balanced(&src) {
+                return Some(src);
+            }
+        }
+        None
+    }
+}
+
+fn balanced(src: &str) -> bool {
+    let mut depth = 0usize;
+    for c in src.bytes() {
+        match c {
+            b'[' => depth += 1,
+            b']' => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            _ => {}
+        }
+    }
+    depth == 0
+}
+
+/// A program and input a backend's code disagreed with the interpreter on.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub src: String,
+    pub input: Vec<u8>,
+    pub expected: Run,
+    pub found: Run,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` on input \"{}\": expected it {} but it {}",
+            self.src,
+            self.input.escape_ascii(),
+            self.expected,
+            self.found
+        )
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub programs: usize,
+    /// Runs compared, a program and an input each.
+    pub runs: usize,
+    /// Runs left out because the interpreter did not finish them.
+    pub skipped: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Checks every program of at most `max_len` commands. `run` is given a
+/// program and the inputs to run its code on, and returns how each run went
+/// in the same order; its errors, such as a toolchain that is missing, stop
+/// the check.
+pub fn cross_check<E>(
+    max_len: usize,
+    limits: Limits,
+    mut run: impl FnMut(&str, &[&[u8]]) -> Result<Vec<Run>, E>,
+) -> Result<Report, E> {
+    let mut report = Report::default();
+    for src in programs(max_len) {
+        let prog = crate::parse(&src).expect("enumerated programs parse");
+        let mut inputs = Vec::new();
+        let mut expected = Vec::new();
+        for input in INPUTS {
+            match Run::of(interp::run(&prog, input, limits)) {
+                Some(r) => {
+                    inputs.push(input);
+                    expected.push(r);
+                }
+                None => report.skipped += 1,
+            }
+        }
+        report.programs += 1;
+        if inputs.is_empty() {
+            continue;
+        }
+
+        let found = run(&src, &inputs)?;
+        report.runs += inputs.len();
+        for ((input, expected), found) in inputs.into_iter().zip(expected).zip(found) {
+            if found != expected {
+                report.mismatches.push(Mismatch {
+                    src: src.clone(),
+                    input: input.to_vec(),
+                    expected,
+                    found,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{validate, CompileOptions, OptLevel};
+
+    fn check_il(max_len: usize, opts: &CompileOptions, edit: fn(String) -> String) -> Report {
+        let limits = Limits {
+            steps: 1000,
+            cells: opts.tape_len,
+        };
+        cross_check(max_len, limits, |src, inputs| {
+            let prog = crate::optimize(src, opts).map_err(|e| e.to_string())?;
+            let il = crate::gen::QbeGenerator::with_options(opts)
+                .map_err(|e| e.to_string())?
+                .gen(&prog);
+            let il = edit(il.map_err(|e| e.to_string())?);
+            inputs
+                .iter()
+                .map(|input| validate::run_il(&il, input, 1_000_000))
+                .collect::<Result<_, _>>()
+                .map_err(|e| e.to_string())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn enumerate_and_cross_check() {
+        let short: Vec<_> = programs(2).collect();
+        assert_eq!(short.len(), 1 + 6 + 37);
+        assert_eq!(short[..3], ["", "+", "-"]);
+        assert!(short.contains(&"[]".to_string()) && !short.contains(&"][".to_string()));
+
+        for opt_level in OptLevel::ALL {
+            let opts = CompileOptions {
+                opt_level,
+                tape_len: 8,
+                ..Default::default()
+            };
+            let report = check_il(4, &opts, |il| il);
+            assert_eq!(report.mismatches, []);
+            assert!(report.skipped > 0 && report.runs > 2 * 1000);
+        }
+
+        let broken = check_il(2, &CompileOptions::default(), |il| il.replace("add", "sub"));
+        assert!(broken.mismatches.iter().any(|m| m.src == "+."));
+    }
+}
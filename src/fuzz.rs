@@ -0,0 +1,181 @@
+//! Fuzzing the inputs of a brainfuck program, as opposed to the compiler:
+//! a static hint of what input the program expects seeds a mutation loop
+//! running it under the interpreter, and inputs that make it hang or fall
+//! off the tape are reported.
+
+use std::fmt;
+
+use crate::{
+    ast::{NodeBlock, Statement},
+    interp::{self, Limits, Status},
+};
+
+/// What the `,`s of a program say about the input it consumes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputShape {
+    /// Bytes read by `,`s outside of any loop, the least input the program
+    /// looks at on every run.
+    pub fixed: usize,
+    /// Whether some `,` sits in a loop, so input of any length may be read.
+    pub looped: bool,
+}
+
+impl InputShape {
+    pub fn of(prog: &NodeBlock) -> Self {
+        fn reads(block: &NodeBlock) -> usize {
+            block
+                .stats
+                .iter()
+                .map(|stat| match &stat.stat {
+                    Statement::Read => 1,
+                    Statement::Loop(body) | Statement::If(body) => reads(body),
+                    _ => 0,
+                })
+                .sum()
+        }
+
+        let fixed = prog
+            .stats
+            .iter()
+            .filter(|stat| matches!(stat.stat, Statement::Read))
+            .count();
+        InputShape {
+            fixed,
+            looped: reads(prog) > fixed,
+        }
+    }
+}
+
+impl fmt::Display for InputShape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.fixed, self.looped) {
+            (0, false) => write!(f, "reads no input"),
+            (n, false) => write!(f, "reads exactly {} bytes", n),
+            (n, true) => write!(f, "reads at least {} bytes, more in loops", n),
+        }
+    }
+}
+
+/// An input the program did not finish cleanly on.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub input: Vec<u8>,
+    pub status: Status,
+}
+
+/// xorshift, plenty for picking mutations
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+}
+
+fn mutate(rng: &mut Rng, input: &mut Vec<u8>, looped: bool) {
+    match rng.below(4) {
+        0 if !input.is_empty() => {
+            let i = rng.below(input.len());
+            input[i] ^= 1 << rng.below(8);
+        }
+        1 if !input.is_empty() => {
+            let i = rng.below(input.len());
+            input[i] = rng.next() as u8;
+        }
+        // only grow or shrink inputs the program may read in full
+        2 if looped => input.insert(rng.below(input.len() + 1), rng.next() as u8),
+        3 if looped && !input.is_empty() => {
+            input.remove(rng.below(input.len()));
+        }
+        _ => input.push(rng.next() as u8),
+    }
+}
+
+/// Runs `prog` on `runs` mutated inputs shaped after `shape`, returning the
+/// first input found for every way the program failed to finish. Inputs
+/// that make the program run for a new order of magnitude of steps are kept
+/// around to mutate further.
+pub fn fuzz(prog: &NodeBlock, runs: usize, seed: u64, limits: Limits) -> Vec<Finding> {
+    let shape = InputShape::of(prog);
+    let mut rng = Rng(seed | 1);
+    let mut corpus = vec![vec![0; shape.fixed]];
+    let mut seen_steps = Vec::new();
+    let mut findings: Vec<Finding> = Vec::new();
+
+    for _ in 0..runs {
+        let mut input = corpus[rng.below(corpus.len())].clone();
+        for _ in 0..=rng.below(4) {
+            mutate(&mut rng, &mut input, shape.looped);
+        }
+
+        let outcome = interp::run(prog, &input, limits);
+        if outcome.status != Status::Finished {
+            if !findings.iter().any(|f| f.status == outcome.status) {
+                findings.push(Finding {
+                    input,
+                    status: outcome.status,
+                });
+            }
+            continue;
+        }
+
+        let magnitude = 64 - outcome.steps.leading_zeros();
+        if !seen_steps.contains(&magnitude) {
+            seen_steps.push(magnitude);
+            corpus.push(input);
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shape_of_reads() {
+        let shape = |src| InputShape::of(&crate::parse(src).unwrap());
+        assert_eq!(
+            shape("+."),
+            InputShape {
+                fixed: 0,
+                looped: false
+            }
+        );
+        assert_eq!(
+            shape(",>,"),
+            InputShape {
+                fixed: 2,
+                looped: false
+            }
+        );
+        assert_eq!(
+            shape(",[.,]"),
+            InputShape {
+                fixed: 1,
+                looped: true
+            }
+        );
+    }
+
+    #[test]
+    fn find_hanging_input() {
+        // counts down past zero, for ages, when the first byte is odd
+        let prog = crate::parse(",[--]").unwrap();
+        let limits = Limits {
+            steps: 10_000,
+            ..Default::default()
+        };
+        let findings = fuzz(&prog, 500, 42, limits);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].status, Status::StepLimit);
+        assert_eq!(findings[0].input[0] % 2, 1);
+    }
+}
@@ -0,0 +1,123 @@
+//! A gdb Python script for debugging executables the qbe backend built with
+//! debug info, which keep the tape's address and the pointer in `bf.*`
+//! globals. It adds two commands:
+//!
+//! - `bf-tape [RADIUS]` prints the cells around the pointer, 8 on either
+//!   side unless told otherwise
+//! - `bf-where` prints the source line the program stopped at, from the
+//!   innermost frame with a line table entry, so it works from inside libc
+//!
+//! gdb loads the script by itself when it sits next to the executable as
+//! `<executable>-gdb.py` and auto-loading is allowed, otherwise `source` it.
+
+use std::fmt::Write;
+
+use crate::{
+    gen::{CELL_STRIDE, DEBUG_PTR, TAPE_BASE},
+    CompileOptions,
+};
+
+/// Cells `bf-tape` shows on either side of the pointer by default.
+const RADIUS: usize = 8;
+
+/// The script for a program compiled from `source` with `opts`.
+pub fn helpers(source: &str, opts: &CompileOptions) -> String {
+    let mut out = String::new();
+    writeln!(out, "# gdb helpers for {}, generated by bfc", source).unwrap();
+    out.push_str("import gdb\n\n");
+    writeln!(out, "SOURCE = {}", quote(source)).unwrap();
+    writeln!(out, "TAPE_LEN = {}", opts.tape_len).unwrap();
+    writeln!(out, "CELL_STRIDE = {}", CELL_STRIDE).unwrap();
+    writeln!(out, "RADIUS = {}\n\n", RADIUS).unwrap();
+
+    writeln!(
+        out,
+        r#"def _global(name):
+    return int(gdb.parse_and_eval("*(long *)&'%s'" % name))
+
+
+def _cell(addr):
+    return int(gdb.parse_and_eval("*(unsigned int *)%d" % addr))
+
+
+_lines = None
+
+
+def _source_line(n):
+    global _lines
+    if _lines is None:
+        try:
+            with open(SOURCE) as f:
+                _lines = f.read().splitlines()
+        except OSError:
+            _lines = []
+    return _lines[n - 1] if 0 < n <= len(_lines) else ""
+
+
+class Tape(gdb.Command):
+    """Print the cells around the brainfuck pointer: bf-tape [RADIUS]"""
+
+    def __init__(self):
+        super().__init__("bf-tape", gdb.COMMAND_DATA)
+
+    def invoke(self, arg, from_tty):
+        radius = int(arg) if arg.strip() else RADIUS
+        base = _global("{tape}")
+        if base == 0:
+            print("bf: the tape is not set up yet")
+            return
+        index = (_global("{ptr}") - base) // CELL_STRIDE
+        for i in range(max(0, index - radius), min(TAPE_LEN, index + radius + 1)):
+            value = _cell(base + i * CELL_STRIDE)
+            mark = "->" if i == index else "  "
+            char = " %r" % chr(value) if 32 <= value < 127 else ""
+            print("%s %6d: %d%s" % (mark, i, value, char))
+
+
+class Where(gdb.Command):
+    """Print the brainfuck source line the program stopped at."""
+
+    def __init__(self):
+        super().__init__("bf-where", gdb.COMMAND_STACK)
+
+    def invoke(self, arg, from_tty):
+        frame = gdb.selected_frame()
+        while frame is not None:
+            sal = frame.find_sal()
+            if sal.symtab is not None and sal.line > 0:
+                print("%s:%d: %s" % (SOURCE, sal.line, _source_line(sal.line)))
+                return
+            frame = frame.older()
+        print("bf: no line information, build with -g")
+
+
+Tape()
+Where()"#,
+        tape = TAPE_BASE,
+        ptr = DEBUG_PTR
+    )
+    .unwrap();
+    out
+}
+
+/// `s` as a Python string literal.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn script_names_the_globals() {
+        let opts = CompileOptions {
+            tape_len: 100,
+            ..Default::default()
+        };
+        let script = helpers("dir/\"hello\".b", &opts);
+        assert!(script.contains("SOURCE = \"dir/\\\"hello\\\".b\"\nTAPE_LEN = 100\n"));
+        assert!(script.contains("base = _global(\"bf.tape_base\")\n"));
+        assert!(script.contains("(_global(\"bf.ptr\") - base)"));
+    }
+}
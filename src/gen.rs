@@ -1,12 +1,116 @@
-use crate::ast;
+use crate::{
+    ast,
+    backend::CodeGen,
+    cost::{self, CostModel},
+    lex::Span,
+    stamp,
+    target::Target,
+    AtExit, Checkpoint, CompileOptions, Entry, Linkage, Newline, OptLevel,
+};
 
 #[derive(Debug)]
 pub enum Error {}
 
+// `errno` after an interrupted call, 4 on Linux and macOS alike. Where
+// `errno` lives differs, see `Target::errno_location`.
+const EINTR: u64 = 4;
+
 pub struct QbeGenerator {
     label_counter: usize,
     tmp_counter: usize,
     tape_len: usize,
+    // `calloc` the tape rather than put it on the stack
+    heap_tape: bool,
+    io_strict: bool,
+    crlf: bool,
+    atexit: AtExit,
+    opt_level: OptLevel,
+    // call shared helpers for `.`/`,` rather than inlining the retry logic
+    outline_io: bool,
+    // set while generating any function other than `main`
+    in_module: bool,
+    debug_file: Option<String>,
+    errno_location: &'static str,
+    split_loops: bool,
+    // symbol prefix of outlined loops, `bf_` or that of the current module
+    loop_prefix: String,
+    // outlined loops, rendered as soon as they are complete
+    loops: Vec<String>,
+    // source line of the last location marker in the current function
+    last_line: usize,
+    // the function generated in place of `main`
+    entry: Option<Entry>,
+    // symbol the I/O hooks called in place of `read` and `write` are named
+    // after, for functions called from C, see `io_hooks`
+    io_hooks: Option<String>,
+    checkpoint: Option<Checkpoint>,
+    // written into snapshots and checked on resuming, see `stamp::compat`
+    compat: u64,
+    // entry functions run on their caller's tape
+    shared_tape: bool,
+    // loops a run can resume in, by the number in their labels
+    resume_points: Vec<usize>,
+    // moves are not bounds checked
+    unchecked: bool,
+    // address cells at an offset from `%ptr` within straight-line code,
+    // moving it at loop boundaries and I/O
+    offset_addressing: bool,
+    pending: Pending,
+    // with offset addressing, keep cells in temporaries between commits,
+    // loading each once and storing it once
+    cache_cells: bool,
+    cached: Vec<Cached>,
+    // data definitions `Fill` statements copy onto the tape, by name
+    fills: Vec<(String, Vec<u32>)>,
+    // and those `Print` statements write out
+    prints: Vec<(String, Vec<u8>)>,
+}
+
+/// The moves of a straight-line run not applied to `%ptr` yet, with
+/// offset addressing, in cells.
+#[derive(Debug, Default, Clone, Copy)]
+struct Pending {
+    /// Where the pointer is, from `%ptr`.
+    offset: i64,
+    /// The furthest the run got on either side, all of which has to be on
+    /// the tape before a cell is touched or the pointer moved.
+    low: i64,
+    high: i64,
+    /// The range bounds checked so far. `%ptr` itself always is.
+    checked: (i64, i64),
+}
+
+/// A cell held in a temporary with load caching, stored back to memory by
+/// the next commit when it changed.
+#[derive(Debug, Clone)]
+struct Cached {
+    /// Which cell, from `%ptr`.
+    offset: i64,
+    addr: qbe::Value,
+    value: qbe::Value,
+    dirty: bool,
+}
+
+impl Pending {
+    fn moved(&mut self, by: i64) {
+        self.offset = self.offset.saturating_add(by);
+        self.low = self.low.min(self.offset);
+        self.high = self.high.max(self.offset);
+    }
+}
+
+impl Default for QbeGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for QbeGenerator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, crate::Error> {
+        Ok(self.gen(prog)?)
+    }
 }
 
 impl QbeGenerator {
@@ -16,201 +120,2425 @@ impl QbeGenerator {
             label_counter: 0,
             // FIXME: holes in the tape
             tape_len: 16 * 30_000,
+            heap_tape: false,
+            io_strict: false,
+            crlf: false,
+            atexit: AtExit::default(),
+            opt_level: OptLevel::O0,
+            outline_io: false,
+            in_module: false,
+            debug_file: None,
+            errno_location: "__errno_location",
+            split_loops: false,
+            loop_prefix: "bf_".to_string(),
+            loops: Vec::new(),
+            last_line: 0,
+            entry: None,
+            io_hooks: None,
+            checkpoint: None,
+            compat: 0,
+            shared_tape: false,
+            resume_points: Vec::new(),
+            unchecked: false,
+            offset_addressing: false,
+            pending: Pending::default(),
+            cache_cells: false,
+            cached: Vec::new(),
+            fills: Vec::new(),
+            prints: Vec::new(),
+        }
+    }
+
+    /// The generator for `opts`, failing on a tape with no cells or one too
+    /// long to address.
+    pub fn with_options(opts: &CompileOptions) -> Result<Self, crate::Error> {
+        if opts.tape_len == 0 {
+            return Err(crate::Error::Unsupported(
+                "the tape needs at least one cell",
+            ));
         }
+        let tape_len = opts
+            .tape_len
+            .checked_mul(16)
+            .ok_or(crate::Error::Unsupported("the tape does not fit in memory"))?;
+        Ok(QbeGenerator {
+            tape_len,
+            heap_tape: !opts.shared_tape
+                && (opts.memcheck || opts.max_stack.is_some_and(|max| tape_len > max)),
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            atexit: opts.atexit,
+            opt_level: opts.opt_level,
+            debug_file: opts.debug_file.clone(),
+            // qbe generates for the host unless told otherwise
+            errno_location: opts
+                .target
+                .or_else(Target::host)
+                .map_or("__errno_location", |t| t.errno_location()),
+            split_loops: opts.split_loops,
+            entry: opts.entry.clone(),
+            io_hooks: opts
+                .entry
+                .as_ref()
+                .filter(|entry| entry.linkage != Linkage::Local)
+                .map(|entry| entry.symbol.clone()),
+            checkpoint: opts.checkpoint.clone(),
+            compat: stamp::compat_hash(opts),
+            shared_tape: opts.shared_tape,
+            unchecked: opts.unchecked,
+            // a debugger follows the pointer after every move
+            offset_addressing: opts.opt_level.rank() >= 2 && opts.debug_file.is_none(),
+            // the tape has to be up to date whenever a run ending on a move
+            // off it is followed by something looking at it
+            cache_cells: opts.opt_level.rank() >= 2
+                && opts.debug_file.is_none()
+                && !opts.shared_tape
+                && !(opts.atexit.dump_tape || opts.atexit.stats || opts.atexit.histogram),
+            ..Self::new()
+        })
     }
 
     pub fn gen(&mut self, prog: &ast::Prog) -> Result<String, ast::Error> {
-        let mut main = qbe::Function::new(
-            qbe::Linkage::public(),
-            "main".to_string(),
-            Vec::new(),
-            Some(qbe::Type::Word),
-        );
+        let mut module = qbe::Module::new();
+        self.generate_io_helpers(&mut module, count_io(prog));
+        self.generate_atexit(&mut module);
+
+        let mut main = self.entry_function();
         main.add_block("runtime".to_string());
         self.generate_runtime(&mut main);
+        self.generate_resume_check(&mut main);
         main.add_block("start".to_string());
         self.generate_block(&mut main, prog);
-        main.add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(0))));
+        self.generate_halt(&mut main, 0);
+        self.generate_resume(&mut main);
+
+        module.add_function(main);
+        self.generate_checkpoint_helpers(&mut module);
+        self.generate_data(&mut module);
+        Ok(self.finish(module))
+    }
+
+    /// The IL of `module` and the loops outlined from it, with the location
+    /// markers lowered when generating line tables.
+    fn finish(&self, module: qbe::Module) -> String {
+        let il = format!("{}\n{}", module, self.loops.concat());
+        match &self.debug_file {
+            Some(file) => format!(
+                "dbgfile \"{}\"\n{}",
+                file.replace('"', "\\\""),
+                lower_locations(&il)
+            ),
+            None => il,
+        }
+    }
 
+    /// Generates several programs sharing one tape and pointer. Each program
+    /// becomes a `bf_<name>` function; `main` runs the first one and then
+    /// dispatches on the selector cell (tape cell 0): after a program
+    /// returns, a value `k` in that cell runs the `k`-th program (1-based),
+    /// zero ends the run. The dispatcher clears the cell before every call
+    /// so a program only chains into another one by setting it explicitly.
+    /// An out-of-range selector exits with status 1.
+    pub fn gen_modules(&mut self, progs: &[(String, ast::Prog)]) -> Result<String, ast::Error> {
         let mut module = qbe::Module::new();
+        let uses = progs.iter().map(|(_, prog)| count_io(prog)).sum();
+        self.generate_io_helpers(&mut module, uses);
+        self.generate_atexit(&mut module);
+
+        self.in_module = true;
+        for (name, prog) in progs {
+            self.loop_prefix = format!("{}_", module_symbol(name));
+            self.last_line = 0;
+            let mut func = qbe::Function::new(
+                qbe::Linkage::private(),
+                module_symbol(name),
+                vec![
+                    (qbe::Type::Long, self.generate_tape()),
+                    (qbe::Type::Long, self.generate_ptr()),
+                ],
+                Some(qbe::Type::Long),
+            );
+            func.add_block("start".to_string());
+            self.generate_block(&mut func, prog);
+            func.add_instr(qbe::Instr::Ret(Some(self.generate_ptr())));
+            module.add_function(func);
+        }
+        self.in_module = false;
+
+        let mut main = self.entry_function();
+        main.add_block("runtime".to_string());
+        self.generate_runtime(&mut main);
+        main.add_block("start".to_string());
+        self.generate_dispatch(&mut main, progs);
+
         module.add_function(main);
-        Ok(format!("{}\n", module))
+        self.generate_data(&mut module);
+        Ok(self.finish(module))
+    }
+
+    /// Generates several independent programs into one module, each as an
+    /// exported `int <name>(void)` function running it on a tape of its
+    /// own, like `entry` does for a single one. Labels and temporaries are
+    /// numbered afresh in every function.
+    pub fn gen_functions(&mut self, progs: &[(String, ast::Prog)]) -> Result<String, ast::Error> {
+        let mut module = qbe::Module::new();
+        let uses = progs.iter().map(|(_, prog)| count_io(prog)).sum();
+        self.generate_io_helpers(&mut module, uses);
+        self.generate_atexit(&mut module);
+
+        self.io_hooks = progs.first().map(|(name, _)| name.clone());
+        for (name, prog) in progs {
+            self.tmp_counter = 0;
+            self.label_counter = 0;
+            self.last_line = 0;
+            self.loop_prefix = format!("{}_", name);
+            self.entry = Some(Entry {
+                symbol: name.clone(),
+                linkage: Linkage::Export,
+            });
+            let mut func = self.entry_function();
+            func.add_block("runtime".to_string());
+            self.generate_runtime(&mut func);
+            func.add_block("start".to_string());
+            self.generate_block(&mut func, prog);
+            self.generate_halt(&mut func, 0);
+            module.add_function(func);
+        }
+
+        self.generate_data(&mut module);
+        Ok(self.finish(module))
+    }
+
+    /// `main`, or the function `entry` names in its place.
+    fn entry_function(&self) -> qbe::Function<'static> {
+        let (linkage, name) = match &self.entry {
+            Some(Entry {
+                symbol,
+                linkage: Linkage::Local,
+            }) => (qbe::Linkage::private(), symbol.clone()),
+            Some(Entry { symbol, .. }) => (qbe::Linkage::public(), symbol.clone()),
+            None => (qbe::Linkage::public(), "main".to_string()),
+        };
+        let params = if self.takes_tape() {
+            vec![
+                (qbe::Type::Long, qbe::Value::Temporary("tape".to_string())),
+                (qbe::Type::Long, qbe::Value::Temporary("pos".to_string())),
+            ]
+        } else if self.checkpoint.is_some() {
+            // `--resume` comes on the command line
+            vec![
+                (qbe::Type::Word, qbe::Value::Temporary("argc".to_string())),
+                (qbe::Type::Long, qbe::Value::Temporary("argv".to_string())),
+            ]
+        } else {
+            Vec::new()
+        };
+        qbe::Function::new(linkage, name, params, Some(qbe::Type::Word))
+    }
+
+    fn generate_dispatch(&mut self, func: &mut qbe::Function, progs: &[(String, ast::Prog)]) {
+        let select = self.generate_label("select");
+        let exit = self.generate_label("exit");
+        let unknown = self.generate_label("unknown");
+
+        let run = |func: &mut qbe::Function, this: &mut Self, name: &str| {
+            func.assign_instr(
+                this.generate_ptr(),
+                qbe::Type::Long,
+                qbe::Instr::Call(
+                    module_symbol(name),
+                    vec![
+                        (qbe::Type::Long, this.generate_tape()),
+                        (qbe::Type::Long, this.generate_ptr()),
+                    ],
+                ),
+            );
+            func.add_instr(qbe::Instr::Jmp(select.clone()));
+        };
+
+        if let Some((name, _)) = progs.first() {
+            run(func, self, name);
+        }
+
+        func.add_block(select.clone());
+        let sel = self.generate_tmp();
+        func.assign_instr(
+            sel.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, self.generate_tape()),
+        );
+        func.add_instr(qbe::Instr::Store(
+            qbe::Type::Word,
+            self.generate_tape(),
+            qbe::Value::Const(0),
+        ));
+        let next = self.generate_label("sel");
+        func.add_instr(qbe::Instr::Jnz(sel.clone(), next.clone(), exit.clone()));
+        func.add_block(next);
+
+        for (i, (name, _)) in progs.iter().enumerate() {
+            let hit = self.generate_label("run");
+            let miss = self.generate_label("sel");
+            let is = self.generate_tmp();
+            func.assign_instr(
+                is.clone(),
+                qbe::Type::Word,
+                qbe::Instr::Cmp(
+                    qbe::Type::Word,
+                    qbe::Cmp::Eq,
+                    sel.clone(),
+                    qbe::Value::Const(i as u64 + 1),
+                ),
+            );
+            func.add_instr(qbe::Instr::Jnz(is, hit.clone(), miss.clone()));
+            func.add_block(hit);
+            run(func, self, name);
+            func.add_block(miss);
+        }
+        func.add_instr(qbe::Instr::Jmp(unknown.clone()));
+
+        func.add_block(unknown);
+        self.generate_halt(func, 1);
+        func.add_block(exit);
+        self.generate_halt(func, 0);
+    }
+
+    /// Outlines `.` and `,` into helper functions taking and returning the
+    /// pointer when the cost model finds that pays off for `uses` of them.
+    fn generate_io_helpers(&mut self, module: &mut qbe::Module, uses: usize) {
+        let inline = cost::QbeCost.statement(&ast::Statement::Write);
+        self.outline_io = cost::prefer_outlined(&cost::QbeCost, self.opt_level, inline, uses);
+        if !self.outline_io {
+            return;
+        }
+
+        self.in_module = true;
+        for kind in [IoKind::Read, IoKind::Write] {
+            let mut func = qbe::Function::new(
+                qbe::Linkage::private(),
+                kind.helper().to_string(),
+                vec![(qbe::Type::Long, self.generate_ptr())],
+                Some(qbe::Type::Long),
+            );
+            func.add_block("start".to_string());
+            match kind {
+                IoKind::Read => {
+                    let ptr = self.generate_ptr();
+                    self.generate_io(&mut func, kind, ptr)
+                }
+                IoKind::Write => self.generate_write(&mut func),
+            }
+            func.add_instr(qbe::Instr::Ret(Some(self.generate_ptr())));
+            module.add_function(func);
+        }
+        self.in_module = false;
+    }
+
+    fn generate_data(&self, module: &mut qbe::Module) {
+        if self.crlf {
+            module.add_data(qbe::DataDef::new(
+                qbe::Linkage::private(),
+                "newline_cr".to_string(),
+                None,
+                vec![(qbe::Type::Byte, qbe::DataItem::Const(b'\r' as u64))],
+            ));
+        }
+        if self.io_strict {
+            for kind in [IoKind::Read, IoKind::Write] {
+                let (name, msg) = kind.message();
+                module.add_data(qbe::DataDef::new(
+                    qbe::Linkage::private(),
+                    name.to_string(),
+                    None,
+                    vec![(qbe::Type::Byte, qbe::DataItem::Str(msg.to_string()))],
+                ));
+            }
+        }
+        // a long per cell, its value in the low 4 of its `CELL_STRIDE` bytes
+        for (name, values) in &self.fills {
+            module.add_data(qbe::DataDef::new(
+                qbe::Linkage::private(),
+                name.clone(),
+                None,
+                values
+                    .iter()
+                    .map(|v| (qbe::Type::Long, qbe::DataItem::Const(*v as u64)))
+                    .collect(),
+            ));
+        }
+        for (name, bytes) in &self.prints {
+            module.add_data(qbe::DataDef::new(
+                qbe::Linkage::private(),
+                name.clone(),
+                None,
+                bytes
+                    .iter()
+                    .map(|b| (qbe::Type::Byte, qbe::DataItem::Const(*b as u64)))
+                    .collect(),
+            ));
+        }
+        let mut counters = Vec::new();
+        if self.keeps_tape_base() {
+            counters.push(TAPE_BASE);
+        }
+        if self.debug_file.is_some() {
+            counters.push(DEBUG_PTR);
+        }
+        if self.atexit.stats {
+            counters.extend([IoKind::Read.counter(), IoKind::Write.counter()]);
+        }
+        for name in counters {
+            module.add_data(qbe::DataDef::new(
+                qbe::Linkage::private(),
+                name.to_string(),
+                Some(8),
+                vec![(qbe::Type::Long, qbe::DataItem::Const(0))],
+            ));
+        }
+        if self.atexit.histogram {
+            for (name, buckets) in [(CELL_HISTOGRAM, CELL_BUCKETS), (BYTE_HISTOGRAM, 256)] {
+                module.add_data(qbe::DataDef::new(
+                    qbe::Linkage::private(),
+                    name.to_string(),
+                    Some(8),
+                    vec![(qbe::Type::Long, qbe::DataItem::Const(0)); buckets],
+                ));
+            }
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            let path = checkpoint.file.replace('\\', "\\\\").replace('"', "\\\"");
+            for (name, s) in [
+                ("bf.cp_file", path.clone()),
+                ("bf.cp_tmp", format!("{}.tmp", path)),
+                ("bf.cp_write", "wb".to_string()),
+                ("bf.cp_read", "rb".to_string()),
+                (RESUME_FLAG, "--resume".to_string()),
+                ("bf.msg_checkpoint", CHECKPOINT_ERR.to_string()),
+                ("bf.msg_resume", RESUME_ERR.to_string()),
+            ] {
+                // C strings, for `fopen` and friends
+                module.add_data(qbe::DataDef::new(
+                    qbe::Linkage::private(),
+                    name.to_string(),
+                    None,
+                    vec![
+                        (qbe::Type::Byte, qbe::DataItem::Str(s)),
+                        (qbe::Type::Byte, qbe::DataItem::Const(0)),
+                    ],
+                ));
+            }
+        }
+        if self.reports() {
+            for (name, msg) in ATEXIT_MESSAGES {
+                module.add_data(qbe::DataDef::new(
+                    qbe::Linkage::private(),
+                    name.to_string(),
+                    None,
+                    vec![(qbe::Type::Byte, qbe::DataItem::Str(msg.to_string()))],
+                ));
+            }
+        }
     }
 
     fn generate_block(&mut self, func: &mut qbe::Function, block: &ast::NodeBlock) {
         for stat in &block.stats {
-            self.generate_statement(func, &stat)
+            self.generate_statement(func, stat)
         }
+        self.generate_commit(func);
     }
 
     fn generate_statement(&mut self, func: &mut qbe::Function, stat: &ast::NodeStatement) {
+        let line = stat.attr.span.line;
+        if self.debug_file.is_some() && line != self.last_line {
+            self.last_line = line;
+            // qbe has no `dbgloc` instruction, see `lower_locations`
+            func.assign_instr(
+                qbe::Value::Temporary(format!("{}{}", LOC_MARKER, line)),
+                qbe::Type::Word,
+                qbe::Instr::Copy(qbe::Value::Const(0)),
+            );
+        }
+
         match &stat.stat {
+            ast::Statement::MoveL(n) | ast::Statement::MoveR(n) if self.offset_addressing => {
+                let by = i64::try_from(*n).unwrap_or(i64::MAX);
+                self.pending.moved(match stat.stat {
+                    ast::Statement::MoveL(_) => -by,
+                    _ => by,
+                });
+            }
             ast::Statement::MoveL(n) => {
                 func.assign_instr(
                     self.generate_ptr(),
                     qbe::Type::Long,
-                    qbe::Instr::Sub(self.generate_ptr(), qbe::Value::Const(*n as u64 * 8)),
-                    // TODO: fix pointer addition and offset                         ^^^
+                    qbe::Instr::Sub(
+                        self.generate_ptr(),
+                        qbe::Value::Const((*n as u64).wrapping_mul(CELL_STRIDE)),
+                    ),
+                    // TODO: fix pointer addition and offset       ^
                 );
 
                 self.generate_bounds_check(func);
+                self.generate_debug_ptr(func);
             }
             ast::Statement::MoveR(n) => {
                 func.assign_instr(
                     self.generate_ptr(),
                     qbe::Type::Long,
-                    qbe::Instr::Add(self.generate_ptr(), qbe::Value::Const(*n as u64 * 8)),
-                    // TODO: fix pointer addition and offset                         ^^^
+                    qbe::Instr::Add(
+                        self.generate_ptr(),
+                        qbe::Value::Const((*n as u64).wrapping_mul(CELL_STRIDE)),
+                    ),
+                    // TODO: fix pointer addition and offset       ^
                 );
 
                 self.generate_bounds_check(func);
+                self.generate_debug_ptr(func);
             }
             ast::Statement::Add(n) => {
-                let tmp = self.generate_tmp();
-                func.assign_instr(
-                    tmp.clone(),
-                    qbe::Type::Word,
-                    qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
-                );
+                let (cell, tmp) = self.generate_load(func);
                 func.assign_instr(
                     tmp.clone(),
                     qbe::Type::Word,
                     qbe::Instr::Add(tmp.clone(), qbe::Value::Const(*n as u64)),
                 );
-                func.add_instr(qbe::Instr::Store(
-                    qbe::Type::Word,
-                    self.generate_ptr(),
-                    tmp.clone(),
-                ))
+                self.generate_store(func, self.pending.offset, cell, tmp);
             }
             ast::Statement::Sub(n) => {
-                let tmp = self.generate_tmp();
-                func.assign_instr(
-                    tmp.clone(),
-                    qbe::Type::Word,
-                    qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
-                );
+                let (cell, tmp) = self.generate_load(func);
                 func.assign_instr(
                     tmp.clone(),
                     qbe::Type::Word,
                     qbe::Instr::Sub(tmp.clone(), qbe::Value::Const(*n as u64)),
                 );
-                func.add_instr(qbe::Instr::Store(
-                    qbe::Type::Word,
-                    self.generate_ptr(),
-                    tmp.clone(),
-                ))
+                self.generate_store(func, self.pending.offset, cell, tmp);
             }
-            ast::Statement::Read => {
-                // ssize_t read(int fd, void buf[.count], size_t count);
+            ast::Statement::Read | ast::Statement::Write if self.outline_io => {
+                self.generate_commit(func);
+                let kind = match stat.stat {
+                    ast::Statement::Read => IoKind::Read,
+                    _ => IoKind::Write,
+                };
                 func.add_instr(qbe::Instr::Call(
-                    "read".to_string(),
-                    vec![
-                        (qbe::Type::Word, qbe::Value::Const(0)), // 0 for stdin
-                        (qbe::Type::Long, self.generate_ptr()),
-                        (qbe::Type::Long, qbe::Value::Const(1)), // one byte only
-                    ],
+                    kind.helper().to_string(),
+                    vec![(qbe::Type::Long, self.generate_ptr())],
                 ));
             }
+            ast::Statement::Read => {
+                self.generate_commit(func);
+                let ptr = self.generate_ptr();
+                self.generate_io(func, IoKind::Read, ptr)
+            }
             ast::Statement::Write => {
-                // ssize_t write(int fd, const void buf[.count], size_t count);
-                func.add_instr(qbe::Instr::Call(
-                    "write".to_string(),
-                    vec![
-                        (qbe::Type::Word, qbe::Value::Const(1)), // 0 for stdout
-                        (qbe::Type::Long, self.generate_ptr()),
-                        (qbe::Type::Long, qbe::Value::Const(1)), // one byte only
-                    ],
-                ));
+                self.generate_commit(func);
+                self.generate_write(func)
+            }
+            ast::Statement::Loop(b) if self.split_loops => {
+                self.generate_commit(func);
+                let name = loop_symbol(&self.loop_prefix, stat.attr.span);
+                self.generate_outlined_loop(&name, b);
+                func.assign_instr(
+                    self.generate_ptr(),
+                    qbe::Type::Long,
+                    qbe::Instr::Call(
+                        name,
+                        vec![
+                            (qbe::Type::Long, self.generate_tape()),
+                            (qbe::Type::Long, self.generate_ptr()),
+                        ],
+                    ),
+                );
             }
             ast::Statement::Loop(b) => {
-                let c = self.label_counter;
-                let begin = format!("loop{}", c);
-                let end = format!("end{}", c);
-                self.label_counter += 1;
-
-                let tmp = self.generate_tmp();
+                self.generate_commit(func);
+                self.generate_loop(func, b)
+            }
+            ast::Statement::If(b) => {
+                self.generate_commit(func);
+                self.generate_if(func, b)
+            }
+            ast::Statement::Fill(values) => {
+                self.generate_commit(func);
+                self.generate_fill(func, values)
+            }
+            ast::Statement::Print(bytes) => {
+                self.generate_commit(func);
+                self.generate_print(func, bytes)
+            }
+            ast::Statement::Set(n) if self.cache_cells => {
+                let offset = self.pending.offset;
+                let cached = self.cached.iter().find(|c| c.offset == offset);
+                let (cell, tmp) = match cached {
+                    Some(c) => (c.addr.clone(), c.value.clone()),
+                    None => (self.generate_cell(func), self.generate_tmp()),
+                };
                 func.assign_instr(
                     tmp.clone(),
                     qbe::Type::Word,
-                    qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
+                    qbe::Instr::Copy(qbe::Value::Const(*n as u64)),
                 );
-                func.add_instr(qbe::Instr::Jnz(tmp.clone(), begin.clone(), end.clone()));
-                func.add_block(begin.clone());
+                self.generate_store(func, offset, cell, tmp);
+            }
+            ast::Statement::Set(n) => {
+                let cell = self.generate_cell(func);
+                func.add_instr(qbe::Instr::Store(
+                    qbe::Type::Word,
+                    cell.clone(),
+                    qbe::Value::Const(*n as u64),
+                ));
+                self.generate_record(func, CELL_HISTOGRAM, cell);
+            }
+            ast::Statement::MulAdd { offset, factor } => {
+                self.generate_mul_add(func, *offset as i64, *factor)
+            }
+            ast::Statement::Scan(stride) => {
+                self.generate_commit(func);
+                self.generate_scan(func, *stride as i64)
+            }
+        }
+    }
 
-                self.generate_block(func, b);
+    /// Moves the pointer `stride` cells at a time until it is on a zero
+    /// cell: the loop `[>]` would be, without a checkpoint, as it never
+    /// goes round more often than there are cells.
+    fn generate_scan(&mut self, func: &mut qbe::Function, stride: i64) {
+        let c = self.label_counter;
+        let step = format!("scan{}", c);
+        let end = format!("scanned{}", c);
+        self.label_counter += 1;
 
-                func.assign_instr(
-                    tmp.clone(),
-                    qbe::Type::Word,
-                    qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
-                );
-                func.add_instr(qbe::Instr::Jnz(tmp.clone(), begin.clone(), end.clone()));
-                func.add_block(end.clone());
+        let tmp = self.generate_tmp();
+        func.assign_instr(
+            tmp.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
+        );
+        func.add_instr(qbe::Instr::Jnz(tmp.clone(), step.clone(), end.clone()));
+        func.add_block(step.clone());
+        self.generate_shift(func, stride);
+        func.assign_instr(
+            tmp.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
+        );
+        func.add_instr(qbe::Instr::Jnz(tmp, step, end.clone()));
+        func.add_block(end);
+    }
+
+    /// Adds the cell times `factor` to the cell `offset` away, going there
+    /// and back like a move so that it is bounds checked the same.
+    fn generate_mul_add(&mut self, func: &mut qbe::Function, offset: i64, factor: u32) {
+        // with offset addressing, reaching the other cell is enough
+        if self.offset_addressing {
+            self.pending.moved(offset);
+            let (to, tmp) = self.generate_load(func);
+            let at = self.pending.offset;
+            self.pending.moved(-offset);
+            let (_, from) = self.generate_load(func);
+            let product = self.generate_tmp();
+            func.assign_instr(
+                product.clone(),
+                qbe::Type::Word,
+                qbe::Instr::Mul(from, qbe::Value::Const(factor as u64)),
+            );
+            func.assign_instr(
+                tmp.clone(),
+                qbe::Type::Word,
+                qbe::Instr::Add(tmp.clone(), product),
+            );
+            self.generate_store(func, at, to, tmp);
+            return;
+        }
+        let product = self.generate_tmp();
+        func.assign_instr(
+            product.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
+        );
+        func.assign_instr(
+            product.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Mul(product.clone(), qbe::Value::Const(factor as u64)),
+        );
+        self.generate_shift(func, offset);
+        let to = self.generate_ptr();
+        let tmp = self.generate_tmp();
+        func.assign_instr(
+            tmp.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, to.clone()),
+        );
+        func.assign_instr(
+            tmp.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Add(tmp.clone(), product),
+        );
+        func.add_instr(qbe::Instr::Store(qbe::Type::Word, to.clone(), tmp));
+        self.generate_record(func, CELL_HISTOGRAM, to);
+        self.generate_shift(func, -offset);
+    }
+
+    /// The address of the current cell and a temporary holding what is in
+    /// it, loaded unless load caching holds it already.
+    fn generate_load(&mut self, func: &mut qbe::Function) -> (qbe::Value, qbe::Value) {
+        let offset = self.pending.offset;
+        if let Some(c) = self.cached.iter().find(|c| c.offset == offset) {
+            let hit = (c.addr.clone(), c.value.clone());
+            self.generate_pending_check(func);
+            return hit;
+        }
+        let addr = self.generate_cell(func);
+        let value = self.generate_tmp();
+        func.assign_instr(
+            value.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, addr.clone()),
+        );
+        if self.cache_cells {
+            self.cached.push(Cached {
+                offset,
+                addr: addr.clone(),
+                value: value.clone(),
+                dirty: false,
+            });
+        }
+        (addr, value)
+    }
+
+    /// Stores `value` in the cell `offset` from `%ptr`, at `addr`, or with
+    /// load caching leaves it in the temporary for the next commit to
+    /// store.
+    fn generate_store(
+        &mut self,
+        func: &mut qbe::Function,
+        offset: i64,
+        addr: qbe::Value,
+        value: qbe::Value,
+    ) {
+        if self.cache_cells {
+            match self.cached.iter_mut().find(|c| c.offset == offset) {
+                Some(c) => {
+                    c.value = value;
+                    c.dirty = true;
+                }
+                None => self.cached.push(Cached {
+                    offset,
+                    addr,
+                    value,
+                    dirty: true,
+                }),
             }
+            return;
         }
+        func.add_instr(qbe::Instr::Store(qbe::Type::Word, addr.clone(), value));
+        self.generate_record(func, CELL_HISTOGRAM, addr);
     }
 
-    fn generate_runtime(&mut self, func: &mut qbe::Function) {
-        let tape_val = qbe::Value::Temporary("tape".to_string());
+    /// The address of the current cell: `%ptr`, or with offset addressing
+    /// one the pending moves away from it, once every cell they reached is
+    /// checked to be on the tape.
+    fn generate_cell(&mut self, func: &mut qbe::Function) -> qbe::Value {
+        if !self.offset_addressing {
+            return self.generate_ptr();
+        }
+        match self.generate_pending_check(func) {
+            Some(addr) => addr,
+            None if self.pending.offset == 0 => self.generate_ptr(),
+            None => self.generate_offset(func, self.pending.offset),
+        }
+    }
 
+    /// `%ptr` plus `offset` cells.
+    fn generate_offset(&mut self, func: &mut qbe::Function, offset: i64) -> qbe::Value {
+        let addr = self.generate_tmp();
         func.assign_instr(
-            tape_val.clone(),
+            addr.clone(),
             qbe::Type::Long,
-            qbe::Instr::Alloc8(self.tape_len as u64),
+            qbe::Instr::Add(
+                self.generate_ptr(),
+                qbe::Value::Const((offset as u64).wrapping_mul(CELL_STRIDE)),
+            ),
         );
+        addr
+    }
+
+    /// Checks the cells the pending moves reached past the range checked
+    /// so far, the furthest on either side standing for those between.
+    /// Returns the address of the current cell when it had to work it out.
+    fn generate_pending_check(&mut self, func: &mut qbe::Function) -> Option<qbe::Value> {
+        let Pending {
+            offset,
+            low,
+            high,
+            checked,
+        } = self.pending;
+        let mut current = None;
+        for (end, past) in [(low, low < checked.0), (high, high > checked.1)] {
+            if past {
+                let addr = self.generate_offset(func, end);
+                self.generate_bounds_check_at(func, addr.clone());
+                if end == offset {
+                    current = Some(addr);
+                }
+            }
+        }
+        self.pending.checked = (low, high);
+        current
+    }
+
+    /// Stores the cells load caching changed and applies the pending moves
+    /// to `%ptr`, checked, ahead of a loop boundary or I/O.
+    fn generate_commit(&mut self, func: &mut qbe::Function) {
+        if !self.offset_addressing {
+            return;
+        }
+        for c in std::mem::take(&mut self.cached) {
+            if c.dirty {
+                func.add_instr(qbe::Instr::Store(qbe::Type::Word, c.addr, c.value));
+            }
+        }
+        self.generate_pending_check(func);
+        if self.pending.offset != 0 {
+            func.assign_instr(
+                self.generate_ptr(),
+                qbe::Type::Long,
+                qbe::Instr::Add(
+                    self.generate_ptr(),
+                    qbe::Value::Const((self.pending.offset as u64).wrapping_mul(CELL_STRIDE)),
+                ),
+            );
+        }
+        self.pending = Pending::default();
+    }
 
+    /// Moves the pointer by `by` cells, checking it stays on the tape.
+    fn generate_shift(&mut self, func: &mut qbe::Function, by: i64) {
         func.assign_instr(
             self.generate_ptr(),
             qbe::Type::Long,
-            qbe::Instr::Copy(tape_val.clone()),
+            qbe::Instr::Add(
+                self.generate_ptr(),
+                qbe::Value::Const((by as u64).wrapping_mul(CELL_STRIDE)),
+            ),
         );
+        self.generate_bounds_check(func);
+        self.generate_debug_ptr(func);
     }
 
-    fn generate_bounds_check(&mut self, func: &mut qbe::Function) {
-        let tape_val = qbe::Value::Temporary("tape".to_string());
+    fn generate_loop(&mut self, func: &mut qbe::Function, block: &ast::NodeBlock) {
+        let c = self.label_counter;
+        let begin = format!("loop{}", c);
+        let end = format!("end{}", c);
+        self.label_counter += 1;
 
-        let cont = self.generate_label("cont");
-        let halt = self.generate_label("halt");
-        let pred = self.generate_tmp();
+        let tmp = self.generate_tmp();
+        func.assign_instr(
+            tmp.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
+        );
+        func.add_instr(qbe::Instr::Jnz(tmp.clone(), begin.clone(), end.clone()));
+        func.add_block(begin.clone());
+
+        self.generate_block(func, block);
+        self.generate_checkpoint(func, c);
 
-        let offset = self.generate_tmp();
         func.assign_instr(
-            offset.clone(),
-            qbe::Type::Long,
-            qbe::Instr::Sub(self.generate_ptr(), tape_val),
+            tmp.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
         );
+        func.add_instr(qbe::Instr::Jnz(tmp.clone(), begin.clone(), end.clone()));
+        func.add_block(end.clone());
+    }
 
-        let in_bounds = self.generate_tmp();
+    /// Runs `block` when the cell is not zero, a loop without its back
+    /// edge, and so without a checkpoint either.
+    fn generate_if(&mut self, func: &mut qbe::Function, block: &ast::NodeBlock) {
+        let c = self.label_counter;
+        let then = format!("then{}", c);
+        let end = format!("fi{}", c);
+        self.label_counter += 1;
+
+        let tmp = self.generate_tmp();
         func.assign_instr(
-            in_bounds.clone(),
+            tmp.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
+        );
+        func.add_instr(qbe::Instr::Jnz(tmp, then.clone(), end.clone()));
+        func.add_block(then);
+        self.generate_block(func, block);
+        func.add_block(end);
+    }
+
+    /// Copies `values` onto the tape from the current cell on, out of a
+    /// data definition laid out like the tape.
+    fn generate_fill(&mut self, func: &mut qbe::Function, values: &[u32]) {
+        let name = format!("fill{}", self.label_counter);
+        self.label_counter += 1;
+        func.add_instr(qbe::Instr::Call(
+            "memcpy".to_string(),
+            vec![
+                (qbe::Type::Long, self.generate_ptr()),
+                (qbe::Type::Long, qbe::Value::Global(name.clone())),
+                (
+                    qbe::Type::Long,
+                    qbe::Value::Const(values.len() as u64 * CELL_STRIDE),
+                ),
+            ],
+        ));
+        self.fills.push((name, values.to_vec()));
+    }
+
+    /// Writes `bytes` out of a data definition, newlines translated while
+    /// compiling, calling `write` for as long as some are left, and leaves
+    /// the last of them in the cell.
+    fn generate_print(&mut self, func: &mut qbe::Function, bytes: &[u8]) {
+        let mut data = Vec::with_capacity(bytes.len());
+        for byte in bytes {
+            if *byte == b'\n' && self.crlf {
+                data.push(b'\r');
+            }
+            data.push(*byte);
+            if self.atexit.histogram {
+                func.add_instr(qbe::Instr::Call(
+                    format!("{}.record", BYTE_HISTOGRAM),
+                    vec![(qbe::Type::Long, qbe::Value::Const(*byte as u64))],
+                ));
+            }
+        }
+        let name = format!("print{}", self.label_counter);
+        self.label_counter += 1;
+        let retry = self.generate_label("print");
+        let wrote = self.generate_label("printed");
+        let check = self.generate_label("printchk");
+        let errno = self.generate_label("printerrno");
+        let fail = self.generate_label("printfail");
+        let done = self.generate_label("printdone");
+
+        let at = self.generate_tmp();
+        func.assign_instr(
+            at.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Copy(qbe::Value::Global(name.clone())),
+        );
+        let left = self.generate_tmp();
+        func.assign_instr(
+            left.clone(),
             qbe::Type::Long,
+            qbe::Instr::Copy(qbe::Value::Const(data.len() as u64)),
+        );
+        func.add_instr(qbe::Instr::Jmp(retry.clone()));
+        func.add_block(retry.clone());
+        let res = self.generate_tmp();
+        func.assign_instr(
+            res.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Call(
+                IoKind::Write.syscall().to_string(),
+                vec![
+                    (qbe::Type::Word, qbe::Value::Const(IoKind::Write.fd())),
+                    (qbe::Type::Long, at.clone()),
+                    (qbe::Type::Long, left.clone()),
+                ],
+            ),
+        );
+        let some = self.generate_tmp();
+        func.assign_instr(
+            some.clone(),
+            qbe::Type::Word,
             qbe::Instr::Cmp(
                 qbe::Type::Long,
                 qbe::Cmp::Sgt,
-                qbe::Value::Const(self.tape_len as u64 / 2),
-                offset.clone(),
+                res.clone(),
+                qbe::Value::Const(0),
             ),
         );
+        func.add_instr(qbe::Instr::Jnz(some, wrote.clone(), check.clone()));
 
-        func.add_instr(qbe::Instr::Jnz(
-            in_bounds.clone(),
-            cont.clone(),
-            halt.clone(),
-        ));
+        func.add_block(wrote);
+        if self.atexit.stats {
+            let counter = qbe::Value::Global(IoKind::Write.counter().to_string());
+            let count = self.generate_tmp();
+            func.assign_instr(
+                count.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Load(qbe::Type::Long, counter.clone()),
+            );
+            func.assign_instr(
+                count.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Add(count.clone(), res.clone()),
+            );
+            func.add_instr(qbe::Instr::Store(qbe::Type::Long, counter, count));
+        }
+        func.assign_instr(
+            at.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(at.clone(), res.clone()),
+        );
+        func.assign_instr(
+            left.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(left.clone(), res.clone()),
+        );
+        let more = self.generate_tmp();
+        func.assign_instr(
+            more.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(qbe::Type::Long, qbe::Cmp::Ne, left, qbe::Value::Const(0)),
+        );
+        func.add_instr(qbe::Instr::Jnz(more, retry.clone(), done.clone()));
 
-        func.add_block(halt.clone());
-        func.add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(1))));
-        func.add_block(cont.clone());
-    }
+        // nothing written is retried, as for `.`
+        func.add_block(check);
+        let failed = self.generate_tmp();
+        func.assign_instr(
+            failed.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(qbe::Type::Long, qbe::Cmp::Slt, res, qbe::Value::Const(0)),
+        );
+        func.add_instr(qbe::Instr::Jnz(failed, errno.clone(), retry.clone()));
+        self.generate_io_error(func, IoKind::Write, errno, fail, retry, done.clone());
 
-    fn generate_ptr(&mut self) -> qbe::Value {
-        qbe::Value::Temporary("ptr".to_string())
+        func.add_block(done);
+        let ptr = self.generate_ptr();
+        func.add_instr(qbe::Instr::Store(
+            qbe::Type::Word,
+            ptr.clone(),
+            qbe::Value::Const(bytes[bytes.len() - 1] as u64),
+        ));
+        self.generate_record(func, CELL_HISTOGRAM, ptr);
+        self.prints.push((name, data));
     }
 
-    fn generate_tmp(&mut self) -> qbe::Value {
-        let c = self.tmp_counter;
-        self.tmp_counter += 1;
+    /// Counts down the iterations left until the next checkpoint at the
+    /// end of the body of loop `c`, taking it when there are none. A
+    /// resumed run comes back in right after it, at `test{c}`.
+    fn generate_checkpoint(&mut self, func: &mut qbe::Function, c: usize) {
+        let Some(checkpoint) = &self.checkpoint else {
+            return;
+        };
+        let every = checkpoint.every;
+        let save = format!("save{}", c);
+        let test = format!("test{}", c);
+        self.resume_points.push(c);
+        func.assign_instr(
+            self.generate_steps(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(self.generate_steps(), qbe::Value::Const(1)),
+        );
+        func.add_instr(qbe::Instr::Jnz(
+            self.generate_steps(),
+            test.clone(),
+            save.clone(),
+        ));
+        func.add_block(save);
+        func.add_instr(qbe::Instr::Call(
+            CHECKPOINT_SAVE.to_string(),
+            vec![
+                (qbe::Type::Long, self.generate_tape()),
+                (qbe::Type::Long, self.generate_ptr()),
+                (qbe::Type::Long, qbe::Value::Const(c as u64)),
+            ],
+        ));
+        func.assign_instr(
+            self.generate_steps(),
+            qbe::Type::Long,
+            qbe::Instr::Copy(qbe::Value::Const(every)),
+        );
+        func.add_block(test);
+    }
+
+    /// Sets up the checkpoint countdown and, when the first argument is
+    /// `--resume`, heads for the snapshot rather than the program's start.
+    fn generate_resume_check(&mut self, func: &mut qbe::Function) {
+        let Some(checkpoint) = &self.checkpoint else {
+            return;
+        };
+        let every = checkpoint.every;
+        func.assign_instr(
+            self.generate_steps(),
+            qbe::Type::Long,
+            qbe::Instr::Copy(qbe::Value::Const(every)),
+        );
+        let has_arg = self.generate_tmp();
+        func.assign_instr(
+            has_arg.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Word,
+                qbe::Cmp::Sgt,
+                qbe::Value::Temporary("argc".to_string()),
+                qbe::Value::Const(1),
+            ),
+        );
+        let arg = self.generate_label("arg");
+        func.add_instr(qbe::Instr::Jnz(has_arg, arg.clone(), "start".to_string()));
+        func.add_block(arg);
+        let first = self.generate_tmp();
+        func.assign_instr(
+            first.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(
+                qbe::Value::Temporary("argv".to_string()),
+                qbe::Value::Const(8),
+            ),
+        );
+        func.assign_instr(
+            first.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, first.clone()),
+        );
+        let differs = self.generate_tmp();
+        func.assign_instr(
+            differs.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Call(
+                "strcmp".to_string(),
+                vec![
+                    (qbe::Type::Long, first),
+                    (qbe::Type::Long, qbe::Value::Global(RESUME_FLAG.to_string())),
+                ],
+            ),
+        );
+        func.add_instr(qbe::Instr::Jnz(
+            differs,
+            "start".to_string(),
+            "resume".to_string(),
+        ));
+    }
+
+    /// Restores the snapshot and jumps to where it was taken. Comes after
+    /// the program's code, once every loop it can jump to is known.
+    fn generate_resume(&mut self, func: &mut qbe::Function) {
+        if self.checkpoint.is_none() {
+            return;
+        }
+        func.add_block("resume".to_string());
+        let header = self.generate_tmp();
+        func.assign_instr(
+            header.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Alloc8(SNAPSHOT_HEADER),
+        );
+        let failed = self.generate_tmp();
+        func.assign_instr(
+            failed.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Call(
+                CHECKPOINT_RESTORE.to_string(),
+                vec![
+                    (qbe::Type::Long, self.generate_tape()),
+                    (qbe::Type::Long, header.clone()),
+                ],
+            ),
+        );
+        let restored = self.generate_label("restored");
+        let unknown = self.generate_label("unknown");
+        func.add_instr(qbe::Instr::Jnz(failed, unknown.clone(), restored.clone()));
+
+        func.add_block(restored);
+        let id = self.generate_tmp();
+        func.assign_instr(
+            id.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, header.clone()),
+        );
+        let offset = self.generate_tmp();
+        func.assign_instr(
+            offset.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(header, qbe::Value::Const(8)),
+        );
+        func.assign_instr(
+            offset.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, offset.clone()),
+        );
+        func.assign_instr(
+            self.generate_ptr(),
+            qbe::Type::Long,
+            qbe::Instr::Add(self.generate_tape(), offset),
+        );
+        self.generate_debug_ptr(func);
+        for c in std::mem::take(&mut self.resume_points) {
+            let is = self.generate_tmp();
+            func.assign_instr(
+                is.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Cmp(
+                    qbe::Type::Long,
+                    qbe::Cmp::Eq,
+                    id.clone(),
+                    qbe::Value::Const(c as u64),
+                ),
+            );
+            let miss = self.generate_label("miss");
+            func.add_instr(qbe::Instr::Jnz(is, format!("test{}", c), miss.clone()));
+            func.add_block(miss);
+        }
+        func.add_instr(qbe::Instr::Jmp(unknown.clone()));
+
+        // a snapshot missing, cut short or from some other program
+        func.add_block(unknown);
+        func.add_instr(qbe::Instr::Call(
+            "write".to_string(),
+            vec![
+                (qbe::Type::Word, qbe::Value::Const(2)),
+                (
+                    qbe::Type::Long,
+                    qbe::Value::Global("bf.msg_resume".to_string()),
+                ),
+                (qbe::Type::Long, qbe::Value::Const(message_len(RESUME_ERR))),
+            ],
+        ));
+        func.add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(1))));
+    }
+
+    /// `bf.checkpoint(tape, ptr, id)`, writing a snapshot to a file next to
+    /// the checkpoint file and renaming it over it so that a run killed
+    /// halfway leaves the last one whole, and `bf.restore(tape, header)`,
+    /// reading the tape back and the loop and pointer offset into `header`
+    /// and returning 0 when that worked. Snapshots also record the
+    /// [`stamp::compat_hash`] of the build, and one from a build where
+    /// cells, the tape or I/O work differently is refused. A snapshot that
+    /// cannot be written is reported and the run carries on.
+    fn generate_checkpoint_helpers(&mut self, module: &mut qbe::Module) {
+        if self.checkpoint.is_none() {
+            return;
+        }
+        let header = qbe::Value::Temporary("header".to_string());
+        let file = qbe::Value::Temporary("file".to_string());
+        let ok = qbe::Value::Temporary("ok".to_string());
+        let failed = qbe::Value::Temporary("failed".to_string());
+        let global = |name: &str| (qbe::Type::Long, qbe::Value::Global(name.to_string()));
+
+        let mut save = qbe::Function::new(
+            qbe::Linkage::private(),
+            CHECKPOINT_SAVE.to_string(),
+            vec![
+                (qbe::Type::Long, self.generate_tape()),
+                (qbe::Type::Long, self.generate_ptr()),
+                (qbe::Type::Long, qbe::Value::Temporary("id".to_string())),
+            ],
+            None,
+        );
+        save.add_block("start".to_string());
+        save.assign_instr(
+            header.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Alloc8(SNAPSHOT_HEADER),
+        );
+        save.add_instr(qbe::Instr::Store(
+            qbe::Type::Long,
+            header.clone(),
+            qbe::Value::Temporary("id".to_string()),
+        ));
+        let offset = qbe::Value::Temporary("offset".to_string());
+        save.assign_instr(
+            offset.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(self.generate_ptr(), self.generate_tape()),
+        );
+        let at = qbe::Value::Temporary("at".to_string());
+        save.assign_instr(
+            at.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(header.clone(), qbe::Value::Const(8)),
+        );
+        save.add_instr(qbe::Instr::Store(qbe::Type::Long, at.clone(), offset));
+        save.assign_instr(
+            at.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(header.clone(), qbe::Value::Const(16)),
+        );
+        save.add_instr(qbe::Instr::Store(
+            qbe::Type::Long,
+            at,
+            qbe::Value::Const(self.compat),
+        ));
+        save.assign_instr(
+            file.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Call(
+                "fopen".to_string(),
+                vec![global("bf.cp_tmp"), global("bf.cp_write")],
+            ),
+        );
+        save.add_instr(qbe::Instr::Jnz(
+            file.clone(),
+            "open".to_string(),
+            "fail".to_string(),
+        ));
+        save.add_block("open".to_string());
+        self.generate_transfer(&mut save, "fwrite", &header, &file);
+        save.assign_instr(
+            failed.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Call("fclose".to_string(), vec![(qbe::Type::Long, file.clone())]),
+        );
+        save.add_instr(qbe::Instr::Jnz(
+            failed.clone(),
+            "fail".to_string(),
+            "closed".to_string(),
+        ));
+        save.add_block("closed".to_string());
+        save.add_instr(qbe::Instr::Jnz(
+            ok.clone(),
+            "rename".to_string(),
+            "fail".to_string(),
+        ));
+        save.add_block("rename".to_string());
+        save.assign_instr(
+            failed.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Call(
+                "rename".to_string(),
+                vec![global("bf.cp_tmp"), global("bf.cp_file")],
+            ),
+        );
+        save.add_instr(qbe::Instr::Jnz(
+            failed,
+            "fail".to_string(),
+            "done".to_string(),
+        ));
+        save.add_block("fail".to_string());
+        save.add_instr(qbe::Instr::Call(
+            "write".to_string(),
+            vec![
+                (qbe::Type::Word, qbe::Value::Const(2)),
+                global("bf.msg_checkpoint"),
+                (
+                    qbe::Type::Long,
+                    qbe::Value::Const(message_len(CHECKPOINT_ERR)),
+                ),
+            ],
+        ));
+        save.add_block("done".to_string());
+        save.add_instr(qbe::Instr::Ret(None));
+        module.add_function(save);
+
+        let mut restore = qbe::Function::new(
+            qbe::Linkage::private(),
+            CHECKPOINT_RESTORE.to_string(),
+            vec![
+                (qbe::Type::Long, self.generate_tape()),
+                (qbe::Type::Long, header.clone()),
+            ],
+            Some(qbe::Type::Word),
+        );
+        restore.add_block("start".to_string());
+        restore.assign_instr(
+            file.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Call(
+                "fopen".to_string(),
+                vec![global("bf.cp_file"), global("bf.cp_read")],
+            ),
+        );
+        restore.add_instr(qbe::Instr::Jnz(
+            file.clone(),
+            "open".to_string(),
+            "fail".to_string(),
+        ));
+        restore.add_block("open".to_string());
+        self.generate_transfer(&mut restore, "fread", &header, &file);
+        restore.add_instr(qbe::Instr::Call(
+            "fclose".to_string(),
+            vec![(qbe::Type::Long, file)],
+        ));
+        restore.add_instr(qbe::Instr::Jnz(
+            ok.clone(),
+            "read".to_string(),
+            "fail".to_string(),
+        ));
+        // a pointer off the tape would not be caught until it moves
+        restore.add_block("read".to_string());
+        let offset = qbe::Value::Temporary("offset".to_string());
+        restore.assign_instr(
+            offset.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(header.clone(), qbe::Value::Const(8)),
+        );
+        restore.assign_instr(
+            offset.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, offset.clone()),
+        );
+        let from_start = qbe::Value::Temporary("from_start".to_string());
+        restore.assign_instr(
+            from_start.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Sge,
+                offset.clone(),
+                qbe::Value::Const(0),
+            ),
+        );
+        restore.assign_instr(
+            ok.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Sgt,
+                qbe::Value::Const(self.tape_len as u64 / 2),
+                offset,
+            ),
+        );
+        restore.assign_instr(
+            ok.clone(),
+            qbe::Type::Long,
+            qbe::Instr::And(ok.clone(), from_start),
+        );
+        let compat = qbe::Value::Temporary("compat".to_string());
+        restore.assign_instr(
+            compat.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(header, qbe::Value::Const(16)),
+        );
+        restore.assign_instr(
+            compat.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, compat.clone()),
+        );
+        restore.assign_instr(
+            compat.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Eq,
+                compat.clone(),
+                qbe::Value::Const(self.compat),
+            ),
+        );
+        restore.assign_instr(
+            ok.clone(),
+            qbe::Type::Long,
+            qbe::Instr::And(ok.clone(), compat),
+        );
+        restore.add_instr(qbe::Instr::Jnz(ok, "done".to_string(), "fail".to_string()));
+        restore.add_block("fail".to_string());
+        restore.add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(1))));
+        restore.add_block("done".to_string());
+        restore.add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(0))));
+        module.add_function(restore);
+    }
+
+    /// Moves the header and then the cells in use between memory and `file`
+    /// with `fread` or `fwrite`, leaving whether both went through in `%ok`.
+    fn generate_transfer(
+        &mut self,
+        func: &mut qbe::Function,
+        call: &str,
+        header: &qbe::Value,
+        file: &qbe::Value,
+    ) {
+        let ok = qbe::Value::Temporary("ok".to_string());
+        let parts = [
+            (header.clone(), SNAPSHOT_HEADER),
+            (self.generate_tape(), self.tape_len as u64 / 2),
+        ];
+        for (i, (buf, len)) in parts.into_iter().enumerate() {
+            let done = self.generate_tmp();
+            // the number of whole items moved, 1 or 0
+            func.assign_instr(
+                done.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Call(
+                    call.to_string(),
+                    vec![
+                        (qbe::Type::Long, buf),
+                        (qbe::Type::Long, qbe::Value::Const(len)),
+                        (qbe::Type::Long, qbe::Value::Const(1)),
+                        (qbe::Type::Long, file.clone()),
+                    ],
+                ),
+            );
+            let instr = match i {
+                0 => qbe::Instr::Copy(done),
+                _ => qbe::Instr::And(ok.clone(), done),
+            };
+            func.assign_instr(ok.clone(), qbe::Type::Long, instr);
+        }
+    }
+
+    /// Compiles a loop into a function `name` taking the tape and pointer and
+    /// returning the pointer. Nested loops are outlined in turn, so a
+    /// profiler's call graph mirrors the loop structure of the source.
+    fn generate_outlined_loop(&mut self, name: &str, block: &ast::NodeBlock) {
+        let in_module = std::mem::replace(&mut self.in_module, true);
+        let last_line = std::mem::replace(&mut self.last_line, 0);
+
+        let mut func = qbe::Function::new(
+            qbe::Linkage::private(),
+            name.to_string(),
+            vec![
+                (qbe::Type::Long, self.generate_tape()),
+                (qbe::Type::Long, self.generate_ptr()),
+            ],
+            Some(qbe::Type::Long),
+        );
+        func.add_block("start".to_string());
+        self.generate_loop(&mut func, block);
+        func.add_instr(qbe::Instr::Ret(Some(self.generate_ptr())));
+        self.loops.push(format!("{}\n", func));
+
+        self.in_module = in_module;
+        self.last_line = last_line;
+    }
+
+    /// Terminates the whole program with `code`, running the exit hooks
+    /// first. Module functions cannot simply return from `main`, so they call
+    /// `exit` instead.
+    fn generate_halt(&mut self, func: &mut qbe::Function, code: u64) {
+        if self.atexit.any() {
+            func.add_instr(qbe::Instr::Call(
+                "bf.atexit".to_string(),
+                vec![
+                    (qbe::Type::Long, self.generate_ptr()),
+                    (qbe::Type::Long, qbe::Value::Const(code)),
+                ],
+            ));
+        }
+        if self.in_module {
+            func.add_instr(qbe::Instr::Call(
+                "exit".to_string(),
+                vec![(qbe::Type::Word, qbe::Value::Const(code))],
+            ));
+            func.add_instr(qbe::Instr::Ret(Some(self.generate_ptr())));
+        } else {
+            if self.takes_tape() {
+                self.generate_store_pos(func);
+            }
+            if self.heap_tape && self.entry.is_some() {
+                // the entry function may be called again, unlike `main`
+                func.add_instr(qbe::Instr::Call(
+                    "free".to_string(),
+                    vec![(qbe::Type::Long, self.generate_tape())],
+                ));
+            }
+            func.add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(code))));
+        }
+    }
+
+    /// Hands the index of the current cell back through `%pos`.
+    fn generate_store_pos(&mut self, func: &mut qbe::Function) {
+        let index = self.generate_tmp();
+        func.assign_instr(
+            index.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(self.generate_ptr(), self.generate_tape()),
+        );
+        func.assign_instr(
+            index.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Div(index.clone(), qbe::Value::Const(CELL_STRIDE)),
+        );
+        func.add_instr(qbe::Instr::Store(
+            qbe::Type::Long,
+            qbe::Value::Temporary("pos".to_string()),
+            index,
+        ));
+    }
+
+    fn generate_runtime(&mut self, func: &mut qbe::Function) {
+        let tape_val = self.generate_tape();
+
+        if self.takes_tape() {
+            // the caller's tape, from the cell `%pos` holds the index of
+            let offset = self.generate_tmp();
+            func.assign_instr(
+                offset.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Load(qbe::Type::Long, qbe::Value::Temporary("pos".to_string())),
+            );
+            func.assign_instr(
+                offset.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Mul(offset.clone(), qbe::Value::Const(CELL_STRIDE)),
+            );
+            func.assign_instr(
+                self.generate_ptr(),
+                qbe::Type::Long,
+                qbe::Instr::Add(tape_val.clone(), offset),
+            );
+        } else if self.heap_tape {
+            let ok = self.generate_label("tape");
+            let oom = self.generate_label("oom");
+            func.assign_instr(
+                tape_val.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Call(
+                    "calloc".to_string(),
+                    vec![
+                        (qbe::Type::Long, qbe::Value::Const(self.tape_len as u64)),
+                        (qbe::Type::Long, qbe::Value::Const(1)),
+                    ],
+                ),
+            );
+            func.add_instr(qbe::Instr::Jnz(tape_val.clone(), ok.clone(), oom.clone()));
+            func.add_block(oom);
+            func.add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(1))));
+            func.add_block(ok);
+        } else {
+            func.assign_instr(
+                tape_val.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Alloc8(self.tape_len as u64),
+            );
+        }
+
+        if !self.takes_tape() {
+            func.assign_instr(
+                self.generate_ptr(),
+                qbe::Type::Long,
+                qbe::Instr::Copy(tape_val.clone()),
+            );
+        }
+        if self.keeps_tape_base() {
+            func.add_instr(qbe::Instr::Store(
+                qbe::Type::Long,
+                qbe::Value::Global(TAPE_BASE.to_string()),
+                tape_val,
+            ));
+        }
+        self.generate_debug_ptr(func);
+        if self.takes_tape() {
+            // the caller may start anywhere
+            self.generate_bounds_check(func);
+        }
+    }
+
+    /// Whether the function generated runs on a tape its caller passes in,
+    /// with `shared_tape`, see [`CompileOptions::shared_tape`].
+    fn takes_tape(&self) -> bool {
+        self.shared_tape && self.entry.is_some() && !self.in_module
+    }
+
+    /// Whether the tape's address is kept in `TAPE_BASE`, for dumping it at
+    /// exit or for a debugger to find it.
+    fn keeps_tape_base(&self) -> bool {
+        self.atexit.dump_tape || self.debug_file.is_some()
+    }
+
+    /// Keeps the pointer in `DEBUG_PTR` for a debugger to find, in builds
+    /// with debug info.
+    fn generate_debug_ptr(&mut self, func: &mut qbe::Function) {
+        if self.debug_file.is_some() {
+            func.add_instr(qbe::Instr::Store(
+                qbe::Type::Long,
+                qbe::Value::Global(DEBUG_PTR.to_string()),
+                self.generate_ptr(),
+            ));
+        }
+    }
+
+    /// Writes the current cell, preceded by a carriage return when it holds a
+    /// newline and CRLF translation is on.
+    fn generate_write(&mut self, func: &mut qbe::Function) {
+        let ptr = self.generate_ptr();
+        self.generate_record(func, BYTE_HISTOGRAM, ptr);
+        if self.crlf {
+            let newline = self.generate_label("nl");
+            let plain = self.generate_label("char");
+            let is_newline = self.generate_byte_eq(func, b'\n');
+            func.add_instr(qbe::Instr::Jnz(is_newline, newline.clone(), plain.clone()));
+            func.add_block(newline);
+            let cr = qbe::Value::Global("newline_cr".to_string());
+            self.generate_io(func, IoKind::Write, cr);
+            func.add_block(plain);
+        }
+
+        let ptr = self.generate_ptr();
+        self.generate_io(func, IoKind::Write, ptr)
+    }
+
+    /// Compares the low byte of the current cell against `byte`.
+    fn generate_byte_eq(&mut self, func: &mut qbe::Function, byte: u8) -> qbe::Value {
+        let cell = self.generate_tmp();
+        func.assign_instr(
+            cell.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, self.generate_ptr()),
+        );
+        func.assign_instr(
+            cell.clone(),
+            qbe::Type::Word,
+            qbe::Instr::And(cell.clone(), qbe::Value::Const(0xff)),
+        );
+        let eq = self.generate_tmp();
+        func.assign_instr(
+            eq.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Word,
+                qbe::Cmp::Eq,
+                cell,
+                qbe::Value::Const(byte as u64),
+            ),
+        );
+        eq
+    }
+
+    /// Emits a single byte `read`/`write` of `buf`, retrying on `EINTR` and
+    /// short writes. Any other failure is either ignored or, in strict mode,
+    /// reported on stderr before exiting with status 1. With CRLF
+    /// translation reads skip over carriage returns.
+    fn generate_io(&mut self, func: &mut qbe::Function, kind: IoKind, buf: qbe::Value) {
+        let retry = self.generate_label("io");
+        let check = self.generate_label("iochk");
+        let errno = self.generate_label("ioerrno");
+        let fail = self.generate_label("iofail");
+        let done = self.generate_label("iodone");
+        let skip_cr = matches!(kind, IoKind::Read) && self.crlf;
+        // only a byte actually read counts as written to the cell
+        let record = matches!(kind, IoKind::Read) && self.atexit.histogram;
+        let read = if record {
+            self.generate_label("ioread")
+        } else {
+            done.clone()
+        };
+        let success = if skip_cr {
+            self.generate_label("iocr")
+        } else {
+            read.clone()
+        };
+
+        func.add_instr(qbe::Instr::Jmp(retry.clone()));
+        func.add_block(retry.clone());
+
+        // ssize_t read(int fd, void buf[.count], size_t count);
+        // ssize_t write(int fd, const void buf[.count], size_t count);
+        let res = self.generate_tmp();
+        func.assign_instr(
+            res.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Call(
+                match (&self.io_hooks, kind) {
+                    (Some(symbol), IoKind::Read) => io_hooks(symbol)[0].clone(),
+                    (Some(symbol), IoKind::Write) => io_hooks(symbol)[1].clone(),
+                    (None, _) => kind.syscall().to_string(),
+                },
+                vec![
+                    (qbe::Type::Word, qbe::Value::Const(kind.fd())),
+                    (qbe::Type::Long, buf),
+                    (qbe::Type::Long, qbe::Value::Const(1)), // one byte only
+                ],
+            ),
+        );
+
+        let ok = self.generate_tmp();
+        func.assign_instr(
+            ok.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Eq,
+                res.clone(),
+                qbe::Value::Const(1),
+            ),
+        );
+        if self.atexit.stats {
+            let count = self.generate_label("iocount");
+            func.add_instr(qbe::Instr::Jnz(ok, count.clone(), check.clone()));
+            func.add_block(count);
+            self.generate_increment(func, kind.counter());
+            func.add_instr(qbe::Instr::Jmp(success.clone()));
+        } else {
+            func.add_instr(qbe::Instr::Jnz(ok, success.clone(), check.clone()));
+        }
+
+        func.add_block(check);
+        let failed = self.generate_tmp();
+        func.assign_instr(
+            failed.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(qbe::Type::Long, qbe::Cmp::Slt, res, qbe::Value::Const(0)),
+        );
+        // nothing transferred: EOF for reads, a short write to be retried otherwise
+        let zero = match kind {
+            IoKind::Read => done.clone(),
+            IoKind::Write => retry.clone(),
+        };
+        func.add_instr(qbe::Instr::Jnz(failed, errno.clone(), zero));
+
+        self.generate_io_error(func, kind, errno, fail, retry.clone(), done.clone());
+
+        if skip_cr {
+            func.add_block(success);
+            let is_cr = self.generate_byte_eq(func, b'\r');
+            func.add_instr(qbe::Instr::Jnz(is_cr, retry, read.clone()));
+        }
+
+        if record {
+            func.add_block(read);
+            let ptr = self.generate_ptr();
+            self.generate_record(func, CELL_HISTOGRAM, ptr);
+            func.add_instr(qbe::Instr::Jmp(done.clone()));
+        }
+
+        func.add_block(done);
+    }
+
+    /// The blocks `errno` and `fail`, which a failed `read` or `write` of
+    /// `kind` jumps to: calls interrupted by a signal go back to `retry`,
+    /// others end the program with an error under strict I/O and carry on
+    /// at `done` otherwise.
+    fn generate_io_error(
+        &mut self,
+        func: &mut qbe::Function,
+        kind: IoKind,
+        errno: String,
+        fail: String,
+        retry: String,
+        done: String,
+    ) {
+        func.add_block(errno);
+        let errno_ptr = self.generate_tmp();
+        func.assign_instr(
+            errno_ptr.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Call(self.errno_location.to_string(), Vec::new()),
+        );
+        let errno_val = self.generate_tmp();
+        func.assign_instr(
+            errno_val.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, errno_ptr),
+        );
+        let interrupted = self.generate_tmp();
+        func.assign_instr(
+            interrupted.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Word,
+                qbe::Cmp::Eq,
+                errno_val,
+                qbe::Value::Const(EINTR),
+            ),
+        );
+        func.add_instr(qbe::Instr::Jnz(interrupted, retry, fail.clone()));
+
+        func.add_block(fail);
+        if self.io_strict {
+            let (name, msg) = kind.message();
+            func.add_instr(qbe::Instr::Call(
+                "write".to_string(),
+                vec![
+                    (qbe::Type::Word, qbe::Value::Const(2)), // 2 for stderr
+                    (qbe::Type::Long, qbe::Value::Global(name.to_string())),
+                    (qbe::Type::Long, qbe::Value::Const(message_len(msg))),
+                ],
+            ));
+            self.generate_halt(func, 1);
+        } else {
+            func.add_instr(qbe::Instr::Jmp(done));
+        }
+    }
+
+    fn generate_increment(&mut self, func: &mut qbe::Function, counter: &str) {
+        let count = self.generate_tmp();
+        func.assign_instr(
+            count.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, qbe::Value::Global(counter.to_string())),
+        );
+        func.assign_instr(
+            count.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(count.clone(), qbe::Value::Const(1)),
+        );
+        func.add_instr(qbe::Instr::Store(
+            qbe::Type::Long,
+            qbe::Value::Global(counter.to_string()),
+            count,
+        ));
+    }
+
+    /// Generates `bf.atexit`, taking the pointer and the exit status, which
+    /// every way out of the program calls with `--atexit`.
+    fn generate_atexit(&mut self, module: &mut qbe::Module) {
+        if !self.atexit.any() {
+            return;
+        }
+        if self.reports() {
+            self.generate_putnum(module);
+        }
+        if self.atexit.histogram {
+            self.generate_record_helpers(module);
+        }
+
+        let status = qbe::Value::Temporary("status".to_string());
+        let mut func = qbe::Function::new(
+            qbe::Linkage::private(),
+            "bf.atexit".to_string(),
+            vec![
+                (qbe::Type::Long, self.generate_ptr()),
+                (qbe::Type::Long, status.clone()),
+            ],
+            None,
+        );
+        func.add_block("start".to_string());
+        if self.atexit.flush {
+            func.add_instr(qbe::Instr::Call(
+                "fflush".to_string(),
+                vec![(qbe::Type::Long, qbe::Value::Const(0))],
+            ));
+        }
+        if self.atexit.dump_tape {
+            self.generate_dump_tape(&mut func);
+        }
+        if self.atexit.stats {
+            self.generate_message(&mut func, "bf.msg_status");
+            Self::generate_putnum_call(&mut func, status);
+            for kind in [IoKind::Read, IoKind::Write] {
+                let count = self.generate_tmp();
+                func.assign_instr(
+                    count.clone(),
+                    qbe::Type::Long,
+                    qbe::Instr::Load(
+                        qbe::Type::Long,
+                        qbe::Value::Global(kind.counter().to_string()),
+                    ),
+                );
+                self.generate_message(
+                    &mut func,
+                    match kind {
+                        IoKind::Read => "bf.msg_read",
+                        IoKind::Write => "bf.msg_written",
+                    },
+                );
+                Self::generate_putnum_call(&mut func, count);
+            }
+            self.generate_newline(&mut func);
+        }
+        if self.atexit.histogram {
+            self.generate_message(&mut func, "bf.msg_cells");
+            self.generate_histogram(&mut func, CELL_HISTOGRAM);
+            self.generate_newline(&mut func);
+            self.generate_message(&mut func, "bf.msg_bytes");
+            self.generate_histogram(&mut func, BYTE_HISTOGRAM);
+            self.generate_newline(&mut func);
+        }
+        func.add_instr(qbe::Instr::Ret(None));
+        module.add_function(func);
+    }
+
+    /// Whether any hook prints to stderr.
+    fn reports(&self) -> bool {
+        self.atexit.dump_tape || self.atexit.stats || self.atexit.histogram
+    }
+
+    /// Counts the cell at `addr` in `histogram`, see
+    /// `generate_record_helpers`.
+    fn generate_record(&mut self, func: &mut qbe::Function, histogram: &str, addr: qbe::Value) {
+        if !self.atexit.histogram {
+            return;
+        }
+        let cell = self.generate_tmp();
+        // cells are unsigned, `loadw` into a long sign extends
+        func.assign_instr(
+            cell.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Word, addr),
+        );
+        func.assign_instr(
+            cell.clone(),
+            qbe::Type::Long,
+            qbe::Instr::And(cell.clone(), qbe::Value::Const(0xffff_ffff)),
+        );
+        func.add_instr(qbe::Instr::Call(
+            format!("{}.record", histogram),
+            vec![(qbe::Type::Long, cell)],
+        ));
+    }
+
+    /// Generates `bf.cell_hist.record` and `bf.byte_hist.record`, which take
+    /// a cell and count it in their histogram. Cells count by value up to
+    /// 255 and in the buckets of `CELL_RANGES` above that, bytes are the
+    /// cell's low byte.
+    fn generate_record_helpers(&mut self, module: &mut qbe::Module) {
+        let v = qbe::Value::Temporary("v".to_string());
+        let bucket = qbe::Value::Temporary("bucket".to_string());
+        for name in [CELL_HISTOGRAM, BYTE_HISTOGRAM] {
+            let mut func = qbe::Function::new(
+                qbe::Linkage::private(),
+                format!("{}.record", name),
+                vec![(qbe::Type::Long, v.clone())],
+                None,
+            );
+            func.add_block("start".to_string());
+            if name == BYTE_HISTOGRAM {
+                func.assign_instr(
+                    bucket.clone(),
+                    qbe::Type::Long,
+                    qbe::Instr::And(v.clone(), qbe::Value::Const(0xff)),
+                );
+            } else {
+                func.assign_instr(bucket.clone(), qbe::Type::Long, qbe::Instr::Copy(v.clone()));
+                // the first range a cell is below, checked smallest first
+                let mut bound = 256;
+                for (i, (_, end)) in CELL_RANGES.iter().enumerate() {
+                    let below = self.generate_tmp();
+                    let next = format!("range{}", i);
+                    func.assign_instr(
+                        below.clone(),
+                        qbe::Type::Word,
+                        qbe::Instr::Cmp(
+                            qbe::Type::Long,
+                            qbe::Cmp::Slt,
+                            v.clone(),
+                            qbe::Value::Const(bound),
+                        ),
+                    );
+                    func.add_instr(qbe::Instr::Jnz(below, "count".to_string(), next.clone()));
+                    func.add_block(next);
+                    func.assign_instr(
+                        bucket.clone(),
+                        qbe::Type::Long,
+                        qbe::Instr::Copy(qbe::Value::Const(256 + i as u64)),
+                    );
+                    bound = end + 1;
+                }
+                func.add_instr(qbe::Instr::Jmp("count".to_string()));
+                func.add_block("count".to_string());
+            }
+            func.assign_instr(
+                bucket.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Mul(bucket.clone(), qbe::Value::Const(8)),
+            );
+            func.assign_instr(
+                bucket.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Add(bucket.clone(), qbe::Value::Global(name.to_string())),
+            );
+            let count = self.generate_tmp();
+            func.assign_instr(
+                count.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Load(qbe::Type::Long, bucket.clone()),
+            );
+            func.assign_instr(
+                count.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Add(count.clone(), qbe::Value::Const(1)),
+            );
+            func.add_instr(qbe::Instr::Store(qbe::Type::Long, bucket.clone(), count));
+            func.add_instr(qbe::Instr::Ret(None));
+            module.add_function(func);
+        }
+    }
+
+    /// Prints every bucket of `histogram` counted at least once, as
+    /// ` value:count` and the cell ranges as ` low-high:count`.
+    fn generate_histogram(&mut self, func: &mut qbe::Function, histogram: &str) {
+        let i = self.generate_tmp();
+        func.assign_instr(
+            i.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Copy(qbe::Value::Const(0)),
+        );
+        let next = self.generate_label("histnext");
+        let bucket = self.generate_label("histbucket");
+        let print = self.generate_label("histprint");
+        let skip = self.generate_label("histskip");
+        let done = self.generate_label("histdone");
+        func.add_instr(qbe::Instr::Jmp(next.clone()));
+
+        func.add_block(next.clone());
+        let more = self.generate_tmp();
+        func.assign_instr(
+            more.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Slt,
+                i.clone(),
+                qbe::Value::Const(256),
+            ),
+        );
+        func.add_instr(qbe::Instr::Jnz(more, bucket.clone(), done.clone()));
+        func.add_block(bucket);
+        let count = self.generate_bucket(func, histogram, i.clone());
+        func.add_instr(qbe::Instr::Jnz(count.1, print.clone(), skip.clone()));
+        func.add_block(print);
+        self.generate_message(func, "bf.msg_space");
+        Self::generate_putnum_call(func, i.clone());
+        self.generate_message(func, "bf.msg_colon");
+        Self::generate_putnum_call(func, count.0);
+        func.add_instr(qbe::Instr::Jmp(skip.clone()));
+        func.add_block(skip);
+        func.assign_instr(
+            i.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(i, qbe::Value::Const(1)),
+        );
+        func.add_instr(qbe::Instr::Jmp(next));
+        func.add_block(done);
+
+        if histogram != CELL_HISTOGRAM {
+            return;
+        }
+        for (n, (msg, _)) in CELL_RANGES.iter().enumerate() {
+            let (count, seen) =
+                self.generate_bucket(func, histogram, qbe::Value::Const(256 + n as u64));
+            let print = self.generate_label("histprint");
+            let skip = self.generate_label("histskip");
+            func.add_instr(qbe::Instr::Jnz(seen, print.clone(), skip.clone()));
+            func.add_block(print);
+            self.generate_message(func, msg);
+            Self::generate_putnum_call(func, count);
+            func.add_instr(qbe::Instr::Jmp(skip.clone()));
+            func.add_block(skip);
+        }
+    }
+
+    /// Loads bucket `i` of `histogram`, returning the count and whether it
+    /// is nonzero.
+    fn generate_bucket(
+        &mut self,
+        func: &mut qbe::Function,
+        histogram: &str,
+        i: qbe::Value,
+    ) -> (qbe::Value, qbe::Value) {
+        let at = self.generate_tmp();
+        func.assign_instr(
+            at.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Mul(i, qbe::Value::Const(8)),
+        );
+        func.assign_instr(
+            at.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(at.clone(), qbe::Value::Global(histogram.to_string())),
+        );
+        let count = self.generate_tmp();
+        func.assign_instr(
+            count.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, at),
+        );
+        let seen = self.generate_tmp();
+        func.assign_instr(
+            seen.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Ne,
+                count.clone(),
+                qbe::Value::Const(0),
+            ),
+        );
+        (count, seen)
+    }
+
+    /// Prints the cells up to the last nonzero one and the pointer's index.
+    fn generate_dump_tape(&mut self, func: &mut qbe::Function) {
+        let base = self.generate_tmp();
+        let end = self.generate_tmp();
+        func.assign_instr(
+            base.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, qbe::Value::Global(TAPE_BASE.to_string())),
+        );
+        func.assign_instr(
+            end.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(base.clone(), qbe::Value::Const(self.tape_len as u64 / 2)),
+        );
+
+        // walk back from the end to just past the last nonzero cell
+        let scan = self.generate_label("scan");
+        let scan_cell = self.generate_label("scancell");
+        let found = self.generate_label("found");
+        let dump = self.generate_label("dump");
+        func.add_instr(qbe::Instr::Jmp(scan.clone()));
+        func.add_block(scan.clone());
+        let more = self.generate_tmp();
+        func.assign_instr(
+            more.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(qbe::Type::Long, qbe::Cmp::Sgt, end.clone(), base.clone()),
+        );
+        func.add_instr(qbe::Instr::Jnz(more, scan_cell.clone(), dump.clone()));
+        func.add_block(scan_cell);
+        func.assign_instr(
+            end.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(end.clone(), qbe::Value::Const(8)),
+        );
+        let cell = self.generate_tmp();
+        func.assign_instr(
+            cell.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Load(qbe::Type::Word, end.clone()),
+        );
+        func.add_instr(qbe::Instr::Jnz(cell, found.clone(), scan));
+        func.add_block(found);
+        func.assign_instr(
+            end.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(end.clone(), qbe::Value::Const(8)),
+        );
+        func.add_instr(qbe::Instr::Jmp(dump.clone()));
+
+        func.add_block(dump);
+        self.generate_message(func, "bf.msg_tape");
+        let at = self.generate_tmp();
+        func.assign_instr(at.clone(), qbe::Type::Long, qbe::Instr::Copy(base.clone()));
+        let next = self.generate_label("dumpnext");
+        let print = self.generate_label("dumpcell");
+        let done = self.generate_label("dumpdone");
+        func.add_instr(qbe::Instr::Jmp(next.clone()));
+        func.add_block(next.clone());
+        let more = self.generate_tmp();
+        func.assign_instr(
+            more.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(qbe::Type::Long, qbe::Cmp::Slt, at.clone(), end),
+        );
+        func.add_instr(qbe::Instr::Jnz(more, print.clone(), done.clone()));
+        func.add_block(print);
+        self.generate_message(func, "bf.msg_space");
+        // cells are unsigned, `loadw` into a long sign extends
+        let value = self.generate_tmp();
+        func.assign_instr(
+            value.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Word, at.clone()),
+        );
+        func.assign_instr(
+            value.clone(),
+            qbe::Type::Long,
+            qbe::Instr::And(value.clone(), qbe::Value::Const(0xffff_ffff)),
+        );
+        Self::generate_putnum_call(func, value);
+        func.assign_instr(
+            at.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(at, qbe::Value::Const(8)),
+        );
+        func.add_instr(qbe::Instr::Jmp(next));
+
+        func.add_block(done);
+        self.generate_message(func, "bf.msg_pointer");
+        let index = self.generate_tmp();
+        func.assign_instr(
+            index.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(self.generate_ptr(), base),
+        );
+        func.assign_instr(
+            index.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Div(index.clone(), qbe::Value::Const(8)),
+        );
+        Self::generate_putnum_call(func, index);
+        self.generate_newline(func);
+    }
+
+    /// Generates `bf.putnum`, writing a signed long in decimal to stderr.
+    fn generate_putnum(&mut self, module: &mut qbe::Module) {
+        let n = qbe::Value::Temporary("n".to_string());
+        let buf = qbe::Value::Temporary("buf".to_string());
+        let at = qbe::Value::Temporary("at".to_string());
+        let mut func = qbe::Function::new(
+            qbe::Linkage::private(),
+            "bf.putnum".to_string(),
+            vec![(qbe::Type::Long, n.clone())],
+            None,
+        );
+        func.add_block("start".to_string());
+        // the digits of the largest long and then some
+        func.assign_instr(buf.clone(), qbe::Type::Long, qbe::Instr::Alloc8(24));
+        func.assign_instr(
+            at.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(buf.clone(), qbe::Value::Const(24)),
+        );
+        let negative = self.generate_tmp();
+        func.assign_instr(
+            negative.clone(),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Slt,
+                n.clone(),
+                qbe::Value::Const(0),
+            ),
+        );
+        func.add_instr(qbe::Instr::Jnz(
+            negative,
+            "minus".to_string(),
+            "digit".to_string(),
+        ));
+        func.add_block("minus".to_string());
+        self.generate_message(&mut func, "bf.msg_minus");
+        func.assign_instr(
+            n.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(qbe::Value::Const(0), n.clone()),
+        );
+        func.add_instr(qbe::Instr::Jmp("digit".to_string()));
+
+        func.add_block("digit".to_string());
+        func.assign_instr(
+            at.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(at.clone(), qbe::Value::Const(1)),
+        );
+        let digit = self.generate_tmp();
+        func.assign_instr(
+            digit.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Rem(n.clone(), qbe::Value::Const(10)),
+        );
+        func.assign_instr(
+            digit.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(digit.clone(), qbe::Value::Const(b'0' as u64)),
+        );
+        func.add_instr(qbe::Instr::Store(qbe::Type::Byte, at.clone(), digit));
+        func.assign_instr(
+            n.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Div(n.clone(), qbe::Value::Const(10)),
+        );
+        func.add_instr(qbe::Instr::Jnz(n, "digit".to_string(), "write".to_string()));
+
+        func.add_block("write".to_string());
+        let len = self.generate_tmp();
+        func.assign_instr(
+            len.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Add(buf, qbe::Value::Const(24)),
+        );
+        func.assign_instr(
+            len.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(len.clone(), at.clone()),
+        );
+        func.add_instr(qbe::Instr::Call(
+            "write".to_string(),
+            vec![
+                (qbe::Type::Word, qbe::Value::Const(2)),
+                (qbe::Type::Long, at),
+                (qbe::Type::Long, len),
+            ],
+        ));
+        func.add_instr(qbe::Instr::Ret(None));
+        module.add_function(func);
+    }
+
+    fn generate_putnum_call(func: &mut qbe::Function, n: qbe::Value) {
+        func.add_instr(qbe::Instr::Call(
+            "bf.putnum".to_string(),
+            vec![(qbe::Type::Long, n)],
+        ));
+    }
+
+    /// Writes one of `ATEXIT_MESSAGES` to stderr.
+    fn generate_message(&mut self, func: &mut qbe::Function, name: &str) {
+        let (name, msg) = ATEXIT_MESSAGES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .expect("unknown message");
+        func.add_instr(qbe::Instr::Call(
+            "write".to_string(),
+            vec![
+                (qbe::Type::Word, qbe::Value::Const(2)),
+                (qbe::Type::Long, qbe::Value::Global(name.to_string())),
+                (qbe::Type::Long, qbe::Value::Const(message_len(msg))),
+            ],
+        ));
+    }
+
+    fn generate_newline(&mut self, func: &mut qbe::Function) {
+        self.generate_message(func, "bf.msg_newline");
+    }
+
+    fn generate_bounds_check(&mut self, func: &mut qbe::Function) {
+        let ptr = self.generate_ptr();
+        self.generate_bounds_check_at(func, ptr);
+    }
+
+    /// Ends the program with status 1 unless `addr` is a cell on the tape.
+    fn generate_bounds_check_at(&mut self, func: &mut qbe::Function, addr: qbe::Value) {
+        if self.unchecked {
+            return;
+        }
+        let tape_val = self.generate_tape();
+
+        let cont = self.generate_label("cont");
+        let halt = self.generate_label("halt");
+
+        let offset = self.generate_tmp();
+        func.assign_instr(
+            offset.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Sub(addr, tape_val),
+        );
+
+        let in_bounds = self.generate_tmp();
+        func.assign_instr(
+            in_bounds.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Sgt,
+                qbe::Value::Const(self.tape_len as u64 / 2),
+                offset.clone(),
+            ),
+        );
+        // moving left of the tape leaves the offset negative
+        let from_start = self.generate_tmp();
+        func.assign_instr(
+            from_start.clone(),
+            qbe::Type::Long,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Sge,
+                offset.clone(),
+                qbe::Value::Const(0),
+            ),
+        );
+        func.assign_instr(
+            in_bounds.clone(),
+            qbe::Type::Long,
+            qbe::Instr::And(in_bounds.clone(), from_start),
+        );
+
+        func.add_instr(qbe::Instr::Jnz(
+            in_bounds.clone(),
+            cont.clone(),
+            halt.clone(),
+        ));
+
+        func.add_block(halt.clone());
+        self.generate_halt(func, 1);
+        func.add_block(cont.clone());
+    }
+
+    fn generate_tape(&mut self) -> qbe::Value {
+        qbe::Value::Temporary("tape".to_string())
+    }
+
+    fn generate_ptr(&mut self) -> qbe::Value {
+        qbe::Value::Temporary("ptr".to_string())
+    }
+
+    // iterations left until the next checkpoint
+    fn generate_steps(&mut self) -> qbe::Value {
+        qbe::Value::Temporary("steps".to_string())
+    }
+
+    fn generate_tmp(&mut self) -> qbe::Value {
+        let c = self.tmp_counter;
+        self.tmp_counter += 1;
         qbe::Value::Temporary(format!("v{}", c))
     }
 
@@ -220,3 +2548,526 @@ impl QbeGenerator {
         format!("{}{}", prefix, c)
     }
 }
+
+/// Symbol of the function a shared-tape module is compiled into.
+pub fn module_symbol(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("bf_{}", name)
+}
+
+/// Symbol of the function a loop starting at `span` is outlined into with
+/// `split_loops`, `prefix` tells apart the loops of different modules.
+pub fn loop_symbol(prefix: &str, span: Span) -> String {
+    format!("{}loop_l{}_c{}", prefix, span.line, span.col)
+}
+
+const LOC_MARKER: &str = "loc.";
+
+/// Rewrites the location markers left by `generate_statement` into `dbgloc`
+/// directives, which the qbe crate cannot express.
+fn lower_locations(il: &str) -> String {
+    let prefix = format!("\t%{}", LOC_MARKER);
+    il.lines()
+        .map(|line| {
+            match line
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(" =w copy 0"))
+            {
+                Some(n) => format!("\tdbgloc {}\n", n),
+                None => format!("{}\n", line),
+            }
+        })
+        .collect()
+}
+
+fn count_io(block: &ast::NodeBlock) -> usize {
+    block
+        .stats
+        .iter()
+        .map(|stat| match &stat.stat {
+            ast::Statement::Read | ast::Statement::Write => 1,
+            ast::Statement::Loop(b) | ast::Statement::If(b) => count_io(b),
+            _ => 0,
+        })
+        .sum()
+}
+
+const READ_ERR: &str = "bfc: read error\\n";
+// where the tape starts, kept for `--atexit=dump-tape` and debuggers
+pub(crate) const TAPE_BASE: &str = "bf.tape_base";
+// the pointer, kept up to date for debuggers in builds with debug info
+pub(crate) const DEBUG_PTR: &str = "bf.ptr";
+/// Bytes between cells on the tape, of which the first 4 hold the value.
+pub(crate) const CELL_STRIDE: u64 = 8;
+// values written to cells and bytes written, kept for `--atexit=histogram`
+const CELL_HISTOGRAM: &str = "bf.cell_hist";
+const BYTE_HISTOGRAM: &str = "bf.byte_hist";
+/// Buckets past the first 256 values of a cell, the message naming each and
+/// the largest value in it. The last one mostly holds cells decremented
+/// below zero.
+const CELL_RANGES: [(&str, u64); 3] = [
+    ("bf.msg_range16", 0xffff),
+    ("bf.msg_range31", 0x7fff_ffff),
+    ("bf.msg_range32", 0xffff_ffff),
+];
+const CELL_BUCKETS: usize = 256 + CELL_RANGES.len();
+const ATEXIT_MESSAGES: [(&str, &str); 14] = [
+    ("bf.msg_tape", "bfc: tape:"),
+    ("bf.msg_space", " "),
+    ("bf.msg_minus", "-"),
+    ("bf.msg_pointer", "\\nbfc: pointer at cell "),
+    ("bf.msg_status", "bfc: exit status "),
+    ("bf.msg_read", ", bytes read "),
+    ("bf.msg_written", ", bytes written "),
+    ("bf.msg_newline", "\\n"),
+    ("bf.msg_cells", "bfc: cell values written:"),
+    ("bf.msg_bytes", "bfc: bytes written:"),
+    ("bf.msg_colon", ":"),
+    ("bf.msg_range16", " 256-65535:"),
+    ("bf.msg_range31", " 65536-2147483647:"),
+    ("bf.msg_range32", " 2147483648-4294967295:"),
+];
+const WRITE_ERR: &str = "bfc: write error\\n";
+// saving and restoring snapshots with `--checkpoint`
+const CHECKPOINT_SAVE: &str = "bf.checkpoint";
+/// Bytes of a snapshot before the cells: the loop it was taken in, the
+/// pointer's offset and the build's compat hash.
+const SNAPSHOT_HEADER: u64 = 24;
+const CHECKPOINT_RESTORE: &str = "bf.restore";
+const RESUME_FLAG: &str = "bf.cp_flag";
+const CHECKPOINT_ERR: &str = "bfc: cannot write checkpoint\\n";
+const RESUME_ERR: &str = "bfc: cannot resume from checkpoint\\n";
+
+/// Length in bytes of a message once the assembler expands its `\n` escapes.
+fn message_len(msg: &str) -> u64 {
+    (msg.len() - msg.matches("\\n").count()) as u64
+}
+
+/// The functions code generated for C to call reads and writes through,
+/// in place of `read` and `write`, named after the first `symbol` it
+/// defines, see [`crate::header`].
+pub fn io_hooks(symbol: &str) -> [String; 2] {
+    [format!("{}_read", symbol), format!("{}_write", symbol)]
+}
+
+#[derive(Clone, Copy)]
+enum IoKind {
+    Read,
+    Write,
+}
+
+impl IoKind {
+    fn syscall(self) -> &'static str {
+        match self {
+            IoKind::Read => "read",
+            IoKind::Write => "write",
+        }
+    }
+
+    fn fd(self) -> u64 {
+        match self {
+            IoKind::Read => 0,  // 0 for stdin
+            IoKind::Write => 1, // 1 for stdout
+        }
+    }
+
+    /// Name of the outlined helper, the dot keeps it clear of module names.
+    fn helper(self) -> &'static str {
+        match self {
+            IoKind::Read => "bf.read",
+            IoKind::Write => "bf.write",
+        }
+    }
+
+    /// Bytes transferred so far, kept for `--atexit=stats`.
+    fn counter(self) -> &'static str {
+        match self {
+            IoKind::Read => "bf.bytes_read",
+            IoKind::Write => "bf.bytes_written",
+        }
+    }
+
+    fn message(self) -> (&'static str, &'static str) {
+        match self {
+            IoKind::Read => ("io_read_err", READ_ERR),
+            IoKind::Write => ("io_write_err", WRITE_ERR),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lex;
+
+    fn gen(src: &str, opts: &CompileOptions) -> String {
+        let tokens = lex::lex(src.to_string());
+        let ast = ast::parse(&tokens).unwrap();
+        QbeGenerator::with_options(opts).unwrap().gen(&ast).unwrap()
+    }
+
+    #[test]
+    fn io_retries_on_eintr() {
+        let out = gen(",.", &CompileOptions::default());
+        assert!(out.contains("call $read(w 0, l %ptr, l 1)"));
+        assert!(out.contains("call $write(w 1, l %ptr, l 1)"));
+        assert!(out.contains("call $__errno_location()"));
+        assert!(!out.contains("$io_write_err"));
+    }
+
+    #[test]
+    fn tape_on_heap_past_max_stack() {
+        let opts = CompileOptions {
+            max_stack: Some(128 * 1024),
+            ..Default::default()
+        };
+        let out = gen("+", &opts);
+        assert!(out.contains("%tape =l call $calloc(l 480000, l 1)"));
+        assert!(!out.contains("alloc8"));
+        let opts = CompileOptions {
+            max_stack: Some(1 << 20),
+            ..Default::default()
+        };
+        assert!(gen("+", &opts).contains("%tape =l alloc8 480000"));
+    }
+
+    #[test]
+    fn atexit_on_every_exit() {
+        let opts = CompileOptions {
+            atexit: "flush,stats".parse().unwrap(),
+            ..Default::default()
+        };
+        let out = gen(">.", &opts);
+        assert!(out.contains("call $bf.atexit(l %ptr, l 1)"));
+        assert!(out.contains("call $bf.atexit(l %ptr, l 0)"));
+        assert!(out.contains("call $fflush(l 0)"));
+        assert!(out.contains("data $bf.bytes_written = align 8 { l 0 }"));
+        assert!(!gen(">.", &CompileOptions::default()).contains("bf.atexit"));
+    }
+
+    #[test]
+    fn histogram_records_writes() {
+        let opts = CompileOptions {
+            atexit: "histogram".parse().unwrap(),
+            ..Default::default()
+        };
+        let out = gen("+,-.", &opts);
+        assert_eq!(out.matches("call $bf.cell_hist.record(l ").count(), 3);
+        assert_eq!(out.matches("call $bf.byte_hist.record(l ").count(), 1);
+        assert!(out.contains("csltl %v, 65536"));
+        assert!(!gen("+,-.", &CompileOptions::default()).contains("_hist"));
+    }
+
+    #[test]
+    fn offset_addressing() {
+        let opts = CompileOptions {
+            opt_level: OptLevel::O2,
+            ..Default::default()
+        };
+        let src = "+>++>+++<<.>>>>>+<<<<<[>+<-]>>";
+        let out = gen(src, &opts);
+        // cells are addressed from the pointer, which only moves at the end
+        assert_eq!(out.matches("%ptr =l add").count(), 1);
+        assert!(out.contains("\t%ptr =l add %ptr, 16\n\tret 0\n"));
+        // and checked once as they are reached
+        assert!(out.contains("%v1 =l add %ptr, 8\n"));
+        assert!(out.contains("%v5 =w loadw %v1\n\t%v5 =w add %v5, 2\n"));
+        // and stored once the run is over
+        assert!(out.contains("storew %v0, %ptr\n\tstorew %v5, %v1\n\tstorew %v10, %v6\n"));
+        assert_eq!(out.matches("add %ptr, 40\n").count(), 1);
+        assert_eq!(out.matches("@halt").count(), 10);
+
+        let unoptimized = gen(src, &CompileOptions::default());
+        assert_eq!(unoptimized.matches("%ptr =l add").count(), 5);
+        assert_eq!(unoptimized.matches("@halt").count(), 16);
+    }
+
+    #[test]
+    fn cache_cells() {
+        let opts = CompileOptions {
+            opt_level: OptLevel::O2,
+            ..Default::default()
+        };
+        let src = "+>+<+.";
+        let out = gen(src, &opts);
+        assert_eq!(out.matches("loadw %ptr").count(), 1);
+        assert!(out.contains("\t%v0 =w add %v0, 1\n\tstorew %v0, %ptr\n"));
+        assert_eq!(out.matches("storew").count(), 2);
+
+        // a histogram counts every value a cell takes
+        let hooks = CompileOptions {
+            atexit: "histogram".parse().unwrap(),
+            ..opts
+        };
+        assert_eq!(gen(src, &hooks).matches("storew").count(), 3);
+        assert_eq!(
+            gen(src, &CompileOptions::default())
+                .matches("storew")
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn set_is_one_store() {
+        let mut ast = ast::parse(&lex::lex("+[-]".to_string())).unwrap();
+        crate::opt::clear_loops(&mut ast);
+        let out = QbeGenerator::with_options(&CompileOptions::default())
+            .unwrap()
+            .gen(&ast)
+            .unwrap();
+        assert!(out.contains("storew 0, "));
+        assert!(!out.contains("jnz"));
+    }
+
+    #[test]
+    fn newline_raw_by_default() {
+        let out = gen(",.", &CompileOptions::default());
+        assert!(!out.contains("$newline_cr"));
+        assert!(!out.contains("and "));
+    }
+
+    #[test]
+    fn newline_crlf() {
+        let opts = CompileOptions {
+            newline: Newline::Crlf,
+            ..Default::default()
+        };
+        let out = gen(".", &opts);
+        assert!(out.contains("call $write(w 1, l $newline_cr, l 1)"));
+        assert!(out.contains("%v1 =w ceqw %v0, 10"));
+        assert!(out.contains("data $newline_cr = { b 13 }"));
+
+        let out = gen(",", &opts);
+        assert!(out.contains("%v7 =w ceqw %v6, 13"));
+        assert!(!out.contains("call $write"));
+    }
+
+    #[test]
+    fn outline_io_for_size() {
+        let opts = CompileOptions {
+            opt_level: OptLevel::Size,
+            ..Default::default()
+        };
+        let out = gen(".>.", &opts);
+        assert!(out.contains("function l $bf.write(l %ptr)"));
+        assert_eq!(out.matches("call $bf.write(l %ptr)").count(), 2);
+        assert_eq!(out.matches("call $write(w 1").count(), 1);
+
+        let out = gen(".>.", &CompileOptions::default());
+        assert!(!out.contains("$bf.write"));
+    }
+
+    #[test]
+    fn debug_locations() {
+        let opts = CompileOptions {
+            debug_file: Some("hello.b".to_string()),
+            ..Default::default()
+        };
+        let ast = crate::parse("+\n+>\n\n[-]").unwrap();
+        let out = QbeGenerator::with_options(&opts)
+            .unwrap()
+            .gen(&ast)
+            .unwrap();
+        assert!(out.starts_with("dbgfile \"hello.b\"\n"));
+        assert_eq!(out.matches("\tdbgloc ").count(), 3);
+        assert!(out.contains("\tdbgloc 4\n"));
+        assert!(!out.contains(LOC_MARKER));
+    }
+
+    #[test]
+    fn apple_errno() {
+        let opts = CompileOptions {
+            target: Some(crate::target::Target::Arm64Apple),
+            ..Default::default()
+        };
+        let out = gen(".", &opts);
+        assert!(out.contains("call $__error()"));
+        assert!(!out.contains("__errno_location"));
+    }
+
+    #[test]
+    fn split_loops_by_location() {
+        let opts = CompileOptions {
+            split_loops: true,
+            ..Default::default()
+        };
+        let ast = crate::parse("+[->\n [-]<]").unwrap();
+        let out = QbeGenerator::with_options(&opts)
+            .unwrap()
+            .gen(&ast)
+            .unwrap();
+        assert!(out.contains("function l $bf_loop_l1_c2(l %tape, l %ptr)"));
+        assert!(out.contains("function l $bf_loop_l2_c2(l %tape, l %ptr)"));
+        assert!(out.contains("%ptr =l call $bf_loop_l1_c2(l %tape, l %ptr)"));
+        assert!(out.contains("%ptr =l call $bf_loop_l2_c2(l %tape, l %ptr)"));
+    }
+
+    #[test]
+    fn entry_function_replaces_main() {
+        let opts = CompileOptions {
+            entry: Some(Entry {
+                symbol: "bf_hello".to_string(),
+                linkage: Linkage::Export,
+            }),
+            memcheck: true,
+            ..Default::default()
+        };
+        let out = gen("<", &opts);
+        assert!(out.contains("export function w $bf_hello()"));
+        assert!(!out.contains("$main"));
+        assert!(out.contains("call $free(l %tape)\n\tret 1"));
+        let opts = CompileOptions {
+            entry: Some(Entry {
+                symbol: "run".to_string(),
+                linkage: Linkage::Local,
+            }),
+            ..Default::default()
+        };
+        let out = gen("+.", &opts);
+        assert!(out.contains("function w $run()") && !out.contains("export"));
+        assert!(!out.contains("$free"));
+    }
+
+    #[test]
+    fn checkpoints_and_resume() {
+        let checkpoint: Checkpoint = "every:500steps,file:run \"1\".snap".parse().unwrap();
+        assert_eq!(checkpoint.every, 500);
+        assert!("file:x".parse::<Checkpoint>().is_err());
+        assert!("every:0,file:x".parse::<Checkpoint>().is_err());
+        let opts = CompileOptions {
+            checkpoint: Some(checkpoint),
+            ..Default::default()
+        };
+        let out = gen("+[-[>]]", &opts);
+        assert!(out.contains("export function w $main(w %argc, l %argv)"));
+        assert!(out.contains("%steps =l copy 500"));
+        for c in [1, 2] {
+            assert!(out.contains(&format!("call $bf.checkpoint(l %tape, l %ptr, l {})", c)));
+            assert!(out.contains(&format!(", {}\n\tjnz %v", c)));
+            assert!(out.contains(&format!("jnz %steps, @test{}, @save{}", c, c)));
+        }
+        assert!(out.contains("data $bf.cp_tmp = { b \"run \\\"1\\\".snap.tmp\", b 0 }"));
+        // snapshots from a build with another tape are refused
+        let compat = stamp::compat_hash(&opts);
+        assert!(out.contains(&format!("storel {}, %at", compat)));
+        assert!(out.contains(&format!("%compat =l ceql %compat, {}", compat)));
+        let other = CompileOptions {
+            tape_len: 10,
+            ..opts.clone()
+        };
+        assert_ne!(stamp::compat_hash(&other), compat);
+        assert!(!gen("+[-]", &CompileOptions::default()).contains("$bf.checkpoint"));
+    }
+
+    #[test]
+    fn functions_get_own_tapes() {
+        let progs = ["+[-].", "+."]
+            .iter()
+            .zip(["bf_first", "bf_second"])
+            .map(|(src, name)| {
+                let tokens = lex::lex(src.to_string());
+                (name.to_string(), ast::parse(&tokens).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let out = QbeGenerator::new().gen_functions(&progs).unwrap();
+        assert!(!out.contains("$main"));
+        for name in ["bf_first", "bf_second"] {
+            let start = out.find(&format!("export function w ${}()", name)).unwrap();
+            // numbering starts over in every function
+            assert!(out[start..].contains("\n@runtime\n\t%tape =l alloc8 480000\n"));
+            assert!(out[start..].contains("%v0 =w loadw %ptr"));
+        }
+    }
+
+    #[test]
+    fn entry_takes_shared_tape() {
+        let opts = CompileOptions {
+            entry: Some(Entry {
+                symbol: "step".to_string(),
+                linkage: Linkage::Export,
+            }),
+            shared_tape: true,
+            memcheck: true,
+            ..Default::default()
+        };
+        let out = gen("+>", &opts);
+        assert!(out.contains(
+            "export function w $step(l %tape, l %pos) {\n@runtime\n\t%v0 =l loadl %pos\n"
+        ));
+        assert!(!out.contains("calloc") && !out.contains("alloc8"));
+        assert!(out.contains("div %v"));
+        assert_eq!(out.matches(", %pos\n").count(), 3);
+
+        // C provides the I/O, unless the function is only seen from IL
+        let out = gen(",.", &opts);
+        assert!(out.contains("call $step_read(w 0, "));
+        assert!(out.contains("call $step_write(w 1, "));
+        let local = CompileOptions {
+            entry: Some(Entry {
+                symbol: "step".to_string(),
+                linkage: Linkage::Local,
+            }),
+            ..opts
+        };
+        assert!(gen(",", &local).contains("call $read(w 0, "));
+    }
+
+    #[test]
+    fn modules_share_tape() {
+        let progs = ["+.", "-"]
+            .iter()
+            .zip(["first", "second-one"])
+            .map(|(src, name)| {
+                let tokens = lex::lex(src.to_string());
+                (name.to_string(), ast::parse(&tokens).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let out = QbeGenerator::new().gen_modules(&progs).unwrap();
+        assert!(out.contains("function l $bf_first(l %tape, l %ptr)"));
+        assert!(out.contains("function l $bf_second_one(l %tape, l %ptr)"));
+        assert!(out.contains("%ptr =l call $bf_second_one(l %tape, l %ptr)"));
+        assert!(out.contains("storew 0, %tape"));
+
+        // every function starts out on no line
+        let progs = vec![
+            ("first".to_string(), crate::parse("+.").unwrap()),
+            ("second".to_string(), crate::parse("-").unwrap()),
+        ];
+        let opts = CompileOptions {
+            debug_file: Some("first.b".to_string()),
+            ..Default::default()
+        };
+        let out = QbeGenerator::with_options(&opts)
+            .unwrap()
+            .gen_modules(&progs)
+            .unwrap();
+        assert!(!out.contains(LOC_MARKER));
+        assert_eq!(out.matches("\tdbgloc 1\n").count(), 2);
+    }
+
+    #[test]
+    fn io_strict_reports_errors() {
+        let opts = CompileOptions {
+            io_strict: true,
+            ..Default::default()
+        };
+        let out = gen(".", &opts);
+        assert!(out.contains("call $write(w 2, l $io_write_err, l 17)"));
+        assert!(out.contains("data $io_write_err"));
+    }
+
+    #[test]
+    fn tape_has_to_fit() {
+        for tape_len in [0, usize::MAX / 8] {
+            let opts = CompileOptions {
+                tape_len,
+                ..Default::default()
+            };
+            assert!(QbeGenerator::with_options(&opts).is_err(), "{}", tape_len);
+        }
+    }
+}
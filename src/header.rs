@@ -0,0 +1,153 @@
+//! A C header for the functions `--entry` and `--functions` generate, so
+//! that C code calling compiled programs only has to include it. Besides
+//! the prototypes, it gives the size of the tape, which the functions set
+//! up afresh on every call unless they run on their caller's with
+//! `--shared-tape`, and the I/O hooks the programs call in place of `read`
+//! and `write`, see [`gen::io_hooks`], which the C side provides to run
+//! them against whatever it likes, or has the header define on top of
+//! `read` and `write`.
+
+use std::fmt::Write;
+
+use crate::{
+    gen::{self, CELL_STRIDE},
+    CompileOptions, Error,
+};
+
+/// The header declaring the functions `symbols`, compiled from `source`
+/// with `opts`. Its include guard, macros and I/O hooks are named after the
+/// first, and there has to be one.
+pub fn c_header(source: &str, symbols: &[String], opts: &CompileOptions) -> Result<String, Error> {
+    let Some(first) = symbols.first() else {
+        return Err(Error::Unsupported(
+            "a header declares at least one function",
+        ));
+    };
+    let prefix: String = first
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    let guard = format!("{}_H", prefix);
+    let stdio = format!("{}_STDIO", prefix);
+    let [read, write] = gen::io_hooks(first);
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "/* {}, generated by bfc from {} */",
+        symbols.join(", "),
+        // a path could end the comment early
+        source.replace("*/", "*\\/")
+    )
+    .unwrap();
+    writeln!(out, "#ifndef {}\n#define {}\n", guard, guard).unwrap();
+    out.push_str("#include <stddef.h>\n#include <stdint.h>\n#include <sys/types.h>\n");
+    writeln!(out, "#ifdef {}\n#include <unistd.h>\n#endif\n", stdio).unwrap();
+    out.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+
+    if opts.shared_tape {
+        out.push_str("/* Cells on the tape, 32 bits each. */\n");
+    } else {
+        out.push_str("/* Cells on the tape, 32 bits each and zero when a program starts. */\n");
+    }
+    writeln!(out, "#define {}_TAPE_CELLS {}\n", prefix, opts.tape_len).unwrap();
+
+    let status = if opts.io_strict {
+        "off the tape or I/O failed"
+    } else {
+        "off the tape"
+    };
+    if opts.shared_tape {
+        // every header of a shared tape declares the same cell type
+        writeln!(
+            out,
+            "#ifndef BFC_CELL\n#define BFC_CELL\n\
+             /* A cell of the tape, {} bytes apart. */\n\
+             typedef struct bf_cell {{\n    uint32_t value;\n    unsigned char unused[{}];\n}} bf_cell;\n\
+             #endif\n",
+            CELL_STRIDE,
+            CELL_STRIDE - 4
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "/* Runs a program on `tape`, of {}_TAPE_CELLS cells, from cell `*pos`\n \
+             * on, leaving the cell it ends on in `*pos`. Returns 0 once it is\n \
+             * done and 1 when it moved {}. */",
+            prefix, status
+        )
+        .unwrap();
+        for symbol in symbols {
+            writeln!(out, "int {}(bf_cell *tape, size_t *pos);", symbol).unwrap();
+        }
+    } else {
+        writeln!(
+            out,
+            "/* Runs a program, returning 0 once it is done and 1 when it moved\n \
+             * {}. */",
+            status
+        )
+        .unwrap();
+        for symbol in symbols {
+            writeln!(out, "int {}(void);", symbol).unwrap();
+        }
+    }
+    out.push('\n');
+
+    writeln!(
+        out,
+        "/* The programs read file descriptor 0 and write 1 through these, a\n \
+         * byte at a time, retrying when interrupted. Define them, or define\n \
+         * {} before including this header in one file for ones calling\n \
+         * read and write. */\n\
+         ssize_t {}(int fd, void *buf, size_t len);\n\
+         ssize_t {}(int fd, const void *buf, size_t len);\n\n\
+         #ifdef {}\n\
+         ssize_t {}(int fd, void *buf, size_t len) {{ return read(fd, buf, len); }}\n\
+         ssize_t {}(int fd, const void *buf, size_t len) {{ return write(fd, buf, len); }}\n\
+         #endif\n",
+        stdio, read, write, stdio, read, write
+    )
+    .unwrap();
+
+    out.push_str("#ifdef __cplusplus\n}\n#endif\n\n");
+    writeln!(out, "#endif /* {} */", guard).unwrap();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header_declares_entry() {
+        let symbols = ["bf_hello".to_string()];
+        let opts = CompileOptions {
+            tape_len: 100,
+            ..Default::default()
+        };
+        let header = c_header("hello.bf", &symbols, &opts).unwrap();
+        assert!(header.starts_with("/* bf_hello, generated by bfc from hello.bf */\n"));
+        assert!(header.contains("#ifndef BF_HELLO_H\n#define BF_HELLO_H\n"));
+        assert!(header.contains("#define BF_HELLO_TAPE_CELLS 100\n"));
+        assert!(header.contains("\nint bf_hello(void);\n"));
+        assert!(header.contains("\nssize_t bf_hello_write(int fd, const void *buf, size_t len);"));
+        assert!(header.contains("#ifdef BF_HELLO_STDIO\n#include <unistd.h>\n"));
+        assert!(header.ends_with("#endif /* BF_HELLO_H */\n"));
+
+        let symbols = ["bf_a".to_string(), "bf_b".to_string()];
+        let opts = CompileOptions {
+            shared_tape: true,
+            ..Default::default()
+        };
+        let header = c_header("a*/b.bf", &symbols, &opts).unwrap();
+        assert!(header.starts_with("/* bf_a, bf_b, generated by bfc from a*\\/b.bf */\n"));
+        assert!(header.contains("    uint32_t value;\n    unsigned char unused[4];\n"));
+        assert!(header.contains("\nint bf_a(bf_cell *tape, size_t *pos);\nint bf_b("));
+        assert!(header.contains("\nssize_t bf_a_read(int fd, void *buf, size_t len);"));
+        assert!(c_header("", &[], &opts).is_err());
+    }
+}
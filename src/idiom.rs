@@ -0,0 +1,576 @@
+//! Idioms recognized by pattern: a short run of statements, spelled the way
+//! the tree printer spells them, and what to replace it with, such as
+//! `Set(0) Add(a) => Set(a)` for a cell cleared and counted up to a
+//! constant. The passes of [`crate::opt`] recognize the idioms every
+//! program is full of, clear, multiply and scan loops, with code of their
+//! own; an [`Idiom`] adds another without any, for trying one out or for
+//! the idioms of programs from one generator or another, see
+//! [`CompileOptions::idioms`](crate::CompileOptions::idioms).
+//!
+//! A pattern is a sequence of statements separated by whitespace, each a
+//! name with its arguments in parentheses, `MulAdd(1, 3)`, or `Loop` or
+//! `If` with the pattern its body has to match in brackets, `Loop[Sub(1)]`.
+//! An argument is a number, `_` for any number or a variable, a lowercase
+//! name standing for the same number wherever it appears. A replacement is
+//! spelled the same way, from the variables its pattern binds. `Fill` and
+//! `Print` are never matched.
+//!
+//! Nothing checks that a replacement does what the statements it replaces
+//! did, an idiom is trusted as the code of a pass is.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::{
+    ast::{Attr, NodeBlock, NodeStatement, Statement},
+    lex::Span,
+};
+
+/// The statements patterns can name, with the number of arguments each
+/// takes.
+const STATEMENTS: [(&str, usize); 9] = [
+    ("MoveL", 1),
+    ("MoveR", 1),
+    ("Add", 1),
+    ("Sub", 1),
+    ("Read", 0),
+    ("Write", 0),
+    ("Set", 1),
+    ("MulAdd", 2),
+    ("Scan", 1),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Arg {
+    Num(i64),
+    Var(String),
+    Any,
+}
+
+/// A statement of a pattern or a replacement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Stat {
+        name: &'static str,
+        args: Vec<Arg>,
+    },
+    /// A loop, an `If` when `once`, with its body.
+    Loop {
+        once: bool,
+        body: Vec<Node>,
+    },
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Stat { name, args } if args.is_empty() => write!(f, "{}", name),
+            Node::Stat { name, args } => {
+                let args: Vec<_> = args
+                    .iter()
+                    .map(|arg| match arg {
+                        Arg::Num(n) => n.to_string(),
+                        Arg::Var(var) => var.clone(),
+                        Arg::Any => "_".to_string(),
+                    })
+                    .collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            Node::Loop { once, body } => {
+                write!(f, "{}[{}]", if *once { "If" } else { "Loop" }, spell(body))
+            }
+        }
+    }
+}
+
+fn spell(nodes: &[Node]) -> String {
+    let nodes: Vec<_> = nodes.iter().map(|node| node.to_string()).collect();
+    nodes.join(" ")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Num(i64),
+    Punct(char),
+}
+
+fn tokens(s: &str) -> Result<Vec<Token>, String> {
+    let mut out = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        let mut take = |first: usize, pred: fn(char) -> bool| {
+            let mut end = first + c.len_utf8();
+            while let Some((i, c)) = chars.next_if(|(_, c)| pred(*c)) {
+                end = i + c.len_utf8();
+            }
+            &s[first..end]
+        };
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' | '[' | ']' | ',' => out.push(Token::Punct(c)),
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let word = take(at, |c| c.is_ascii_alphanumeric() || c == '_');
+                out.push(Token::Word(word.to_string()));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let num = take(at, |c| c.is_ascii_digit());
+                let num = num.parse().map_err(|_| format!("bad number `{}`", num))?;
+                out.push(Token::Num(num));
+            }
+            c => return Err(format!("unexpected `{}`", c)),
+        }
+    }
+    Ok(out)
+}
+
+/// Parses statements up to `close`, or to the end of the input when
+/// `None`.
+fn nodes(tokens: &mut std::slice::Iter<Token>, close: Option<char>) -> Result<Vec<Node>, String> {
+    let mut out = Vec::new();
+    loop {
+        let word = match tokens.next() {
+            None if close.is_none() => return Ok(out),
+            Some(Token::Punct(c)) if Some(*c) == close => return Ok(out),
+            Some(Token::Word(word)) => word,
+            Some(Token::Num(n)) => return Err(format!("expected a statement, found `{}`", n)),
+            Some(Token::Punct(c)) => return Err(format!("expected a statement, found `{}`", c)),
+            None => return Err("unclosed `[`".to_string()),
+        };
+        if word == "Loop" || word == "If" {
+            if tokens.next() != Some(&Token::Punct('[')) {
+                return Err(format!("expected `[` after `{}`", word));
+            }
+            out.push(Node::Loop {
+                once: word == "If",
+                body: nodes(tokens, Some(']'))?,
+            });
+            continue;
+        }
+        let Some(&(name, arity)) = STATEMENTS.iter().find(|(name, _)| name == word) else {
+            return Err(format!("unknown statement `{}`", word));
+        };
+        let mut args = Vec::new();
+        if arity > 0 {
+            if tokens.next() != Some(&Token::Punct('(')) {
+                return Err(format!("expected `(` after `{}`", name));
+            }
+            loop {
+                args.push(match tokens.next() {
+                    Some(Token::Num(n)) => Arg::Num(*n),
+                    Some(Token::Word(w)) if w == "_" => Arg::Any,
+                    Some(Token::Word(w)) if w.starts_with(|c: char| c.is_ascii_lowercase()) => {
+                        Arg::Var(w.clone())
+                    }
+                    _ => return Err(format!("expected an argument of `{}`", name)),
+                });
+                match tokens.next() {
+                    Some(Token::Punct(',')) => {}
+                    Some(Token::Punct(')')) => break,
+                    _ => return Err(format!("expected `,` or `)` in `{}`", name)),
+                }
+            }
+        }
+        if args.len() != arity {
+            return Err(format!(
+                "`{}` takes {} arguments, not {}",
+                name,
+                arity,
+                args.len()
+            ));
+        }
+        out.push(Node::Stat { name, args });
+    }
+}
+
+fn parse(s: &str) -> Result<Vec<Node>, String> {
+    nodes(&mut tokens(s)?.iter(), None)
+}
+
+/// Calls `f` on every argument of `nodes`.
+fn each_arg(nodes: &[Node], f: &mut impl FnMut(&Arg)) {
+    for node in nodes {
+        match node {
+            Node::Stat { args, .. } => args.iter().for_each(&mut *f),
+            Node::Loop { body, .. } => each_arg(body, f),
+        }
+    }
+}
+
+/// The name and arguments of a statement patterns can match.
+fn parts(stat: &Statement) -> Option<(&'static str, Vec<i64>)> {
+    Some(match stat {
+        Statement::MoveL(n) => ("MoveL", vec![i64::try_from(*n).ok()?]),
+        Statement::MoveR(n) => ("MoveR", vec![i64::try_from(*n).ok()?]),
+        Statement::Add(n) => ("Add", vec![i64::try_from(*n).ok()?]),
+        Statement::Sub(n) => ("Sub", vec![i64::try_from(*n).ok()?]),
+        Statement::Read => ("Read", Vec::new()),
+        Statement::Write => ("Write", Vec::new()),
+        Statement::Set(n) => ("Set", vec![*n as i64]),
+        Statement::MulAdd { offset, factor } => ("MulAdd", vec![*offset as i64, *factor as i64]),
+        Statement::Scan(stride) => ("Scan", vec![*stride as i64]),
+        Statement::Loop(_) | Statement::If(_) | Statement::Fill(_) | Statement::Print(_) => {
+            return None
+        }
+    })
+}
+
+/// The statement called `name` with `args`, if they fit it.
+fn statement(name: &str, args: &[i64]) -> Option<Statement> {
+    Some(match (name, args) {
+        ("MoveL", [n]) => Statement::MoveL(usize::try_from(*n).ok()?),
+        ("MoveR", [n]) => Statement::MoveR(usize::try_from(*n).ok()?),
+        ("Add", [n]) => Statement::Add(usize::try_from(*n).ok()?),
+        ("Sub", [n]) => Statement::Sub(usize::try_from(*n).ok()?),
+        ("Read", []) => Statement::Read,
+        ("Write", []) => Statement::Write,
+        ("Set", [n]) => Statement::Set(u32::try_from(*n).ok()?),
+        ("MulAdd", [offset, factor]) => Statement::MulAdd {
+            offset: isize::try_from(*offset).ok()?,
+            factor: u32::try_from(*factor).ok()?,
+        },
+        ("Scan", [stride]) => Statement::Scan(isize::try_from(*stride).ok()?),
+        _ => return None,
+    })
+}
+
+/// Whether `stats` match `pattern` statement for statement, binding its
+/// variables in `vars`. Loops marked no-opt never match.
+fn matches(pattern: &[Node], stats: &[NodeStatement], vars: &mut HashMap<String, i64>) -> bool {
+    pattern.len() == stats.len()
+        && pattern
+            .iter()
+            .zip(stats)
+            .all(|(node, stat)| match (node, &stat.stat) {
+                (Node::Loop { once, body }, Statement::Loop(b) | Statement::If(b)) => {
+                    *once == matches!(stat.stat, Statement::If(_))
+                        && !stat.attr.no_opt
+                        && matches(body, &b.stats, vars)
+                }
+                (Node::Stat { name, args }, stat) => match parts(stat) {
+                    Some((n, values)) if n == *name => {
+                        args.iter().zip(values).all(|(arg, value)| match arg {
+                            Arg::Num(n) => *n == value,
+                            Arg::Any => true,
+                            Arg::Var(var) => *vars.entry(var.clone()).or_insert(value) == value,
+                        })
+                    }
+                    _ => false,
+                },
+                _ => false,
+            })
+}
+
+/// Collects the spans of `stats` and of the statements in them, each once,
+/// and their comments, in source order.
+fn gather(stats: &[NodeStatement], spans: &mut Vec<Span>, comments: &mut String) {
+    for stat in stats {
+        if !spans.contains(&stat.attr.span) {
+            spans.push(stat.attr.span);
+        }
+        comments.push_str(&stat.attr.trivia.leading);
+        comments.push_str(&stat.attr.trivia.trailing);
+        if let Statement::Loop(body) | Statement::If(body) = &stat.stat {
+            gather(&body.stats, spans, comments);
+            comments.push_str(&body.attr.trivia.trailing);
+        }
+    }
+}
+
+/// The statements of `replacement` with the values of `vars`, taking the
+/// span of `attr` but for loops, which take one of `spans` each, if every
+/// value fits its statement and there are spans enough.
+fn build(
+    replacement: &[Node],
+    vars: &HashMap<String, i64>,
+    attr: &Attr,
+    spans: &mut impl Iterator<Item = Span>,
+) -> Option<Vec<NodeStatement>> {
+    replacement
+        .iter()
+        .map(|node| {
+            let mut attr = attr.clone();
+            let stat = match node {
+                Node::Stat { name, args } => {
+                    let args: Option<Vec<_>> = args
+                        .iter()
+                        .map(|arg| match arg {
+                            Arg::Num(n) => Some(*n),
+                            Arg::Var(var) => vars.get(var).copied(),
+                            Arg::Any => None,
+                        })
+                        .collect();
+                    statement(name, &args?)?
+                }
+                Node::Loop { once, body } => {
+                    attr.span = spans.next()?;
+                    let body = Box::new(NodeBlock {
+                        attr: attr.clone(),
+                        stats: build(body, vars, &attr, spans)?,
+                    });
+                    if *once {
+                        Statement::If(body)
+                    } else {
+                        Statement::Loop(body)
+                    }
+                }
+            };
+            Some(NodeStatement { attr, stat })
+        })
+        .collect()
+}
+
+/// A pattern of statements and what to replace them with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Idiom {
+    name: String,
+    pattern: Vec<Node>,
+    replacement: Vec<Node>,
+}
+
+impl Idiom {
+    /// The idiom called `name` replacing statements matching `pattern` with
+    /// `replacement`, spelled as the [module documentation](self) says.
+    /// Fails on a pattern matching nothing and on a replacement using a
+    /// variable the pattern does not bind, or `_`.
+    pub fn new(name: &str, pattern: &str, replacement: &str) -> Result<Idiom, String> {
+        let in_idiom = |e| format!("in idiom `{}`: {}", name, e);
+        let pattern = parse(pattern).map_err(in_idiom)?;
+        let replacement = parse(replacement).map_err(in_idiom)?;
+        if pattern.is_empty() {
+            return Err(in_idiom("the pattern is empty".to_string()));
+        }
+        let mut bound = Vec::new();
+        each_arg(&pattern, &mut |arg| {
+            if let Arg::Var(var) = arg {
+                bound.push(var.clone());
+            }
+        });
+        let mut unbound = None;
+        each_arg(&replacement, &mut |arg| match arg {
+            Arg::Var(var) if !bound.contains(var) => {
+                unbound.get_or_insert(format!("`{}` is not bound by the pattern", var));
+            }
+            Arg::Any => {
+                unbound.get_or_insert("`_` stands for no number in a replacement".to_string());
+            }
+            _ => {}
+        });
+        if let Some(e) = unbound {
+            return Err(in_idiom(e));
+        }
+        Ok(Idiom {
+            name: name.to_string(),
+            pattern,
+            replacement,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The replacement for the statements `stats` starts with, if they
+    /// match the pattern and the values bound fit the replacement. The
+    /// replacement takes the span of the first statement, its first
+    /// statement the comments of all of them. Its loops take a span each of
+    /// the statements replaced, so that no two loops share the symbol they
+    /// are outlined into, and the replacement does not apply when there are
+    /// more loops than statements.
+    fn replace(&self, stats: &[NodeStatement]) -> Option<Vec<NodeStatement>> {
+        let stats = stats.get(..self.pattern.len())?;
+        let mut vars = HashMap::new();
+        if !matches(&self.pattern, stats, &mut vars) {
+            return None;
+        }
+        let (mut spans, mut comments) = (Vec::new(), String::new());
+        gather(stats, &mut spans, &mut comments);
+        let attr = Attr {
+            span: stats[0].attr.span,
+            ..Default::default()
+        };
+        let mut out = build(&self.replacement, &vars, &attr, &mut spans.into_iter())?;
+        if let Some(first) = out.first_mut() {
+            let leading = &stats[0].attr.trivia.leading;
+            first.attr.trivia.trailing = comments.split_off(leading.len());
+            first.attr.trivia.leading = comments;
+        }
+        Some(out)
+    }
+}
+
+/// As `NAME: PATTERN => REPLACEMENT`, the way `--idiom` takes it.
+impl FromStr for Idiom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rule) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected `NAME: PATTERN => REPLACEMENT`, found `{}`", s))?;
+        let (pattern, replacement) = rule
+            .split_once("=>")
+            .ok_or_else(|| format!("expected `=>` in idiom `{}`", name.trim()))?;
+        Idiom::new(name.trim(), pattern, replacement)
+    }
+}
+
+impl fmt::Display for Idiom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} => {}",
+            self.name,
+            spell(&self.pattern),
+            spell(&self.replacement)
+        )
+    }
+}
+
+/// The idioms the `idioms` pass recognizes in every program, before those
+/// of [`CompileOptions::idioms`](crate::CompileOptions::idioms).
+pub fn builtin() -> Vec<Idiom> {
+    // a cell cleared and counted up, as in `[-]+++`
+    [("set-add", "Set(0) Add(a)", "Set(a)")]
+        .iter()
+        .map(|(name, pattern, replacement)| {
+            Idiom::new(name, pattern, replacement).expect("built-in idioms parse")
+        })
+        .collect()
+}
+
+/// Replaces the statements of every block of the tree matching an idiom
+/// with its replacement. Bodies are done before the loops around them,
+/// and each block from its first statement on, trying `idioms` in order
+/// at each, the first to match winning; a replacement is not matched
+/// again. Loops marked no-opt are left alone. Returns whether anything
+/// was replaced.
+pub fn recognize(block: &mut NodeBlock, idioms: &[Idiom]) -> bool {
+    let mut changed = false;
+    for stat in &mut block.stats {
+        if let Statement::Loop(body) | Statement::If(body) = &mut stat.stat {
+            if !stat.attr.no_opt {
+                changed |= recognize(body, idioms);
+            }
+        }
+    }
+
+    let mut found = Vec::new();
+    let mut at = 0;
+    while at < block.stats.len() {
+        let stats = &block.stats[at..];
+        match idioms.iter().find_map(|idiom| {
+            let replacement = idiom.replace(stats)?;
+            Some((idiom.pattern.len(), replacement))
+        }) {
+            Some((len, replacement)) => {
+                found.push((at, len, replacement));
+                at += len;
+            }
+            None => at += 1,
+        }
+    }
+    // from the back, so the statements ahead stay where they were found
+    for (at, len, replacement) in found.iter().rev() {
+        block
+            .stats
+            .splice(*at..*at + *len, replacement.iter().cloned());
+    }
+    changed || !found.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{opt, CompileOptions, OptLevel};
+
+    fn stats(block: &NodeBlock) -> Vec<String> {
+        block.stats.iter().map(|s| s.stat.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_and_match_idioms() {
+        let copy: Idiom = "copy: Loop[Sub(1) MoveR(a) Add(1) MoveL(a)] => MulAdd(a, 1) Set(0)"
+            .parse()
+            .unwrap();
+        assert_eq!(copy.name(), "copy");
+        assert_eq!(copy.to_string().parse::<Idiom>(), Ok(copy.clone()));
+
+        // in a loop body, and only where both moves go as far
+        let mut prog = crate::parse("+[[->>+<<]>[->+<<]]").unwrap();
+        assert!(recognize(&mut prog, std::slice::from_ref(&copy)));
+        let Statement::Loop(body) = &prog.stats[1].stat else {
+            panic!("the outer loop is gone");
+        };
+        assert_eq!(stats(body), ["MulAdd(2, 1)", "Set(0)", "MoveR(1)", "Loop"]);
+        assert!(!recognize(&mut prog, &[copy]));
+
+        for (idiom, e) in [
+            ("a: Add(x) =>", None),
+            ("a: => Write", Some("in idiom `a`: the pattern is empty")),
+            (
+                "a: Add(x) => Sub(y)",
+                Some("in idiom `a`: `y` is not bound"),
+            ),
+            (
+                "a: Add(_) => Sub(_)",
+                Some("in idiom `a`: `_` stands for no"),
+            ),
+            (
+                "a: Fill(1) => Write",
+                Some("in idiom `a`: unknown statement `Fill`"),
+            ),
+            (
+                "a: MulAdd(1) => Write",
+                Some("in idiom `a`: `MulAdd` takes 2"),
+            ),
+            ("a: Loop[Add(1) =>", Some("in idiom `a`: unclosed `[`")),
+            ("a Add(1)", Some("expected `NAME: PATTERN => REPLACEMENT`")),
+        ] {
+            match (idiom.parse::<Idiom>(), e) {
+                (Ok(_), None) => {}
+                (Err(found), Some(e)) => assert!(found.starts_with(e), "{}", found),
+                (found, _) => panic!("{}: {:?}", idiom, found),
+            }
+        }
+    }
+
+    #[test]
+    fn recognize_in_the_pipeline() {
+        let mut opts = CompileOptions {
+            opt_level: OptLevel::O2,
+            keep_all_io: true,
+            ..Default::default()
+        };
+        let prog = crate::optimize(",[-]+++.", &opts).unwrap();
+        assert_eq!(stats(&prog), ["Read", "Set(3)", "Write"]);
+
+        opts.idioms.push(
+            "echo-twice: Read Write => Read Write Write"
+                .parse()
+                .unwrap(),
+        );
+        let prog = crate::optimize(",.", &opts).unwrap();
+        assert_eq!(stats(&prog), ["Read", "Write", "Write"]);
+        assert!(opt::lookup("idioms").is_some());
+    }
+
+    #[test]
+    fn replacements_keep_spans_apart_and_comments() {
+        let twice: Idiom = "twice: Write Write => Loop[Write] Loop[Write]"
+            .parse()
+            .unwrap();
+        let mut prog = crate::parse_with_trivia("one . two\n. three").unwrap();
+        assert!(recognize(&mut prog, std::slice::from_ref(&twice)));
+        assert_eq!(stats(&prog), ["Loop", "Loop"]);
+        // outlined with `split_loops` into functions of their own
+        assert_ne!(prog.stats[0].attr.span, prog.stats[1].attr.span);
+        let trivia = &prog.stats[0].attr.trivia;
+        assert_eq!(
+            (&*trivia.leading, &*trivia.trailing),
+            ("one ", " two\n three")
+        );
+
+        // more loops than statements replaced
+        let once: Idiom = "once: Write => Loop[Write] Loop[Write]".parse().unwrap();
+        assert!(!recognize(&mut crate::parse(".").unwrap(), &[once]));
+    }
+}
@@ -0,0 +1,545 @@
+//! A parser for the subset of QBE IL [`crate::gen::QbeGenerator`] emits, so
+//! IL can be inspected, rewritten and run, see [`crate::validate`], without
+//! the `qbe` tool. Printing a parsed [`Module`] gives back IL `qbe` accepts,
+//! which parses to the same module again.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// Counted from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ty {
+    Word,
+    Long,
+    Half,
+    Byte,
+}
+
+impl Ty {
+    fn parse(s: &str) -> Option<Ty> {
+        match s {
+            "w" => Some(Ty::Word),
+            "l" => Some(Ty::Long),
+            "h" => Some(Ty::Half),
+            "b" => Some(Ty::Byte),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::Word => write!(f, "w"),
+            Ty::Long => write!(f, "l"),
+            Ty::Half => write!(f, "h"),
+            Ty::Byte => write!(f, "b"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Temp(String),
+    Global(String),
+    Const(u64),
+}
+
+impl Value {
+    fn parse(s: &str) -> Option<Value> {
+        if let Some(name) = s.strip_prefix('%') {
+            Some(Value::Temp(name.to_string()))
+        } else if let Some(name) = s.strip_prefix('$') {
+            Some(Value::Global(name.to_string()))
+        } else {
+            s.parse::<u64>()
+                .ok()
+                .or_else(|| s.parse::<i64>().ok().map(|n| n as u64))
+                .map(Value::Const)
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Temp(name) => write!(f, "%{}", name),
+            Value::Global(name) => write!(f, "${}", name),
+            Value::Const(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inst {
+    /// `%dest =ty op args`, arithmetic, comparisons, loads and allocations.
+    Assign {
+        dest: String,
+        ty: Ty,
+        op: String,
+        args: Vec<Value>,
+    },
+    /// An instruction without a result, like the stores.
+    Volatile {
+        op: String,
+        args: Vec<Value>,
+    },
+    Call {
+        dest: Option<(String, Ty)>,
+        func: String,
+        args: Vec<(Ty, Value)>,
+    },
+    Jmp(String),
+    Jnz(Value, String, String),
+    Ret(Option<Value>),
+    /// A source line for the debug info.
+    Dbgloc(usize),
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter, args: &[Value]| {
+            for (i, arg) in args.iter().enumerate() {
+                write!(f, "{}{}", if i == 0 { " " } else { ", " }, arg)?;
+            }
+            Ok(())
+        };
+        match self {
+            Inst::Assign { dest, ty, op, args } => {
+                write!(f, "%{} ={} {}", dest, ty, op)?;
+                list(f, args)
+            }
+            Inst::Volatile { op, args } => {
+                write!(f, "{}", op)?;
+                list(f, args)
+            }
+            Inst::Call { dest, func, args } => {
+                if let Some((dest, ty)) = dest {
+                    write!(f, "%{} ={} ", dest, ty)?;
+                }
+                write!(f, "call ${}(", func)?;
+                for (i, (ty, arg)) in args.iter().enumerate() {
+                    write!(f, "{}{} {}", if i == 0 { "" } else { ", " }, ty, arg)?;
+                }
+                write!(f, ")")
+            }
+            Inst::Jmp(label) => write!(f, "jmp @{}", label),
+            Inst::Jnz(cond, then, els) => write!(f, "jnz {}, @{}, @{}", cond, then, els),
+            Inst::Ret(None) => write!(f, "ret"),
+            Inst::Ret(Some(val)) => write!(f, "ret {}", val),
+            Inst::Dbgloc(line) => write!(f, "dbgloc {}", line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub label: String,
+    pub insts: Vec<Inst>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub exported: bool,
+    pub name: String,
+    pub ret: Option<Ty>,
+    pub params: Vec<(Ty, String)>,
+    /// In order, each falling through to the next unless it jumps.
+    pub blocks: Vec<Block>,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.exported {
+            write!(f, "export ")?;
+        }
+        write!(f, "function ")?;
+        if let Some(ty) = self.ret {
+            write!(f, "{} ", ty)?;
+        }
+        write!(f, "${}(", self.name)?;
+        for (i, (ty, name)) in self.params.iter().enumerate() {
+            write!(f, "{}{} %{}", if i == 0 { "" } else { ", " }, ty, name)?;
+        }
+        writeln!(f, ") {{")?;
+        for block in &self.blocks {
+            writeln!(f, "@{}", block.label)?;
+            for inst in &block.insts {
+                writeln!(f, "\t{}", inst)?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataItem {
+    /// A string as written, escapes and all.
+    Str(Ty, String),
+    Const(Ty, u64),
+    /// `z n`, that many zero bytes.
+    Zero(u64),
+}
+
+impl fmt::Display for DataItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataItem::Str(ty, s) => write!(f, "{} \"{}\"", ty, s),
+            DataItem::Const(ty, n) => write!(f, "{} {}", ty, n),
+            DataItem::Zero(n) => write!(f, "z {}", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Data {
+    pub exported: bool,
+    pub name: String,
+    pub align: Option<u64>,
+    pub items: Vec<DataItem>,
+}
+
+impl Data {
+    /// The initial contents, strings unescaped.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for item in &self.items {
+            match item {
+                DataItem::Str(_, s) => {
+                    let mut chars = s.chars();
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\\' => bytes.push(match chars.next() {
+                                Some('n') => b'\n',
+                                Some('r') => b'\r',
+                                Some('t') => b'\t',
+                                Some('0') => 0,
+                                Some(c) => c as u8,
+                                None => b'\\',
+                            }),
+                            c => bytes.extend(c.to_string().as_bytes()),
+                        }
+                    }
+                }
+                DataItem::Const(ty, n) => {
+                    let width = match ty {
+                        Ty::Byte => 1,
+                        Ty::Half => 2,
+                        Ty::Word => 4,
+                        Ty::Long => 8,
+                    };
+                    bytes.extend(&n.to_le_bytes()[..width]);
+                }
+                DataItem::Zero(n) => bytes.resize(bytes.len() + *n as usize, 0),
+            }
+        }
+        bytes
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.exported {
+            write!(f, "export ")?;
+        }
+        write!(f, "data ${} = ", self.name)?;
+        if let Some(align) = self.align {
+            write!(f, "align {} ", align)?;
+        }
+        write!(f, "{{ ")?;
+        for (i, item) in self.items.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { "" } else { ", " }, item)?;
+        }
+        write!(f, " }}")
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Module {
+    /// The source file named for the debug info, as written.
+    pub dbgfile: Option<String>,
+    pub functions: Vec<Function>,
+    pub data: Vec<Data>,
+}
+
+impl Module {
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.dbgfile {
+            writeln!(f, "dbgfile \"{}\"", file)?;
+        }
+        for func in &self.functions {
+            writeln!(f, "{}", func)?;
+        }
+        for data in &self.data {
+            writeln!(f, "{}", data)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse(il: &str) -> Result<Module, Error> {
+    let mut module = Module::default();
+    let mut lines = il
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        // `#` starts a comment
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+    while let Some((n, line)) = lines.next() {
+        let err = |message: &str| Error {
+            line: n,
+            message: message.to_string(),
+        };
+        let (exported, rest) = match line.strip_prefix("export ") {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        if let Some(rest) = rest.strip_prefix("function ") {
+            let mut func = parse_header(rest).ok_or_else(|| err("bad function header"))?;
+            func.exported = exported;
+            parse_body(&mut func, &mut lines, n)?;
+            module.functions.push(func);
+        } else if let Some(rest) = rest.strip_prefix("data ") {
+            let mut data = parse_data(rest).ok_or_else(|| err("bad data definition"))?;
+            data.exported = exported;
+            module.data.push(data);
+        } else if let Some(file) = rest.strip_prefix("dbgfile ") {
+            let file = file.strip_prefix('"').and_then(|f| f.strip_suffix('"'));
+            module.dbgfile = Some(file.ok_or_else(|| err("bad dbgfile"))?.to_string());
+        } else {
+            return Err(err(&format!("unexpected `{}`", line)));
+        }
+    }
+    Ok(module)
+}
+
+/// `[ty] $name(ty %param, ...) {`
+fn parse_header(s: &str) -> Option<Function> {
+    let (ret, rest) = match s.split_once(' ') {
+        Some((ty, rest)) if !ty.starts_with('$') => (Some(Ty::parse(ty)?), rest),
+        _ => (None, s),
+    };
+    let (name, rest) = rest.strip_prefix('$')?.split_once('(')?;
+    let (params, rest) = rest.split_once(')')?;
+    if rest.trim() != "{" {
+        return None;
+    }
+    let params = split_list(params)
+        .map(|param| {
+            let (ty, name) = param.split_once(" %")?;
+            Some((Ty::parse(ty)?, name.to_string()))
+        })
+        .collect::<Option<_>>()?;
+    Some(Function {
+        exported: false,
+        name: name.to_string(),
+        ret,
+        params,
+        blocks: Vec::new(),
+    })
+}
+
+fn parse_body<'a>(
+    func: &mut Function,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    start: usize,
+) -> Result<(), Error> {
+    for (n, line) in lines {
+        if line == "}" {
+            return Ok(());
+        }
+        let err = |message: String| Error { line: n, message };
+        if let Some(label) = line.strip_prefix('@') {
+            func.blocks.push(Block {
+                label: label.to_string(),
+                insts: Vec::new(),
+            });
+            continue;
+        }
+        let inst = parse_inst(line).ok_or_else(|| err(format!("cannot parse `{}`", line)))?;
+        func.blocks
+            .last_mut()
+            .ok_or_else(|| err("instruction outside a block".to_string()))?
+            .insts
+            .push(inst);
+    }
+    Err(Error {
+        line: start,
+        message: format!("${} is not closed", func.name),
+    })
+}
+
+fn parse_inst(line: &str) -> Option<Inst> {
+    let (dest, rest) = match line.strip_prefix('%') {
+        Some(rest) => {
+            let (name, rest) = rest.split_once(" =")?;
+            let (ty, rest) = rest.split_once(' ')?;
+            (Some((name.to_string(), Ty::parse(ty)?)), rest)
+        }
+        None => (None, line),
+    };
+    let (op, args) = rest.split_once(' ').unwrap_or((rest, ""));
+    let label = |s: &str| s.strip_prefix('@').map(str::to_string);
+    let inst = match (op, dest) {
+        ("call", dest) => {
+            let (func, args) = args.strip_prefix('$')?.split_once('(')?;
+            let args = split_list(args.strip_suffix(')')?)
+                .map(|arg| {
+                    let (ty, val) = arg.split_once(' ')?;
+                    Some((Ty::parse(ty)?, Value::parse(val)?))
+                })
+                .collect::<Option<_>>()?;
+            Inst::Call {
+                dest,
+                func: func.to_string(),
+                args,
+            }
+        }
+        (op, Some((dest, ty))) => Inst::Assign {
+            dest,
+            ty,
+            op: op.to_string(),
+            args: split_list(args).map(Value::parse).collect::<Option<_>>()?,
+        },
+        ("jmp", None) => Inst::Jmp(label(args)?),
+        ("jnz", None) => {
+            let mut parts = split_list(args);
+            let inst = Inst::Jnz(
+                Value::parse(parts.next()?)?,
+                label(parts.next()?)?,
+                label(parts.next()?)?,
+            );
+            if parts.next().is_some() {
+                return None;
+            }
+            inst
+        }
+        ("ret", None) if args.is_empty() => Inst::Ret(None),
+        ("ret", None) => Inst::Ret(Some(Value::parse(args)?)),
+        ("dbgloc", None) => Inst::Dbgloc(args.split(", ").next()?.parse().ok()?),
+        (op, None) => Inst::Volatile {
+            op: op.to_string(),
+            args: split_list(args).map(Value::parse).collect::<Option<_>>()?,
+        },
+    };
+    Some(inst)
+}
+
+/// `[export] data $name = [align n] { item, ... }`, `export` already gone.
+fn parse_data(s: &str) -> Option<Data> {
+    let (name, rest) = s.strip_prefix('$')?.split_once(" = ")?;
+    let (align, rest) = match rest.strip_prefix("align ") {
+        Some(rest) => {
+            let (align, rest) = rest.split_once(' ')?;
+            (Some(align.parse().ok()?), rest)
+        }
+        None => (None, rest),
+    };
+    let mut rest = rest.strip_prefix('{')?.strip_suffix('}')?.trim();
+
+    let mut items = Vec::new();
+    while !rest.is_empty() {
+        let (ty, after) = rest.split_once(' ')?;
+        let after = after.trim_start();
+        if let Some(s) = after.strip_prefix('"') {
+            // the closing quote is the first one not escaped
+            let mut end = None;
+            let mut escaped = false;
+            for (i, c) in s.char_indices() {
+                match c {
+                    '"' if !escaped => {
+                        end = Some(i);
+                        break;
+                    }
+                    '\\' => escaped = !escaped,
+                    _ => escaped = false,
+                }
+            }
+            let end = end?;
+            items.push(DataItem::Str(Ty::parse(ty)?, s[..end].to_string()));
+            rest = &s[end + 1..];
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            let n = match Value::parse(after[..end].trim())? {
+                Value::Const(n) => n,
+                _ => return None,
+            };
+            items.push(match ty {
+                "z" => DataItem::Zero(n),
+                ty => DataItem::Const(Ty::parse(ty)?, n),
+            });
+            rest = &after[end..];
+        }
+        rest = rest.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Some(Data {
+        exported: false,
+        name: name.to_string(),
+        align,
+        items,
+    })
+}
+
+fn split_list(s: &str) -> impl Iterator<Item = &str> {
+    s.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CompileOptions;
+
+    #[test]
+    fn round_trip_generated_il() {
+        let variants = [
+            CompileOptions::default(),
+            CompileOptions {
+                split_loops: true,
+                io_strict: true,
+                debug_file: Some("prog.bf".to_string()),
+                ..Default::default()
+            },
+            CompileOptions {
+                newline: crate::Newline::Crlf,
+                atexit: "dump-tape,stats".parse().unwrap(),
+                ..Default::default()
+            },
+        ];
+        for opts in &variants {
+            let il = crate::compile_with(",[.,]+[->+<]".to_string(), opts).unwrap();
+            let module = parse(&il).unwrap();
+            assert_eq!(parse(&module.to_string()).unwrap(), module);
+            assert!(module.function("main").unwrap().exported);
+        }
+    }
+
+    #[test]
+    fn report_lines() {
+        let err = parse("function $f() {\n@start\n\tbogus %x,\n").unwrap_err();
+        assert_eq!(err.line, 1);
+        let err = parse("function $f() {\n\tret\n}").unwrap_err();
+        assert_eq!(err.line, 2);
+        let data = parse("data $s = { b \"a\\\"b\\n\", b 0 }").unwrap();
+        assert_eq!(data.data[0].bytes(), b"a\"b\n\0");
+    }
+}
@@ -0,0 +1,271 @@
+//! Flat images for flashing bare-metal boards, made from a linked ELF file:
+//! the contents of its loadable segments at the addresses they are loaded
+//! at, as a raw binary or as Intel HEX. Segments are placed at their
+//! physical addresses, which differ from the virtual ones where data is
+//! copied out of flash at startup, as on AVR.
+
+use std::{fmt, fmt::Write, str::FromStr};
+
+/// Largest raw image written, gaps between segments are filled in.
+const MAX_RAW_LEN: u64 = 1 << 28;
+
+/// Data bytes per Intel HEX record.
+const IHEX_RECORD_LEN: usize = 16;
+
+const PT_LOAD: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// The linked ELF file as it is.
+    #[default]
+    Elf,
+    /// Segment contents from the load address on, gaps filled with `0xff`
+    /// as in erased flash.
+    Raw,
+    /// Intel HEX records for each segment.
+    Ihex,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "elf" => Ok(Format::Elf),
+            "raw" => Ok(Format::Raw),
+            "ihex" => Ok(Format::Ihex),
+            _ => Err(format!("expected `elf`, `raw` or `ihex`, found `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Elf => write!(f, "elf"),
+            Format::Raw => write!(f, "raw"),
+            Format::Ihex => write!(f, "ihex"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Not an ELF file, or one cut short.
+    NotElf,
+    /// A segment starts at the address, below the load address.
+    BelowLoadAddress(u64),
+    /// The image would span more than the raw format or Intel HEX allow.
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotElf => write!(f, "not an ELF file"),
+            Error::BelowLoadAddress(addr) => {
+                write!(f, "a segment at {:#x} lies below the load address", addr)
+            }
+            Error::TooLarge => write!(f, "the segments span too much memory for an image"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parses an address in decimal or, prefixed with `0x`, hexadecimal.
+pub fn parse_address(s: &str) -> Result<u64, String> {
+    let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    res.map_err(|e| format!("invalid address `{}`: {}", s, e))
+}
+
+/// Bytes loaded at `addr`.
+#[derive(Debug, PartialEq)]
+struct Segment<'a> {
+    addr: u64,
+    data: &'a [u8],
+}
+
+/// Turns the ELF file `elf` into an image in `format`. `load_address` is
+/// where the image starts, the lowest segment's address by default.
+pub fn convert(elf: &[u8], format: Format, load_address: Option<u64>) -> Result<Vec<u8>, Error> {
+    if format == Format::Elf {
+        return Ok(elf.to_vec());
+    }
+    let mut segments = segments(elf)?;
+    segments.sort_by_key(|seg| seg.addr);
+    let base = match (load_address, segments.first()) {
+        (Some(addr), _) => addr,
+        (None, Some(seg)) => seg.addr,
+        (None, None) => 0,
+    };
+    if let Some(seg) = segments.iter().find(|seg| seg.addr < base) {
+        return Err(Error::BelowLoadAddress(seg.addr));
+    }
+    match format {
+        Format::Raw => raw(&segments, base),
+        _ => ihex(&segments),
+    }
+}
+
+/// The loadable segments of `elf` with anything in the file, 32 or 64
+/// bits and either byte order.
+fn segments(elf: &[u8]) -> Result<Vec<Segment<'_>>, Error> {
+    if elf.get(..4) != Some(b"\x7fELF") {
+        return Err(Error::NotElf);
+    }
+    let wide = match elf.get(4) {
+        Some(1) => false,
+        Some(2) => true,
+        _ => return Err(Error::NotElf),
+    };
+    let big = match elf.get(5) {
+        Some(1) => false,
+        Some(2) => true,
+        _ => return Err(Error::NotElf),
+    };
+    let uint = |at: usize, len: usize| -> Result<u64, Error> {
+        let bytes = elf.get(at..at + len).ok_or(Error::NotElf)?;
+        let fold = |n: u64, b: &u8| n << 8 | *b as u64;
+        Ok(if big {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        })
+    };
+    // the word sized fields of the program headers
+    let word = |at: usize| uint(at, if wide { 8 } else { 4 });
+
+    let (phoff, phentsize, phnum) = if wide {
+        (uint(0x20, 8)?, uint(0x36, 2)?, uint(0x38, 2)?)
+    } else {
+        (uint(0x1c, 4)?, uint(0x2a, 2)?, uint(0x2c, 2)?)
+    };
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let at = usize::try_from(phoff + i * phentsize).map_err(|_| Error::NotElf)?;
+        if uint(at, 4)? != PT_LOAD as u64 {
+            continue;
+        }
+        let (offset, paddr, filesz) = if wide {
+            (word(at + 8)?, word(at + 24)?, word(at + 32)?)
+        } else {
+            (word(at + 4)?, word(at + 12)?, word(at + 16)?)
+        };
+        if filesz == 0 {
+            continue;
+        }
+        let data = usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(filesz).ok())
+            .and_then(|(offset, len)| elf.get(offset..offset.checked_add(len)?))
+            .ok_or(Error::NotElf)?;
+        segments.push(Segment { addr: paddr, data });
+    }
+    Ok(segments)
+}
+
+fn raw(segments: &[Segment], base: u64) -> Result<Vec<u8>, Error> {
+    let end = segments
+        .iter()
+        .map(|seg| seg.addr + seg.data.len() as u64)
+        .max()
+        .unwrap_or(base);
+    if end - base > MAX_RAW_LEN {
+        return Err(Error::TooLarge);
+    }
+    let mut image = vec![0xff; (end - base) as usize];
+    for seg in segments {
+        let at = (seg.addr - base) as usize;
+        image[at..at + seg.data.len()].copy_from_slice(seg.data);
+    }
+    Ok(image)
+}
+
+fn ihex(segments: &[Segment]) -> Result<Vec<u8>, Error> {
+    let mut out = String::new();
+    let mut upper = 0;
+    for seg in segments {
+        if seg.addr + seg.data.len() as u64 > 1 << 32 {
+            return Err(Error::TooLarge);
+        }
+        for (i, chunk) in seg.data.chunks(IHEX_RECORD_LEN).enumerate() {
+            let addr = seg.addr + (i * IHEX_RECORD_LEN) as u64;
+            // a record may not run past 64K, start a new one there
+            let split = ((0x1_0000 - (addr & 0xffff)) as usize).min(chunk.len());
+            for (addr, part) in [
+                (addr, &chunk[..split]),
+                (addr + split as u64, &chunk[split..]),
+            ] {
+                if part.is_empty() {
+                    continue;
+                }
+                if addr >> 16 != upper {
+                    upper = addr >> 16;
+                    record(&mut out, 0, 4, &(upper as u16).to_be_bytes());
+                }
+                record(&mut out, addr as u16, 0, part);
+            }
+        }
+    }
+    record(&mut out, 0, 1, &[]);
+    Ok(out.into_bytes())
+}
+
+/// Appends an Intel HEX record of type `kind`.
+fn record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+
+    out.push(':');
+    for b in bytes {
+        write!(out, "{:02X}", b).unwrap();
+    }
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn images_of_segments() {
+        let segments = [
+            Segment {
+                addr: 0x1_0000,
+                data: &[1, 2, 3],
+            },
+            Segment {
+                addr: 0x1_0005,
+                data: &[4],
+            },
+        ];
+        let hex = ihex(&segments).unwrap();
+        assert_eq!(
+            String::from_utf8(hex).unwrap(),
+            ":020000040001F9\n:03000000010203F7\n:0100050004F6\n:00000001FF\n"
+        );
+        assert_eq!(
+            raw(&segments, 0xffff).unwrap(),
+            [0xff, 1, 2, 3, 0xff, 0xff, 4]
+        );
+
+        let exe = crate::compile_executable("+.".to_string(), &Default::default()).unwrap();
+        let image = convert(&exe, Format::Raw, None).unwrap();
+        // the first segment maps the headers along with the code
+        assert_eq!(image[..4], *b"\x7fELF");
+        assert_eq!(
+            convert(&exe, Format::Ihex, Some(u64::MAX)),
+            Err(Error::BelowLoadAddress(0))
+        );
+        assert_eq!(convert(b"#!/bin/sh", Format::Raw, None), Err(Error::NotElf));
+        assert_eq!(parse_address("0x8000"), Ok(0x8000));
+    }
+}
@@ -0,0 +1,423 @@
+//! A tree walking interpreter, for running many untrusted programs without
+//! going through `qbe` and `cc`. Cells are 32 bits wide and `,` at end of
+//! input leaves the cell alone, matching the compiled code.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::ast::{NodeBlock, NodeStatement, Statement};
+
+/// Bounds on a single run, enforced by the interpreter itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Statements executed, each loop test counting as one.
+    pub steps: u64,
+    /// Tape cells the program may touch.
+    pub cells: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            steps: 10_000_000,
+            cells: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Finished,
+    StepLimit,
+    CellLimit,
+    /// The pointer moved left of the first cell.
+    OutOfBounds,
+    /// The program did not parse, nothing ran.
+    Invalid(String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Finished => write!(f, "finished"),
+            Status::StepLimit => write!(f, "hit the step limit"),
+            Status::CellLimit => write!(f, "ran out of cells"),
+            Status::OutOfBounds => write!(f, "moved left of the tape"),
+            Status::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    pub status: Status,
+    /// Everything written before the run ended, however it ended.
+    pub output: Vec<u8>,
+    pub steps: u64,
+    /// Step at which `,` first found the input exhausted.
+    pub eof_at: Option<u64>,
+    pub usage: Usage,
+}
+
+/// How much of the tape and of the cells' range a run used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Cells up to the furthest one the pointer reached.
+    pub cells: usize,
+    /// Smallest and largest values cells held, reading them as signed so
+    /// that taking a cell below zero shows up as a negative `low`.
+    pub low: i32,
+    pub high: i32,
+}
+
+/// Bytes of output shown by `Outcome`'s `Display`.
+const PREVIEW_LEN: usize = 64;
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.status {
+            Status::Finished => write!(f, "finished after {} steps", self.steps)?,
+            Status::StepLimit => write!(f, "still running after {} steps", self.steps - 1)?,
+            Status::CellLimit => write!(f, "ran out of cells after {} steps", self.steps)?,
+            Status::OutOfBounds => write!(f, "moved left of the tape after {} steps", self.steps)?,
+            Status::Invalid(e) => return write!(f, "{}", e),
+        }
+        if let Some(step) = self.eof_at {
+            write!(f, ", reading end of input at step {}", step)?;
+        }
+        write!(f, ", wrote {} bytes", self.output.len())?;
+        match self.output.get(..PREVIEW_LEN) {
+            Some(head) if self.output.len() > PREVIEW_LEN => {
+                write!(f, " starting \"{}\"", head.escape_ascii())
+            }
+            _ => write!(f, ": \"{}\"", self.output.escape_ascii()),
+        }
+    }
+}
+
+struct Machine<'a> {
+    tape: Vec<u32>,
+    ptr: usize,
+    input: &'a [u8],
+    output: Vec<u8>,
+    steps: u64,
+    eof_at: Option<u64>,
+    low: i32,
+    high: i32,
+    limits: Limits,
+}
+
+impl Machine<'_> {
+    fn block(&mut self, block: &NodeBlock) -> Result<(), Status> {
+        block
+            .stats
+            .iter()
+            .try_for_each(|stat| self.statement(&stat.stat))
+    }
+
+    fn statement(&mut self, stat: &Statement) -> Result<(), Status> {
+        self.step()?;
+        match stat {
+            Statement::MoveL(n) => {
+                self.ptr = self.ptr.checked_sub(*n).ok_or(Status::OutOfBounds)?
+            }
+            Statement::MoveR(n) => {
+                self.ptr = self
+                    .ptr
+                    .checked_add(*n)
+                    .filter(|ptr| *ptr < self.limits.cells)
+                    .ok_or(Status::CellLimit)?;
+                if self.ptr >= self.tape.len() {
+                    self.tape.resize(self.ptr + 1, 0);
+                }
+            }
+            Statement::Add(n) => {
+                self.tape[self.ptr] = self.tape[self.ptr].wrapping_add(*n as u32);
+                self.high = self.high.max(self.tape[self.ptr] as i32);
+            }
+            Statement::Sub(n) => {
+                self.tape[self.ptr] = self.tape[self.ptr].wrapping_sub(*n as u32);
+                self.low = self.low.min(self.tape[self.ptr] as i32);
+            }
+            Statement::Read => match self.input.split_first() {
+                Some((byte, rest)) => {
+                    self.tape[self.ptr] = *byte as u32;
+                    self.high = self.high.max(*byte as i32);
+                    self.input = rest;
+                }
+                None => {
+                    self.eof_at.get_or_insert(self.steps);
+                }
+            },
+            Statement::Write => self.output.push(self.tape[self.ptr] as u8),
+            Statement::Print(bytes) => {
+                self.output.extend_from_slice(bytes);
+                if let Some(last) = bytes.last() {
+                    self.tape[self.ptr] = *last as u32;
+                    self.high = self.high.max(*last as i32);
+                }
+            }
+            Statement::Fill(values) => {
+                let end = self.ptr + values.len();
+                if end > self.limits.cells {
+                    return Err(Status::CellLimit);
+                }
+                if end > self.tape.len() {
+                    self.tape.resize(end, 0);
+                }
+                self.tape[self.ptr..end].copy_from_slice(values);
+                for value in values {
+                    self.high = self.high.max(*value as i32);
+                    self.low = self.low.min(*value as i32);
+                }
+            }
+            Statement::Set(n) => {
+                self.tape[self.ptr] = *n;
+                self.high = self.high.max(*n as i32);
+                self.low = self.low.min(*n as i32);
+            }
+            Statement::MulAdd { offset, factor } => {
+                let at = self
+                    .ptr
+                    .checked_add_signed(*offset)
+                    .ok_or(Status::OutOfBounds)?;
+                if at >= self.limits.cells {
+                    return Err(Status::CellLimit);
+                }
+                if at >= self.tape.len() {
+                    self.tape.resize(at + 1, 0);
+                }
+                let product = self.tape[self.ptr].wrapping_mul(*factor);
+                self.tape[at] = self.tape[at].wrapping_add(product);
+                self.high = self.high.max(self.tape[at] as i32);
+                self.low = self.low.min(self.tape[at] as i32);
+            }
+            Statement::Scan(stride) => {
+                let by = stride.unsigned_abs();
+                let to = if *stride < 0 {
+                    Statement::MoveL(by)
+                } else {
+                    Statement::MoveR(by)
+                };
+                while self.tape[self.ptr] != 0 {
+                    self.statement(&to)?;
+                }
+            }
+            Statement::Loop(body) => {
+                while self.tape[self.ptr] != 0 {
+                    self.block(body)?;
+                    self.step()?;
+                }
+            }
+            Statement::If(body) => {
+                if self.tape[self.ptr] != 0 {
+                    self.block(body)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<(), Status> {
+        self.steps += 1;
+        if self.steps > self.limits.steps {
+            Err(Status::StepLimit)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Runs `prog` on `input` until it finishes or breaks `limits`.
+pub fn run(prog: &NodeBlock, input: &[u8], limits: Limits) -> Outcome {
+    let mut machine = Machine {
+        tape: vec![0; limits.cells.min(1)],
+        ptr: 0,
+        input,
+        output: Vec::new(),
+        steps: 0,
+        eof_at: None,
+        low: 0,
+        high: 0,
+        limits,
+    };
+    let status = if limits.cells == 0 {
+        Status::CellLimit
+    } else {
+        match machine.block(prog) {
+            Ok(()) => Status::Finished,
+            Err(status) => status,
+        }
+    };
+    Outcome {
+        status,
+        output: machine.output,
+        steps: machine.steps,
+        eof_at: machine.eof_at,
+        usage: Usage {
+            cells: machine.tape.len(),
+            low: machine.low,
+            high: machine.high,
+        },
+    }
+}
+
+/// How far a program gets before it needs input, see [`run_prefix`].
+#[derive(Debug, PartialEq)]
+pub struct Prefix {
+    /// Statements run.
+    pub len: usize,
+    /// The tape they leave, up to the furthest cell they reached.
+    pub tape: Vec<u32>,
+    pub ptr: usize,
+    pub output: Vec<u8>,
+}
+
+/// Runs `stats`, of a program's outermost block, up to the first one that
+/// reads, or that would end the program or break `limits`, such as by
+/// moving off the tape. Only whole statements count, loops included.
+pub fn run_prefix(stats: &[NodeStatement], limits: Limits) -> Prefix {
+    fn reads(stat: &Statement) -> bool {
+        match stat {
+            Statement::Read => true,
+            Statement::Loop(body) | Statement::If(body) => {
+                body.stats.iter().any(|stat| reads(&stat.stat))
+            }
+            _ => false,
+        }
+    }
+
+    let machine = || Machine {
+        tape: vec![0; limits.cells.min(1)],
+        ptr: 0,
+        input: &[],
+        output: Vec::new(),
+        steps: 0,
+        eof_at: None,
+        low: 0,
+        high: 0,
+        limits,
+    };
+    let mut ran = machine();
+    let mut len = 0;
+    if limits.cells > 0 {
+        for stat in stats {
+            if reads(&stat.stat) {
+                break;
+            }
+            if ran.statement(&stat.stat).is_err() {
+                // the statement left the machine half way, run the ones
+                // before it again
+                ran = machine();
+                for stat in &stats[..len] {
+                    let _ = ran.statement(&stat.stat);
+                }
+                break;
+            }
+            len += 1;
+        }
+    }
+    Prefix {
+        len,
+        tape: ran.tape,
+        ptr: ran.ptr,
+        output: ran.output,
+    }
+}
+
+/// Evaluates `prog` as if stdin were empty, as it is in a lot of real runs,
+/// to tell what it prints and whether it terminates without any input.
+pub fn assume_empty_input(prog: &NodeBlock, limits: Limits) -> Outcome {
+    run(prog, &[], limits)
+}
+
+/// Parses and runs every `(source, input)` task on up to `parallelism`
+/// threads, each under its own `limits`. Outcomes come back in task order.
+pub fn run_batch(tasks: &[(String, Vec<u8>)], parallelism: usize, limits: Limits) -> Vec<Outcome> {
+    let next = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<Option<Outcome>>> = Mutex::new(tasks.iter().map(|_| None).collect());
+
+    thread::scope(|s| {
+        for _ in 0..parallelism.clamp(1, tasks.len().max(1)) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((src, input)) = tasks.get(i) else {
+                    break;
+                };
+                let outcome = match crate::parse(src) {
+                    Ok(prog) => run(&prog, input, limits),
+                    Err(e) => Outcome {
+                        status: Status::Invalid(e.to_string()),
+                        output: Vec::new(),
+                        steps: 0,
+                        eof_at: None,
+                        usage: Usage::default(),
+                    },
+                };
+                outcomes.lock().unwrap()[i] = Some(outcome);
+            });
+        }
+    });
+
+    outcomes
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|outcome| outcome.expect("every task is run"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn run_echo_and_limits() {
+        let prog = crate::parse(",[.[-],]").unwrap();
+        let out = run(&prog, b"hi", Limits::default());
+        assert_eq!(out.status, Status::Finished);
+        assert_eq!(out.output, b"hi");
+
+        let spin = crate::parse("+[]").unwrap();
+        assert_eq!(run(&spin, b"", Limits::default()).status, Status::StepLimit);
+        let walk = crate::parse("+[>+]").unwrap();
+        let limits = Limits {
+            cells: 16,
+            ..Default::default()
+        };
+        assert_eq!(run(&walk, b"", limits).status, Status::CellLimit);
+        let left = crate::parse("<").unwrap();
+        assert_eq!(run(&left, b"", limits).status, Status::OutOfBounds);
+    }
+
+    #[test]
+    fn evaluate_with_empty_input() {
+        let prog = crate::parse("+++.,[-]>,.").unwrap();
+        let out = assume_empty_input(&prog, Limits::default());
+        assert_eq!(out.status, Status::Finished);
+        assert_eq!(out.eof_at, Some(3));
+        assert_eq!(
+            out.to_string(),
+            "finished after 13 steps, reading end of input at step 3, wrote 2 bytes: \"\\x03\\x00\""
+        );
+    }
+
+    #[test]
+    fn batch_keeps_task_order() {
+        let tasks: Vec<_> = (0..20u8)
+            .map(|n| ("+".repeat(n as usize) + ".", Vec::new()))
+            .chain([("[".to_string(), Vec::new())])
+            .collect();
+        let outcomes = run_batch(&tasks, 4, Limits::default());
+        for (n, outcome) in outcomes.iter().take(20).enumerate() {
+            assert_eq!(outcome.output, [n as u8]);
+        }
+        assert!(matches!(outcomes[20].status, Status::Invalid(_)));
+    }
+}
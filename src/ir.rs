@@ -0,0 +1,475 @@
+//! Canonical forms of programs, for telling programs that do the same thing
+//! apart from those that merely look alike: corpora collect many spellings
+//! of one program, differing in the order of independent changes, in
+//! `+-` pairs or in code that never runs.
+//!
+//! The straight-line arithmetic between I/O and loops becomes a [`Block`]
+//! of the changes to each cell, sorted by offset, and where the pointer
+//! ends up. Loops that cannot run, on a cell known to be zero, are dropped
+//! along with arithmetic at the end of the program nobody sees. Programs
+//! with the same canonical form behave the same; the converse does not
+//! hold, this is no decision procedure.
+//!
+//! [`lower`] turns a program into the other form here, a flat list of
+//! [`Inst`]s: changes to cells are addressed relative to the pointer,
+//! which only moves at loop boundaries and I/O, and clear loops become
+//! stores. It is what `--emit ir` shows, for seeing what the pointer
+//! arithmetic of a program comes to; the backends generate from the tree,
+//! not from this.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    ast::{self, NodeStatement, Prog, Statement},
+    opt,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CanonicalForm {
+    pub ops: Vec<Op>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Op {
+    Block(Block),
+    Read,
+    Write,
+    /// Adds the current cell times `factor` to the one `offset` away.
+    MulAdd {
+        offset: i64,
+        factor: u32,
+    },
+    Loop(Vec<Op>),
+}
+
+/// Straight-line arithmetic, relative to where the pointer starts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Block {
+    /// What is added to each cell that changes, wrapping around, in order
+    /// of offset.
+    pub deltas: Vec<(i64, u32)>,
+    /// Where the pointer ends up.
+    pub shift: i64,
+    /// The furthest the pointer gets on either side, which decides whether
+    /// it runs off the tape.
+    pub low: i64,
+    pub high: i64,
+}
+
+impl Block {
+    fn is_empty(&self) -> bool {
+        self.deltas.is_empty() && self.shift == 0 && self.low == 0 && self.high == 0
+    }
+
+    /// This block followed by `next`.
+    fn then(&self, next: &Block) -> Block {
+        let mut deltas: BTreeMap<i64, u32> = self.deltas.iter().copied().collect();
+        for (offset, delta) in &next.deltas {
+            let cell = deltas.entry(self.shift + offset).or_default();
+            *cell = cell.wrapping_add(*delta);
+        }
+        Block {
+            deltas: deltas.into_iter().filter(|(_, d)| *d != 0).collect(),
+            shift: self.shift + next.shift,
+            low: self.low.min(self.shift + next.low),
+            high: self.high.max(self.shift + next.high),
+        }
+    }
+}
+
+impl CanonicalForm {
+    /// A hash of the form, the same across runs and platforms, for
+    /// indexing programs by what they do.
+    pub fn hash(&self) -> u128 {
+        u128::from_be_bytes(md5::compute(self.to_string()).0)
+    }
+}
+
+/// The canonical form of `prog`.
+pub fn canonicalize(prog: &Prog) -> CanonicalForm {
+    // every cell is zero when the program starts
+    let mut ops = ops(&prog.stats, true);
+    if let Some(Op::Block(last)) = ops.last_mut() {
+        // only whether the pointer stays on the tape still matters
+        last.deltas.clear();
+        last.shift = 0;
+        if last.is_empty() {
+            ops.pop();
+        }
+    }
+    CanonicalForm { ops }
+}
+
+/// The ops of `stats`, entered with the current cell known to be zero
+/// when `zero` is set.
+fn ops(stats: &[NodeStatement], zero: bool) -> Vec<Op> {
+    let mut out = Vec::new();
+    push_ops(&mut out, stats, zero);
+    out
+}
+
+/// Appends the ops of `stats` to `out`, returning whether the current cell
+/// is known to be zero after them.
+fn push_ops(out: &mut Vec<Op>, stats: &[NodeStatement], mut zero: bool) -> bool {
+    for stat in stats {
+        let op = match &stat.stat {
+            Statement::Add(n) => single(0, *n as u32),
+            Statement::Sub(n) => single(0, (*n as u32).wrapping_neg()),
+            Statement::MoveL(n) => moved(-(*n as i64)),
+            Statement::MoveR(n) => moved(*n as i64),
+            Statement::Read => Op::Read,
+            Statement::Write => Op::Write,
+            Statement::MulAdd { offset, factor } => Op::MulAdd {
+                offset: *offset as i64,
+                factor: *factor,
+            },
+            Statement::Fill(values) => {
+                zero = push_ops(out, &ast::spell_out_fill(values).stats, zero);
+                continue;
+            }
+            Statement::Print(bytes) => {
+                zero = push_ops(out, &ast::spell_out_print(bytes).stats, zero);
+                continue;
+            }
+            // the same as the `[-]` it stands for, followed by adding `n`
+            Statement::Set(n) => {
+                if !zero {
+                    out.push(Op::Loop(vec![single(0, u32::MAX)]));
+                }
+                zero = true;
+                if *n == 0 {
+                    continue;
+                }
+                single(0, *n)
+            }
+            // the same as the `[>]` or `[<]` it stands for
+            Statement::Scan(_) if zero => continue,
+            Statement::Scan(stride) => Op::Loop(vec![moved(*stride as i64)]),
+            // never entered
+            Statement::Loop(_) | Statement::If(_) if zero => continue,
+            Statement::Loop(body) | Statement::If(body) => Op::Loop(ops(&body.stats, false)),
+        };
+        zero = match &op {
+            Op::Block(b) => zero && b.shift == 0 && b.deltas.is_empty(),
+            Op::Read => false,
+            Op::Write | Op::MulAdd { .. } => zero,
+            Op::Loop(_) => true,
+        };
+        match (out.last_mut(), op) {
+            (Some(Op::Block(prev)), Op::Block(next)) => {
+                *prev = prev.then(&next);
+                if prev.is_empty() {
+                    out.pop();
+                }
+            }
+            (_, op) => out.push(op),
+        }
+    }
+    zero
+}
+
+fn single(offset: i64, delta: u32) -> Op {
+    Op::Block(Block {
+        deltas: vec![(offset, delta)],
+        ..Default::default()
+    })
+}
+
+fn moved(shift: i64) -> Op {
+    Op::Block(Block {
+        shift,
+        low: shift.min(0),
+        high: shift.max(0),
+        ..Default::default()
+    })
+}
+
+impl fmt::Display for CanonicalForm {
+    /// One op per line, loop bodies indented.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn ops(f: &mut fmt::Formatter, list: &[Op], depth: usize) -> fmt::Result {
+            for op in list {
+                write!(f, "{:indent$}", "", indent = depth * 2)?;
+                match op {
+                    Op::Block(b) => {
+                        write!(f, "block")?;
+                        for (offset, delta) in &b.deltas {
+                            write!(f, " [{}]{:+}", offset, *delta as i32)?;
+                        }
+                        writeln!(f, " >{} ({}..={})", b.shift, b.low, b.high)?;
+                    }
+                    Op::Read => writeln!(f, "read")?,
+                    Op::Write => writeln!(f, "write")?,
+                    Op::MulAdd { offset, factor } => {
+                        writeln!(f, "muladd [{}]*{}", offset, *factor as i32)?
+                    }
+                    Op::Loop(body) => {
+                        writeln!(f, "loop")?;
+                        ops(f, body, depth + 1)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        ops(f, &self.ops, 0)
+    }
+}
+
+/// An instruction of the lowered form, see [`lower`]. Offsets are in cells
+/// from the pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inst {
+    /// Adds `amount` to a cell, wrapping around.
+    Add {
+        offset: i64,
+        amount: u32,
+    },
+    Set {
+        offset: i64,
+        value: u32,
+    },
+    /// Ends the program with status 1 unless every cell from `low` to
+    /// `high` is on the tape. Comes before the instructions reaching that
+    /// far, so that nothing is touched off the tape.
+    Check {
+        low: i64,
+        high: i64,
+    },
+    /// Moves the pointer, within what the last check covered.
+    MoveGroup {
+        shift: i64,
+    },
+    Read {
+        offset: i64,
+    },
+    Write {
+        offset: i64,
+    },
+    /// Adds the cell at `from` times `factor` to the one at `offset`.
+    MulAdd {
+        from: i64,
+        offset: i64,
+        factor: u32,
+    },
+    /// Moves the pointer `stride` cells at a time until it is on a zero
+    /// cell, checking every move.
+    Scan {
+        stride: i64,
+    },
+    /// Runs the `len` instructions after it while the current cell is not
+    /// zero.
+    Loop {
+        len: usize,
+    },
+}
+
+/// What a straight-line run still has to emit.
+#[derive(Default)]
+struct Run {
+    changes: BTreeMap<i64, Inst>,
+    shift: i64,
+    low: i64,
+    high: i64,
+    /// The range already checked.
+    checked: (i64, i64),
+}
+
+impl Run {
+    fn change(&mut self, amount: u32) {
+        let offset = self.shift;
+        let inst = match self.changes.get(&offset) {
+            Some(Inst::Set { value, .. }) => Inst::Set {
+                offset,
+                value: value.wrapping_add(amount),
+            },
+            Some(Inst::Add { amount: prev, .. }) => Inst::Add {
+                offset,
+                amount: prev.wrapping_add(amount),
+            },
+            _ => Inst::Add { offset, amount },
+        };
+        self.changes.insert(offset, inst);
+    }
+
+    fn moved(&mut self, by: i64) {
+        self.shift += by;
+        self.low = self.low.min(self.shift);
+        self.high = self.high.max(self.shift);
+    }
+
+    /// Emits the changes so far, checked first, for I/O at the pointer to
+    /// come next.
+    fn flush(&mut self, out: &mut Vec<Inst>) {
+        if (self.low, self.high) != self.checked {
+            out.push(Inst::Check {
+                low: self.low,
+                high: self.high,
+            });
+            self.checked = (self.low, self.high);
+        }
+        out.extend(
+            std::mem::take(&mut self.changes)
+                .into_values()
+                .filter(|inst| !matches!(inst, Inst::Add { amount: 0, .. })),
+        );
+    }
+
+    /// Emits the rest of the run, leaving the pointer where it ends up.
+    fn finish(mut self, out: &mut Vec<Inst>) {
+        self.flush(out);
+        if self.shift != 0 {
+            out.push(Inst::MoveGroup { shift: self.shift });
+        }
+    }
+}
+
+/// The lowered form of `prog`, behaving like it.
+pub fn lower(prog: &Prog) -> Vec<Inst> {
+    fn push(out: &mut Vec<Inst>, run: &mut Run, stats: &[NodeStatement]) {
+        for stat in stats {
+            match &stat.stat {
+                Statement::Add(n) => run.change(*n as u32),
+                Statement::Sub(n) => run.change((*n as u32).wrapping_neg()),
+                Statement::MoveL(n) => run.moved(-(*n as i64)),
+                Statement::MoveR(n) => run.moved(*n as i64),
+                Statement::Read | Statement::Write => {
+                    run.flush(out);
+                    let offset = run.shift;
+                    out.push(match stat.stat {
+                        Statement::Read => Inst::Read { offset },
+                        _ => Inst::Write { offset },
+                    });
+                }
+                Statement::Set(value) => {
+                    let offset = run.shift;
+                    let value = *value;
+                    run.changes.insert(offset, Inst::Set { offset, value });
+                }
+                Statement::Fill(values) => push(out, run, &ast::spell_out_fill(values).stats),
+                Statement::Print(bytes) => push(out, run, &ast::spell_out_print(bytes).stats),
+                &Statement::MulAdd { offset, factor } => {
+                    // checked as if moving there and back
+                    run.moved(offset as i64);
+                    run.moved(-(offset as i64));
+                    run.flush(out);
+                    out.push(Inst::MulAdd {
+                        from: run.shift,
+                        offset: run.shift + offset as i64,
+                        factor,
+                    });
+                }
+                Statement::Scan(stride) => {
+                    std::mem::take(run).finish(out);
+                    out.push(Inst::Scan {
+                        stride: *stride as i64,
+                    });
+                }
+                Statement::Loop(body) | Statement::If(body) if opt::clears(body) => {
+                    let offset = run.shift;
+                    run.changes.insert(offset, Inst::Set { offset, value: 0 });
+                }
+                Statement::Loop(body) | Statement::If(body) => {
+                    std::mem::take(run).finish(out);
+                    let at = out.len();
+                    out.push(Inst::Loop { len: 0 });
+                    block(out, &body.stats);
+                    out[at] = Inst::Loop {
+                        len: out.len() - at - 1,
+                    };
+                }
+            }
+        }
+    }
+
+    fn block(out: &mut Vec<Inst>, body: &[NodeStatement]) {
+        let mut run = Run::default();
+        push(out, &mut run, body);
+        run.finish(out);
+    }
+
+    let mut out = Vec::new();
+    block(&mut out, &prog.stats);
+    out
+}
+
+/// `insts` one per line, loop bodies indented.
+pub fn display(insts: &[Inst]) -> String {
+    fn list(out: &mut String, insts: &[Inst], depth: usize) {
+        let mut i = 0;
+        while i < insts.len() {
+            out.push_str(&" ".repeat(depth * 2));
+            match insts[i] {
+                Inst::Add { offset, amount } => {
+                    out.push_str(&format!("add [{}] {:+}\n", offset, amount as i32))
+                }
+                Inst::Set { offset, value } => {
+                    out.push_str(&format!("set [{}] {}\n", offset, value))
+                }
+                Inst::Check { low, high } => out.push_str(&format!("check {}..={}\n", low, high)),
+                Inst::MoveGroup { shift } => out.push_str(&format!("move {}\n", shift)),
+                Inst::Read { offset } => out.push_str(&format!("read [{}]\n", offset)),
+                Inst::Write { offset } => out.push_str(&format!("write [{}]\n", offset)),
+                Inst::MulAdd {
+                    from,
+                    offset,
+                    factor,
+                } => out.push_str(&format!(
+                    "muladd [{}] +[{}]*{}\n",
+                    offset, from, factor as i32
+                )),
+                Inst::Scan { stride } => out.push_str(&format!("scan {}\n", stride)),
+                Inst::Loop { len } => {
+                    out.push_str("loop\n");
+                    list(out, &insts[i + 1..=i + len], depth + 1);
+                    i += len;
+                }
+            }
+            i += 1;
+        }
+    }
+
+    let mut out = String::new();
+    list(&mut out, insts, 0);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn canon(src: &str) -> CanonicalForm {
+        canonicalize(&crate::parse(src).unwrap())
+    }
+
+    #[test]
+    fn same_program_same_form() {
+        let form = canon(",>++<+[->+<]>.");
+        assert_eq!(
+            form.to_string(),
+            "read\nblock [0]+1 [1]+2 >0 (0..=1)\nloop\n  block [0]-1 [1]+1 >0 (0..=1)\nblock >1 (0..=1)\nwrite\n"
+        );
+        for same in [
+            ",+>++<[->+<]>.",
+            ",>+-++<+[>+<-]>.+++",
+            "[.],>++<+[->+<][+]>.",
+        ] {
+            assert_eq!(canon(same), form, "{}", same);
+            assert_eq!(canon(same).hash(), form.hash());
+        }
+        assert_eq!(canon("+-<>"), canon("<>"));
+        assert_ne!(canon("<>"), canon(""));
+        assert_eq!(canon("+++>"), canon(">"));
+        assert_ne!(canon(",[-]"), canon(","));
+    }
+
+    #[test]
+    fn lower_offsets() {
+        let prog = crate::parse("+>++>[---]+<<.>>[->+<]<-,").unwrap();
+        assert_eq!(
+            display(&lower(&prog)),
+            "check 0..=2\nadd [0] +1\nadd [1] +2\nset [2] 1\nwrite [0]\nmove 2\n\
+             loop\n  check 0..=1\n  add [0] -1\n  add [1] +1\n\
+             check -1..=0\nadd [-1] -1\nread [-1]\nmove -1\n"
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Running programs in process: the syntax tree is lowered to Cranelift IR,
+//! compiled to machine code for the host in memory and called, no external
+//! tool involved. Only built with the `jit` feature.
+//!
+//! The code works like the compiled executables, 32 bit cells and moving off
+//! the tape ending the run with status 1, and goes through the runtime
+//! functions below for I/O, which translate line endings and report errors
+//! with `io_strict` as the executables do. [`crate::aot`] lowers programs
+//! the same way into objects, with those functions written in IR.
+
+use std::io::{self, Read, Write};
+
+use cranelift_codegen::{
+    ir::{condcodes::IntCC, types, AbiParam, FuncRef, InstBuilder, MemFlags, Value},
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module, ModuleError};
+
+use crate::{ast, CompileOptions, Error, Newline};
+
+/// Bytes in a cell.
+pub(crate) const CELL_SIZE: i64 = 4;
+
+/// What `bf_getc` returns at end of input.
+pub(crate) const EOF: i64 = -1;
+/// What the runtime functions return after an I/O error with `io_strict`.
+pub(crate) const IO_ERROR: i64 = -2;
+
+/// The streams a program runs against, handed to the runtime functions.
+struct Io<'a> {
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    crlf: bool,
+    io_strict: bool,
+}
+
+impl Io<'_> {
+    /// Reports an I/O error, returning `IO_ERROR`.
+    fn fail(&self, msg: &str) -> i32 {
+        eprintln!("bfc: {} error", msg);
+        IO_ERROR as i32
+    }
+}
+
+/// Returns the next byte of input, `EOF` or `IO_ERROR`.
+extern "C" fn bf_getc(io: &mut Io) -> i32 {
+    if io.output.flush().is_err() && io.io_strict {
+        return io.fail("write");
+    }
+    let mut byte = [0];
+    loop {
+        match io.input.read(&mut byte) {
+            Ok(0) => return EOF as i32,
+            Ok(_) if io.crlf && byte[0] == b'\r' => continue,
+            Ok(_) => return byte[0] as i32,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) if io.io_strict => return io.fail("read"),
+            Err(_) => return EOF as i32,
+        }
+    }
+}
+
+/// Writes the low byte of `c`, returning 0 or `IO_ERROR`.
+extern "C" fn bf_putc(io: &mut Io, c: u32) -> i32 {
+    let c = c as u8;
+    let res = if io.crlf && c == b'\n' {
+        io.output.write_all(b"\r\n")
+    } else {
+        io.output.write_all(&[c])
+    };
+    match res {
+        Err(_) if io.io_strict => io.fail("write"),
+        _ => 0,
+    }
+}
+
+/// Compiles `prog` and runs it on `input`, returning its exit status.
+pub fn run(
+    prog: &ast::Prog,
+    opts: &CompileOptions,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<i32, Error> {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").map_err(jit_error)?;
+    let isa = cranelift_native::builder()
+        .map_err(|e| Error::Jit(e.to_string()))?
+        .finish(settings::Flags::new(flags))
+        .map_err(jit_error)?;
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    builder.symbol("bf_getc", bf_getc as *const u8);
+    builder.symbol("bf_putc", bf_putc as *const u8);
+    let mut module = JITModule::new(builder);
+
+    let main = Lowering::declare(&mut module, prog, opts).map_err(jit_error)?;
+    module.finalize_definitions().map_err(jit_error)?;
+    let code = module.get_finalized_function(main);
+    // SAFETY: `Lowering::declare` gave the function this signature
+    let main: extern "C" fn(*mut u32, &mut Io) -> i32 = unsafe { std::mem::transmute(code) };
+
+    let mut tape = vec![0u32; opts.tape_len];
+    let mut io = Io {
+        input,
+        output,
+        crlf: opts.newline.resolve() == Newline::Crlf,
+        io_strict: opts.io_strict,
+    };
+    let mut status = main(tape.as_mut_ptr(), &mut io);
+    if io.output.flush().is_err() && io.io_strict {
+        io.fail("write");
+        status = 1;
+    }
+    // SAFETY: nothing points into the code any more
+    unsafe { module.free_memory() };
+    Ok(status)
+}
+
+fn jit_error(e: impl std::fmt::Display) -> Error {
+    Error::Jit(e.to_string())
+}
+
+/// Builds the IR of one function, taking the tape and the `Io` and
+/// returning the exit status.
+pub(crate) struct Lowering<'a> {
+    b: FunctionBuilder<'a>,
+    tape: Value,
+    io: Value,
+    /// Index of the current cell.
+    ptr: Variable,
+    tape_len: usize,
+    io_strict: bool,
+    getc: FuncRef,
+    putc: FuncRef,
+}
+
+impl Lowering<'_> {
+    /// Defines the function `bf_main` running `prog` in `module`, calling
+    /// `bf_getc` and `bf_putc` the module has to provide.
+    pub(crate) fn declare(
+        module: &mut impl Module,
+        prog: &ast::Prog,
+        opts: &CompileOptions,
+    ) -> Result<FuncId, Box<ModuleError>> {
+        let ptr_ty = module.target_config().pointer_type();
+        let mut sig = module.make_signature();
+        sig.params
+            .extend([AbiParam::new(ptr_ty), AbiParam::new(ptr_ty)]);
+        sig.returns.push(AbiParam::new(types::I32));
+        let mut getc_sig = module.make_signature();
+        getc_sig.params.push(AbiParam::new(ptr_ty));
+        getc_sig.returns.push(AbiParam::new(types::I32));
+        let mut putc_sig = getc_sig.clone();
+        putc_sig.params.push(AbiParam::new(types::I32));
+
+        let getc = module.declare_function("bf_getc", Linkage::Import, &getc_sig)?;
+        let putc = module.declare_function("bf_putc", Linkage::Import, &putc_sig)?;
+        let main = module.declare_function("bf_main", Linkage::Local, &sig)?;
+
+        let mut ctx = module.make_context();
+        ctx.func.signature = sig;
+        let mut fctx = FunctionBuilderContext::new();
+        let mut b = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+        let getc = module.declare_func_in_func(getc, b.func);
+        let putc = module.declare_func_in_func(putc, b.func);
+
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let (tape, io) = (b.block_params(entry)[0], b.block_params(entry)[1]);
+        let ptr = Variable::from_u32(0);
+        b.declare_var(ptr, ptr_ty);
+        let zero = b.ins().iconst(ptr_ty, 0);
+        b.def_var(ptr, zero);
+
+        let mut lowering = Lowering {
+            b,
+            tape,
+            io,
+            ptr,
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            getc,
+            putc,
+        };
+        lowering.block(prog);
+        lowering.exit(0);
+        lowering.b.seal_all_blocks();
+        lowering.b.finalize();
+
+        module.define_function(main, &mut ctx)?;
+        module.clear_context(&mut ctx);
+        Ok(main)
+    }
+
+    fn block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.statement(&stat.stat);
+        }
+    }
+
+    fn statement(&mut self, stat: &ast::Statement) {
+        match stat {
+            ast::Statement::MoveL(n) => self.generate_move(-(*n as i64)),
+            ast::Statement::MoveR(n) => self.generate_move(*n as i64),
+            ast::Statement::Add(n) => {
+                let (addr, cell) = self.cell();
+                let cell = self.b.ins().iadd_imm(cell, *n as u32 as i64);
+                self.b.ins().store(MemFlags::trusted(), cell, addr, 0);
+            }
+            ast::Statement::Sub(n) => {
+                let (addr, cell) = self.cell();
+                let cell = self.b.ins().iadd_imm(cell, -(*n as u32 as i64));
+                self.b.ins().store(MemFlags::trusted(), cell, addr, 0);
+            }
+            ast::Statement::Set(n) => {
+                let addr = self.addr();
+                let value = self.b.ins().iconst(types::I32, *n as i64);
+                self.b.ins().store(MemFlags::trusted(), value, addr, 0);
+            }
+            ast::Statement::MulAdd { offset, factor } => {
+                let (_, cell) = self.cell();
+                let product = self.b.ins().imul_imm(cell, *factor as i64);
+                self.generate_move(*offset as i64);
+                let (addr, cell) = self.cell();
+                let sum = self.b.ins().iadd(cell, product);
+                self.b.ins().store(MemFlags::trusted(), sum, addr, 0);
+                self.generate_move(-(*offset as i64));
+            }
+            ast::Statement::Read => {
+                let call = self.b.ins().call(self.getc, &[self.io]);
+                let c = self.b.inst_results(call)[0];
+                self.check_io(c);
+                // negative at end of input, leaving the cell alone
+                let store = self.b.create_block();
+                let done = self.b.create_block();
+                let read = self.b.ins().icmp_imm(IntCC::SignedGreaterThanOrEqual, c, 0);
+                self.b.ins().brif(read, store, &[], done, &[]);
+                self.b.switch_to_block(store);
+                let addr = self.addr();
+                self.b.ins().store(MemFlags::trusted(), c, addr, 0);
+                self.b.ins().jump(done, &[]);
+                self.b.switch_to_block(done);
+            }
+            ast::Statement::Write => {
+                let (_, cell) = self.cell();
+                let call = self.b.ins().call(self.putc, &[self.io, cell]);
+                let res = self.b.inst_results(call)[0];
+                self.check_io(res);
+            }
+            ast::Statement::Scan(stride) => {
+                let head = self.b.create_block();
+                let inner = self.b.create_block();
+                let end = self.b.create_block();
+                self.b.ins().jump(head, &[]);
+                self.b.switch_to_block(head);
+                let (_, cell) = self.cell();
+                self.b.ins().brif(cell, inner, &[], end, &[]);
+                self.b.switch_to_block(inner);
+                self.generate_move(*stride as i64);
+                self.b.ins().jump(head, &[]);
+                self.b.switch_to_block(end);
+            }
+            ast::Statement::Loop(body) => {
+                let head = self.b.create_block();
+                let inner = self.b.create_block();
+                let end = self.b.create_block();
+                self.b.ins().jump(head, &[]);
+                self.b.switch_to_block(head);
+                let (_, cell) = self.cell();
+                self.b.ins().brif(cell, inner, &[], end, &[]);
+                self.b.switch_to_block(inner);
+                self.block(body);
+                self.b.ins().jump(head, &[]);
+                self.b.switch_to_block(end);
+            }
+            ast::Statement::If(body) => {
+                let inner = self.b.create_block();
+                let end = self.b.create_block();
+                let (_, cell) = self.cell();
+                self.b.ins().brif(cell, inner, &[], end, &[]);
+                self.b.switch_to_block(inner);
+                self.block(body);
+                self.b.ins().jump(end, &[]);
+                self.b.switch_to_block(end);
+            }
+            // there is no data to copy from, see `spell_out_fill`
+            ast::Statement::Fill(values) => self.block(&ast::spell_out_fill(values)),
+            // nor any to write from, see `spell_out_print`
+            ast::Statement::Print(bytes) => self.block(&ast::spell_out_print(bytes)),
+        }
+    }
+
+    /// Moves the pointer by `n` cells and ends the run with status 1 when
+    /// it ends up off the tape, left of it wrapping around past its end.
+    fn generate_move(&mut self, n: i64) {
+        let ptr = self.b.use_var(self.ptr);
+        let ptr = self.b.ins().iadd_imm(ptr, n);
+        self.b.def_var(self.ptr, ptr);
+        let off =
+            self.b
+                .ins()
+                .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, ptr, self.tape_len as i64);
+        self.exit_if(off);
+    }
+
+    /// Ends the run with status 1 when a runtime function returned
+    /// `IO_ERROR`, which it only does with `io_strict`.
+    fn check_io(&mut self, res: Value) {
+        if self.io_strict {
+            let failed = self.b.ins().icmp_imm(IntCC::Equal, res, IO_ERROR);
+            self.exit_if(failed);
+        }
+    }
+
+    fn exit_if(&mut self, cond: Value) {
+        let halt = self.b.create_block();
+        let cont = self.b.create_block();
+        self.b.ins().brif(cond, halt, &[], cont, &[]);
+        self.b.switch_to_block(halt);
+        self.exit(1);
+        self.b.switch_to_block(cont);
+    }
+
+    fn exit(&mut self, status: i64) {
+        let status = self.b.ins().iconst(types::I32, status);
+        self.b.ins().return_(&[status]);
+    }
+
+    fn addr(&mut self) -> Value {
+        let ptr = self.b.use_var(self.ptr);
+        let off = self.b.ins().imul_imm(ptr, CELL_SIZE);
+        self.b.ins().iadd(self.tape, off)
+    }
+
+    /// The current cell's address and value.
+    fn cell(&mut self) -> (Value, Value) {
+        let addr = self.addr();
+        let cell = self.b.ins().load(types::I32, MemFlags::trusted(), addr, 0);
+        (addr, cell)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_on(src: &str, input: &[u8], opts: &CompileOptions) -> (i32, Vec<u8>) {
+        let prog = crate::parse(src).unwrap();
+        let mut output = Vec::new();
+        let status = run(&prog, opts, &mut &input[..], &mut output).unwrap();
+        (status, output)
+    }
+
+    #[test]
+    fn run_in_process() {
+        let opts = CompileOptions::default();
+        assert_eq!(run_on(",[.[-],]", b"hi", &opts), (0, b"hi".to_vec()));
+        // `,` at end of input leaves the cell alone
+        assert_eq!(run_on("+++,.", b"", &opts), (0, vec![3]));
+        assert_eq!(run_on("-.+[+]", b"", &opts).1, [255]);
+        assert_eq!(run_on("<", b"", &opts).0, 1);
+        let crlf = CompileOptions {
+            newline: Newline::Crlf,
+            ..Default::default()
+        };
+        assert_eq!(run_on(",.,.", b"\r\n", &crlf), (0, b"\r\n\r\n".to_vec()));
+    }
+}
@@ -0,0 +1,254 @@
+//! JavaScript, for web playgrounds and anywhere else a JS engine is the only
+//! thing around. The output is a self-contained ES module exporting
+//! `run(io)`, which resolves to the exit status. `io.read()` returns the
+//! next byte of input, or a promise of it, and -1 at its end; `io.write(c)`
+//! takes one byte and `io.flush()`, if there is one, is called before every
+//! read and on the way out. Without an `io`, and when the module is Node's
+//! entry point, stdin and stdout are used.
+//!
+//! Cells are 32 bits like everywhere else, so the tape is a `Uint32Array`
+//! and bytes only appear at the I/O boundary. Moving off the tape ends the
+//! program with status 1.
+
+use std::fmt::Write;
+
+use crate::{ast, backend::CodeGen, CompileOptions, Error, Newline};
+
+const READ_ERR: &str = "bfc: read error\\n";
+const WRITE_ERR: &str = "bfc: write error\\n";
+
+const NODE_IO: &str = r#"function nodeIo() {
+  const input = process.stdin[Symbol.asyncIterator]();
+  let chunk = new Uint8Array(0);
+  let at = 0;
+  const out = [];
+  return {
+    async read() {
+      while (at === chunk.length) {
+        this.flush();
+        const { value, done } = await input.next();
+        if (done) return -1;
+        chunk = value;
+        at = 0;
+      }
+      return chunk[at++];
+    },
+    write(c) {
+      out.push(c);
+      if (out.length >= 4096) this.flush();
+    },
+    flush() {
+      if (out.length === 0) return;
+      process.stdout.write(Uint8Array.from(out));
+      out.length = 0;
+    },
+  };
+}
+"#;
+
+pub struct JsGenerator {
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    debug_file: Option<String>,
+    indent: usize,
+    body: String,
+}
+
+impl Default for JsGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for JsGenerator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl JsGenerator {
+    pub fn new() -> Self {
+        JsGenerator {
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            debug_file: None,
+            indent: 1,
+            body: String::new(),
+        }
+    }
+
+    pub fn with_options(opts: &CompileOptions) -> Self {
+        JsGenerator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            debug_file: opts.debug_file.clone(),
+            ..Self::new()
+        }
+    }
+
+    pub fn gen(&mut self, prog: &ast::Prog) -> String {
+        self.generate_block(prog);
+
+        let mut out = String::new();
+        writeln!(out, "const TAPE_LEN = {};\n", self.tape_len).unwrap();
+        out.push_str(NODE_IO);
+
+        out.push_str("\nexport async function run(io = nodeIo()) {\n");
+        out.push_str("  const tape = new Uint32Array(TAPE_LEN);\n  let p = 0;\n");
+        out.push_str("  const flush = () => io.flush && io.flush();\n");
+        out.push_str("  const putc = (c) => {\n");
+        if self.crlf {
+            out.push_str("    if ((c & 0xff) === 10) io.write(13);\n");
+        }
+        out.push_str("    io.write(c & 0xff);\n  };\n");
+        out.push_str("  const getc = async () => {\n    flush();\n");
+        if self.crlf {
+            out.push_str("    let c;\n    do c = await io.read();\n    while (c === 13);\n");
+            out.push_str("    return c;\n  };\n");
+        } else {
+            out.push_str("    return io.read();\n  };\n");
+        }
+        out.push_str("  try {\n");
+        out.push_str(&std::mem::take(&mut self.body));
+        out.push_str("  } finally {\n    flush();\n  }\n  return 0;\n}\n\n");
+
+        // run when Node loads the module as its entry point
+        out.push_str("if (typeof process === \"object\" && process.argv && process.argv[1]) {\n");
+        out.push_str("  const { pathToFileURL } = await import(\"node:url\");\n");
+        out.push_str("  if (import.meta.url === pathToFileURL(process.argv[1]).href) {\n");
+        if self.io_strict {
+            for (stream, msg) in [("stdin", READ_ERR), ("stdout", WRITE_ERR)] {
+                writeln!(
+                    out,
+                    "    process.{}.on(\"error\", () => {{\n      process.stderr.write(\"{}\");\n      process.exit(1);\n    }});",
+                    stream, msg
+                )
+                .unwrap();
+            }
+        } else {
+            // like the native programs, carry on when nobody is listening
+            out.push_str("    process.stdout.on(\"error\", () => {});\n");
+        }
+        out.push_str("    process.exitCode = await run();\n  }\n}\n");
+        out
+    }
+
+    fn generate_block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.generate_statement(stat)
+        }
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) {
+        if let Some(file) = &self.debug_file {
+            let span = stat.attr.span;
+            let line = format!("// {}:{}:{}", file, span.line, span.col);
+            self.line(&line);
+        }
+
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move("-=", *n),
+            ast::Statement::MoveR(n) => self.generate_move("+=", *n),
+            ast::Statement::Add(n) => self.line(&format!("tape[p] += {};", *n as u32)),
+            ast::Statement::Sub(n) => self.line(&format!("tape[p] -= {};", *n as u32)),
+            ast::Statement::Set(n) => self.line(&format!("tape[p] = {};", n)),
+            ast::Statement::MulAdd { offset, factor } => {
+                let (there, back) = if *offset < 0 {
+                    ("-=", "+")
+                } else {
+                    ("+=", "-")
+                };
+                let n = offset.unsigned_abs();
+                self.generate_move(there, n);
+                // a plain `*` loses the low bits of large products
+                self.line(&format!(
+                    "tape[p] += Math.imul(tape[p {} {}], {});",
+                    back, n, *factor as i32
+                ));
+                self.generate_move(if *offset < 0 { "+=" } else { "-=" }, n);
+            }
+            ast::Statement::Read => {
+                self.line("{");
+                self.indent += 1;
+                self.line("const c = await getc();");
+                self.line("if (c >= 0) tape[p] = c;");
+                self.indent -= 1;
+                self.line("}");
+            }
+            ast::Statement::Write => self.line("putc(tape[p]);"),
+            ast::Statement::Scan(stride) => {
+                self.line("while (tape[p]) {");
+                self.indent += 1;
+                let op = if *stride < 0 { "-=" } else { "+=" };
+                self.generate_move(op, stride.unsigned_abs());
+                self.indent -= 1;
+                self.line("}");
+            }
+            ast::Statement::Loop(body) => {
+                self.line("while (tape[p]) {");
+                self.indent += 1;
+                self.generate_block(body);
+                self.indent -= 1;
+                self.line("}");
+            }
+            ast::Statement::If(body) => {
+                self.line("if (tape[p]) {");
+                self.indent += 1;
+                self.generate_block(body);
+                self.indent -= 1;
+                self.line("}");
+            }
+            ast::Statement::Fill(values) => {
+                let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+                self.line(&format!("tape.set([{}], p);", values.join(", ")));
+            }
+            ast::Statement::Print(bytes) => {
+                let chars: Vec<_> = bytes.iter().map(|b| b.to_string()).collect();
+                self.line(&format!("for (const c of [{}]) putc(c);", chars.join(", ")));
+                self.line(&format!("tape[p] = {};", bytes[bytes.len() - 1]));
+            }
+        }
+    }
+
+    /// Moves the pointer by `n` cells, `op` being `+=` or `-=`, and ends the
+    /// program with status 1 when it ends up off the tape.
+    fn generate_move(&mut self, op: &str, n: usize) {
+        if n >= self.tape_len {
+            self.line("return 1;");
+            return;
+        }
+        self.line(&format!("p {} {};", op, n));
+        self.line("if (p < 0 || p >= TAPE_LEN) return 1;");
+    }
+
+    fn line(&mut self, line: &str) {
+        // one more level for the `try` around the body
+        for _ in 0..=self.indent {
+            self.body.push_str("  ");
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn products_keep_their_low_bits() {
+        let opts = CompileOptions {
+            opt_level: crate::OptLevel::O2,
+            ..Default::default()
+        };
+        let prog = crate::optimize(",[->-<]>.", &opts).unwrap();
+        let js = JsGenerator::with_options(&opts).gen(&prog);
+        assert!(js.contains("      tape[p] += Math.imul(tape[p - 1], -1);\n"));
+        assert!(js.contains("      const c = await getc();\n      if (c >= 0) tape[p] = c;\n"));
+    }
+}
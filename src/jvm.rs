@@ -0,0 +1,742 @@
+//! JVM class files, for running programs anywhere there is a `java`. The
+//! class has a `main` and keeps the tape in an `int[]`, so cells wrap at 32
+//! bits like everywhere else, with `System.in` and a buffered stdout for
+//! I/O. Behaves like the other backends: `,` at end of input leaves the cell
+//! alone and moving off the tape exits with status 1.
+//!
+//! Classes are version 49, the last without stack map frames, which every
+//! JVM still loads. A method's code is capped at 64k and its branches reach
+//! 32k, so loops and runs of statements larger than [`CHUNK`] bytes of
+//! bytecode are moved into methods of their own. Those share the tape and
+//! pointer through static fields.
+
+use std::collections::HashMap;
+
+use crate::{ast, backend::CodeGen, CompileOptions, Error, Newline};
+
+/// Bytecode kept in one method before splitting it up.
+pub const CHUNK: usize = 8192;
+
+const MAGIC: u32 = 0xcafe_babe;
+const VERSION: u16 = 49;
+
+const ACC_PUBLIC: u16 = 0x0001;
+const ACC_PRIVATE: u16 = 0x0002;
+const ACC_STATIC: u16 = 0x0008;
+const ACC_FINAL: u16 = 0x0010;
+const ACC_SUPER: u16 = 0x0020;
+
+const T_INT: u8 = 10;
+
+const READ_ERR: &str = "bfc: read error\n";
+const WRITE_ERR: &str = "bfc: write error\n";
+
+/// The opcodes used, named as in the JVM specification.
+mod op {
+    pub const ICONST_M1: u8 = 0x02;
+    pub const ICONST_0: u8 = 0x03;
+    pub const ICONST_1: u8 = 0x04;
+    pub const BIPUSH: u8 = 0x10;
+    pub const SIPUSH: u8 = 0x11;
+    pub const LDC_W: u8 = 0x13;
+    pub const ILOAD_0: u8 = 0x1a;
+    pub const ILOAD_1: u8 = 0x1b;
+    pub const ILOAD_2: u8 = 0x1c;
+    pub const ALOAD_0: u8 = 0x2a;
+    pub const IALOAD: u8 = 0x2e;
+    pub const ISTORE_1: u8 = 0x3c;
+    pub const ISTORE_2: u8 = 0x3d;
+    pub const ASTORE_0: u8 = 0x4b;
+    pub const IASTORE: u8 = 0x4f;
+    pub const POP: u8 = 0x57;
+    pub const DUP: u8 = 0x59;
+    pub const DUP2: u8 = 0x5c;
+    pub const IADD: u8 = 0x60;
+    pub const ISUB: u8 = 0x64;
+    pub const IMUL: u8 = 0x68;
+    pub const IINC: u8 = 0x84;
+    pub const IFEQ: u8 = 0x99;
+    pub const IFLT: u8 = 0x9b;
+    pub const IF_ICMPNE: u8 = 0xa0;
+    pub const IF_ICMPLT: u8 = 0xa1;
+    pub const GOTO: u8 = 0xa7;
+    pub const IRETURN: u8 = 0xac;
+    pub const RETURN: u8 = 0xb1;
+    pub const GETSTATIC: u8 = 0xb2;
+    pub const PUTSTATIC: u8 = 0xb3;
+    pub const INVOKEVIRTUAL: u8 = 0xb6;
+    pub const INVOKESPECIAL: u8 = 0xb7;
+    pub const INVOKESTATIC: u8 = 0xb8;
+    pub const NEW: u8 = 0xbb;
+    pub const NEWARRAY: u8 = 0xbc;
+    pub const WIDE: u8 = 0xc4;
+}
+
+/// A class name for a program written to `<stem>.class`: the stem with
+/// anything a Java identifier cannot hold replaced by `_`.
+pub fn class_name(stem: &str) -> String {
+    let mut name: String = stem
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        name.insert(0, '_');
+    }
+    name
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Constant {
+    Utf8(String),
+    Int(i32),
+    Class(String),
+    Str(String),
+    NameAndType(String, String),
+    Field(String, String, String),
+    Method(String, String, String),
+}
+
+/// The constant pool, each constant added once.
+#[derive(Default)]
+struct Pool {
+    bytes: Vec<u8>,
+    count: u16,
+    indices: HashMap<Constant, u16>,
+}
+
+impl Pool {
+    fn get(&mut self, constant: Constant) -> u16 {
+        if let Some(&index) = self.indices.get(&constant) {
+            return index;
+        }
+        let mut bytes = Vec::new();
+        match &constant {
+            Constant::Utf8(s) => {
+                // plain ASCII is all that ends up here, the same in modified UTF-8
+                bytes.push(1);
+                bytes.extend((s.len() as u16).to_be_bytes());
+                bytes.extend(s.as_bytes());
+            }
+            Constant::Int(n) => {
+                bytes.push(3);
+                bytes.extend(n.to_be_bytes());
+            }
+            Constant::Class(name) => {
+                let name = self.utf8(name);
+                bytes.push(7);
+                bytes.extend(name.to_be_bytes());
+            }
+            Constant::Str(s) => {
+                let s = self.utf8(s);
+                bytes.push(8);
+                bytes.extend(s.to_be_bytes());
+            }
+            Constant::NameAndType(name, desc) => {
+                let (name, desc) = (self.utf8(name), self.utf8(desc));
+                bytes.push(12);
+                bytes.extend(name.to_be_bytes());
+                bytes.extend(desc.to_be_bytes());
+            }
+            Constant::Field(class, name, desc) | Constant::Method(class, name, desc) => {
+                let class = self.get(Constant::Class(class.clone()));
+                let nat = self.get(Constant::NameAndType(name.clone(), desc.clone()));
+                bytes.push(if let Constant::Field(..) = constant {
+                    9
+                } else {
+                    10
+                });
+                bytes.extend(class.to_be_bytes());
+                bytes.extend(nat.to_be_bytes());
+            }
+        }
+        self.count += 1;
+        self.bytes.extend(bytes);
+        self.indices.insert(constant, self.count);
+        self.count
+    }
+
+    fn utf8(&mut self, s: &str) -> u16 {
+        self.get(Constant::Utf8(s.to_string()))
+    }
+}
+
+struct Method {
+    access: u16,
+    name: String,
+    desc: String,
+    max_stack: u16,
+    max_locals: u16,
+    code: Vec<u8>,
+    /// Start, end and handler of code catching `IOException`.
+    handlers: Vec<(u16, u16, u16)>,
+}
+
+pub struct JvmGenerator {
+    class: String,
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    pool: Pool,
+    methods: Vec<Method>,
+    method_counter: usize,
+}
+
+impl CodeGen for JvmGenerator {
+    type Output = Vec<u8>;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<Vec<u8>, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl JvmGenerator {
+    pub fn new(class: &str) -> Self {
+        JvmGenerator {
+            class: class.to_string(),
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            pool: Pool::default(),
+            methods: Vec::new(),
+            method_counter: 0,
+        }
+    }
+
+    pub fn with_options(class: &str, opts: &CompileOptions) -> Self {
+        JvmGenerator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            ..Self::new(class)
+        }
+    }
+
+    /// The class file.
+    pub fn gen(&mut self, prog: &ast::Prog) -> Vec<u8> {
+        let body = self.generate_block(prog);
+        self.add_program_method("run", body);
+        self.generate_main();
+        self.generate_helpers();
+
+        let this = self.pool.get(Constant::Class(self.class.clone()));
+        let object = self
+            .pool
+            .get(Constant::Class("java/lang/Object".to_string()));
+        let fields = [
+            ("out", "Ljava/io/OutputStream;"),
+            ("tape", "[I"),
+            ("p", "I"),
+        ]
+        .map(|(name, desc)| (self.pool.utf8(name), self.pool.utf8(desc)));
+        let code = self.pool.utf8("Code");
+        let methods = std::mem::take(&mut self.methods);
+        let methods: Vec<_> = methods
+            .into_iter()
+            .map(|m| {
+                let name = self.pool.utf8(&m.name);
+                let desc = self.pool.utf8(&m.desc);
+                let exception = match m.handlers.is_empty() {
+                    true => 0,
+                    false => self
+                        .pool
+                        .get(Constant::Class("java/io/IOException".to_string())),
+                };
+                (m, name, desc, exception)
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        out.extend(MAGIC.to_be_bytes());
+        out.extend(0u16.to_be_bytes());
+        out.extend(VERSION.to_be_bytes());
+        out.extend((self.pool.count + 1).to_be_bytes());
+        out.extend(&self.pool.bytes);
+        out.extend((ACC_PUBLIC | ACC_FINAL | ACC_SUPER).to_be_bytes());
+        out.extend(this.to_be_bytes());
+        out.extend(object.to_be_bytes());
+        out.extend(0u16.to_be_bytes()); // no interfaces
+
+        out.extend((fields.len() as u16).to_be_bytes());
+        for (name, desc) in fields {
+            out.extend((ACC_PRIVATE | ACC_STATIC).to_be_bytes());
+            out.extend(name.to_be_bytes());
+            out.extend(desc.to_be_bytes());
+            out.extend(0u16.to_be_bytes());
+        }
+
+        out.extend((methods.len() as u16).to_be_bytes());
+        for (m, name, desc, exception) in methods {
+            out.extend(m.access.to_be_bytes());
+            out.extend(name.to_be_bytes());
+            out.extend(desc.to_be_bytes());
+            out.extend(1u16.to_be_bytes());
+            out.extend(code.to_be_bytes());
+            let len = 2 + 2 + 4 + m.code.len() + 2 + 8 * m.handlers.len() + 2;
+            out.extend((len as u32).to_be_bytes());
+            out.extend(m.max_stack.to_be_bytes());
+            out.extend(m.max_locals.to_be_bytes());
+            out.extend((m.code.len() as u32).to_be_bytes());
+            out.extend(&m.code);
+            out.extend((m.handlers.len() as u16).to_be_bytes());
+            for (start, end, handler) in m.handlers {
+                for n in [start, end, handler, exception] {
+                    out.extend(n.to_be_bytes());
+                }
+            }
+            out.extend(0u16.to_be_bytes());
+        }
+        out.extend(0u16.to_be_bytes()); // no attributes
+        out
+    }
+
+    /// Code for `block`, split into methods when it gets too big for one.
+    fn generate_block(&mut self, block: &ast::NodeBlock) -> Vec<u8> {
+        let pieces = block
+            .stats
+            .iter()
+            .map(|stat| self.generate_statement(stat))
+            .collect();
+        self.fit(pieces)
+    }
+
+    /// Joins `pieces` of code, moving runs of them into methods of their
+    /// own until what is left fits in [`CHUNK`].
+    fn fit(&mut self, pieces: Vec<Vec<u8>>) -> Vec<u8> {
+        if pieces.iter().map(Vec::len).sum::<usize>() <= CHUNK {
+            return pieces.concat();
+        }
+        let mut calls = Vec::new();
+        let mut run: Vec<u8> = Vec::new();
+        for piece in pieces {
+            if !run.is_empty() && run.len() + piece.len() > CHUNK {
+                calls.push(self.outline(std::mem::take(&mut run)));
+            }
+            run.extend(piece);
+        }
+        calls.push(self.outline(run));
+        self.fit(calls)
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) -> Vec<u8> {
+        let mut code = Vec::new();
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move(&mut code, -(*n as i64)),
+            ast::Statement::MoveR(n) => self.generate_move(&mut code, *n as i64),
+            ast::Statement::Add(n) | ast::Statement::Sub(n) => {
+                code.extend([op::ALOAD_0, op::ILOAD_1, op::DUP2, op::IALOAD]);
+                self.push_int(&mut code, *n as u32 as i32);
+                code.push(match stat.stat {
+                    ast::Statement::Add(_) => op::IADD,
+                    _ => op::ISUB,
+                });
+                code.push(op::IASTORE);
+            }
+            ast::Statement::Set(n) => {
+                code.extend([op::ALOAD_0, op::ILOAD_1]);
+                self.push_int(&mut code, *n as i32);
+                code.push(op::IASTORE);
+            }
+            ast::Statement::MulAdd { offset, factor } => {
+                // the product waits in local 2 while moving to the other
+                // cell and back, checked like any move
+                code.extend([op::ALOAD_0, op::ILOAD_1, op::IALOAD]);
+                self.push_int(&mut code, *factor as i32);
+                code.extend([op::IMUL, op::ISTORE_2]);
+                self.generate_move(&mut code, *offset as i64);
+                code.extend([op::ALOAD_0, op::ILOAD_1, op::DUP2, op::IALOAD]);
+                code.extend([op::ILOAD_2, op::IADD, op::IASTORE]);
+                self.generate_move(&mut code, -(*offset as i64));
+            }
+            ast::Statement::Read => {
+                self.invoke_static(&mut code, "getc", "()I");
+                code.extend([op::ISTORE_2, op::ILOAD_2]);
+                // past the store at end of input
+                branch(&mut code, op::IFLT, 7);
+                code.extend([op::ALOAD_0, op::ILOAD_1, op::ILOAD_2, op::IASTORE]);
+            }
+            ast::Statement::Write => {
+                code.extend([op::ALOAD_0, op::ILOAD_1, op::IALOAD]);
+                self.invoke_static(&mut code, "putc", "(I)V");
+            }
+            ast::Statement::Scan(stride) => {
+                let mut step = Vec::new();
+                self.generate_move(&mut step, *stride as i64);
+                code.extend([op::ALOAD_0, op::ILOAD_1, op::IALOAD]);
+                branch(&mut code, op::IFEQ, step.len() as i32 + 6);
+                code.extend(&step);
+                branch(&mut code, op::GOTO, -(step.len() as i32 + 6));
+            }
+            ast::Statement::Loop(body) => {
+                let mut body = self.generate_block(body);
+                if body.len() > CHUNK {
+                    body = self.outline(body);
+                }
+                code.extend([op::ALOAD_0, op::ILOAD_1, op::IALOAD]);
+                branch(&mut code, op::IFEQ, body.len() as i32 + 6);
+                code.extend(&body);
+                branch(&mut code, op::GOTO, -(body.len() as i32 + 6));
+            }
+            ast::Statement::If(body) => {
+                let mut body = self.generate_block(body);
+                if body.len() > CHUNK {
+                    body = self.outline(body);
+                }
+                code.extend([op::ALOAD_0, op::ILOAD_1, op::IALOAD]);
+                branch(&mut code, op::IFEQ, body.len() as i32 + 3);
+                code.extend(&body);
+            }
+            // there is no data to copy from, see `spell_out_fill`
+            ast::Statement::Fill(values) => {
+                code.extend(self.generate_block(&ast::spell_out_fill(values)))
+            }
+            // nor any to write from, see `spell_out_print`
+            ast::Statement::Print(bytes) => {
+                code.extend(self.generate_block(&ast::spell_out_print(bytes)))
+            }
+        }
+        code
+    }
+
+    /// Moves the pointer by `n` cells and exits with status 1 when it ends
+    /// up off the tape.
+    fn generate_move(&mut self, code: &mut Vec<u8>, n: i64) {
+        if n.unsigned_abs() as usize >= self.tape_len {
+            self.invoke_static(code, "oob", "()V");
+            return;
+        }
+        match i16::try_from(n) {
+            Ok(n) => {
+                code.extend([op::WIDE, op::IINC, 0, 1]);
+                code.extend(n.to_be_bytes());
+            }
+            Err(_) => {
+                code.push(op::ILOAD_1);
+                self.push_int(code, n.unsigned_abs() as i32);
+                code.extend([if n < 0 { op::ISUB } else { op::IADD }, op::ISTORE_1]);
+            }
+        }
+        let mut limit = Vec::new();
+        self.push_int(&mut limit, self.tape_len as i32);
+        code.push(op::ILOAD_1);
+        // to the call of `oob`
+        branch(code, op::IFLT, 3 + 1 + limit.len() as i32 + 3);
+        code.push(op::ILOAD_1);
+        code.extend(limit);
+        // past it
+        branch(code, op::IF_ICMPLT, 3 + 3);
+        self.invoke_static(code, "oob", "()V");
+    }
+
+    /// Moves `code` into a method of its own, returning the code calling it.
+    fn outline(&mut self, code: Vec<u8>) -> Vec<u8> {
+        let name = format!("chunk{}", self.method_counter);
+        self.method_counter += 1;
+        self.add_program_method(&name, code);
+
+        let mut call = Vec::new();
+        let p = self.field("p", "I");
+        call.push(op::ILOAD_1);
+        call.push(op::PUTSTATIC);
+        call.extend(p.to_be_bytes());
+        self.invoke_static(&mut call, &name, "()V");
+        call.push(op::GETSTATIC);
+        call.extend(p.to_be_bytes());
+        call.push(op::ISTORE_1);
+        call
+    }
+
+    /// Adds a method running `body` with the tape in local 0, the pointer
+    /// in local 1 and room for a byte read in local 2.
+    fn add_program_method(&mut self, name: &str, body: Vec<u8>) {
+        let tape = self.field("tape", "[I");
+        let p = self.field("p", "I");
+        let mut code = vec![op::GETSTATIC];
+        code.extend(tape.to_be_bytes());
+        code.push(op::ASTORE_0);
+        code.push(op::GETSTATIC);
+        code.extend(p.to_be_bytes());
+        code.push(op::ISTORE_1);
+        code.extend(body);
+        code.push(op::ILOAD_1);
+        code.push(op::PUTSTATIC);
+        code.extend(p.to_be_bytes());
+        code.push(op::RETURN);
+        self.methods.push(Method {
+            access: ACC_PRIVATE | ACC_STATIC,
+            name: name.to_string(),
+            desc: "()V".to_string(),
+            max_stack: 4,
+            max_locals: 3,
+            code,
+            handlers: Vec::new(),
+        });
+    }
+
+    fn generate_main(&mut self) {
+        let mut code = Vec::new();
+        let buffered = self.class_ref("java/io/BufferedOutputStream");
+        let file = self.class_ref("java/io/FileOutputStream");
+        let fd = self.pool.get(Constant::Field(
+            "java/io/FileDescriptor".to_string(),
+            "out".to_string(),
+            "Ljava/io/FileDescriptor;".to_string(),
+        ));
+        code.push(op::NEW);
+        code.extend(buffered.to_be_bytes());
+        code.push(op::DUP);
+        code.push(op::NEW);
+        code.extend(file.to_be_bytes());
+        code.push(op::DUP);
+        code.push(op::GETSTATIC);
+        code.extend(fd.to_be_bytes());
+        self.invoke_special(
+            &mut code,
+            "java/io/FileOutputStream",
+            "(Ljava/io/FileDescriptor;)V",
+        );
+        self.invoke_special(
+            &mut code,
+            "java/io/BufferedOutputStream",
+            "(Ljava/io/OutputStream;)V",
+        );
+        code.push(op::PUTSTATIC);
+        code.extend(self.field("out", "Ljava/io/OutputStream;").to_be_bytes());
+
+        self.push_int(&mut code, self.tape_len as i32);
+        code.extend([op::NEWARRAY, T_INT, op::PUTSTATIC]);
+        code.extend(self.field("tape", "[I").to_be_bytes());
+        self.invoke_static(&mut code, "run", "()V");
+        self.invoke_static(&mut code, "flush", "()V");
+        code.push(op::RETURN);
+        self.methods.push(Method {
+            access: ACC_PUBLIC | ACC_STATIC,
+            name: "main".to_string(),
+            desc: "([Ljava/lang/String;)V".to_string(),
+            max_stack: 5,
+            max_locals: 1,
+            code,
+            handlers: Vec::new(),
+        });
+    }
+
+    /// `putc`, `getc`, `flush`, `oob` and, with `--io-strict`, `fail`.
+    fn generate_helpers(&mut self) {
+        let out = self.field("out", "Ljava/io/OutputStream;");
+
+        // putc(int c): write a byte, preceded by a carriage return for a
+        // newline with CRLF translation
+        let mut code = Vec::new();
+        if self.crlf {
+            code.extend([op::ILOAD_0, op::SIPUSH, 0, 0xff, 0x7e]); // iand
+            code.extend([op::BIPUSH, b'\n']);
+            branch(&mut code, op::IF_ICMPNE, 11);
+            code.push(op::GETSTATIC);
+            code.extend(out.to_be_bytes());
+            code.extend([op::BIPUSH, b'\r']);
+            self.invoke_virtual(&mut code, "java/io/OutputStream", "write", "(I)V");
+        }
+        code.push(op::GETSTATIC);
+        code.extend(out.to_be_bytes());
+        code.push(op::ILOAD_0);
+        self.invoke_virtual(&mut code, "java/io/OutputStream", "write", "(I)V");
+        let end = code.len() as u16;
+        code.push(op::RETURN);
+        let handler = code.len() as u16;
+        code.push(op::POP);
+        self.fail(&mut code, WRITE_ERR);
+        code.push(op::RETURN);
+        self.add_helper("putc", "(I)V", 1, code, vec![(0, end, handler)]);
+
+        // getc(): the next byte of input, -1 at its end
+        let mut code = Vec::new();
+        self.invoke_static(&mut code, "flush", "()V");
+        let start = code.len() as u16;
+        let input = self.pool.get(Constant::Field(
+            "java/lang/System".to_string(),
+            "in".to_string(),
+            "Ljava/io/InputStream;".to_string(),
+        ));
+        code.push(op::GETSTATIC);
+        code.extend(input.to_be_bytes());
+        self.invoke_virtual(&mut code, "java/io/InputStream", "read", "()I");
+        if self.crlf {
+            code.extend([op::DUP, op::BIPUSH, b'\r']);
+            branch(&mut code, op::IF_ICMPNE, 7);
+            code.push(op::POP);
+            let back = start as i32 - code.len() as i32;
+            branch(&mut code, op::GOTO, back);
+        }
+        let end = code.len() as u16;
+        code.push(op::IRETURN);
+        let handler = code.len() as u16;
+        code.push(op::POP);
+        self.fail(&mut code, READ_ERR);
+        code.extend([op::ICONST_M1, op::IRETURN]);
+        self.add_helper("getc", "()I", 0, code, vec![(start, end, handler)]);
+
+        let mut code = vec![op::GETSTATIC];
+        code.extend(out.to_be_bytes());
+        self.invoke_virtual(&mut code, "java/io/OutputStream", "flush", "()V");
+        let end = code.len() as u16;
+        code.push(op::RETURN);
+        let handler = code.len() as u16;
+        code.push(op::POP);
+        self.fail(&mut code, WRITE_ERR);
+        code.push(op::RETURN);
+        self.add_helper("flush", "()V", 0, code, vec![(0, end, handler)]);
+
+        let mut code = Vec::new();
+        self.invoke_static(&mut code, "flush", "()V");
+        code.push(op::ICONST_1);
+        self.exit(&mut code);
+        code.push(op::RETURN);
+        self.add_helper("oob", "()V", 0, code, Vec::new());
+
+        if self.io_strict {
+            let err = self.pool.get(Constant::Field(
+                "java/lang/System".to_string(),
+                "err".to_string(),
+                "Ljava/io/PrintStream;".to_string(),
+            ));
+            let mut code = vec![op::GETSTATIC];
+            code.extend(err.to_be_bytes());
+            code.push(op::ALOAD_0);
+            self.invoke_virtual(
+                &mut code,
+                "java/io/PrintStream",
+                "print",
+                "(Ljava/lang/String;)V",
+            );
+            code.push(op::ICONST_1);
+            self.exit(&mut code);
+            code.push(op::RETURN);
+            self.add_helper("fail", "(Ljava/lang/String;)V", 1, code, Vec::new());
+        }
+    }
+
+    fn add_helper(
+        &mut self,
+        name: &str,
+        desc: &str,
+        max_locals: u16,
+        code: Vec<u8>,
+        handlers: Vec<(u16, u16, u16)>,
+    ) {
+        self.methods.push(Method {
+            access: ACC_PRIVATE | ACC_STATIC,
+            name: name.to_string(),
+            desc: desc.to_string(),
+            max_stack: 3,
+            max_locals,
+            code,
+            handlers,
+        });
+    }
+
+    /// Reports `msg` and exits with status 1 in strict mode, otherwise the
+    /// error is ignored like the native programs do.
+    fn fail(&mut self, code: &mut Vec<u8>, msg: &str) {
+        if self.io_strict {
+            let msg = self.pool.get(Constant::Str(msg.to_string()));
+            code.push(op::LDC_W);
+            code.extend(msg.to_be_bytes());
+            self.invoke_static(code, "fail", "(Ljava/lang/String;)V");
+        }
+    }
+
+    /// Calls `System.exit` with the status on the stack.
+    fn exit(&mut self, code: &mut Vec<u8>) {
+        let exit = self.pool.get(Constant::Method(
+            "java/lang/System".to_string(),
+            "exit".to_string(),
+            "(I)V".to_string(),
+        ));
+        code.push(op::INVOKESTATIC);
+        code.extend(exit.to_be_bytes());
+    }
+
+    fn push_int(&mut self, code: &mut Vec<u8>, n: i32) {
+        match n {
+            -1..=5 => code.push((op::ICONST_0 as i32 + n) as u8),
+            -128..=127 => code.extend([op::BIPUSH, n as u8]),
+            -32768..=32767 => {
+                code.push(op::SIPUSH);
+                code.extend((n as i16).to_be_bytes());
+            }
+            _ => {
+                code.push(op::LDC_W);
+                code.extend(self.pool.get(Constant::Int(n)).to_be_bytes());
+            }
+        }
+    }
+
+    fn field(&mut self, name: &str, desc: &str) -> u16 {
+        self.pool.get(Constant::Field(
+            self.class.clone(),
+            name.to_string(),
+            desc.to_string(),
+        ))
+    }
+
+    fn class_ref(&mut self, name: &str) -> u16 {
+        self.pool.get(Constant::Class(name.to_string()))
+    }
+
+    fn invoke_static(&mut self, code: &mut Vec<u8>, name: &str, desc: &str) {
+        let method = self.pool.get(Constant::Method(
+            self.class.clone(),
+            name.to_string(),
+            desc.to_string(),
+        ));
+        code.push(op::INVOKESTATIC);
+        code.extend(method.to_be_bytes());
+    }
+
+    fn invoke_virtual(&mut self, code: &mut Vec<u8>, class: &str, name: &str, desc: &str) {
+        let method = self.pool.get(Constant::Method(
+            class.to_string(),
+            name.to_string(),
+            desc.to_string(),
+        ));
+        code.push(op::INVOKEVIRTUAL);
+        code.extend(method.to_be_bytes());
+    }
+
+    fn invoke_special(&mut self, code: &mut Vec<u8>, class: &str, desc: &str) {
+        let method = self.pool.get(Constant::Method(
+            class.to_string(),
+            "<init>".to_string(),
+            desc.to_string(),
+        ));
+        code.push(op::INVOKESPECIAL);
+        code.extend(method.to_be_bytes());
+    }
+}
+
+/// A branch `offset` bytes from the instruction itself.
+fn branch(code: &mut Vec<u8>, opcode: u8, offset: i32) {
+    code.push(opcode);
+    code.extend((offset as i16).to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn class_names() {
+        assert_eq!(class_name("hello"), "hello");
+        assert_eq!(class_name("99-bottles"), "_99_bottles");
+    }
+
+    #[test]
+    fn big_loops_get_methods() {
+        let small = JvmGenerator::new("Small").gen(&crate::parse("+[->+<]").unwrap());
+        assert_eq!(small[..4], MAGIC.to_be_bytes());
+        assert!(!small.windows(5).any(|w| w == b"chunk"));
+
+        let src = format!("+[{}]", "+>".repeat(CHUNK));
+        let big = JvmGenerator::new("Big").gen(&crate::parse(&src).unwrap());
+        assert!(big.windows(6).any(|w| w == b"chunk0"));
+    }
+}
@@ -12,84 +12,272 @@ pub enum Token {
     JmpNoZero,
 }
 
+/// Starts a pragma line, setting an option for the program, see
+/// [`crate::config::Config::from_pragmas`]. The rest of the line is not code.
+pub const PRAGMA: &str = "#bfc ";
+
+/// Starts a pragma anywhere in the code, running to the next `}`, such as
+/// [`NO_OPT`]. Nothing in it is code.
+pub const INLINE_PRAGMA: &str = "{#pragma ";
+
+/// Keeps the optimization passes away from the loop right after it, see
+/// [`no_opt_loops`].
+pub const NO_OPT: &str = "{#pragma no-opt}";
+
+/// Where a token starts in the source, both counted from 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
 struct LexerState<'a> {
+    input: &'a str,
     iter: Peekable<Chars<'a>>,
+    line: usize,
+    col: usize,
+    // byte offset into the input
+    pos: usize,
 }
 
-fn next(state: &mut LexerState) -> Option<Token> {
-    let c = state.iter.peek()?;
-    match c {
-        '<' => {
-            let mut count = 0;
-            while let Some('<') = state.iter.peek() {
-                count += 1;
-                state.iter.next();
+impl LexerState<'_> {
+    fn peek(&mut self) -> Option<&char> {
+        self.iter.peek()
+    }
+
+    fn bump(&mut self) {
+        match self.iter.next() {
+            Some('\n') => {
+                self.pos += 1;
+                self.line += 1;
+                self.col = 1;
             }
-            Some(Token::MoveL(count))
-        }
-        '>' => {
-            let mut count = 0;
-            while let Some('>') = state.iter.peek() {
-                count += 1;
-                state.iter.next();
+            Some(c) => {
+                self.pos += c.len_utf8();
+                self.col += 1;
             }
-            Some(Token::MoveR(count))
+            None => {}
         }
-        '+' => {
-            let mut count = 0;
-            while let Some('+') = state.iter.peek() {
-                count += 1;
-                state.iter.next();
-            }
-            Some(Token::Inc(count))
+    }
+
+    fn count(&mut self, of: char) -> usize {
+        let mut count = 0;
+        while self.peek() == Some(&of) {
+            count += 1;
+            self.bump();
+        }
+        count
+    }
+
+    fn at_pragma(&self) -> bool {
+        self.col == 1 && self.input[self.pos..].starts_with(PRAGMA)
+    }
+
+    fn at_inline_pragma(&self) -> bool {
+        self.input[self.pos..].starts_with(INLINE_PRAGMA)
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            col: self.col,
         }
-        '-' => {
-            let mut count = 0;
-            while let Some('-') = state.iter.peek() {
-                count += 1;
-                state.iter.next();
+    }
+}
+
+/// The next token, where it starts and its byte offset.
+fn next(state: &mut LexerState) -> Option<(Token, Span, usize)> {
+    loop {
+        if state.at_pragma() {
+            while state.peek().is_some_and(|c| *c != '\n') {
+                state.bump();
+            }
+            state.bump();
+        } else if state.at_inline_pragma() {
+            while let Some(c) = state.peek().copied() {
+                state.bump();
+                if c == '}' {
+                    break;
+                }
             }
-            Some(Token::Dec(count))
+        } else {
+            break;
         }
+    }
+    let c = *state.peek()?;
+    let span = state.span();
+    let start = state.pos;
+    let token = match c {
+        '<' => Token::MoveL(state.count('<')),
+        '>' => Token::MoveR(state.count('>')),
+        '+' => Token::Inc(state.count('+')),
+        '-' => Token::Dec(state.count('-')),
         '.' => {
-            state.iter.next();
-            Some(Token::Write)
+            state.bump();
+            Token::Write
         }
         ',' => {
-            state.iter.next();
-            Some(Token::Read)
+            state.bump();
+            Token::Read
         }
         '[' => {
-            state.iter.next();
-            Some(Token::JmpZero)
+            state.bump();
+            Token::JmpZero
         }
         ']' => {
-            state.iter.next();
-            Some(Token::JmpNoZero)
+            state.bump();
+            Token::JmpNoZero
         }
         _ => {
-            while let Some(c) = state.iter.peek() {
+            while let Some(c) = state.peek().copied() {
                 match c {
                     '<' | '>' | '+' | '-' | '.' | ',' | '[' | ']' => break,
-                    _ => {
-                        state.iter.next();
+                    '{' if state.at_inline_pragma() => break,
+                    '\n' => {
+                        state.bump();
+                        if state.at_pragma() {
+                            break;
+                        }
                     }
+                    _ => state.bump(),
                 }
             }
-            
-            next(state)
+
+            return next(state);
         }
-    }
+    };
+    Some((token, span, start))
 }
 
 pub fn lex(input: String) -> Vec<Token> {
-    let mut iter = input.chars().peekable();
-    let mut state = LexerState { iter };
+    lex_spanned(&input).into_iter().map(|(t, _)| t).collect()
+}
+
+/// Like [`lex`], also returning where each token starts.
+pub fn lex_spanned(input: &str) -> Vec<(Token, Span)> {
+    lex_offsets(input)
+        .into_iter()
+        .map(|(t, s, _, _)| (t, s))
+        .collect()
+}
+
+/// Tokens with their spans and byte ranges.
+fn lex_offsets(input: &str) -> Vec<(Token, Span, usize, usize)> {
+    let mut state = LexerState {
+        input,
+        iter: input.chars().peekable(),
+        line: 1,
+        col: 1,
+        pos: 0,
+    };
     let mut tokens = Vec::new();
 
-    while let Some(token) = next(&mut state) {
-        tokens.push(token);
+    while let Some((token, span, start)) = next(&mut state) {
+        tokens.push((token, span, start, state.pos));
     }
 
     tokens
 }
+
+/// Where the loops with [`NO_OPT`] before them start, nothing but white
+/// space coming between the two.
+pub fn no_opt_loops(input: &str) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut prev = 0;
+    for (token, span, start, end) in lex_offsets(input) {
+        if token == Token::JmpZero && input[prev..start].trim_end().ends_with(NO_OPT) {
+            spans.push(span);
+        }
+        prev = end;
+    }
+    spans
+}
+
+/// Text around tokens that is not code, split between the tokens on either
+/// side of it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Gap {
+    /// Rest of the line after the previous token, unless that token opened
+    /// a loop. Excludes the line break.
+    pub trailing: String,
+    /// Everything else, up to the next token.
+    pub leading: String,
+}
+
+/// Like [`lex_spanned`], also returning the gap before every token and a
+/// last one before the end of input, so there is one more gap than tokens.
+pub fn lex_trivia(input: &str) -> (Vec<(Token, Span)>, Vec<Gap>) {
+    let tokens = lex_offsets(input);
+    let mut gaps = Vec::with_capacity(tokens.len() + 1);
+    let mut prev: Option<(&Token, usize)> = None;
+    let starts = tokens.iter().map(|(_, _, start, _)| *start);
+    for start in starts.chain([input.len()]) {
+        let text = &input[prev.map_or(0, |(_, end)| end)..start];
+        let gap = match prev {
+            Some((Token::JmpZero, _)) | None => Gap {
+                trailing: String::new(),
+                leading: text.to_string(),
+            },
+            Some(_) => {
+                let split = text.find('\n').unwrap_or(text.len());
+                Gap {
+                    trailing: text[..split].to_string(),
+                    leading: text[split..].to_string(),
+                }
+            }
+        };
+        gaps.push(gap);
+        if let Some((token, _, _, end)) = tokens.get(gaps.len() - 1) {
+            prev = Some((token, *end));
+        }
+    }
+
+    let tokens = tokens.into_iter().map(|(t, s, _, _)| (t, s)).collect();
+    (tokens, gaps)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lex_spans() {
+        let tokens = lex_spanned("++ x\n  [->.]");
+        let spans: Vec<_> = tokens.iter().map(|(_, s)| (s.line, s.col)).collect();
+        assert_eq!(tokens[0].0, Token::Inc(2));
+        assert_eq!(spans, [(1, 1), (2, 3), (2, 4), (2, 5), (2, 6), (2, 7)]);
+    }
+
+    #[test]
+    fn skip_pragmas() {
+        let tokens = lex_spanned("#bfc tape-len = 10\n+ x\n#bfc newline = \"crlf\"\n.");
+        let spans: Vec<_> = tokens.iter().map(|(_, s)| (s.line, s.col)).collect();
+        assert_eq!(spans, [(2, 1), (4, 1)]);
+        // only at the start of a line
+        assert_eq!(lex_spanned(" #bfc -").len(), 1);
+
+        let src = "+ {#pragma no-opt}\n[-]{#pragma no-opt}.[-]{#pragma x-y} [-]";
+        assert_eq!(lex_spanned(src).len(), 11);
+        assert_eq!(no_opt_loops(src), [Span { line: 2, col: 1 }]);
+    }
+
+    #[test]
+    fn lex_gaps() {
+        let (tokens, gaps) = lex_trivia("init ++ two\n[ loop\n-] é");
+        assert_eq!(tokens.len(), 4);
+        let gap = |trailing: &str, leading: &str| Gap {
+            trailing: trailing.to_string(),
+            leading: leading.to_string(),
+        };
+        assert_eq!(
+            gaps,
+            [
+                gap("", "init "),
+                gap(" two", "\n"),
+                gap("", " loop\n"),
+                gap("", ""),
+                gap(" é", ""),
+            ]
+        );
+    }
+}
@@ -1,14 +1,1068 @@
-#![allow(dead_code)]
-#![allow(unused)]
+//! The compiling entry points never panic: malformed or hostile sources
+//! come back as an [`Error`], which is what embedders like online judges
+//! rely on.
+//!
+//! Embedders should reach for [`prelude`], the part of the API that stays
+//! put between releases.
 
+pub mod aarch64;
+#[cfg(feature = "aot")]
+pub mod aot;
+pub mod assume;
 pub mod ast;
+pub mod avr;
+#[doc(hidden)]
+pub mod backend;
+pub mod bytecode;
+pub mod c;
+pub mod combinator;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod cost;
+#[doc(hidden)]
+pub mod driver;
+#[doc(hidden)]
+pub mod elf;
+#[doc(hidden)]
+pub mod enumerate;
+#[doc(hidden)]
+pub mod fuzz;
+#[doc(hidden)]
+pub mod gdb;
 pub mod gen;
+#[doc(hidden)]
+pub mod header;
+pub mod idiom;
+pub mod il;
+#[doc(hidden)]
+pub mod image;
+pub mod interp;
+#[doc(hidden)]
+pub mod ir;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod js;
+pub mod jvm;
+#[doc(hidden)]
 pub mod lex;
+pub mod llvm;
+#[doc(hidden)]
+pub mod loops;
+pub mod lua;
+#[doc(hidden)]
+pub mod matrix;
+#[doc(hidden)]
+pub mod obj;
+pub mod opt;
+#[doc(hidden)]
+pub mod playground;
+pub mod prelude;
+pub mod python;
+#[doc(hidden)]
+pub mod recommend;
+#[doc(hidden)]
+pub mod reduce;
+#[doc(hidden)]
+pub mod rewrite;
+pub mod riscv;
+pub mod stamp;
+pub mod target;
+pub mod timing;
+pub mod validate;
+pub mod x86;
 
-pub fn compile(src: String) -> String {
-    let tokens = lex::lex(src);
-    let ast = ast::parse(&tokens).unwrap();
-    let buf = gen::QbeGenerator::new().gen(&ast).unwrap();
+use std::{collections::HashMap, fmt, str::FromStr};
 
-    buf
+use target::Target;
+use timing::Timings;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    Parse(ast::Error),
+    /// The options asked for something the chosen backend cannot do.
+    Unsupported(&'static str),
+    Object(object::write::Error),
+    /// The generated code does not do what the program does.
+    Validation(validate::Error),
+    Il(il::Error),
+    Bytecode(bytecode::Error),
+    /// Two sources would be compiled into functions of the same name.
+    DuplicateSymbol {
+        symbol: String,
+        first: String,
+        second: String,
+    },
+    /// Cranelift could not compile the program in process.
+    #[cfg(feature = "jit")]
+    Jit(String),
+    /// Cranelift could not build the object.
+    #[cfg(feature = "aot")]
+    Aot(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "parse error: {}", e),
+            Error::Unsupported(what) => write!(f, "unsupported: {}", what),
+            Error::Object(e) => write!(f, "could not write object: {}", e),
+            Error::Validation(e) => write!(f, "translation validation failed: {}", e),
+            Error::Il(e) => write!(f, "IL parse error: {}", e),
+            Error::Bytecode(e) => write!(f, "invalid bytecode: {}", e),
+            Error::DuplicateSymbol {
+                symbol,
+                first,
+                second,
+            } => write!(
+                f,
+                "`{}` and `{}` would both be compiled to `{}`",
+                first, second, symbol
+            ),
+            #[cfg(feature = "jit")]
+            Error::Jit(e) => write!(f, "JIT compilation failed: {}", e),
+            #[cfg(feature = "aot")]
+            Error::Aot(e) => write!(f, "Cranelift could not build the object: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ast::Error> for Error {
+    fn from(e: ast::Error) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<il::Error> for Error {
+    fn from(e: il::Error) -> Self {
+        Error::Il(e)
+    }
+}
+
+impl From<bytecode::Error> for Error {
+    fn from(e: bytecode::Error) -> Self {
+        Error::Bytecode(e)
+    }
+}
+
+/// How `.` and `,` translate line endings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Newline {
+    /// Bytes pass through untouched.
+    #[default]
+    Raw,
+    /// `\n` is written as `\r\n` and carriage returns are dropped on input.
+    Crlf,
+    /// CRLF on Windows, raw everywhere else.
+    Auto,
+}
+
+impl Newline {
+    /// Settles `Auto` for the platform the program is built on.
+    pub fn resolve(self) -> Newline {
+        match self {
+            Newline::Auto if cfg!(windows) => Newline::Crlf,
+            Newline::Auto => Newline::Raw,
+            n => n,
+        }
+    }
+}
+
+impl FromStr for Newline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Newline::Raw),
+            "crlf" => Ok(Newline::Crlf),
+            "auto" => Ok(Newline::Auto),
+            _ => Err(format!("expected raw, crlf or auto, found `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Newline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Newline::Raw => write!(f, "raw"),
+            Newline::Crlf => write!(f, "crlf"),
+            Newline::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Which code generator turns the tree into text for the toolchain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Backend {
+    /// QBE IL, see [`gen::QbeGenerator`].
+    #[default]
+    Qbe,
+    /// Textual LLVM IR for clang, see [`llvm::LlvmGenerator`].
+    Llvm,
+    /// x86-64 assembly for Linux, see [`x86::X86Generator`].
+    #[serde(rename = "x86-64-asm")]
+    X86_64Asm,
+    /// AArch64 assembly for Linux, see [`aarch64::Aarch64Generator`].
+    #[serde(rename = "aarch64-asm")]
+    Aarch64Asm,
+    /// RV64 assembly for Linux, see [`riscv::Rv64Generator`].
+    #[serde(rename = "rv64-asm")]
+    Rv64Asm,
+    /// AVR assembly for Arduino boards, see [`avr::AvrGenerator`].
+    #[serde(rename = "avr-asm")]
+    AvrAsm,
+    /// Portable C, see [`c::CGenerator`].
+    C,
+    /// An ES module, see [`js::JsGenerator`].
+    Js,
+    /// A Python 3 script, see [`python::PythonGenerator`].
+    Python,
+    /// A Lua chunk, see [`lua::LuaGenerator`].
+    Lua,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qbe" => Ok(Backend::Qbe),
+            "llvm" => Ok(Backend::Llvm),
+            "x86-64-asm" => Ok(Backend::X86_64Asm),
+            "aarch64-asm" => Ok(Backend::Aarch64Asm),
+            "rv64-asm" => Ok(Backend::Rv64Asm),
+            "avr-asm" => Ok(Backend::AvrAsm),
+            "c" => Ok(Backend::C),
+            "js" => Ok(Backend::Js),
+            "python" => Ok(Backend::Python),
+            "lua" => Ok(Backend::Lua),
+            _ => Err(format!(
+                "expected qbe, llvm, x86-64-asm, aarch64-asm, rv64-asm, avr-asm, c, js, python or lua, found `{}`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Qbe => write!(f, "qbe"),
+            Backend::Llvm => write!(f, "llvm"),
+            Backend::X86_64Asm => write!(f, "x86-64-asm"),
+            Backend::Aarch64Asm => write!(f, "aarch64-asm"),
+            Backend::Rv64Asm => write!(f, "rv64-asm"),
+            Backend::AvrAsm => write!(f, "avr-asm"),
+            Backend::C => write!(f, "c"),
+            Backend::Js => write!(f, "js"),
+            Backend::Python => write!(f, "python"),
+            Backend::Lua => write!(f, "lua"),
+        }
+    }
+}
+
+impl Backend {
+    /// How many bits a cell of the programs it builds holds.
+    pub fn cell_bits(self) -> u32 {
+        match self {
+            Backend::AvrAsm => 8,
+            _ => 32,
+        }
+    }
+}
+
+/// The function the qbe backend puts a program in instead of `main`, for
+/// linking it into a larger program: `int <symbol>(void)`, which runs the
+/// program on a fresh tape and returns the status `main` would have. An
+/// exported entry does its I/O through `<symbol>_read` and `<symbol>_write`,
+/// which the host provides.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub symbol: String,
+    pub linkage: Linkage,
+}
+
+/// Whether the entry function is seen outside the object it ends up in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Linkage {
+    #[default]
+    Export,
+    /// Only for code in the same IL, such as hand-written QBE built along
+    /// with it through `--from-qbe`.
+    Local,
+}
+
+impl FromStr for Linkage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "export" => Ok(Linkage::Export),
+            "local" => Ok(Linkage::Local),
+            _ => Err(format!("expected export or local, found `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Linkage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Linkage::Export => write!(f, "export"),
+            Linkage::Local => write!(f, "local"),
+        }
+    }
+}
+
+/// Periodic snapshots of the tape and pointer taken by the qbe backend's
+/// executables, which `--resume` on their command line carries on from.
+/// Snapshots are taken at the end of a loop iteration, so a run resumes
+/// at the same point, but with fresh input: what was read before is gone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Loop iterations between snapshots.
+    pub every: u64,
+    /// Where the snapshot is written, replacing the previous one only once
+    /// it is complete.
+    pub file: String,
+}
+
+impl FromStr for Checkpoint {
+    type Err = String;
+
+    /// `every:N,file:PATH`, the path taking the rest of the string.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut every, mut file) = (None, None);
+        let mut rest = s;
+        while !rest.is_empty() {
+            if let Some(path) = rest.strip_prefix("file:") {
+                file = Some(path.to_string());
+                break;
+            }
+            let (part, next) = rest.split_once(',').unwrap_or((rest, ""));
+            let Some(n) = part.strip_prefix("every:") else {
+                return Err(format!("expected every:N or file:PATH, found `{}`", part));
+            };
+            let n = n.strip_suffix("steps").unwrap_or(n);
+            every = match n.parse() {
+                Ok(0) | Err(_) => return Err(format!("invalid step count `{}`", n)),
+                Ok(n) => Some(n),
+            };
+            rest = next;
+        }
+        match (every, file) {
+            (Some(every), Some(file)) if !file.is_empty() => Ok(Checkpoint { every, file }),
+            _ => Err("expected every:N,file:PATH".to_string()),
+        }
+    }
+}
+
+/// Work the generated program does on its way out, whether it returns
+/// normally or halts on an error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AtExit {
+    /// `fflush` every stdio stream, for C code linked in alongside.
+    pub flush: bool,
+    /// Print the tape up to its last nonzero cell and where the pointer is
+    /// to stderr.
+    pub dump_tape: bool,
+    /// Print the exit status and how many bytes were read and written to
+    /// stderr.
+    pub stats: bool,
+    /// Print how often each value was written to a cell and each byte was
+    /// written to stdout to stderr.
+    pub histogram: bool,
+}
+
+impl AtExit {
+    pub fn any(self) -> bool {
+        self.flush || self.dump_tape || self.stats || self.histogram
+    }
+}
+
+impl FromStr for AtExit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut hooks = AtExit::default();
+        for hook in s.split(',').filter(|h| !h.is_empty()) {
+            match hook {
+                "flush" => hooks.flush = true,
+                "dump-tape" => hooks.dump_tape = true,
+                "stats" => hooks.stats = true,
+                "histogram" => hooks.histogram = true,
+                _ => {
+                    return Err(format!(
+                        "expected flush, dump-tape, stats or histogram, found `{}`",
+                        hook
+                    ))
+                }
+            }
+        }
+        Ok(hooks)
+    }
+}
+
+impl fmt::Display for AtExit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hooks = [
+            (self.flush, "flush"),
+            (self.dump_tape, "dump-tape"),
+            (self.stats, "stats"),
+            (self.histogram, "histogram"),
+        ];
+        let names: Vec<&str> = hooks
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// What the generated code is optimized for, which decides the passes run
+/// by default, see [`opt::Pass::level`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum OptLevel {
+    /// Straightforward translation, favouring speed where there is a choice.
+    #[default]
+    #[serde(rename = "0")]
+    O0,
+    /// Cheap local rewrites, folding runs and clearing cells in one go.
+    #[serde(rename = "1")]
+    O1,
+    /// Rewrites of whole loops and addressing relative to the pointer.
+    #[serde(rename = "2")]
+    O2,
+    /// Everything, including analyses that take a while on large programs.
+    #[serde(rename = "3")]
+    O3,
+    /// Smallest code, see [`cost`], with the passes of `-O2`.
+    #[serde(rename = "s")]
+    Size,
+}
+
+impl OptLevel {
+    pub const ALL: [OptLevel; 5] = [
+        OptLevel::O0,
+        OptLevel::O1,
+        OptLevel::O2,
+        OptLevel::O3,
+        OptLevel::Size,
+    ];
+
+    /// How far up the numbered levels this one is, for comparing against
+    /// the levels passes start at.
+    pub fn rank(self) -> u8 {
+        match self {
+            OptLevel::O0 => 0,
+            OptLevel::O1 => 1,
+            OptLevel::O2 | OptLevel::Size => 2,
+            OptLevel::O3 => 3,
+        }
+    }
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            "s" => Ok(OptLevel::Size),
+            _ => Err(format!("expected 0, 1, 2, 3 or s, found `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptLevel::O0 => write!(f, "0"),
+            OptLevel::O1 => write!(f, "1"),
+            OptLevel::O2 => write!(f, "2"),
+            OptLevel::O3 => write!(f, "3"),
+            OptLevel::Size => write!(f, "s"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CompileOptions {
+    /// Number of cells on the tape.
+    pub tape_len: usize,
+    /// Most bytes of stack the generated code may use, `None` for no limit.
+    /// Only the qbe backend keeps the tape on the stack, it moves it to the
+    /// heap when it would take more.
+    pub max_stack: Option<usize>,
+    /// Abort with a diagnostic when `.`/`,` hit an unexpected I/O error
+    /// instead of carrying on with whatever the cell held before.
+    pub io_strict: bool,
+    /// Never elide code trailing the last I/O of a program, see
+    /// [`opt::elide_dead_at_exit`].
+    pub keep_all_io: bool,
+    pub newline: Newline,
+    pub opt_level: OptLevel,
+    /// Name of the source file. When set, the generated IL carries
+    /// `dbgfile`/`dbgloc` directives mapping the code back to it.
+    pub debug_file: Option<String>,
+    /// Machine to generate code for, `None` leaves it to `qbe` and assumes
+    /// Linux conventions. The `bfc` driver defaults to [`Target::host`].
+    pub target: Option<Target>,
+    /// Compile every loop into a function of its own, named after where the
+    /// loop starts so profilers attribute samples to it.
+    pub split_loops: bool,
+    /// Most statements a loop may come to once [`opt::unroll_loops`]
+    /// unrolls it, 0 unrolling none.
+    pub unroll_budget: usize,
+    /// Most steps [`opt::partial_eval`] may run the program for while
+    /// compiling, 0 running none.
+    pub eval_fuel: u64,
+    pub backend: Backend,
+    pub atexit: AtExit,
+    /// Have [`compile_executable`] write a position independent executable,
+    /// loaded wherever the kernel likes, rather than one at a fixed address.
+    pub pie: bool,
+    /// Compare the generated QBE IL with the interpreter on a few inputs,
+    /// see [`validate`].
+    pub validate: bool,
+    /// Allocate the tape with `calloc` so that Valgrind's memcheck checks
+    /// accesses to it and massif counts it. The c backend also surrounds it
+    /// with redzones it marks inaccessible when built against
+    /// `<valgrind/memcheck.h>`.
+    pub memcheck: bool,
+    /// Generate a function to call rather than `main`, see [`Entry`].
+    pub entry: Option<Entry>,
+    pub checkpoint: Option<Checkpoint>,
+    /// Have entry functions run on their caller's tape rather than one of
+    /// their own, as `int <symbol>(bf_cell *tape, size_t *pos)`: the
+    /// program starts at cell `*pos` of a tape of `tape_len` cells, each
+    /// [`gen::CELL_STRIDE`] bytes with the value in the first 4, and the
+    /// cell it ends on is stored back in `*pos`. The function returns the
+    /// exit status, 1 when the program moved off the tape.
+    pub shared_tape: bool,
+    /// The optimization passes to run, in order, instead of the default
+    /// pipeline, see [`opt`].
+    pub passes: Option<opt::Pipeline>,
+    /// Passes switched on or off on top of [`CompileOptions::passes`] or
+    /// the default pipeline, later ones winning.
+    pub toggles: Vec<opt::Toggle>,
+    /// Idioms the `idioms` pass recognizes on top of [`idiom::builtin`],
+    /// tried in order after those.
+    pub idioms: Vec<idiom::Idiom>,
+    /// End the output with a [`stamp::Stamp`] of the compiler version,
+    /// options and source.
+    pub stamp: bool,
+    /// Leave out the bounds checks of moves, so that a program moving off
+    /// the tape reads and writes whatever memory is there rather than
+    /// ending with status 1. [`assume::assumptions`] lists what the program
+    /// has to hold to instead.
+    pub unchecked: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            tape_len: 30_000,
+            max_stack: None,
+            io_strict: false,
+            keep_all_io: false,
+            newline: Newline::default(),
+            opt_level: OptLevel::default(),
+            debug_file: None,
+            target: None,
+            split_loops: false,
+            unroll_budget: 64,
+            eval_fuel: 1_000_000,
+            backend: Backend::default(),
+            atexit: AtExit::default(),
+            pie: true,
+            validate: false,
+            memcheck: false,
+            entry: None,
+            checkpoint: None,
+            shared_tape: false,
+            passes: None,
+            toggles: Vec::new(),
+            idioms: Vec::new(),
+            stamp: false,
+            unchecked: false,
+        }
+    }
+}
+
+impl CompileOptions {
+    /// The defaults, generating code for the machine running the compiler
+    /// as `bfc` does.
+    pub fn host() -> Self {
+        CompileOptions {
+            target: Target::host(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether anything looks at the tape once the program is done: the
+    /// caller of an entry on a shared tape, or exit hooks dumping the tape
+    /// or counting what was written to it. Passes dropping work nobody
+    /// sees leave the tape of such programs alone.
+    pub fn tape_seen_after_exit(&self) -> bool {
+        self.shared_tape || self.atexit.dump_tape || self.atexit.histogram
+    }
+}
+
+pub fn compile(src: String) -> Result<String, Error> {
+    compile_with(src, &CompileOptions::default())
+}
+
+/// Compiles several named programs into one executable sharing a single
+/// tape, see [`gen::QbeGenerator::gen_modules`] for the calling convention.
+pub fn compile_modules(
+    srcs: Vec<(String, String)>,
+    opts: &CompileOptions,
+) -> Result<String, Error> {
+    compile_modules_timed(srcs, opts, &mut Timings::default())
+}
+
+pub fn compile_modules_timed(
+    srcs: Vec<(String, String)>,
+    opts: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<String, Error> {
+    if opts.backend != Backend::Qbe {
+        return Err(Error::Unsupported("modules need the qbe backend"));
+    }
+    if opts.validate {
+        return Err(Error::Unsupported("modules are not validated"));
+    }
+    if opts.shared_tape {
+        // they halt with `exit`, the tape is never handed back
+        return Err(Error::Unsupported("modules cannot run on a shared tape"));
+    }
+    if opts.checkpoint.is_some() {
+        return Err(Error::Unsupported("checkpoints need every loop in `main`"));
+    }
+    if opts.stamp {
+        return Err(Error::Unsupported("stamps name a single source"));
+    }
+    check_target(opts)?;
+    check_symbols(
+        srcs.iter()
+            .map(|(name, _)| (name, gen::module_symbol(name))),
+    )?;
+    let progs = srcs
+        .into_iter()
+        .map(|(name, src)| Ok((name, parse_timed(&src, timings)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let mut gen = gen::QbeGenerator::with_options(opts)?;
+    let il = timings.time("codegen", || gen.gen_modules(&progs))?;
+    Ok(il)
+}
+
+/// Fails on two sources, given by name along with the symbol of their
+/// function, having the same symbol.
+fn check_symbols<'a>(names: impl Iterator<Item = (&'a String, String)>) -> Result<(), Error> {
+    let mut seen: HashMap<String, &String> = HashMap::new();
+    for (name, symbol) in names {
+        if let Some(first) = seen.insert(symbol.clone(), name) {
+            return Err(Error::DuplicateSymbol {
+                symbol,
+                first: first.clone(),
+                second: name.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Compiles several independent programs into one QBE module, each into an
+/// `int <symbol>(void)` function with a tape of its own, see
+/// [`gen::QbeGenerator::gen_functions`]. `srcs` pairs each source with the
+/// symbol it is generated as.
+pub fn compile_functions(
+    srcs: Vec<(String, String)>,
+    opts: &CompileOptions,
+) -> Result<String, Error> {
+    compile_functions_timed(srcs, opts, &mut Timings::default())
+}
+
+pub fn compile_functions_timed(
+    srcs: Vec<(String, String)>,
+    opts: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<String, Error> {
+    if opts.backend != Backend::Qbe {
+        return Err(Error::Unsupported("functions need the qbe backend"));
+    }
+    if opts.validate {
+        return Err(Error::Unsupported("functions are not validated"));
+    }
+    if opts.entry.is_some() {
+        return Err(Error::Unsupported(
+            "functions are named after their sources",
+        ));
+    }
+    if opts.checkpoint.is_some() {
+        return Err(Error::Unsupported("checkpoints need every loop in `main`"));
+    }
+    if opts.stamp {
+        return Err(Error::Unsupported("stamps name a single source"));
+    }
+    check_target(opts)?;
+    check_symbols(srcs.iter().map(|(symbol, _)| (symbol, symbol.clone())))?;
+    let progs = srcs
+        .into_iter()
+        .map(|(symbol, src)| Ok((symbol, optimize_timed(&src, opts, timings)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let mut gen = gen::QbeGenerator::with_options(opts)?;
+    let il = timings.time("codegen", || gen.gen_functions(&progs))?;
+    Ok(il)
+}
+
+/// Parses a program, recording source spans on its statements.
+pub fn parse(src: &str) -> Result<ast::Ast, Error> {
+    parse_timed(src, &mut Timings::default())
+}
+
+/// Reports every syntax error in a program, as fast as possible: no tree is
+/// built.
+pub fn check(src: &str) -> Vec<ast::Diagnostic> {
+    ast::check(&lex::lex_spanned(src))
+}
+
+/// Like [`parse`], also keeping the comments around every node as
+/// [`ast::Trivia`], for tools that have to give them back.
+pub fn parse_with_trivia(src: &str) -> Result<ast::Ast, Error> {
+    let (tokens, gaps) = lex::lex_trivia(src);
+    let (tokens, spans): (Vec<_>, Vec<_>) = tokens.into_iter().unzip();
+    let mut ast = ast::parse(&tokens)?;
+    ast::attach_spans(&mut ast, &spans);
+    ast::attach_no_opt(&mut ast, &lex::no_opt_loops(src));
+    ast::attach_trivia(&mut ast, gaps);
+    ast::attach_metrics(&mut ast);
+    Ok(ast)
+}
+
+fn parse_timed(src: &str, timings: &mut Timings) -> Result<ast::Ast, Error> {
+    let ((tokens, spans), no_opt): ((Vec<_>, Vec<_>), _) = timings.time("lex", || {
+        let tokens = lex::lex_spanned(src).into_iter().unzip();
+        (tokens, lex::no_opt_loops(src))
+    });
+    timings.time("parse", || {
+        let mut ast = ast::parse(&tokens)?;
+        ast::attach_spans(&mut ast, &spans);
+        ast::attach_no_opt(&mut ast, &no_opt);
+        ast::attach_metrics(&mut ast);
+        Ok(ast)
+    })
+}
+
+/// The loop trees of the given programs, laid out the way
+/// [`compile_modules`] would when there is more than one of them.
+pub fn loop_trees(srcs: &[(String, String)]) -> Result<Vec<loops::LoopTree>, Error> {
+    match srcs {
+        [(_, src)] => Ok(vec![loops::LoopTree::program(&parse(src)?)]),
+        srcs => srcs
+            .iter()
+            .map(|(name, src)| Ok(loops::LoopTree::module(name, &parse(src)?)))
+            .collect(),
+    }
+}
+
+/// Parses and optimizes a program the way [`compile_with`] does before
+/// generating code for it.
+pub fn optimize(src: &str, opts: &CompileOptions) -> Result<ast::Ast, Error> {
+    optimize_timed(src, opts, &mut Timings::default())
+}
+
+fn optimize_timed(
+    src: &str,
+    opts: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<ast::Ast, Error> {
+    let mut ast = parse_timed(src, timings)?;
+    opt::PassManager::for_options(opts).run(&mut ast, opts, timings);
+    Ok(ast)
+}
+
+pub fn compile_with(src: String, opts: &CompileOptions) -> Result<String, Error> {
+    compile_timed(src, opts, &mut Timings::default())
+}
+
+/// Like [`compile_with`], recording how long each phase took.
+pub fn compile_timed(
+    src: String,
+    opts: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<String, Error> {
+    check_target(opts)?;
+    let ast = optimize_timed(&src, opts, timings)?;
+    let mut gen = backend::generator(opts)?;
+    let out = timings.time("codegen", || gen.generate(&ast))?;
+    if opts.backend == Backend::Qbe && opts.validate {
+        validate::validate(&ast, &out, opts.tape_len).map_err(Error::Validation)?;
+    }
+    if opts.stamp {
+        return Ok(stamp::embed(out, &stamp::Stamp::new(&src, opts), opts));
+    }
+    Ok(out)
+}
+
+/// Rejects targets the chosen backend's runtime was not written for.
+fn check_target(opts: &CompileOptions) -> Result<(), Error> {
+    match (opts.backend, opts.target) {
+        (Backend::Qbe | Backend::Llvm, Some(t)) if t.is_windows() => Err(Error::Unsupported(
+            "only the x86-64 and c backends generate code for Windows",
+        )),
+        (backend, _) if backend != Backend::Qbe && opts.atexit.any() => {
+            Err(Error::Unsupported("only the qbe backend runs exit hooks"))
+        }
+        (backend, _) if backend != Backend::Qbe && opts.validate => Err(Error::Unsupported(
+            "only the qbe backend's output is validated",
+        )),
+        (backend, _) if opts.memcheck && !matches!(backend, Backend::Qbe | Backend::C) => Err(
+            Error::Unsupported("only the qbe and c backends allocate the tape for memcheck"),
+        ),
+        (backend, _) if opts.unchecked && !matches!(backend, Backend::Qbe | Backend::C) => Err(
+            Error::Unsupported("only the qbe and c backends leave out bounds checks"),
+        ),
+        (backend, _) if backend != Backend::Qbe && opts.entry.is_some() => Err(Error::Unsupported(
+            "only the qbe backend names the function it generates",
+        )),
+        (backend, _) if backend != Backend::Qbe && opts.checkpoint.is_some() => {
+            Err(Error::Unsupported("only the qbe backend takes checkpoints"))
+        }
+        (backend, _) if backend != Backend::Qbe && opts.shared_tape => Err(Error::Unsupported(
+            "only the qbe backend generates functions taking a tape",
+        )),
+        // the IL interpreter starts at `main`
+        _ if opts.validate && opts.entry.is_some() => Err(Error::Unsupported(
+            "validation runs `main`, which an entry function replaces",
+        )),
+        _ if opts.validate && opts.shared_tape => Err(Error::Unsupported(
+            "validation runs `main`, which has a tape of its own",
+        )),
+        _ if opts.validate && opts.checkpoint.is_some() => {
+            Err(Error::Unsupported("validation cannot write checkpoints"))
+        }
+        // resuming jumps back into the loop the snapshot was taken in
+        _ if opts.checkpoint.is_some() && (opts.split_loops || opts.entry.is_some()) => {
+            Err(Error::Unsupported("checkpoints need every loop in `main`"))
+        }
+        // the interpreter only knows raw bytes
+        _ if opts.validate && opts.newline.resolve() == Newline::Crlf => {
+            Err(Error::Unsupported("validation needs raw newlines"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Rejects the options only the qbe backend implements, for the entry
+/// points generating code some other way.
+fn check_qbe_only(opts: &CompileOptions) -> Result<(), Error> {
+    if opts.atexit.any() {
+        return Err(Error::Unsupported("only the qbe backend runs exit hooks"));
+    }
+    if opts.memcheck {
+        return Err(Error::Unsupported(
+            "only the qbe and c backends allocate the tape for memcheck",
+        ));
+    }
+    if opts.entry.is_some() {
+        return Err(Error::Unsupported(
+            "only the qbe backend names the function it generates",
+        ));
+    }
+    if opts.checkpoint.is_some() {
+        return Err(Error::Unsupported("only the qbe backend takes checkpoints"));
+    }
+    if opts.shared_tape {
+        return Err(Error::Unsupported(
+            "only the qbe backend generates functions taking a tape",
+        ));
+    }
+    if opts.unchecked {
+        return Err(Error::Unsupported(
+            "only the qbe and c backends leave out bounds checks",
+        ));
+    }
+    if opts.validate {
+        return Err(Error::Unsupported(
+            "only the qbe backend's output is validated",
+        ));
+    }
+    Ok(())
+}
+
+/// Compiles a program straight to a static Linux x86-64 executable, see
+/// [`elf`].
+pub fn compile_executable(src: String, opts: &CompileOptions) -> Result<Vec<u8>, Error> {
+    compile_executable_timed(src, opts, &mut Timings::default())
+}
+
+pub fn compile_executable_timed(
+    src: String,
+    opts: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<Vec<u8>, Error> {
+    if opts.target.is_some_and(|t| t.is_windows() || t.is_apple()) {
+        return Err(Error::Unsupported(
+            "only Linux executables are written directly",
+        ));
+    }
+    check_qbe_only(opts)?;
+    let ast = optimize_timed(&src, opts, timings)?;
+    let mut gen = x86::X86Generator::with_options(opts)?;
+    let code = timings.time("codegen", || gen.encode(&ast));
+    Ok(timings.time("link", || elf::executable(&code, gen.tape_size(), opts.pie)))
+}
+
+/// Compiles a program to a JVM class named `class`, see [`jvm`].
+pub fn compile_class(src: String, opts: &CompileOptions, class: &str) -> Result<Vec<u8>, Error> {
+    compile_class_timed(src, opts, class, &mut Timings::default())
+}
+
+pub fn compile_class_timed(
+    src: String,
+    opts: &CompileOptions,
+    class: &str,
+    timings: &mut Timings,
+) -> Result<Vec<u8>, Error> {
+    check_qbe_only(opts)?;
+    let ast = optimize_timed(&src, opts, timings)?;
+    let mut gen = jvm::JvmGenerator::with_options(class, opts);
+    Ok(timings.time("codegen", || gen.gen(&ast)))
+}
+
+/// Parses and optimizes a program into bytecode for `bfc run`, see
+/// [`bytecode`].
+pub fn compile_bytecode(src: &str, opts: &CompileOptions) -> Result<Vec<u8>, Error> {
+    let ast = optimize(src, opts)?;
+    Ok(bytecode::encode(&ast, opts.tape_len))
+}
+
+/// Compiles a program to an x86-64 object for the platform linker, see
+/// [`obj`].
+pub fn compile_object(src: String, opts: &CompileOptions) -> Result<Vec<u8>, Error> {
+    compile_object_timed(src, opts, &mut Timings::default())
+}
+
+pub fn compile_object_timed(
+    src: String,
+    opts: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<Vec<u8>, Error> {
+    check_qbe_only(opts)?;
+    let ast = optimize_timed(&src, opts, timings)?;
+    let mut gen = x86::X86Generator::with_options(opts)?;
+    let code = timings.time("codegen", || gen.encode(&ast));
+    let format = match opts.target {
+        Some(t) if t.is_windows() => object::BinaryFormat::Coff,
+        Some(t) if t.is_apple() => object::BinaryFormat::MachO,
+        _ => object::BinaryFormat::Elf,
+    };
+    timings
+        .time("object", || {
+            obj::object(&code, gen.entry(), gen.tape_size(), format)
+        })
+        .map_err(Error::Object)
+}
+
+/// Compiles a program to an object for the host with Cranelift, see
+/// [`aot`].
+#[cfg(feature = "aot")]
+pub fn compile_object_cranelift(src: String, opts: &CompileOptions) -> Result<Vec<u8>, Error> {
+    compile_object_cranelift_timed(src, opts, &mut Timings::default())
+}
+
+#[cfg(feature = "aot")]
+pub fn compile_object_cranelift_timed(
+    src: String,
+    opts: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<Vec<u8>, Error> {
+    check_qbe_only(opts)?;
+    let ast = optimize_timed(&src, opts, timings)?;
+    timings.time("codegen", || aot::object(&ast, opts))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// xorshift, good enough to shake out panics without pulling in a crate
+    fn rng(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    #[test]
+    fn never_panics_on_random_input() {
+        let alphabet: Vec<char> = "<>+-.,[]]x \n#é".chars().collect();
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..2000 {
+            let len = rng(&mut seed) % 64;
+            let src: String = (0..len)
+                .map(|_| alphabet[(rng(&mut seed) % alphabet.len() as u64) as usize])
+                .collect();
+            assert_eq!(check(&src).is_empty(), parse(&src).is_ok(), "{:?}", src);
+            let _ = compile(src.clone());
+            let _ = compile_modules(vec![("a".to_string(), src)], &CompileOptions::default());
+        }
+    }
+
+    #[test]
+    fn never_panics_on_hostile_input() {
+        let deep = "[".repeat(1 << 20) + &"]".repeat(1 << 20);
+        assert!(compile(deep).is_err());
+        let limit = "[".repeat(ast::MAX_NESTING) + &"]".repeat(ast::MAX_NESTING);
+        assert!(compile(limit).is_ok());
+        assert!(compile("]".repeat(10)).is_err());
+        assert!(compile("[".repeat(10)).is_err());
+        assert!(compile(String::new()).is_ok());
+        assert!(compile(">".repeat(1 << 16)).is_ok());
+    }
+
+    #[test]
+    fn direct_output_rejects_what_it_cannot_do() {
+        for opts in [
+            CompileOptions {
+                unchecked: true,
+                ..Default::default()
+            },
+            CompileOptions {
+                validate: true,
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                compile_executable("+.".to_string(), &opts),
+                Err(Error::Unsupported(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn modules_need_distinct_symbols() {
+        let srcs = vec![
+            ("a-b".to_string(), "+".to_string()),
+            ("a_b".to_string(), "-".to_string()),
+        ];
+        let e = compile_modules(srcs, &CompileOptions::default()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "`a-b` and `a_b` would both be compiled to `bf_a_b`"
+        );
+    }
 }
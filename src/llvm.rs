@@ -0,0 +1,477 @@
+//! Textual LLVM IR generator, an alternative to QBE for those who have
+//! clang at hand and want its optimizer. The runtime behaviour matches
+//! [`crate::gen::QbeGenerator`]: 32 bit cells, `,` at end of input leaves
+//! the cell alone, I/O retried on `EINTR`, and moving off the tape exits
+//! with status 1.
+
+use std::fmt::Write;
+
+use crate::{ast, backend::CodeGen, CompileOptions, Error, Newline};
+
+// Linux and macOS agree on this one.
+const EINTR: u32 = 4;
+
+const READ_ERR: &[u8] = b"bfc: read error\n";
+const WRITE_ERR: &[u8] = b"bfc: write error\n";
+
+// metadata ids taken by the compile unit, file, flags and `main`, locations
+// follow
+const DBG_FIRST_LOC: usize = 6;
+
+pub struct LlvmGenerator {
+    label_counter: usize,
+    tmp_counter: usize,
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    errno_location: &'static str,
+    triple: Option<&'static str>,
+    debug_file: Option<String>,
+    // source locations referenced so far, `!dbg` ids counting from
+    // `DBG_FIRST_LOC`
+    locs: Vec<(usize, usize)>,
+    loc: Option<usize>,
+    body: String,
+}
+
+impl Default for LlvmGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for LlvmGenerator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl LlvmGenerator {
+    pub fn new() -> Self {
+        LlvmGenerator {
+            label_counter: 0,
+            tmp_counter: 0,
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            errno_location: "__errno_location",
+            triple: None,
+            debug_file: None,
+            locs: Vec::new(),
+            loc: None,
+            body: String::new(),
+        }
+    }
+
+    pub fn with_options(opts: &CompileOptions) -> Self {
+        LlvmGenerator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            errno_location: opts
+                .target
+                .map_or("__errno_location", |t| t.errno_location()),
+            triple: opts.target.map(|t| t.triple()),
+            debug_file: opts.debug_file.clone(),
+            ..Self::new()
+        }
+    }
+
+    pub fn gen(&mut self, prog: &ast::Prog) -> String {
+        if self.debug_file.is_some() {
+            self.set_loc(1, 1);
+        }
+        self.body.push_str("entry:\n");
+        self.instr("%ptr = alloca ptr");
+        self.instr("%buf = alloca i8");
+        self.instr("store ptr @tape, ptr %ptr");
+        self.generate_block(prog);
+        self.instr("ret i32 0");
+
+        let mut out = String::new();
+        if let Some(triple) = self.triple {
+            writeln!(out, "target triple = \"{}\"\n", triple).unwrap();
+        }
+        writeln!(
+            out,
+            "@tape = internal global [{} x i32] zeroinitializer",
+            self.tape_len
+        )
+        .unwrap();
+        if self.io_strict {
+            for (name, msg) in [("io_read_err", READ_ERR), ("io_write_err", WRITE_ERR)] {
+                writeln!(
+                    out,
+                    "@{} = private unnamed_addr constant [{} x i8] c\"{}\"",
+                    name,
+                    msg.len(),
+                    escape(msg)
+                )
+                .unwrap();
+            }
+        }
+        writeln!(out).unwrap();
+        writeln!(out, "declare i64 @read(i32, ptr, i64)").unwrap();
+        writeln!(out, "declare i64 @write(i32, ptr, i64)").unwrap();
+        writeln!(out, "declare ptr @{}()\n", self.errno_location).unwrap();
+
+        let dbg = match self.debug_file {
+            Some(_) => " !dbg !4",
+            None => "",
+        };
+        writeln!(out, "define i32 @main(){} {{\n{}}}", dbg, self.body).unwrap();
+        if let Some(file) = &self.debug_file {
+            self.generate_debug_info(&mut out, file);
+        }
+        out
+    }
+
+    /// Compile unit, file and subprogram for `main`, followed by the
+    /// locations its instructions point at, so profiles and debuggers map
+    /// the code back to source lines.
+    fn generate_debug_info(&self, out: &mut String, file: &str) {
+        let file = escape(file.as_bytes());
+        out.push_str(&format!(
+            "\n!llvm.dbg.cu = !{{!0}}\n\
+             !llvm.module.flags = !{{!1, !3}}\n\
+             !0 = distinct !DICompileUnit(language: DW_LANG_C99, file: !2, producer: \"bfc\", \
+             isOptimized: false, runtimeVersion: 0, emissionKind: LineTablesOnly)\n\
+             !1 = !{{i32 2, !\"Debug Info Version\", i32 3}}\n\
+             !2 = !DIFile(filename: \"{}\", directory: \"\")\n\
+             !3 = !{{i32 2, !\"Dwarf Version\", i32 4}}\n\
+             !4 = distinct !DISubprogram(name: \"main\", scope: !2, file: !2, line: 1, \
+             type: !5, scopeLine: 1, spFlags: DISPFlagDefinition, unit: !0)\n",
+            file
+        ));
+        out.push_str("!5 = !DISubroutineType(types: !{})\n");
+        for (i, (line, col)) in self.locs.iter().enumerate() {
+            out.push_str(&format!(
+                "!{} = !DILocation(line: {}, column: {}, scope: !4)\n",
+                DBG_FIRST_LOC + i,
+                line,
+                col
+            ));
+        }
+    }
+
+    fn set_loc(&mut self, line: usize, col: usize) {
+        let i = match self.locs.iter().position(|l| *l == (line, col)) {
+            Some(i) => i,
+            None => {
+                self.locs.push((line, col));
+                self.locs.len() - 1
+            }
+        };
+        self.loc = Some(DBG_FIRST_LOC + i);
+    }
+
+    fn generate_block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.generate_statement(stat)
+        }
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) {
+        if self.debug_file.is_some() {
+            self.set_loc(stat.attr.span.line, stat.attr.span.col);
+        }
+
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move((*n as u64).wrapping_neg() as i64),
+            ast::Statement::MoveR(n) => self.generate_move(*n as u64 as i64),
+            ast::Statement::Add(n) => self.generate_add(*n as u32),
+            ast::Statement::Sub(n) => self.generate_add((*n as u32).wrapping_neg()),
+            ast::Statement::Set(n) => {
+                let ptr = self.assign("load ptr, ptr %ptr");
+                self.instr(&format!("store i32 {}, ptr {}", *n as i32, ptr));
+            }
+            ast::Statement::MulAdd { offset, factor } => {
+                // there and back like moves, to check the other cell
+                let cell = self.load_cell();
+                let product = self.assign(&format!("mul i32 {}, {}", cell, *factor as i32));
+                self.generate_move(*offset as i64);
+                let ptr = self.assign("load ptr, ptr %ptr");
+                let val = self.assign(&format!("load i32, ptr {}", ptr));
+                let sum = self.assign(&format!("add i32 {}, {}", val, product));
+                self.instr(&format!("store i32 {}, ptr {}", sum, ptr));
+                self.generate_move(-(*offset as i64));
+            }
+            ast::Statement::Read => self.generate_read(),
+            ast::Statement::Write => self.generate_write(),
+            ast::Statement::Scan(stride) => {
+                let head = self.generate_label("scan");
+                let inner = self.generate_label("step");
+                let end = self.generate_label("scanned");
+                self.instr(&format!("br label %{}", head));
+                self.block(&head);
+                let cell = self.load_cell();
+                let nonzero = self.assign(&format!("icmp ne i32 {}, 0", cell));
+                self.instr(&format!(
+                    "br i1 {}, label %{}, label %{}",
+                    nonzero, inner, end
+                ));
+                self.block(&inner);
+                self.generate_move(*stride as i64);
+                self.instr(&format!("br label %{}", head));
+                self.block(&end);
+            }
+            ast::Statement::Loop(body) => {
+                let head = self.generate_label("loop");
+                let inner = self.generate_label("body");
+                let end = self.generate_label("end");
+                self.instr(&format!("br label %{}", head));
+                self.block(&head);
+                let cell = self.load_cell();
+                let nonzero = self.assign(&format!("icmp ne i32 {}, 0", cell));
+                self.instr(&format!(
+                    "br i1 {}, label %{}, label %{}",
+                    nonzero, inner, end
+                ));
+                self.block(&inner);
+                self.generate_block(body);
+                self.instr(&format!("br label %{}", head));
+                self.block(&end);
+            }
+            ast::Statement::If(body) => {
+                let inner = self.generate_label("then");
+                let end = self.generate_label("fi");
+                let cell = self.load_cell();
+                let nonzero = self.assign(&format!("icmp ne i32 {}, 0", cell));
+                self.instr(&format!(
+                    "br i1 {}, label %{}, label %{}",
+                    nonzero, inner, end
+                ));
+                self.block(&inner);
+                self.generate_block(body);
+                self.instr(&format!("br label %{}", end));
+                self.block(&end);
+            }
+            // there is no data to copy from, see `spell_out_fill`
+            ast::Statement::Fill(values) => self.generate_block(&ast::spell_out_fill(values)),
+            // nor any to write from, see `spell_out_print`
+            ast::Statement::Print(bytes) => self.generate_block(&ast::spell_out_print(bytes)),
+        }
+    }
+
+    fn generate_add(&mut self, n: u32) {
+        let ptr = self.assign("load ptr, ptr %ptr");
+        let val = self.assign(&format!("load i32, ptr {}", ptr));
+        let sum = self.assign(&format!("add i32 {}, {}", val, n as i32));
+        self.instr(&format!("store i32 {}, ptr {}", sum, ptr));
+    }
+
+    /// Moves the pointer by `n` cells and exits with status 1 when it ends up
+    /// off the tape.
+    fn generate_move(&mut self, n: i64) {
+        let ptr = self.assign("load ptr, ptr %ptr");
+        let moved = self.assign(&format!("getelementptr i32, ptr {}, i64 {}", ptr, n));
+        self.instr(&format!("store ptr {}, ptr %ptr", moved));
+
+        let addr = self.assign(&format!("ptrtoint ptr {} to i64", moved));
+        let base = self.assign("ptrtoint ptr @tape to i64");
+        let offset = self.assign(&format!("sub i64 {}, {}", addr, base));
+        let in_bounds = self.assign(&format!(
+            "icmp ult i64 {}, {}",
+            offset,
+            self.tape_len as u64 * 4
+        ));
+        let cont = self.generate_label("cont");
+        let halt = self.generate_label("halt");
+        self.instr(&format!(
+            "br i1 {}, label %{}, label %{}",
+            in_bounds, cont, halt
+        ));
+        self.block(&halt);
+        self.instr("ret i32 1");
+        self.block(&cont);
+    }
+
+    /// Writes the current cell, preceded by a carriage return when it holds a
+    /// newline and CRLF translation is on.
+    fn generate_write(&mut self) {
+        let cell = self.load_cell();
+        let byte = self.assign(&format!("trunc i32 {} to i8", cell));
+        if self.crlf {
+            let cr = self.generate_label("cr");
+            let write = self.generate_label("write");
+            let is_nl = self.assign(&format!("icmp eq i8 {}, 10", byte));
+            self.instr(&format!("br i1 {}, label %{}, label %{}", is_nl, cr, write));
+            self.block(&cr);
+            self.instr("store i8 13, ptr %buf");
+            self.generate_io(Io::Write);
+            self.instr(&format!("br label %{}", write));
+            self.block(&write);
+        }
+        self.instr(&format!("store i8 {}, ptr %buf", byte));
+        self.generate_io(Io::Write);
+    }
+
+    /// Reads a byte into the current cell, skipping carriage returns under
+    /// CRLF translation and leaving the cell alone at end of input.
+    fn generate_read(&mut self) {
+        self.generate_io(Io::Read);
+    }
+
+    /// One byte of I/O through `%buf`, retried on `EINTR` and short writes.
+    /// Continues in a fresh block once done.
+    fn generate_io(&mut self, io: Io) {
+        let retry = self.generate_label("io");
+        let check = self.generate_label("iochk");
+        let errno = self.generate_label("ioerrno");
+        let fail = self.generate_label("iofail");
+        let success = self.generate_label("iook");
+        let done = self.generate_label("iodone");
+
+        self.instr(&format!("br label %{}", retry));
+        self.block(&retry);
+        let (func, fd) = match io {
+            Io::Read => ("read", 0),
+            Io::Write => ("write", 1),
+        };
+        let res = self.assign(&format!("call i64 @{}(i32 {}, ptr %buf, i64 1)", func, fd));
+        let ok = self.assign(&format!("icmp eq i64 {}, 1", res));
+        self.instr(&format!(
+            "br i1 {}, label %{}, label %{}",
+            ok, success, check
+        ));
+
+        self.block(&check);
+        let failed = self.assign(&format!("icmp slt i64 {}, 0", res));
+        // nothing transferred: EOF for reads, a short write to be retried otherwise
+        let zero = match io {
+            Io::Read => &done,
+            Io::Write => &retry,
+        };
+        self.instr(&format!(
+            "br i1 {}, label %{}, label %{}",
+            failed, errno, zero
+        ));
+
+        self.block(&errno);
+        let errno_ptr = self.assign(&format!("call ptr @{}()", self.errno_location));
+        let errno_val = self.assign(&format!("load i32, ptr {}", errno_ptr));
+        let interrupted = self.assign(&format!("icmp eq i32 {}, {}", errno_val, EINTR));
+        self.instr(&format!(
+            "br i1 {}, label %{}, label %{}",
+            interrupted, retry, fail
+        ));
+
+        self.block(&fail);
+        if self.io_strict {
+            let (name, len) = match io {
+                Io::Read => ("io_read_err", READ_ERR.len()),
+                Io::Write => ("io_write_err", WRITE_ERR.len()),
+            };
+            self.assign(&format!(
+                "call i64 @write(i32 2, ptr @{}, i64 {})",
+                name, len
+            ));
+            self.instr("ret i32 1");
+        } else {
+            self.instr(&format!("br label %{}", done));
+        }
+
+        self.block(&success);
+        if let Io::Read = io {
+            let byte = self.assign("load i8, ptr %buf");
+            if self.crlf {
+                let store = self.generate_label("iostore");
+                let is_cr = self.assign(&format!("icmp eq i8 {}, 13", byte));
+                self.instr(&format!(
+                    "br i1 {}, label %{}, label %{}",
+                    is_cr, retry, store
+                ));
+                self.block(&store);
+            }
+            let val = self.assign(&format!("zext i8 {} to i32", byte));
+            let ptr = self.assign("load ptr, ptr %ptr");
+            self.instr(&format!("store i32 {}, ptr {}", val, ptr));
+        }
+        self.instr(&format!("br label %{}", done));
+        self.block(&done);
+    }
+
+    fn load_cell(&mut self) -> String {
+        let ptr = self.assign("load ptr, ptr %ptr");
+        self.assign(&format!("load i32, ptr {}", ptr))
+    }
+
+    fn instr(&mut self, instr: &str) {
+        match self.loc {
+            Some(loc) => writeln!(self.body, "  {}, !dbg !{}", instr, loc),
+            None => writeln!(self.body, "  {}", instr),
+        }
+        .unwrap();
+    }
+
+    /// Emits `instr` into a fresh temporary, returning its name.
+    fn assign(&mut self, instr: &str) -> String {
+        let tmp = format!("%v{}", self.tmp_counter);
+        self.tmp_counter += 1;
+        self.instr(&format!("{} = {}", tmp, instr));
+        tmp
+    }
+
+    fn block(&mut self, label: &str) {
+        writeln!(self.body, "{}:", label).unwrap();
+    }
+
+    fn generate_label(&mut self, prefix: &str) -> String {
+        let c = self.label_counter;
+        self.label_counter += 1;
+        format!("{}{}", prefix, c)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Io {
+    Read,
+    Write,
+}
+
+/// Escapes bytes for an LLVM string literal.
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| match b {
+            b' '..=b'~' if *b != b'"' && *b != b'\\' => (*b as char).to_string(),
+            _ => format!("\\{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gen(src: &str, opts: &CompileOptions) -> String {
+        let ast = crate::parse(src).unwrap();
+        LlvmGenerator::with_options(opts).gen(&ast)
+    }
+
+    #[test]
+    fn tape_and_io() {
+        let out = gen("+[->,.<]", &CompileOptions::default());
+        assert!(out.contains("@tape = internal global [30000 x i32] zeroinitializer"));
+        assert!(out.contains("call i64 @read(i32 0, ptr %buf, i64 1)"));
+        assert!(out.contains("call i64 @write(i32 1, ptr %buf, i64 1)"));
+        assert!(out.contains("icmp ult i64 %v"));
+        assert!(!out.contains("!dbg"));
+    }
+
+    #[test]
+    fn debug_locations() {
+        let opts = CompileOptions {
+            debug_file: Some("a\"b.bf".to_string()),
+            ..Default::default()
+        };
+        let out = gen("+\n .", &opts);
+        assert!(out.contains("define i32 @main() !dbg !4 {"));
+        assert!(out.contains("!2 = !DIFile(filename: \"a\\22b.bf\", directory: \"\")"));
+        assert!(out.contains("!6 = !DILocation(line: 1, column: 1, scope: !4)"));
+        assert!(out.contains("!7 = !DILocation(line: 2, column: 2, scope: !4)"));
+        assert!(out.contains("call i64 @write(i32 1, ptr %buf, i64 1), !dbg !7"));
+    }
+}
@@ -0,0 +1,145 @@
+//! The loop structure of programs, as `--split-loops` partitions them into
+//! functions. Every node carries the symbol its loop is compiled into, so
+//! samples from a profiler can be mapped back to the source.
+
+use crate::{ast, gen, lex::Span};
+
+#[derive(Debug, PartialEq)]
+pub struct LoopNode {
+    pub symbol: String,
+    pub span: Span,
+    /// Statements directly in the body, not counting nested loops' bodies.
+    pub stats: usize,
+    /// The loop's metrics, see [`ast::Metrics`].
+    pub metrics: ast::Metrics,
+    pub loops: Vec<LoopNode>,
+}
+
+/// The loops of one function, `main` or a module's entry point.
+#[derive(Debug, PartialEq)]
+pub struct LoopTree {
+    pub function: String,
+    pub loops: Vec<LoopNode>,
+}
+
+impl LoopTree {
+    /// Loops of a program compiled on its own into `main`.
+    pub fn program(prog: &ast::Prog) -> Self {
+        LoopTree {
+            function: "main".to_string(),
+            loops: collect(prog, "bf_"),
+        }
+    }
+
+    /// Loops of a program compiled as module `name`.
+    pub fn module(name: &str, prog: &ast::Prog) -> Self {
+        let function = gen::module_symbol(name);
+        let loops = collect(prog, &format!("{}_", function));
+        LoopTree { function, loops }
+    }
+}
+
+fn collect(block: &ast::NodeBlock, prefix: &str) -> Vec<LoopNode> {
+    block
+        .stats
+        .iter()
+        .filter_map(|stat| match &stat.stat {
+            ast::Statement::Loop(body) => Some(LoopNode {
+                symbol: gen::loop_symbol(prefix, stat.attr.span),
+                span: stat.attr.span,
+                stats: body.stats.len(),
+                metrics: stat.attr.metrics,
+                loops: collect(body, prefix),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Renders the trees as a JSON array, one object per function.
+pub fn to_json(trees: &[LoopTree]) -> String {
+    fn node(out: &mut String, n: &LoopNode) {
+        out.push_str(&format!(
+            "{{\"symbol\":\"{}\",\"line\":{},\"col\":{},\"statements\":{},\"depth\":{},\"size\":{},\"io\":{},\"balanced\":{},\"loops\":",
+            n.symbol,
+            n.span.line,
+            n.span.col,
+            n.stats,
+            n.metrics.depth,
+            n.metrics.size,
+            n.metrics.io,
+            n.metrics.balanced
+        ));
+        list(out, &n.loops);
+        out.push('}');
+    }
+
+    fn list(out: &mut String, nodes: &[LoopNode]) {
+        out.push('[');
+        for (i, n) in nodes.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            node(out, n);
+        }
+        out.push(']');
+    }
+
+    // symbols are sanitized by `gen`, nothing in here needs escaping
+    let mut out = String::from("[");
+    for (i, tree) in trees.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&format!("{{\"function\":\"{}\",\"loops\":", tree.function));
+        list(&mut out, &tree.loops);
+        out.push('}');
+    }
+    out.push(']');
+    out
+}
+
+/// Renders the trees as a graphviz digraph with an edge from every function
+/// to the loops it calls.
+pub fn to_dot(trees: &[LoopTree]) -> String {
+    fn edges(out: &mut String, from: &str, nodes: &[LoopNode]) {
+        for n in nodes {
+            out.push_str(&format!(
+                "  \"{}\" [label=\"{}\\n{}:{}, {} statements\"];\n  \"{}\" -> \"{}\";\n",
+                n.symbol, n.symbol, n.span.line, n.span.col, n.stats, from, n.symbol
+            ));
+            edges(out, &n.symbol, &n.loops);
+        }
+    }
+
+    let mut out = String::from("digraph loops {\n  node [shape=box];\n");
+    for tree in trees {
+        out.push_str(&format!("  \"{}\";\n", tree.function));
+        edges(&mut out, &tree.function, &tree.loops);
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn export_nested_loops() {
+        let prog = crate::parse("+[>[-]\n<[-]]").unwrap();
+        let tree = LoopTree::program(&prog);
+        assert_eq!(tree.loops.len(), 1);
+        assert_eq!(tree.loops[0].symbol, "bf_loop_l1_c2");
+        assert_eq!(tree.loops[0].stats, 4);
+        assert_eq!(tree.loops[0].loops[1].symbol, "bf_loop_l2_c2");
+        assert_eq!(tree.loops[0].loops[1].metrics.depth, 1);
+
+        let json = to_json(&[tree]);
+        assert!(
+            json.starts_with("[{\"function\":\"main\",\"loops\":[{\"symbol\":\"bf_loop_l1_c2\"")
+        );
+        let dot = to_dot(&[LoopTree::module("a", &prog)]);
+        assert!(dot.contains("\"bf_a_loop_l1_c2\" -> \"bf_a_loop_l1_c4\";"));
+    }
+}
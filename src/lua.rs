@@ -0,0 +1,251 @@
+//! Lua, for embedding programs in game engines and other Lua hosts. The
+//! chunk runs the program when executed on its own and otherwise returns
+//! `run(read, write)`: `read()` returns the next byte of input or `nil` at
+//! its end, `write(c)` takes one byte, and `run` returns the exit status.
+//! Either left out means stdin or stdout.
+//!
+//! Only what Lua 5.1 already has is used, so LuaJIT runs it too: cells wrap
+//! with `%` rather than bitwise operators, which works the same on floats
+//! and integers. Behaves like the other backends otherwise: `,` at end of
+//! input leaves the cell alone and moving off the tape ends the program
+//! with status 1.
+
+use std::fmt::Write;
+
+use crate::{ast, backend::CodeGen, CompileOptions, Error, Newline};
+
+/// Bits in a cell.
+const CELL_BITS: u32 = 32;
+
+const READ_ERR: &str = "bfc: read error\\n";
+const WRITE_ERR: &str = "bfc: write error\\n";
+
+pub struct LuaGenerator {
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    debug_file: Option<String>,
+    indent: usize,
+    body: String,
+}
+
+impl Default for LuaGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for LuaGenerator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl LuaGenerator {
+    pub fn new() -> Self {
+        LuaGenerator {
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            debug_file: None,
+            indent: 1,
+            body: String::new(),
+        }
+    }
+
+    pub fn with_options(opts: &CompileOptions) -> Self {
+        LuaGenerator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            debug_file: opts.debug_file.clone(),
+            ..Self::new()
+        }
+    }
+
+    pub fn gen(&mut self, prog: &ast::Prog) -> String {
+        self.generate_block(prog);
+
+        let mut out = String::new();
+        out.push_str("#!/usr/bin/env lua\n");
+        writeln!(out, "local TAPE_LEN = {}", self.tape_len).unwrap();
+        writeln!(out, "-- cells wrap around at 2^{}", CELL_BITS).unwrap();
+        writeln!(out, "local CELLS = {}\n", 1u64 << CELL_BITS).unwrap();
+
+        if self.io_strict {
+            out.push_str("local function fail(msg)\n  io.stderr:write(msg)\n  os.exit(1)\nend\n\n");
+        }
+        out.push_str("local function stdin()\n  io.stdout:flush()\n");
+        if self.io_strict {
+            out.push_str("  local s, err = io.read(1)\n");
+            writeln!(out, "  if err then\n    fail(\"{}\")\n  end", READ_ERR).unwrap();
+        } else {
+            out.push_str("  local s = io.read(1)\n");
+        }
+        out.push_str("  return s and string.byte(s)\nend\n\n");
+        out.push_str("local function stdout(c)\n");
+        if self.io_strict {
+            out.push_str("  if not io.write(string.char(c)) then\n");
+            writeln!(out, "    fail(\"{}\")\n  end", WRITE_ERR).unwrap();
+        } else {
+            out.push_str("  io.write(string.char(c))\n");
+        }
+        out.push_str("end\n\n");
+
+        out.push_str("local function run(read, write)\n");
+        out.push_str("  read = read or stdin\n  write = write or stdout\n");
+        out.push_str("  local tape = {}\n  for i = 0, TAPE_LEN - 1 do\n    tape[i] = 0\n  end\n");
+        out.push_str("  local p = 0\n\n");
+        out.push_str("  local function getc()\n    local c = read()\n");
+        if self.crlf {
+            out.push_str("    while c == 13 do\n      c = read()\n    end\n");
+        }
+        out.push_str("    if c then\n      tape[p] = c\n    end\n  end\n\n");
+        out.push_str("  local function putc()\n    local c = tape[p] % 256\n");
+        if self.crlf {
+            out.push_str("    if c == 10 then\n      write(13)\n    end\n");
+        }
+        out.push_str("    write(c)\n  end\n\n");
+        out.push_str(&std::mem::take(&mut self.body));
+        out.push_str("  return 0\nend\n\n");
+
+        // `require` leaves locals a few levels up, running the file does not
+        out.push_str("if pcall(debug.getlocal, 4, 1) then\n  return run\nend\n");
+        out.push_str("local status = run()\nio.stdout:flush()\nos.exit(status)\n");
+        out
+    }
+
+    fn generate_block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.generate_statement(stat)
+        }
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) {
+        if let Some(file) = &self.debug_file {
+            let span = stat.attr.span;
+            let line = format!("-- {}:{}:{}", file, span.line, span.col);
+            self.line(&line);
+        }
+
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move("-", *n),
+            ast::Statement::MoveR(n) => self.generate_move("+", *n),
+            ast::Statement::Add(n) => {
+                self.line(&format!("tape[p] = (tape[p] + {}) % CELLS", *n as u32))
+            }
+            ast::Statement::Sub(n) => {
+                self.line(&format!("tape[p] = (tape[p] - {}) % CELLS", *n as u32))
+            }
+            ast::Statement::Set(n) => self.line(&format!("tape[p] = {}", n)),
+            ast::Statement::MulAdd { offset, factor } => {
+                let (there, back) = if *offset < 0 { ("-", "+") } else { ("+", "-") };
+                let n = offset.unsigned_abs();
+                self.generate_move(there, n);
+                // a product of two cells needs 64 bits, more than a float
+                // holds exactly, so the factor goes in 16 bits at a time
+                let from = format!("tape[p {} {}]", back, n);
+                let mut sum = format!("tape[p] + {} * {}", from, factor & 0xffff);
+                if factor >> 16 != 0 {
+                    write!(sum, " + {} * {} % 65536 * 65536", from, factor >> 16).unwrap();
+                }
+                self.line(&format!("tape[p] = ({}) % CELLS", sum));
+                self.generate_move(back, n);
+            }
+            ast::Statement::Read => self.line("getc()"),
+            ast::Statement::Write => self.line("putc()"),
+            ast::Statement::Scan(stride) => {
+                self.line("while tape[p] ~= 0 do");
+                self.indent += 1;
+                let op = if *stride < 0 { "-" } else { "+" };
+                self.generate_move(op, stride.unsigned_abs());
+                self.indent -= 1;
+                self.line("end");
+            }
+            ast::Statement::Loop(body) => {
+                self.line("while tape[p] ~= 0 do");
+                self.indent += 1;
+                self.generate_block(body);
+                self.indent -= 1;
+                self.line("end");
+            }
+            ast::Statement::If(body) => {
+                self.line("if tape[p] ~= 0 then");
+                self.indent += 1;
+                self.generate_block(body);
+                self.indent -= 1;
+                self.line("end");
+            }
+            ast::Statement::Fill(values) => {
+                let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+                self.line(&format!(
+                    "for i, v in ipairs({{{}}}) do tape[p + i - 1] = v end",
+                    values.join(", ")
+                ));
+            }
+            // `putc` writes the cell
+            ast::Statement::Print(bytes) => {
+                let chars: Vec<_> = bytes.iter().map(|b| b.to_string()).collect();
+                self.line(&format!(
+                    "for _, c in ipairs({{{}}}) do tape[p] = c; putc() end",
+                    chars.join(", ")
+                ));
+            }
+        }
+    }
+
+    /// Moves the pointer by `n` cells, `op` being `+` or `-`, and ends the
+    /// program with status 1 when it ends up off the tape.
+    fn generate_move(&mut self, op: &str, n: usize) {
+        if n >= self.tape_len {
+            // `return` has to end a block
+            self.line("do return 1 end");
+            return;
+        }
+        self.line(&format!("p = p {} {}", op, n));
+        self.line("if p < 0 or p >= TAPE_LEN then");
+        self.line("  return 1");
+        self.line("end");
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.body.push_str("  ");
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn products_split_the_factor() {
+        let opts = CompileOptions {
+            opt_level: crate::OptLevel::O2,
+            ..Default::default()
+        };
+        let prog = crate::optimize(",[->-<]>.", &opts).unwrap();
+        let lua = LuaGenerator::with_options(&opts).gen(&prog);
+        assert!(lua.contains(
+            "    tape[p] = (tape[p] + tape[p - 1] * 65535 + tape[p - 1] * 65535 % 65536 * 65536) % CELLS\n"
+        ));
+        let prog = crate::optimize(",[->+++<]", &opts).unwrap();
+        let lua = LuaGenerator::with_options(&opts).gen(&prog);
+        assert!(lua.contains("    tape[p] = (tape[p] + tape[p - 1] * 3) % CELLS\n"));
+    }
+
+    #[test]
+    fn long_moves_return_in_a_block() {
+        let opts = CompileOptions {
+            tape_len: 2,
+            ..Default::default()
+        };
+        let lua = LuaGenerator::with_options(&opts).gen(&crate::parse("+[>>]").unwrap());
+        assert!(lua.contains("  while tape[p] ~= 0 do\n    do return 1 end\n  end\n"));
+    }
+}
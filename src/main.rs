@@ -1,9 +1,2132 @@
-use brainfuck_compiler::{ast, gen, lex};
-use std::{env, fs};
-
-fn main() {
-    let path = env::args().skip(1).next().expect("path expected");
-    let src = fs::read_to_string(path).expect("could not read the file");
-    let out = brainfuck_compiler::compile(src);
-    println!("{}", out);
+//! The `bfc` command line driver.
+//!
+//! Exit codes:
+//! - `0`: success
+//! - `1`: the program failed to compile, `check`, `fuzz-input` or
+//!   `enumerate`, a diagnostic is printed
+//! - `2`: invalid invocation or configuration file
+//! - `3`: an input could not be read or an output could not be written
+//! - `4`: an external tool (`qbe`, `cc`) could not be run or failed
+
+use brainfuck_compiler::{
+    assume, bytecode,
+    config::{self, Config, Origin},
+    driver::{self, BuildOptions, Preset},
+    enumerate, fuzz, gdb, gen, header, idiom, il,
+    image::{self, Format},
+    interp, ir, jvm, lex, loops,
+    matrix::{self, Column, Matrix, Verdict},
+    opt, playground, recommend,
+    reduce::{self, Interesting},
+    stamp::{self, Stamp},
+    target::Target,
+    timing::{CorpusTimings, Timings},
+    validate::Run,
+    AtExit, Backend, Checkpoint, CompileOptions, Entry, Linkage, Newline, OptLevel,
+};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{self, ExitCode},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+#[derive(Parser)]
+#[command(
+    name = "bfc",
+    version,
+    about = "Brainfuck compiler targeting QBE and LLVM"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compile brainfuck programs to an executable, QBE IL or LLVM IR
+    Build(Box<BuildArgs>),
+    /// Run a program in process, under the interpreter or, with --jit,
+    /// compiled to machine code by Cranelift
+    Run(RunArgs),
+    /// Print the bytecode in a `.bfo` file, as built with --emit bytecode
+    Disasm(DisasmArgs),
+    /// Report what an artifact is and how it was built: the stamp --stamp
+    /// left in it, the tape bytecode was built for and the source QBE IL
+    /// built with -g maps back to
+    Inspect(InspectArgs),
+    /// Check programs for syntax errors without generating any code, fast
+    /// enough to run on every keystroke
+    Check(CheckArgs),
+    /// Fuzz the input of a program under the interpreter, reporting inputs
+    /// it hangs or runs off the tape on
+    FuzzInput(FuzzArgs),
+    /// Run a program under the interpreter and print the options it needs:
+    /// how long a tape, how wide cells and what end of input has to do
+    Recommend(RecommendArgs),
+    /// Run every short program through backends and the interpreter,
+    /// reporting those the compiled code behaves differently on
+    Enumerate(EnumerateArgs),
+    /// Cut a program down to the smallest one still interesting, such as
+    /// its compiled code behaving differently from the interpreter
+    Reduce(ReduceArgs),
+    /// Build and run a corpus of programs with every backend and
+    /// optimization level asked for, printing a table of which configurations
+    /// each passes in
+    Matrix(MatrixArgs),
+    /// Compare the optimization levels on how fast and how large the
+    /// executables they produce are
+    Bench(BenchArgs),
+    /// Time every compilation phase at every optimization level across a
+    /// corpus of programs and print the report as JSON
+    BenchPasses(BenchPassesArgs),
+    /// Write a static page for trying programs out in the browser, which
+    /// works offline
+    Playground(PlaygroundArgs),
+    /// Print the options a build would use and where each one's value came
+    /// from: defaults, bfc.toml, the environment, flags or pragmas
+    Options(OptionsArgs),
+    /// Print a shell completion script
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(Args, Clone)]
+struct BuildArgs {
+    /// Source files, several files are linked as modules sharing one tape.
+    /// A single directory builds every `.bf` file in it on its own
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Output file, defaults to the first source without its extension, or
+    /// with `.out` when it has none, for executables and to stdout for QBE
+    /// IL. The output directory when
+    /// building a directory, defaulting to that directory
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: OptionArgs,
+
+    /// Write a static Linux x86-64 executable directly, without running
+    /// any external tool, position independent unless `--no-pie` is given
+    #[arg(long)]
+    self_link: bool,
+
+    /// With --emit obj, compile the object for the host with Cranelift
+    /// rather than bfc's own x86-64 encoder, needs bfc built with the `aot`
+    /// feature
+    #[arg(long)]
+    cranelift: bool,
+
+    /// C compiler used to assemble and link, defaults to `cc` for the host,
+    /// to a cross compiler for other targets and to `clang` for the llvm
+    /// backend
+    #[arg(long)]
+    cc: Option<String>,
+
+    /// Linker the C compiler should use, passed as `-fuse-ld`
+    #[arg(long)]
+    linker: Option<String>,
+
+    /// Extra argument for the final link, passed to the C compiler after
+    /// the object; may be repeated
+    #[arg(long = "link-arg", value_name = "ARG", allow_hyphen_values = true)]
+    link_args: Vec<String>,
+
+    /// Linker script for the final link
+    #[arg(long, value_name = "PATH")]
+    linker_script: Option<PathBuf>,
+
+    /// Link a fully static executable
+    #[arg(long = "static")]
+    static_link: bool,
+
+    /// Build a position independent executable
+    #[arg(long, overrides_with = "no_pie")]
+    pie: bool,
+
+    /// Build an executable at a fixed address
+    #[arg(long, overrides_with = "pie")]
+    no_pie: bool,
+
+    /// Strip symbols from the executable
+    #[arg(long)]
+    strip: bool,
+
+    /// Toolchain preset, `musl` builds static executables with musl-gcc
+    #[arg(long)]
+    preset: Option<Preset>,
+
+    /// Turn the executable into an image for flashing bare-metal boards,
+    /// `raw` for a flat binary or `ihex` for Intel HEX [default: elf]
+    #[arg(long)]
+    format: Option<Format>,
+
+    /// Address the image starts at, decimal or 0x-prefixed hexadecimal
+    /// [default: that of the lowest segment]
+    #[arg(long, value_name = "ADDR", value_parser = image::parse_address, requires = "format")]
+    load_address: Option<u64>,
+
+    /// Work done on the way out of the program, any of `flush`, `dump-tape`,
+    /// `stats` and `histogram` separated by commas
+    #[arg(long, value_name = "HOOKS")]
+    atexit: Option<AtExit>,
+
+    /// Generate the program as `int SYMBOL(void)` rather than `main`, for
+    /// linking into a larger program, reading and writing through
+    /// `SYMBOL_read` and `SYMBOL_write` defined alongside, see --header;
+    /// needs the qbe backend and --emit qbe or asm
+    #[arg(long, value_name = "SYMBOL")]
+    entry: Option<String>,
+
+    /// Linkage of the --entry function: export, or local for IL built
+    /// along with it [default: export]
+    #[arg(long, value_name = "LINKAGE", requires = "entry")]
+    entry_linkage: Option<Linkage>,
+
+    /// Compile every source into an exported `int NAME(void)` function
+    /// with a tape of its own rather than into modules sharing one, named
+    /// `bf_<file stem>` unless --name says otherwise, doing I/O through
+    /// hooks named after the first as --entry does; needs the qbe backend
+    /// and --emit qbe or asm
+    #[arg(long)]
+    functions: bool,
+
+    /// Symbol of the --functions function for each source, in order
+    #[arg(long = "name", value_name = "NAME", requires = "functions")]
+    names: Vec<String>,
+
+    /// Have the --entry or --functions functions run on a tape their
+    /// caller passes in, as `int NAME(bf_cell *tape, size_t *pos)`,
+    /// starting at and handing back the cell `*pos`
+    #[arg(long)]
+    shared_tape: bool,
+
+    /// Also write a C header declaring the --entry or --functions functions
+    /// to PATH
+    #[arg(long, value_name = "PATH")]
+    header: Option<PathBuf>,
+
+    /// Optimization passes to run, in order and separated by commas, none
+    /// when empty [default: every pass]
+    #[arg(long, value_name = "NAMES")]
+    passes: Option<opt::Pipeline>,
+
+    /// Run a pass the pipeline leaves out with `-fPASS`, leave out one it
+    /// runs with `-fno-PASS`; repeatable, later ones winning
+    #[arg(short = 'f', value_name = "[no-]PASS")]
+    toggles: Vec<opt::Toggle>,
+
+    /// Recognize another idiom, as `NAME: PATTERN => REPLACEMENT` with
+    /// statements spelled as --emit ast prints them, such as
+    /// `set-add: Set(0) Add(a) => Set(a)`; repeatable
+    #[arg(long = "idiom", value_name = "IDIOM")]
+    idioms: Vec<idiom::Idiom>,
+
+    /// End the output with a stamp of the version of bfc and hashes of
+    /// the options and source, which `bfc inspect` prints
+    #[arg(long)]
+    stamp: bool,
+
+    /// Save the tape and pointer to a file every so many loop iterations,
+    /// as `every:N,file:PATH`; running the executable with `--resume`
+    /// carries on from the last snapshot
+    #[arg(long, value_name = "SPEC")]
+    checkpoint: Option<Checkpoint>,
+
+    /// What to produce
+    #[arg(long, value_enum, default_value_t = Emit::Exe)]
+    emit: Emit,
+
+    /// Emit line tables mapping the executable back to the source
+    #[arg(short = 'g')]
+    debug: bool,
+
+    /// Keep the intermediate .ssa and .s files next to the executable
+    #[arg(long)]
+    save_temps: bool,
+
+    /// Keep every intermediate file in DIR, named after the output: the
+    /// .ssa, .s or source handed to cc, and the .o it is compiled to
+    #[arg(long, value_name = "DIR", conflicts_with = "save_temps")]
+    keep_intermediates: Option<PathBuf>,
+
+    /// Check the generated QBE IL against the interpreter on a few inputs,
+    /// for small programs
+    #[arg(long)]
+    validate: bool,
+
+    /// Compile for running untrusted programs: bounds checks, overflow
+    /// traps, step limits, aborting on I/O errors and a tape off the stack.
+    /// Refused until bfc generates step limits and overflow traps
+    #[arg(long)]
+    hardened: bool,
+
+    /// Compile for speed, trusting the program: leave out bounds checks and
+    /// optimize at -O3 unless -O says otherwise, printing what the program
+    /// has to hold to for that to be safe
+    #[arg(long, conflicts_with = "hardened")]
+    fast_unsafe: bool,
+
+    /// Sources are QBE IL rather than brainfuck, built as if bfc had
+    /// generated them
+    #[arg(long)]
+    from_qbe: bool,
+
+    /// Allocate the tape on the heap for Valgrind's memcheck and massif to
+    /// see, between redzones memcheck reports any access to with the c
+    /// backend
+    #[arg(long)]
+    memcheck: bool,
+
+    /// Rebuild whenever a source file changes
+    #[arg(long)]
+    watch: bool,
+
+    /// Run the executable after every successful build
+    #[arg(long)]
+    run: bool,
+
+    /// Print how long each compilation phase took
+    #[arg(long)]
+    time_passes: bool,
+
+    /// Print external commands as they run, implies --time-passes
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Stop `qbe` and `cc` when they run for longer than this many seconds
+    #[arg(long, value_name = "SECS")]
+    tool_timeout: Option<u64>,
+}
+
+/// Flags that set the options `bfc.toml` can, see [`config`].
+#[derive(Args, Clone)]
+struct OptionArgs {
+    /// Project configuration, defaults to the bfc.toml next to the first
+    /// source file
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Optimization level, 0 to 3 running more passes the higher it is and
+    /// `-Os` favouring code size [default: 0]
+    #[arg(short = 'O', value_name = "LEVEL")]
+    opt_level: Option<OptLevel>,
+
+    /// Target passed to qbe: amd64_sysv, amd64_apple, arm64, arm64_apple or
+    /// rv64 [default: the host]
+    #[arg(long)]
+    target: Option<Target>,
+
+    /// Code generator: qbe, llvm to compile LLVM IR with clang, c for
+    /// portable C, js for an ES module, python for a script to read, lua to
+    /// embed, or x86-64-asm, aarch64-asm and rv64-asm for assembly needing
+    /// nothing but an assembler and a linker [default: qbe]
+    #[arg(long)]
+    backend: Option<Backend>,
+
+    /// Number of cells on the tape [default: 30000]
+    #[arg(long)]
+    tape_len: Option<usize>,
+
+    /// Bits in a cell, checked against the backend's: 8 for avr-asm, 32
+    /// for every other one
+    #[arg(long, value_name = "BITS")]
+    cell_width: Option<u32>,
+
+    /// Most bytes the program may use of the stack, a tape that would not
+    /// fit is allocated on the heap instead
+    #[arg(long, value_name = "BYTES")]
+    max_stack: Option<usize>,
+
+    /// Abort with a diagnostic on unexpected I/O errors at runtime
+    #[arg(long, overrides_with = "no_io_strict")]
+    io_strict: bool,
+
+    /// Ignore I/O errors at runtime, overriding bfc.toml and the
+    /// environment
+    #[arg(long, overrides_with = "io_strict")]
+    no_io_strict: bool,
+
+    /// Line ending translation for `.` and `,`: raw, crlf or auto [default: raw]
+    #[arg(long)]
+    newline: Option<Newline>,
+
+    /// Keep code after the last I/O even when it has no observable effect
+    #[arg(long, overrides_with = "no_keep_all_io")]
+    keep_all_io: bool,
+
+    /// Leave out code after the last I/O that has no observable effect,
+    /// overriding bfc.toml and the environment
+    #[arg(long, overrides_with = "keep_all_io")]
+    no_keep_all_io: bool,
+
+    /// Compile every loop into its own function, named after its source
+    /// location, so profilers show where time goes
+    #[arg(long, overrides_with = "no_split_loops")]
+    split_loops: bool,
+
+    /// Compile loops inline, overriding bfc.toml and the environment
+    #[arg(long, overrides_with = "split_loops")]
+    no_split_loops: bool,
+
+    /// Most statements a loop whose trip count is known may come to once
+    /// unrolled at -O3, 0 unrolling none [default: 64]
+    #[arg(long, value_name = "STATEMENTS")]
+    unroll_budget: Option<usize>,
+
+    /// Most steps the program may be run for while compiling at -O3, to
+    /// work out what it does before reading any input, 0 running none
+    /// [default: 1000000]
+    #[arg(long, value_name = "STEPS")]
+    eval_fuel: Option<u64>,
+}
+
+#[derive(Args)]
+struct OptionsArgs {
+    /// Program whose options to resolve, along with its pragmas and the
+    /// bfc.toml next to it [default: the bfc.toml in this directory]
+    #[arg(long, value_name = "FILE")]
+    resolve: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: OptionArgs,
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Also run every program with empty stdin, failing if it does not
+    /// terminate, and report what it printed
+    #[arg(long)]
+    assume_empty_input: bool,
+
+    /// Step limit for --assume-empty-input
+    #[arg(long, default_value_t = interp::Limits::default().steps)]
+    max_steps: u64,
+}
+
+#[derive(Args)]
+struct FuzzArgs {
+    file: PathBuf,
+
+    /// Number of inputs to try
+    #[arg(long, default_value_t = 10_000)]
+    runs: usize,
+
+    /// Seed for the mutations, the same seed finds the same inputs
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+
+    /// Step limit per run, going over it counts as a hang
+    #[arg(long, default_value_t = 1_000_000)]
+    max_steps: u64,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Source, or bytecode built with --emit bytecode, which runs with the
+    /// tape length it was built for unless told otherwise
+    file: PathBuf,
+
+    /// Compile the program in process with Cranelift rather than
+    /// interpreting it, needs bfc built with the `jit` feature
+    #[arg(long)]
+    jit: bool,
+
+    #[command(flatten)]
+    options: OptionArgs,
+}
+
+#[derive(Args)]
+struct DisasmArgs {
+    file: PathBuf,
+}
+
+#[derive(Args)]
+struct InspectArgs {
+    /// An executable, object, bytecode, QBE IL or other generated source
+    file: PathBuf,
+}
+
+#[derive(Args)]
+struct RecommendArgs {
+    file: PathBuf,
+
+    /// Files to run the program on, besides empty input
+    #[arg(long)]
+    input: Vec<PathBuf>,
+
+    /// Step limit per run, the figures of runs going over it are lower
+    /// bounds
+    #[arg(long, default_value_t = interp::Limits::default().steps)]
+    max_steps: u64,
+
+    /// Also write the options into the program as pragmas
+    #[arg(long)]
+    write_pragmas: bool,
+}
+
+#[derive(Args)]
+struct ReduceArgs {
+    file: PathBuf,
+
+    /// What a smaller program has to do to be kept: `diff-outputs` for its
+    /// compiled code writing something else or exiting with another status
+    /// than the interpreter
+    #[arg(long, default_value = "diff-outputs")]
+    interesting: Interesting,
+
+    /// Files to run the program on, besides the inputs `bfc enumerate` uses
+    #[arg(long)]
+    input: Vec<PathBuf>,
+
+    /// Where to write the reduced program [default: stdout]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Step limit per run under the interpreter, inputs a program goes over
+    /// it on are left out
+    #[arg(long, default_value_t = 10_000)]
+    max_steps: u64,
+
+    /// Seconds a run of the compiled code may take before it is killed
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+
+    #[command(flatten)]
+    options: OptionArgs,
+}
+
+#[derive(Args)]
+struct MatrixArgs {
+    /// Programs to run, directories standing for every `.bf` file under
+    /// them
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Backend to build every program with at every optimization level,
+    /// may be repeated [default: qbe]
+    #[arg(long = "backend", value_name = "BACKEND")]
+    backends: Vec<Backend>,
+
+    /// Files to run every program on, besides empty input
+    #[arg(long)]
+    input: Vec<PathBuf>,
+
+    /// Step limit per run under the interpreter, inputs a program goes over
+    /// it on are left out
+    #[arg(long, default_value_t = interp::Limits::default().steps)]
+    max_steps: u64,
+
+    /// Seconds a run of the compiled code may take before it is killed
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+
+    /// Write the table to a file rather than stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct EnumerateArgs {
+    /// Longest program tried, there are about eight times as many programs
+    /// with each command more
+    #[arg(long, default_value_t = 4)]
+    max_len: usize,
+
+    /// Backend to check at every optimization level, may be repeated
+    /// [default: qbe]
+    #[arg(long = "backend", value_name = "BACKEND")]
+    backends: Vec<Backend>,
+
+    /// Step limit per run under the interpreter, inputs a program goes over
+    /// it on are left out
+    #[arg(long, default_value_t = 10_000)]
+    max_steps: u64,
+
+    /// Seconds a run of the compiled code may take before it is killed
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+}
+
+#[derive(Args)]
+struct PlaygroundArgs {
+    /// Directory to write the page to, created if missing
+    #[arg(long, value_name = "DIR")]
+    export: PathBuf,
+
+    /// Number of cells on the tape
+    #[arg(long, default_value_t = 30_000)]
+    tape_len: usize,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    file: PathBuf,
+
+    /// File fed to the program on stdin [default: empty input]
+    #[arg(long)]
+    input: Option<PathBuf>,
+
+    /// Runs per level, the fastest one is reported
+    #[arg(long, default_value_t = 3)]
+    runs: usize,
+
+    /// Also time the interpreter
+    #[arg(long)]
+    interp: bool,
+}
+
+#[derive(Args)]
+struct BenchPassesArgs {
+    /// Programs to compile, directories standing for every `.bf` file
+    /// under them
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Compilations per program and level, the fastest one is reported
+    #[arg(long, default_value_t = 5)]
+    runs: usize,
+
+    /// Write the report to a file rather than stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Emit {
+    /// A native executable, built with `qbe` and `cc`
+    Exe,
+    /// The syntax tree after optimization
+    Ast,
+    /// The optimized program lowered to instructions on cells at offsets
+    /// from the pointer
+    Ir,
+    /// QBE intermediate language
+    Qbe,
+    /// LLVM IR, implies `--backend llvm`
+    LlvmIr,
+    /// C source, implies `--backend c`
+    C,
+    /// A JavaScript module, implies `--backend js`
+    Js,
+    /// A Python script, implies `--backend python`
+    Python,
+    /// A Lua chunk, implies `--backend lua`
+    Lua,
+    /// Assembly, from `qbe` or the assembly backends
+    Asm,
+    /// A relocatable object of x86-64 machine code, COFF for
+    /// amd64_windows and ELF otherwise, for the platform linker
+    Obj,
+    /// A JVM class file, named after the output for `java` to find it
+    Class,
+    /// The optimized program as bytecode for `bfc run`, see `bfc disasm`
+    Bytecode,
+    /// Assembly interleaved with the source lines it came from
+    AnnotatedAsm,
+    /// The loop tree `--split-loops` outlines functions along, as JSON
+    LoopsJson,
+    /// The loop tree as a graphviz digraph
+    LoopsDot,
+    /// A gdb Python script adding `bf-tape` and `bf-where` for debugging
+    /// the executable the qbe backend builds with `-g`, loaded by gdb on
+    /// its own when saved as `<executable>-gdb.py`
+    GdbHelpers,
+}
+
+enum Failure {
+    Compile(brainfuck_compiler::Error),
+    Io(PathBuf, io::Error),
+    Build(driver::Error),
+    Config(config::Error),
+    Batch {
+        failed: usize,
+        total: usize,
+    },
+    /// Diagnostics, each with the location it points at.
+    Check(Vec<(String, String)>),
+    /// A program run in process exited with a status other than 0.
+    Exited(i32),
+}
+
+impl Failure {
+    fn report(&self) -> ExitCode {
+        match self {
+            Failure::Compile(e @ brainfuck_compiler::Error::Unsupported(_)) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(2)
+            }
+            Failure::Compile(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(1)
+            }
+            Failure::Io(path, e) => {
+                eprintln!("error: {}: {}", path.display(), e);
+                ExitCode::from(3)
+            }
+            Failure::Build(e @ driver::Error::Unsupported(_)) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(2)
+            }
+            Failure::Build(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(4)
+            }
+            Failure::Config(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(2)
+            }
+            Failure::Batch { failed, total } => {
+                eprintln!("error: {} of {} programs failed to build", failed, total);
+                ExitCode::from(1)
+            }
+            Failure::Check(errors) => {
+                // the `file:line:col: error:` format editors pick up
+                for (loc, e) in errors {
+                    eprintln!("{}: error: {}", loc, e);
+                }
+                ExitCode::from(1)
+            }
+            Failure::Exited(status) => ExitCode::from(*status as u8),
+        }
+    }
+}
+
+impl From<brainfuck_compiler::Error> for Failure {
+    fn from(e: brainfuck_compiler::Error) -> Self {
+        Failure::Compile(e)
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let res = match cli.command {
+        Command::Build(args) => build(*args),
+        Command::Run(args) => run_program(args),
+        Command::Disasm(args) => disasm(args),
+        Command::Inspect(args) => inspect(args),
+        Command::Playground(args) => playground(args),
+        Command::Check(args) => check(args),
+        Command::FuzzInput(args) => fuzz_input(args),
+        Command::Recommend(args) => recommend(args),
+        Command::Enumerate(args) => enumerate(args),
+        Command::Reduce(args) => reduce(args),
+        Command::Matrix(args) => matrix(args),
+        Command::Bench(args) => bench(args),
+        Command::BenchPasses(args) => bench_passes(args),
+        Command::Options(args) => print_options(args),
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "bfc", &mut io::stdout());
+            Ok(())
+        }
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(f) => f.report(),
+    }
+}
+
+/// Symbols of the functions `--functions` compiles the sources into.
+fn function_names(args: &BuildArgs) -> Vec<String> {
+    if !args.names.is_empty() {
+        return args.names.clone();
+    }
+    args.files
+        .iter()
+        .map(|path| gen::module_symbol(&path.file_stem().unwrap_or_default().to_string_lossy()))
+        .collect()
+}
+
+fn read_source(path: &Path) -> Result<String, Failure> {
+    fs::read_to_string(path).map_err(|e| Failure::Io(path.to_path_buf(), e))
+}
+
+/// Reads every source, named after its file stem.
+fn read_sources(paths: &[PathBuf]) -> Result<Vec<(String, String)>, Failure> {
+    paths
+        .iter()
+        .map(|path| {
+            let name = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            Ok((name, read_source(path)?))
+        })
+        .collect()
+}
+
+fn run_program(args: RunArgs) -> Result<(), Failure> {
+    let bytes = fs::read(&args.file).map_err(|e| Failure::Io(args.file.clone(), e))?;
+    let resolve = |src: &str| {
+        let config = args.options.config.as_deref();
+        config::resolve(config, &args.file, src, args.options.to_config()).map_err(Failure::Config)
+    };
+    let mut opts = CompileOptions::default();
+    let prog = if bytecode::is_bytecode(&bytes) {
+        let module = bytecode::decode(&bytes).map_err(brainfuck_compiler::Error::from)?;
+        opts.tape_len = module.tape_len;
+        resolve("")?
+            .config
+            .apply(&mut opts)
+            .map_err(Failure::Config)?;
+        module.prog
+    } else {
+        let src = String::from_utf8(bytes).map_err(|e| {
+            Failure::Io(
+                args.file.clone(),
+                io::Error::new(io::ErrorKind::InvalidData, e),
+            )
+        })?;
+        resolve(&src)?
+            .config
+            .apply(&mut opts)
+            .map_err(Failure::Config)?;
+        brainfuck_compiler::optimize(&src, &opts)?
+    };
+
+    let status = if args.jit {
+        run_jit(&prog, &opts)?
+    } else {
+        // the interpreter takes its input all at once
+        let mut input = Vec::new();
+        io::stdin()
+            .read_to_end(&mut input)
+            .map_err(|e| Failure::Io(PathBuf::from("<stdin>"), e))?;
+        let limits = interp::Limits {
+            steps: u64::MAX,
+            cells: opts.tape_len,
+        };
+        let outcome = interp::run(&prog, &input, limits);
+        io::stdout()
+            .write_all(&outcome.output)
+            .and_then(|()| io::stdout().flush())
+            .map_err(|e| Failure::Io(PathBuf::from("<stdout>"), e))?;
+        match outcome.status {
+            interp::Status::Finished => 0,
+            _ => 1,
+        }
+    };
+    if status == 0 {
+        Ok(())
+    } else {
+        Err(Failure::Exited(status))
+    }
+}
+
+#[cfg(feature = "jit")]
+fn run_jit(prog: &brainfuck_compiler::ast::Prog, opts: &CompileOptions) -> Result<i32, Failure> {
+    let mut output = io::BufWriter::new(io::stdout().lock());
+    Ok(brainfuck_compiler::jit::run(
+        prog,
+        opts,
+        &mut io::stdin().lock(),
+        &mut output,
+    )?)
+}
+
+#[cfg(not(feature = "jit"))]
+fn run_jit(_: &brainfuck_compiler::ast::Prog, _: &CompileOptions) -> Result<i32, Failure> {
+    Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+        "bfc was built without the jit feature",
+    )))
+}
+
+#[cfg(feature = "aot")]
+fn compile_object_cranelift(
+    src: String,
+    opts: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<Vec<u8>, Failure> {
+    Ok(brainfuck_compiler::compile_object_cranelift_timed(
+        src, opts, timings,
+    )?)
+}
+
+#[cfg(not(feature = "aot"))]
+fn compile_object_cranelift(
+    _: String,
+    _: &CompileOptions,
+    _: &mut Timings,
+) -> Result<Vec<u8>, Failure> {
+    Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+        "bfc was built without the aot feature",
+    )))
+}
+
+fn disasm(args: DisasmArgs) -> Result<(), Failure> {
+    let bytes = fs::read(&args.file).map_err(|e| Failure::Io(args.file.clone(), e))?;
+    let text = bytecode::disassemble(&bytes).map_err(brainfuck_compiler::Error::from)?;
+    print!("{}", text);
+    Ok(())
+}
+
+fn inspect(args: InspectArgs) -> Result<(), Failure> {
+    let bytes = fs::read(&args.file).map_err(|e| Failure::Io(args.file.clone(), e))?;
+    let stamp = Stamp::find(&bytes);
+    let mut fields = vec![("artifact".to_string(), stamp::kind(&bytes).to_string())];
+    if let Some(stamp) = &stamp {
+        fields.push(("version".to_string(), stamp.version.clone()));
+        fields.push(("options".to_string(), format!("{:032x}", stamp.options)));
+        fields.push(("source".to_string(), format!("{:032x}", stamp.source)));
+        if let Some(compat) = &stamp.compat {
+            fields.push(("compat".to_string(), compat.clone()));
+        }
+        fields.extend(stamp.settings.iter().cloned());
+    }
+    // bytecode records the tape it was built for on its own
+    let module = bytecode::decode(&bytes).ok();
+    if let Some(module) = module.as_ref().filter(|_| stamp.is_none()) {
+        fields.push(("tape-len".to_string(), module.tape_len.to_string()));
+    }
+    let source_map = std::str::from_utf8(&bytes).ok().and_then(stamp::source_map);
+    if let Some(file) = source_map {
+        fields.push(("source-map".to_string(), file.to_string()));
+    }
+    if stamp.is_none() && module.is_none() && source_map.is_none() {
+        return Err(Failure::Check(vec![(
+            args.file.display().to_string(),
+            "nothing to report, was it built with --stamp?".to_string(),
+        )]));
+    }
+
+    let width = fields.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    for (key, value) in fields {
+        println!("{:width$}  {}", key, value, width = width);
+    }
+    Ok(())
+}
+
+fn playground(args: PlaygroundArgs) -> Result<(), Failure> {
+    let dir = &args.export;
+    fs::create_dir_all(dir).map_err(|e| Failure::Io(dir.clone(), e))?;
+    let mut opts = CompileOptions::default();
+    opts.tape_len = args.tape_len;
+    for (name, contents) in playground::files(&opts) {
+        let path = dir.join(name);
+        fs::write(&path, contents).map_err(|e| Failure::Io(path, e))?;
+    }
+    eprintln!(
+        "bfc: open {} in a browser",
+        dir.join("index.html").display()
+    );
+    Ok(())
+}
+
+fn check(args: CheckArgs) -> Result<(), Failure> {
+    let limits = interp::Limits {
+        steps: args.max_steps,
+        ..Default::default()
+    };
+    let mut errors = Vec::new();
+    for path in &args.files {
+        let src = read_source(path)?;
+        let diags = brainfuck_compiler::check(&src);
+        if !diags.is_empty() {
+            errors.extend(diags.into_iter().map(|d| {
+                let loc = format!("{}:{}:{}", path.display(), d.span.line, d.span.col);
+                (loc, d.message)
+            }));
+            continue;
+        }
+
+        if args.assume_empty_input {
+            let prog = brainfuck_compiler::parse(&src)?;
+            let outcome = interp::assume_empty_input(&prog, limits);
+            let report = format!("with empty input: {}", outcome);
+            if outcome.status == interp::Status::Finished {
+                eprintln!("{}: {}", path.display(), report);
+            } else {
+                errors.push((path.display().to_string(), report));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Failure::Check(errors))
+    }
+}
+
+fn fuzz_input(args: FuzzArgs) -> Result<(), Failure> {
+    let prog = brainfuck_compiler::parse(&read_source(&args.file)?)?;
+    eprintln!("{}: {}", args.file.display(), fuzz::InputShape::of(&prog));
+
+    let limits = interp::Limits {
+        steps: args.max_steps,
+        ..Default::default()
+    };
+    let findings = fuzz::fuzz(&prog, args.runs, args.seed, limits);
+    let errors: Vec<_> = findings
+        .into_iter()
+        .map(|f| {
+            let e = format!("input \"{}\" {}", f.input.escape_ascii(), f.status);
+            (args.file.display().to_string(), e)
+        })
+        .collect();
+    if errors.is_empty() {
+        eprintln!("{}: no failures in {} runs", args.file.display(), args.runs);
+        Ok(())
+    } else {
+        Err(Failure::Check(errors))
+    }
+}
+
+fn recommend(args: RecommendArgs) -> Result<(), Failure> {
+    let src = read_source(&args.file)?;
+    let prog = brainfuck_compiler::parse(&src)?;
+    let mut inputs = vec![Vec::new()];
+    for path in &args.input {
+        inputs.push(fs::read(path).map_err(|e| Failure::Io(path.clone(), e))?);
+    }
+
+    let rec = recommend::recommend(&prog, &inputs, args.max_steps);
+    print!("{}", rec);
+    if args.write_pragmas {
+        fs::write(&args.file, recommend::with_pragmas(&src, &rec))
+            .map_err(|e| Failure::Io(args.file.clone(), e))?;
+    }
+    Ok(())
+}
+
+fn enumerate(args: EnumerateArgs) -> Result<(), Failure> {
+    let backends = match args.backends.as_slice() {
+        [] => &[Backend::Qbe][..],
+        backends => backends,
+    };
+    let timeout = Duration::from_secs(args.timeout);
+    let mut errors = Vec::new();
+    for &backend in backends {
+        for opt_level in OptLevel::ALL {
+            let mut opts = CompileOptions::host();
+            opts.backend = backend;
+            opts.opt_level = opt_level;
+            let limits = interp::Limits {
+                steps: args.max_steps,
+                cells: opts.tape_len,
+            };
+            let build_opts = build_options(&opts);
+            let name = format!("{} -O{}", backend, opt_level);
+            // node only takes ES modules from files named like one
+            let ext = if backend == Backend::Js { ".mjs" } else { "" };
+            let exe = std::env::temp_dir().join(format!(
+                "bfc-enumerate-{}-{}-O{}{}",
+                process::id(),
+                backend,
+                opt_level,
+                ext
+            ));
+            let report = enumerate::cross_check(args.max_len, limits, |src, inputs| {
+                run_compiled(src, &opts, &build_opts, &exe, inputs, timeout)
+            });
+            let _ = fs::remove_file(&exe);
+            let report = report?;
+            eprintln!(
+                "{}: {} programs, {} runs compared, {} left out",
+                name, report.programs, report.runs, report.skipped
+            );
+            errors.extend(
+                report
+                    .mismatches
+                    .into_iter()
+                    .map(|m| (name.clone(), m.to_string())),
+            );
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Failure::Check(errors))
+    }
+}
+
+/// Compiles `src` to `exe`, a script for node with the JavaScript backend,
+/// and runs it on each of `inputs`.
+fn run_compiled(
+    src: &str,
+    opts: &CompileOptions,
+    build_opts: &BuildOptions,
+    exe: &Path,
+    inputs: &[&[u8]],
+    timeout: Duration,
+) -> Result<Vec<Run>, Failure> {
+    let code = brainfuck_compiler::compile_with(src.to_string(), opts)?;
+    let mut cmd = if opts.backend == Backend::Js {
+        fs::write(exe, &code).map_err(|e| Failure::Io(exe.to_path_buf(), e))?;
+        let mut node = process::Command::new("node");
+        node.arg(exe);
+        node
+    } else {
+        link(&code, exe, opts, build_opts, &mut Timings::default())?;
+        process::Command::new(exe)
+    };
+    inputs
+        .iter()
+        .map(|input| run_captured(&mut cmd, input, timeout))
+        .collect()
+}
+
+fn matrix(args: MatrixArgs) -> Result<(), Failure> {
+    let files = corpus(&args.files)?;
+    let backends = match args.backends.as_slice() {
+        [] => &[Backend::Qbe][..],
+        backends => backends,
+    };
+    let mut extra = Vec::new();
+    for path in &args.input {
+        extra.push(fs::read(path).map_err(|e| Failure::Io(path.clone(), e))?);
+    }
+    let mut inputs = vec![&b""[..]];
+    inputs.extend(extra.iter().map(Vec::as_slice));
+    let timeout = Duration::from_secs(args.timeout);
+
+    let mut report = Matrix {
+        columns: Column::product(backends),
+        ..Default::default()
+    };
+    for path in &files {
+        let src = read_source(path)?;
+        let mut opts = CompileOptions::host();
+        resolve_config(None, Config::default(), path)?
+            .config
+            .apply(&mut opts)
+            .map_err(Failure::Config)?;
+        let prog = brainfuck_compiler::parse(&src)?;
+        let limits = interp::Limits {
+            steps: args.max_steps,
+            cells: opts.tape_len,
+        };
+        let expected = matrix::expect(&prog, &inputs, limits);
+        let ran: Vec<&[u8]> = expected.iter().map(|(input, _)| *input).collect();
+
+        let mut verdicts = Vec::new();
+        for column in &report.columns {
+            opts.backend = column.backend;
+            opts.opt_level = column.opt_level;
+            let found = if ran.is_empty() {
+                Ok(Vec::new())
+            } else {
+                // node only takes ES modules from files named like one
+                let ext = if opts.backend == Backend::Js {
+                    ".mjs"
+                } else {
+                    ""
+                };
+                let exe = std::env::temp_dir().join(format!(
+                    "bfc-matrix-{}-{}-O{}{}",
+                    process::id(),
+                    column.backend,
+                    column.opt_level,
+                    ext
+                ));
+                let found = run_compiled(&src, &opts, &build_options(&opts), &exe, &ran, timeout);
+                let _ = fs::remove_file(&exe);
+                found.map_err(|e| match e {
+                    Failure::Compile(e @ brainfuck_compiler::Error::Unsupported(_)) => {
+                        Verdict::Unsupported(e.to_string())
+                    }
+                    Failure::Build(e @ driver::Error::Unsupported(_)) => {
+                        Verdict::Unsupported(e.to_string())
+                    }
+                    Failure::Compile(e) => Verdict::Error(e.to_string()),
+                    Failure::Build(e) => Verdict::Error(e.to_string()),
+                    Failure::Io(path, e) => Verdict::Error(format!("{}: {}", path.display(), e)),
+                    _ => Verdict::Error("the build failed".to_string()),
+                })
+            };
+            verdicts.push(matrix::judge(&expected, found));
+        }
+        report.rows.push((path.display().to_string(), verdicts));
+    }
+
+    let table = report.to_string();
+    match &args.output {
+        Some(path) => fs::write(path, &table).map_err(|e| Failure::Io(path.clone(), e))?,
+        None => print!("{}", table),
+    }
+    let failures: Vec<_> = report
+        .failures()
+        .map(|(name, column, verdict)| {
+            let why = match verdict {
+                Verdict::Fail(why) | Verdict::Error(why) => why.as_str(),
+                _ => verdict.mark(),
+            };
+            (name.to_string(), format!("{}: {}", column, why))
+        })
+        .collect();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Failure::Check(failures))
+    }
+}
+
+fn reduce(args: ReduceArgs) -> Result<(), Failure> {
+    let src = read_source(&args.file)?;
+    let mut opts = CompileOptions::host();
+    let config = args.options.config.as_deref();
+    config::resolve(config, &args.file, &src, args.options.to_config())
+        .map_err(Failure::Config)?
+        .config
+        .apply(&mut opts)
+        .map_err(Failure::Config)?;
+    let prog = brainfuck_compiler::parse(&src)?;
+    let mut extra = Vec::new();
+    for path in &args.input {
+        extra.push(fs::read(path).map_err(|e| Failure::Io(path.clone(), e))?);
+    }
+    let mut inputs = enumerate::INPUTS.to_vec();
+    inputs.extend(extra.iter().map(Vec::as_slice));
+    let limits = interp::Limits {
+        steps: args.max_steps,
+        cells: opts.tape_len,
+    };
+    let timeout = Duration::from_secs(args.timeout);
+    let build_opts = build_options(&opts);
+    // node only takes ES modules from files named like one
+    let ext = if opts.backend == Backend::Js {
+        ".mjs"
+    } else {
+        ""
+    };
+    let exe = std::env::temp_dir().join(format!("bfc-reduce-{}{}", process::id(), ext));
+
+    let mut tries = 0;
+    let reduced = reduce::reduce(&prog, |src| {
+        tries += 1;
+        match args.interesting {
+            Interesting::DiffOutputs => reduce::diverges(src, &inputs, limits, |src, inputs| {
+                run_compiled(src, &opts, &build_opts, &exe, inputs, timeout)
+            }),
+        }
+    });
+    let _ = fs::remove_file(&exe);
+    let Some(reduced) = reduced? else {
+        return Err(Failure::Check(vec![(
+            args.file.display().to_string(),
+            format!(
+                "the program is not interesting, --interesting {}",
+                args.interesting
+            ),
+        )]));
+    };
+    let commands = |src: &str| src.chars().filter(|c| "+-<>,.[]".contains(*c)).count();
+    eprintln!(
+        "reduced {} commands to {} in {} tries",
+        commands(&src),
+        commands(&reduced),
+        tries
+    );
+
+    // the pragmas the options came from go along
+    let mut out: String = src
+        .lines()
+        .filter(|line| line.starts_with(lex::PRAGMA))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    out.push_str(&reduced);
+    out.push('\n');
+    match &args.output {
+        Some(path) => fs::write(path, out).map_err(|e| Failure::Io(path.clone(), e)),
+        None => io::stdout()
+            .write_all(out.as_bytes())
+            .map_err(|e| Failure::Io(PathBuf::from("<stdout>"), e)),
+    }
+}
+
+/// Runs `cmd` on `input` and collects its output, killing it once it has
+/// taken `timeout`.
+fn run_captured(
+    cmd: &mut process::Command,
+    input: &[u8],
+    timeout: Duration,
+) -> Result<Run, Failure> {
+    let program = PathBuf::from(cmd.get_program());
+    let io_error = |e| Failure::Io(program.clone(), e);
+    let mut child = cmd
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .map_err(io_error)?;
+    // a program that stops reading early closes the pipe, that is fine
+    let _ = child.stdin.take().unwrap().write_all(input);
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(io_error)? {
+            break status.code();
+        }
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(1));
+    };
+    let mut output = Vec::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut output)
+        .map_err(io_error)?;
+    Ok(Run { status, output })
+}
+
+fn bench(args: BenchArgs) -> Result<(), Failure> {
+    let src = read_source(&args.file)?;
+    let input = match &args.input {
+        Some(path) => fs::read(path).map_err(|e| Failure::Io(path.clone(), e))?,
+        None => Vec::new(),
+    };
+    let mut opts = CompileOptions::default();
+    resolve_config(None, Config::default(), &args.file)?
+        .config
+        .apply(&mut opts)
+        .map_err(Failure::Config)?;
+    opts.target = opts.target.or_else(Target::host);
+
+    println!("{:<8} {:>12} {:>10}", "level", "time", "size");
+    for level in OptLevel::ALL {
+        opts.opt_level = level;
+        let code = brainfuck_compiler::compile_with(src.clone(), &opts)?;
+        let exe = std::env::temp_dir().join(format!("bfc-bench-{}-O{}", process::id(), level));
+        let build_opts = build_options(&opts);
+        let res = link(&code, &exe, &opts, &build_opts, &mut Timings::default()).and_then(|()| {
+            let size = fs::metadata(&exe)
+                .map_err(|e| Failure::Io(exe.clone(), e))?
+                .len();
+            let times = (0..args.runs.max(1))
+                .map(|_| time_run(&exe, &input))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((times.into_iter().min().unwrap(), size))
+        });
+        let _ = fs::remove_file(&exe);
+        let (time, size) = res?;
+        println!(
+            "{:<8} {:>10.3}ms {:>10}",
+            format!("-O{}", level),
+            time.as_secs_f64() * 1e3,
+            size
+        );
+    }
+
+    if args.interp {
+        let prog = brainfuck_compiler::optimize(&src, &opts)?;
+        let limits = interp::Limits {
+            steps: u64::MAX,
+            cells: opts.tape_len,
+        };
+        let time = (0..args.runs.max(1))
+            .map(|_| {
+                let start = Instant::now();
+                interp::run(&prog, &input, limits);
+                start.elapsed()
+            })
+            .min()
+            .unwrap();
+        println!(
+            "{:<8} {:>10.3}ms {:>10}",
+            "interp",
+            time.as_secs_f64() * 1e3,
+            "-"
+        );
+    }
+    Ok(())
+}
+
+fn bench_passes(args: BenchPassesArgs) -> Result<(), Failure> {
+    let files = corpus(&args.files)?;
+    let mut report = CorpusTimings {
+        runs: args.runs.max(1),
+        ..Default::default()
+    };
+    for path in &files {
+        let src = read_source(path)?;
+        let mut opts = CompileOptions::default();
+        resolve_config(None, Config::default(), path)?
+            .config
+            .apply(&mut opts)
+            .map_err(Failure::Config)?;
+        for level in OptLevel::ALL {
+            opts.opt_level = level;
+            let mut fastest: Option<Timings> = None;
+            for _ in 0..report.runs {
+                let mut timings = Timings::default();
+                brainfuck_compiler::compile_timed(src.clone(), &opts, &mut timings)?;
+                if fastest.as_ref().is_none_or(|f| timings.total() < f.total()) {
+                    fastest = Some(timings);
+                }
+            }
+            let name = path.display().to_string();
+            report.entries.push((name, level, fastest.unwrap()));
+        }
+    }
+    write_output(args.output, &report.to_json())
+}
+
+/// Wall clock time `exe` takes to run on `input`, its output discarded.
+fn time_run(exe: &Path, input: &[u8]) -> Result<Duration, Failure> {
+    let start = Instant::now();
+    let mut child = process::Command::new(exe)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .spawn()
+        .map_err(|e| Failure::Io(exe.to_path_buf(), e))?;
+    // a program that stops reading early closes the pipe, that is fine
+    let _ = child.stdin.take().unwrap().write_all(input);
+    child
+        .wait()
+        .map_err(|e| Failure::Io(exe.to_path_buf(), e))?;
+    Ok(start.elapsed())
+}
+
+fn build(args: BuildArgs) -> Result<(), Failure> {
+    if args.watch {
+        watch(&args)
+    } else {
+        build_once(&args)
+    }
+}
+
+/// Polls the sources and rebuilds on every modification, reporting
+/// diagnostics instead of giving up on them.
+fn watch(args: &BuildArgs) -> Result<(), Failure> {
+    let mut last = None;
+    loop {
+        let stamps = args
+            .files
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect::<Vec<Option<SystemTime>>>();
+
+        if last.as_ref() != Some(&stamps) {
+            match build_once(args) {
+                Ok(()) => eprintln!("bfc: build finished, watching for changes"),
+                Err(f) => {
+                    f.report();
+                    eprintln!("bfc: build failed, watching for changes");
+                }
+            }
+            last = Some(stamps);
+        }
+
+        thread::sleep(Duration::from_millis(300));
+    }
+}
+
+impl OptionArgs {
+    /// The flags that were given, as a layer over the configuration.
+    fn to_config(&self) -> Config {
+        Config {
+            tape_len: self.tape_len,
+            cell_width: self.cell_width,
+            max_stack: self.max_stack,
+            io_strict: toggle(self.io_strict, self.no_io_strict),
+            keep_all_io: toggle(self.keep_all_io, self.no_keep_all_io),
+            split_loops: toggle(self.split_loops, self.no_split_loops),
+            unroll_budget: self.unroll_budget,
+            eval_fuel: self.eval_fuel,
+            newline: self.newline,
+            opt_level: self.opt_level,
+            target: self.target,
+            backend: self.backend,
+            output: None,
+        }
+    }
+}
+
+/// The setting of a flag with a `--no-` counterpart, `None` when neither
+/// is given so that whatever else sets it stands.
+fn toggle(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Layers `cli` and everything else that sets options for the program at
+/// `path`, see [`config::resolve`].
+fn resolve_config(
+    config: Option<&Path>,
+    cli: Config,
+    path: &Path,
+) -> Result<config::Resolved, Failure> {
+    let src = read_source(path)?;
+    config::resolve(config, path, &src, cli).map_err(Failure::Config)
+}
+
+/// The compile options once every source of them is resolved, and the
+/// output path.
+fn resolve_options(args: &BuildArgs) -> Result<(CompileOptions, Option<PathBuf>), Failure> {
+    let cli = Config {
+        output: args.output.clone(),
+        ..args.options.to_config()
+    };
+    let mut resolved = resolve_config(args.options.config.as_deref(), cli, &args.files[0])?;
+    if args.hardened {
+        resolved.check_hardened().map_err(Failure::Config)?;
+        return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+            "--hardened needs step limits and overflow traps, which bfc does not generate yet",
+        )));
+    }
+    // nothing else can change what the output is written in
+    let backend = match args.emit {
+        Emit::LlvmIr => Some(Backend::Llvm),
+        Emit::C => Some(Backend::C),
+        Emit::Js => Some(Backend::Js),
+        Emit::Python => Some(Backend::Python),
+        Emit::Lua => Some(Backend::Lua),
+        _ => None,
+    };
+    resolved.layer(
+        Origin::Cli,
+        Config {
+            backend,
+            ..Default::default()
+        },
+    );
+
+    let mut opts = CompileOptions::default();
+    resolved.config.apply(&mut opts).map_err(Failure::Config)?;
+    opts.target = opts.target.or_else(Target::host);
+    if let Some(atexit) = args.atexit {
+        opts.atexit = atexit;
+    }
+    opts.pie = !args.no_pie;
+    opts.validate = args.validate;
+    opts.memcheck = args.memcheck;
+    opts.entry = args.entry.clone().map(|symbol| Entry {
+        symbol,
+        linkage: args.entry_linkage.unwrap_or_default(),
+    });
+    opts.checkpoint = args.checkpoint.clone();
+    opts.shared_tape = args.shared_tape;
+    opts.passes = args.passes.clone();
+    opts.toggles = args.toggles.clone();
+    opts.idioms = args.idioms.clone();
+    opts.stamp = args.stamp;
+    if args.fast_unsafe {
+        opts.unchecked = true;
+        if args.options.opt_level.is_none() {
+            opts.opt_level = OptLevel::O3;
+        }
+    }
+
+    Ok((opts, resolved.config.output))
+}
+
+/// Prints the options a build would use, as `bfc.toml` would set them,
+/// each with where its value came from.
+fn print_options(args: OptionsArgs) -> Result<(), Failure> {
+    let cli = args.options.to_config();
+    let config = args.options.config.as_deref();
+    let resolved = match &args.resolve {
+        Some(path) => resolve_config(config, cli, path)?,
+        // the configuration of the current directory
+        None => config::resolve(config, Path::new(""), "", cli).map_err(Failure::Config)?,
+    };
+
+    let mut opts = CompileOptions::default();
+    resolved.config.apply(&mut opts).map_err(Failure::Config)?;
+    opts.target = opts.target.or_else(Target::host);
+    let lines: Vec<_> = Config::of(&opts, resolved.config.output.clone())
+        .entries()
+        .into_iter()
+        .map(|(key, val)| match val {
+            Some(val) => (format!("{} = {}", key, val), resolved.describe_origin(key)),
+            None => (format!("# {} is not set", key), String::new()),
+        })
+        .collect();
+    let width = lines.iter().map(|(line, _)| line.len()).max().unwrap_or(0);
+    for (line, origin) in lines {
+        if origin.is_empty() {
+            println!("{}", line);
+        } else {
+            println!("{:<width$}  # {}", line, origin);
+        }
+    }
+    Ok(())
+}
+
+fn build_once(args: &BuildArgs) -> Result<(), Failure> {
+    if let [dir] = args.files.as_slice() {
+        if dir.is_dir() {
+            return build_dir(args, dir);
+        }
+    }
+
+    let mut timings = Timings::default();
+    let res = build_timed(args, &mut timings);
+    if args.time_passes || args.verbose {
+        eprint!("{}", timings);
+    }
+    res
+}
+
+/// Builds every `.bf` file under `dir` separately, mirroring the directory
+/// layout into the output directory, and sums up how it went.
+fn build_dir(args: &BuildArgs, dir: &Path) -> Result<(), Failure> {
+    let mut srcs = Vec::new();
+    find_sources(dir, &mut srcs)?;
+    srcs.sort();
+
+    let out_dir = args.output.as_deref().unwrap_or(dir);
+    let mut failed = 0;
+    for src in &srcs {
+        let rel = src.strip_prefix(dir).unwrap_or(src);
+        let mut output = out_dir.join(rel).with_extension(args.emit.extension());
+        if args.emit == Emit::GdbHelpers {
+            // named after the executable for gdb to load it along with it
+            let mut name = out_dir.join(rel).with_extension("").into_os_string();
+            name.push("-gdb.py");
+            output = PathBuf::from(name);
+        }
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent).map_err(|e| Failure::Io(parent.to_path_buf(), e))?;
+        }
+        let file_args = BuildArgs {
+            files: vec![src.clone()],
+            output: Some(output),
+            ..args.clone()
+        };
+        if let Err(f) = build_once(&file_args) {
+            eprint!("{}: ", src.display());
+            f.report();
+            failed += 1;
+        }
+    }
+
+    eprintln!(
+        "bfc: built {} of {} programs in {}",
+        srcs.len() - failed,
+        srcs.len(),
+        dir.display()
+    );
+    if failed > 0 {
+        Err(Failure::Batch {
+            failed,
+            total: srcs.len(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// `paths`, with each directory replaced by the `.bf` files under it.
+fn corpus(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Failure> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut srcs = Vec::new();
+            find_sources(path, &mut srcs)?;
+            srcs.sort();
+            files.extend(srcs);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+fn find_sources(dir: &Path, srcs: &mut Vec<PathBuf>) -> Result<(), Failure> {
+    let entries = fs::read_dir(dir).map_err(|e| Failure::Io(dir.to_path_buf(), e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| Failure::Io(dir.to_path_buf(), e))?.path();
+        if path.is_dir() {
+            find_sources(&path, srcs)?;
+        } else if path.extension().is_some_and(|ext| ext == "bf") {
+            srcs.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Prints what the programs built with unchecked bounds have to hold to,
+/// see [`assume::assumptions`].
+fn report_assumptions(args: &BuildArgs, opts: &CompileOptions) -> Result<(), Failure> {
+    // modules pick up the tape where the one before left it
+    let mut opts = opts.clone();
+    opts.shared_tape |= args.files.len() > 1 && !args.functions;
+    for path in &args.files {
+        let prog = brainfuck_compiler::optimize(&read_source(path)?, &opts)?;
+        let found = assume::assumptions(&prog, &opts);
+        if found.is_empty() {
+            eprintln!(
+                "{}: note: the pointer provably stays on the tape",
+                path.display()
+            );
+        }
+        for assumption in found {
+            match assumption.span {
+                Some(span) => eprintln!(
+                    "{}:{}:{}: note: {}",
+                    path.display(),
+                    span.line,
+                    span.col,
+                    assumption.what
+                ),
+                None => eprintln!("{}: note: {}", path.display(), assumption.what),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn build_timed(args: &BuildArgs, timings: &mut Timings) -> Result<(), Failure> {
+    let (mut opts, output) = resolve_options(args)?;
+    if let Some(output) = output.as_ref().filter(|output| args.files.contains(output)) {
+        return Err(Failure::Io(
+            output.clone(),
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the output would overwrite the source",
+            ),
+        ));
+    }
+    if args.debug || args.emit == Emit::AnnotatedAsm {
+        opts.debug_file = Some(args.files[0].display().to_string());
+    }
+
+    if args.from_qbe {
+        let supported = matches!(args.emit, Emit::Exe | Emit::Qbe | Emit::Asm)
+            && opts.backend == Backend::Qbe
+            && args.files.len() == 1
+            && !args.self_link
+            && !opts.validate;
+        if !supported {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "--from-qbe builds a single IL file with the qbe backend, to QBE IL, assembly or an executable",
+            )));
+        }
+    }
+
+    if opts.stamp {
+        let generated = matches!(
+            args.emit,
+            Emit::Exe
+                | Emit::Qbe
+                | Emit::LlvmIr
+                | Emit::C
+                | Emit::Js
+                | Emit::Python
+                | Emit::Lua
+                | Emit::Asm
+                | Emit::AnnotatedAsm
+        );
+        if !generated || args.from_qbe || args.self_link {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "--stamp goes in code bfc generates: executables, IL, assembly or source",
+            )));
+        }
+    }
+
+    if opts.unchecked && !args.from_qbe {
+        report_assumptions(args, &opts)?;
+    }
+
+    let format = args.format.unwrap_or_default();
+    if format != Format::Elf && (args.emit != Emit::Exe || args.run) {
+        return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+            "--format turns executables into images, which cannot be run",
+        )));
+    }
+
+    if (opts.entry.is_some() || args.functions) && (args.emit == Emit::Exe || args.self_link) {
+        return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+            "an executable needs `main`, emit QBE IL or assembly to link the entry function into another program",
+        )));
+    }
+    if !args.names.is_empty() && args.names.len() != args.files.len() {
+        return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+            "--name has to be given once for every source",
+        )));
+    }
+    if (args.shared_tape || args.header.is_some()) && opts.entry.is_none() && !args.functions {
+        return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+            "--shared-tape and --header are for --entry and --functions",
+        )));
+    }
+
+    if let Some(path) = &args.header {
+        let symbols = match &opts.entry {
+            Some(entry) if entry.linkage == Linkage::Local => {
+                return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                    "a local entry function is not seen from C, there is nothing to declare",
+                )))
+            }
+            Some(entry) => vec![entry.symbol.clone()],
+            None => function_names(args),
+        };
+        let sources: Vec<_> = args.files.iter().map(|f| f.display().to_string()).collect();
+        let out = header::c_header(&sources.join(", "), &symbols, &opts)?;
+        fs::write(path, out).map_err(|e| Failure::Io(path.clone(), e))?;
+    }
+
+    if let Emit::LoopsJson | Emit::LoopsDot = args.emit {
+        let trees = brainfuck_compiler::loop_trees(&read_sources(&args.files)?)?;
+        let out = match args.emit {
+            Emit::LoopsJson => loops::to_json(&trees),
+            _ => loops::to_dot(&trees),
+        };
+        return write_output(output, &out);
+    }
+
+    if args.emit == Emit::GdbHelpers {
+        let ([path], Backend::Qbe) = (args.files.as_slice(), opts.backend) else {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "gdb helpers are for executables the qbe backend builds from a single source",
+            )));
+        };
+        return write_output(output, &gdb::helpers(&path.display().to_string(), &opts));
+    }
+
+    if args.emit == Emit::Ast {
+        let out = match args.files.as_slice() {
+            [path] => brainfuck_compiler::optimize(&read_source(path)?, &opts)?.to_string(),
+            // modules are not optimized, they may carry on after returning
+            paths => read_sources(paths)?
+                .into_iter()
+                .map(|(name, src)| Ok(format!("{}:\n{}", name, brainfuck_compiler::parse(&src)?)))
+                .collect::<Result<String, Failure>>()?,
+        };
+        return write_output(output, &out);
+    }
+
+    if args.emit == Emit::Ir {
+        let [path] = args.files.as_slice() else {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "instructions are lowered from a single source",
+            )));
+        };
+        let prog = brainfuck_compiler::optimize(&read_source(path)?, &opts)?;
+        return write_output(output, &ir::display(&ir::lower(&prog)));
+    }
+
+    if args.cranelift && args.emit != Emit::Obj {
+        return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+            "--cranelift only builds objects, see --emit obj",
+        )));
+    }
+
+    if args.emit == Emit::Obj {
+        let [path] = args.files.as_slice() else {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "objects are built from a single source",
+            )));
+        };
+        let obj = if args.cranelift {
+            compile_object_cranelift(read_source(path)?, &opts, timings)?
+        } else {
+            brainfuck_compiler::compile_object_timed(read_source(path)?, &opts, timings)?
+        };
+        let output = output.unwrap_or_else(|| {
+            let ext = match opts.target {
+                Some(t) if t.is_windows() => "obj",
+                _ => "o",
+            };
+            default_output(path, ext)
+        });
+        return fs::write(&output, obj).map_err(|e| Failure::Io(output, e));
+    }
+
+    if args.emit == Emit::Bytecode {
+        let [path] = args.files.as_slice() else {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "bytecode is built from a single source",
+            )));
+        };
+        let bytes = brainfuck_compiler::compile_bytecode(&read_source(path)?, &opts)?;
+        let output = output.unwrap_or_else(|| default_output(path, "bfo"));
+        return fs::write(&output, bytes).map_err(|e| Failure::Io(output, e));
+    }
+
+    if args.emit == Emit::Class {
+        let [path] = args.files.as_slice() else {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "classes are built from a single source",
+            )));
+        };
+        let output = output.unwrap_or_else(|| {
+            // named after the class, as the JVM looks for it
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            path.with_file_name(jvm::class_name(&stem))
+                .with_extension("class")
+        });
+        let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+        let class = jvm::class_name(&stem);
+        let bytes =
+            brainfuck_compiler::compile_class_timed(read_source(path)?, &opts, &class, timings)?;
+        return fs::write(&output, bytes).map_err(|e| Failure::Io(output, e));
+    }
+
+    if args.self_link {
+        let path = match (args.emit, args.files.as_slice()) {
+            (Emit::Exe, [path]) => path,
+            _ => {
+                return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                    "--self-link only builds executables from a single source",
+                )))
+            }
+        };
+        let exe = brainfuck_compiler::compile_executable_timed(read_source(path)?, &opts, timings)?;
+        let output = output.unwrap_or_else(|| default_output(path, ""));
+        let exe = image::convert(&exe, format, args.load_address)
+            .map_err(|e| Failure::Build(driver::Error::Image(output.clone(), e)))?;
+        write_executable(&output, &exe)?;
+        if args.run {
+            run(&output)?;
+        }
+        return Ok(());
+    }
+
+    match (args.emit, opts.backend) {
+        (Emit::Qbe, backend) if backend != Backend::Qbe => {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "only the qbe backend emits QBE IL",
+            )))
+        }
+        (Emit::Asm | Emit::AnnotatedAsm, Backend::Llvm) => {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "the llvm backend leaves assembly to clang",
+            )))
+        }
+        (Emit::Asm | Emit::AnnotatedAsm, Backend::C) => {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "the c backend leaves assembly to the C compiler",
+            )))
+        }
+        (Emit::Asm | Emit::AnnotatedAsm, Backend::Js | Backend::Python | Backend::Lua) => {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "the js, python and lua backends leave machine code to the interpreter",
+            )))
+        }
+        _ => {}
+    }
+
+    let out = match args.files.as_slice() {
+        // printed back so the IL is checked before qbe sees it
+        [path] if args.from_qbe => il::parse(&read_source(path)?)
+            .map_err(brainfuck_compiler::Error::from)?
+            .to_string(),
+        paths if args.functions => {
+            let mut srcs = read_sources(paths)?;
+            for ((name, _), symbol) in srcs.iter_mut().zip(function_names(args)) {
+                *name = symbol;
+            }
+            brainfuck_compiler::compile_functions_timed(srcs, &opts, timings)?
+        }
+        [path] => brainfuck_compiler::compile_timed(read_source(path)?, &opts, timings)?,
+        // several files are linked together as modules sharing one tape
+        paths => brainfuck_compiler::compile_modules_timed(read_sources(paths)?, &opts, timings)?,
+    };
+
+    let mut build_opts = build_options(&opts);
+    if let Some(preset) = args.preset {
+        preset.apply(&mut build_opts);
+    }
+    if let Some(cc) = &args.cc {
+        // whoever names the compiler knows how to drive it
+        build_opts.cc = cc.clone();
+        build_opts.cc_args.clear();
+    }
+    if let Backend::Llvm | Backend::C = opts.backend {
+        // the IR or C is left for the compiler to optimize, but for -O0
+        // keeping the naive translation to bisect miscompiles with
+        build_opts.cc_args.push(match opts.opt_level {
+            OptLevel::O0 => "-O0".to_string(),
+            OptLevel::O1 | OptLevel::O2 => "-O2".to_string(),
+            OptLevel::O3 => "-O3".to_string(),
+            OptLevel::Size => "-Os".to_string(),
+        });
+    }
+    build_opts.linker = args.linker.clone();
+    build_opts.static_link |= args.static_link;
+    build_opts.strip = args.strip;
+    build_opts.format = format;
+    build_opts.load_address = args.load_address;
+    if args.pie || args.no_pie {
+        build_opts.pie = Some(args.pie);
+    }
+    build_opts.link_args = args.link_args.clone();
+    build_opts.linker_script = args.linker_script.clone();
+    build_opts.save_temps = args.save_temps;
+    build_opts.keep_intermediates = args.keep_intermediates.clone();
+    build_opts.verbose = args.verbose;
+    build_opts.timeout = args.tool_timeout.map(Duration::from_secs);
+
+    match (args.emit, output) {
+        (
+            Emit::Ast
+            | Emit::Ir
+            | Emit::Qbe
+            | Emit::LlvmIr
+            | Emit::C
+            | Emit::Js
+            | Emit::Python
+            | Emit::Lua
+            | Emit::LoopsJson
+            | Emit::LoopsDot
+            | Emit::GdbHelpers,
+            output,
+        ) => write_output(output, &out),
+        (Emit::Asm | Emit::AnnotatedAsm, output) => {
+            let asm = match opts.backend {
+                Backend::Qbe => {
+                    driver::assemble(&out, &build_opts, timings).map_err(Failure::Build)?
+                }
+                _ => out,
+            };
+            if args.emit == Emit::Asm {
+                return write_output(output, &asm);
+            }
+            let src = read_source(&args.files[0])?;
+            write_output(output, &driver::annotate_asm(&asm, &src))
+        }
+        (Emit::Obj | Emit::Class | Emit::Bytecode, _) => {
+            unreachable!("objects, classes and bytecode are written before generating any code")
+        }
+        (Emit::Exe, output) => {
+            let output = output.unwrap_or_else(|| default_output(&args.files[0], ""));
+            link(&out, &output, &opts, &build_opts, timings)?;
+            if args.run {
+                run(&output)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Toolchain defaults for the backend and target in `opts`.
+fn build_options(opts: &CompileOptions) -> BuildOptions {
+    match opts.backend {
+        Backend::Qbe | Backend::C | Backend::Js | Backend::Python | Backend::Lua => {
+            BuildOptions::for_target(opts.target)
+        }
+        Backend::Llvm => BuildOptions::for_llvm(opts.target),
+        Backend::X86_64Asm | Backend::Aarch64Asm | Backend::Rv64Asm => {
+            BuildOptions::for_asm(opts.target)
+        }
+        Backend::AvrAsm => BuildOptions::for_avr(),
+        _ => BuildOptions::for_target(opts.target),
+    }
+}
+
+/// Turns the output of `opts.backend` into an executable.
+fn link(
+    code: &str,
+    output: &Path,
+    opts: &CompileOptions,
+    build_opts: &BuildOptions,
+    timings: &mut Timings,
+) -> Result<(), Failure> {
+    match opts.backend {
+        Backend::Qbe => driver::build(code, output, build_opts, timings),
+        Backend::Llvm => driver::build_llvm(code, output, build_opts, timings),
+        Backend::C => driver::build_c(code, output, build_opts, timings),
+        // the script is the executable
+        Backend::Python | Backend::Lua => return write_executable(output, code.as_bytes()),
+        Backend::Js => {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "the js backend only emits a module, see --emit js",
+            )))
+        }
+        Backend::X86_64Asm | Backend::Aarch64Asm | Backend::Rv64Asm => {
+            driver::build_asm(code, output, build_opts, timings)
+        }
+        Backend::AvrAsm => driver::build_avr(code, output, build_opts, timings),
+        _ => {
+            return Err(Failure::Compile(brainfuck_compiler::Error::Unsupported(
+                "bfc cannot link the output of this backend",
+            )))
+        }
+    }
+    .map_err(Failure::Build)
+}
+
+impl Emit {
+    /// Extension of the file each program is written to in a batch build.
+    fn extension(self) -> &'static str {
+        match self {
+            Emit::Exe => "",
+            Emit::Ast => "ast",
+            Emit::Ir => "ir",
+            Emit::Qbe => "ssa",
+            Emit::LlvmIr => "ll",
+            Emit::C => "c",
+            // ES modules whatever package.json says
+            Emit::Js => "mjs",
+            Emit::Python => "py",
+            Emit::Lua => "lua",
+            Emit::Asm | Emit::AnnotatedAsm => "s",
+            Emit::Obj => "o",
+            Emit::Class => "class",
+            Emit::Bytecode => "bfo",
+            Emit::LoopsJson => "json",
+            Emit::LoopsDot => "dot",
+            Emit::GdbHelpers => "py",
+        }
+    }
+}
+
+fn write_output(output: Option<PathBuf>, out: &str) -> Result<(), Failure> {
+    match output {
+        Some(path) => fs::write(&path, out).map_err(|e| Failure::Io(path, e)),
+        None => {
+            println!("{}", out);
+            Ok(())
+        }
+    }
+}
+
+fn write_executable(path: &Path, exe: &[u8]) -> Result<(), Failure> {
+    let mut file = fs::OpenOptions::new();
+    file.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o755);
+    file.open(path)
+        .and_then(|mut f| f.write_all(exe))
+        .map_err(|e| Failure::Io(path.to_path_buf(), e))
+}
+
+fn run(exe: &Path) -> Result<(), Failure> {
+    // a bare file name would be looked up in PATH
+    let exe = if exe.is_relative() {
+        Path::new(".").join(exe)
+    } else {
+        exe.to_path_buf()
+    };
+    let status = process::Command::new(&exe)
+        .status()
+        .map_err(|e| Failure::Io(exe.clone(), e))?;
+    if !status.success() {
+        eprintln!("bfc: {} exited with {}", exe.display(), status);
+    }
+    Ok(())
+}
+
+/// Where what is built from `src` goes without `-o`: next to it, with the
+/// extension `ext`, or `.out` where that would be `src` itself.
+fn default_output(src: &Path, ext: &str) -> PathBuf {
+    let output = src.with_extension(ext);
+    if output == src {
+        src.with_extension("out")
+    } else {
+        output
+    }
 }
@@ -0,0 +1,242 @@
+//! The conformance matrix `bfc matrix` prints: every program of a corpus
+//! built for each combination of backend and optimization level asked for,
+//! its runs checked against [`interp`] the way [`crate::enumerate`] checks
+//! short programs. Each column also names the cell width and what `,` does
+//! at end of input, the other settings of [`crate::stamp::compat`] a
+//! program's behaviour turns on; neither is an option of its own, both
+//! follow from the backend.
+
+use std::fmt;
+
+use crate::{
+    ast::Prog,
+    interp::{self, Limits},
+    validate::Run,
+    Backend, OptLevel,
+};
+
+/// A build configuration, one column of the matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Column {
+    pub backend: Backend,
+    pub opt_level: OptLevel,
+}
+
+impl Column {
+    /// Every combination of `backends` and the optimization levels, in the
+    /// order given and then by level.
+    pub fn product(backends: &[Backend]) -> Vec<Column> {
+        backends
+            .iter()
+            .flat_map(|&backend| {
+                OptLevel::ALL
+                    .into_iter()
+                    .map(move |opt_level| Column { backend, opt_level })
+            })
+            .collect()
+    }
+
+    pub fn cell_bits(self) -> u32 {
+        self.backend.cell_bits()
+    }
+
+    /// What `,` does to the cell at end of input, the same everywhere.
+    pub fn eof(self) -> &'static str {
+        "unchanged"
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -O{}", self.backend, self.opt_level)
+    }
+}
+
+/// How a program fared in one configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Every run behaved as it does under the interpreter.
+    Pass,
+    /// A run did not, as described.
+    Fail(String),
+    /// The interpreter finished on none of the inputs.
+    Skipped,
+    /// The backend cannot build the program with these options, as
+    /// described.
+    Unsupported(String),
+    /// Building or running the program went wrong, as described, such as
+    /// for a toolchain that is missing.
+    Error(String),
+}
+
+impl Verdict {
+    /// The word the matrix shows for it.
+    pub fn mark(&self) -> &'static str {
+        match self {
+            Verdict::Pass => "ok",
+            Verdict::Fail(_) => "FAIL",
+            Verdict::Skipped => "skip",
+            Verdict::Unsupported(_) => "n/a",
+            Verdict::Error(_) => "error",
+        }
+    }
+
+    /// Whether it should hold up a release: a run going wrong or a build
+    /// that could not be made.
+    pub fn is_failure(&self) -> bool {
+        matches!(self, Verdict::Fail(_) | Verdict::Error(_))
+    }
+}
+
+/// The runs of `prog` under the interpreter on each of `inputs`, for those
+/// it finished within `limits`.
+pub fn expect<'a>(prog: &Prog, inputs: &[&'a [u8]], limits: Limits) -> Vec<(&'a [u8], Run)> {
+    inputs
+        .iter()
+        .filter_map(|&input| Some((input, Run::of(interp::run(prog, input, limits))?)))
+        .collect()
+}
+
+/// The verdict on runs `found`, of the compiled code on the inputs of
+/// `expected` in the same order, or the verdict on why there are none.
+pub fn judge(expected: &[(&[u8], Run)], found: Result<Vec<Run>, Verdict>) -> Verdict {
+    if expected.is_empty() {
+        return Verdict::Skipped;
+    }
+    let found = match found {
+        Ok(found) => found,
+        Err(verdict) => return verdict,
+    };
+    for ((input, expected), found) in expected.iter().zip(found) {
+        if found != *expected {
+            return Verdict::Fail(format!(
+                "on input \"{}\": expected it {} but it {}",
+                input.escape_ascii(),
+                expected,
+                found
+            ));
+        }
+    }
+    Verdict::Pass
+}
+
+/// The verdicts on a corpus, a row for each program.
+#[derive(Debug, Default)]
+pub struct Matrix {
+    pub columns: Vec<Column>,
+    pub rows: Vec<(String, Vec<Verdict>)>,
+}
+
+impl Matrix {
+    /// Every verdict holding up a release, with its program and column.
+    pub fn failures(&self) -> impl Iterator<Item = (&str, Column, &Verdict)> {
+        self.rows.iter().flat_map(move |(name, verdicts)| {
+            self.columns
+                .iter()
+                .zip(verdicts)
+                .filter(|(_, v)| v.is_failure())
+                .map(move |(column, v)| (name.as_str(), *column, v))
+        })
+    }
+}
+
+impl fmt::Display for Matrix {
+    /// A table with a column for each configuration and a row for each
+    /// program, under rows for the cell width and end of input behaviour
+    /// and above one counting passes.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let passed = |i: usize| {
+            let rows = self.rows.iter().map(|(_, v)| &v[i]);
+            let ran = rows
+                .clone()
+                .filter(|v| v.mark() != "skip" && v.mark() != "n/a");
+            let ok = rows.filter(|v| **v == Verdict::Pass).count();
+            format!("{}/{}", ok, ran.count())
+        };
+        let mut table: Vec<Vec<String>> = vec![
+            self.columns.iter().map(|c| c.to_string()).collect(),
+            self.columns
+                .iter()
+                .map(|c| c.cell_bits().to_string())
+                .collect(),
+            self.columns.iter().map(|c| c.eof().to_string()).collect(),
+        ];
+        let mut names = vec!["program", "cell bits", "eof"];
+        for (name, verdicts) in &self.rows {
+            names.push(name);
+            table.push(verdicts.iter().map(|v| v.mark().to_string()).collect());
+        }
+        names.push("passed");
+        table.push((0..self.columns.len()).map(passed).collect());
+
+        let first = names.iter().map(|n| n.len()).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..self.columns.len())
+            .map(|i| table.iter().map(|row| row[i].len()).max().unwrap_or(0))
+            .collect();
+        for (name, row) in names.iter().zip(&table) {
+            let mut line = format!("{:first$}", name, first = first);
+            for (cell, width) in row.iter().zip(&widths) {
+                line.push_str(&format!("  {:width$}", cell, width = width));
+            }
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn judge_and_tabulate() {
+        let limits = Limits::default();
+        let inputs: [&[u8]; 2] = [b"", b"a"];
+        let prog = crate::parse(",+.").unwrap();
+        let expected = expect(&prog, &inputs, limits);
+        assert_eq!(expected.len(), 2);
+        let runs = |outputs: [&[u8]; 2]| {
+            Ok(outputs
+                .iter()
+                .map(|out| Run {
+                    status: Some(0),
+                    output: out.to_vec(),
+                })
+                .collect())
+        };
+        assert_eq!(judge(&expected, runs([b"\x01", b"b"])), Verdict::Pass);
+        let Verdict::Fail(why) = judge(&expected, runs([b"\x01", b"a"])) else {
+            panic!("a wrong output passed");
+        };
+        assert!(why.starts_with("on input \"a\": expected it exited with 0"));
+        let forever = crate::parse("+[]").unwrap();
+        assert_eq!(
+            judge(&expect(&forever, &inputs, limits), runs([b"", b""])),
+            Verdict::Skipped
+        );
+
+        let matrix = Matrix {
+            columns: Column::product(&[Backend::C, Backend::AvrAsm])[..2].to_vec(),
+            rows: vec![
+                ("echo.bf".to_string(), vec![Verdict::Pass, Verdict::Skipped]),
+                (
+                    "cat.bf".to_string(),
+                    vec![Verdict::Pass, Verdict::Error("no cc".to_string())],
+                ),
+            ],
+        };
+        assert_eq!(
+            matrix.to_string(),
+            "program    c -O0      c -O1\n\
+             cell bits  32         32\n\
+             eof        unchanged  unchanged\n\
+             echo.bf    ok         skip\n\
+             cat.bf     ok         error\n\
+             passed     2/2        0/1\n"
+        );
+        let failures: Vec<_> = matrix.failures().collect();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].1.to_string(), "c -O1");
+        assert_eq!(Column::product(&[Backend::AvrAsm])[0].cell_bits(), 8);
+    }
+}
@@ -0,0 +1,157 @@
+//! Relocatable objects holding the machine code of [`crate::x86`], for the
+//! platform linker to finish: COFF for `link.exe` or `lld-link` on Windows,
+//! Mach-O for `ld64` on macOS, ELF for `ld` elsewhere.
+
+use object::{
+    macho,
+    write::{
+        MachOBuildVersion, Mangling, Object, Relocation, StandardSection, Symbol, SymbolId,
+        SymbolSection,
+    },
+    Architecture, BinaryFormat, Endianness, RelocationEncoding, RelocationFlags, RelocationKind,
+    SymbolFlags, SymbolKind, SymbolScope,
+};
+
+use crate::x86::{self, Code, Sym};
+
+// macOS 10.13, the oldest `ld64` still links for, as `xxxx.yy.zz` nibbles
+const MACOS_MIN_VERSION: u32 = 10 << 16 | 13 << 8;
+
+/// `code` with its entry point exported as `entry`, `tape_size` bytes of
+/// zeroed tape, and the `kernel32.dll` or `libSystem` functions it calls
+/// left for the linker to resolve.
+pub fn object(
+    code: &Code,
+    entry: &str,
+    tape_size: usize,
+    format: BinaryFormat,
+) -> Result<Vec<u8>, object::write::Error> {
+    let mut obj = Object::new(format, Architecture::X86_64, Endianness::Little);
+    // the generator already spells symbols the way the linker sees them
+    obj.set_mangling(Mangling::None);
+    if format == BinaryFormat::MachO {
+        // without it `ld64` warns that the object names no platform
+        let mut version = MachOBuildVersion::default();
+        version.platform = macho::PLATFORM_MACOS;
+        version.minos = MACOS_MIN_VERSION;
+        obj.set_macho_build_version(version);
+    }
+
+    let text = obj.section_id(StandardSection::Text);
+    obj.append_section_data(text, &code.text, 16);
+    obj.add_symbol(Symbol {
+        name: entry.as_bytes().to_vec(),
+        value: code.entry as u64,
+        size: 0,
+        kind: SymbolKind::Text,
+        scope: SymbolScope::Linkage,
+        weak: false,
+        section: SymbolSection::Section(text),
+        flags: SymbolFlags::None,
+    });
+
+    let rodata = obj.section_id(StandardSection::ReadOnlyData);
+    let read_err = obj.append_section_data(rodata, x86::READ_ERR, 1);
+    let write_err = obj.append_section_data(rodata, x86::WRITE_ERR, 1);
+    let bss = obj.section_id(StandardSection::UninitializedData);
+    let tape = obj.append_section_bss(bss, tape_size as u64, 16);
+    let buf = obj.append_section_bss(bss, 1, 1);
+
+    let mut symbols: Vec<(Sym, SymbolId)> = Vec::new();
+    for &(at, sym) in &code.relocs {
+        let id = match symbols.iter().find(|(s, _)| *s == sym) {
+            Some((_, id)) => *id,
+            None => {
+                let (value, section) = match sym {
+                    Sym::Tape => (tape, SymbolSection::Section(bss)),
+                    Sym::Buf => (buf, SymbolSection::Section(bss)),
+                    Sym::ReadErr => (read_err, SymbolSection::Section(rodata)),
+                    Sym::WriteErr => (write_err, SymbolSection::Section(rodata)),
+                    Sym::Import(_) | Sym::Func(_) => (0, SymbolSection::Undefined),
+                };
+                let id = obj.add_symbol(Symbol {
+                    name: sym.name().as_bytes().to_vec(),
+                    value,
+                    size: 0,
+                    kind: match sym {
+                        Sym::Func(_) => SymbolKind::Text,
+                        _ => SymbolKind::Data,
+                    },
+                    scope: match sym {
+                        Sym::Import(_) | Sym::Func(_) => SymbolScope::Linkage,
+                        _ => SymbolScope::Compilation,
+                    },
+                    weak: false,
+                    section,
+                    flags: SymbolFlags::None,
+                });
+                symbols.push((sym, id));
+                id
+            }
+        };
+        obj.add_relocation(
+            text,
+            Relocation {
+                offset: at as u64,
+                symbol: id,
+                // the field is relative to the end of itself
+                addend: -4,
+                flags: RelocationFlags::Generic {
+                    kind: RelocationKind::Relative,
+                    // a branch relocation for calls, which Mach-O tells
+                    // apart from data references
+                    encoding: match sym {
+                        Sym::Func(_) => RelocationEncoding::X86Branch,
+                        _ => RelocationEncoding::Generic,
+                    },
+                    size: 32,
+                },
+            },
+        )?;
+    }
+
+    obj.write()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{target::Target, CompileOptions};
+
+    #[test]
+    fn coff_imports_kernel32() {
+        let opts = CompileOptions {
+            target: Some(Target::Amd64Windows),
+            ..Default::default()
+        };
+        let prog = crate::parse(",.").unwrap();
+        let mut gen = x86::X86Generator::with_options(&opts).unwrap();
+        let code = gen.encode(&prog);
+        let coff = object(&code, gen.entry(), gen.tape_size(), BinaryFormat::Coff).unwrap();
+        // IMAGE_FILE_MACHINE_AMD64
+        assert_eq!(coff[..2], [0x64, 0x86]);
+        let has = |name: &str| coff.windows(name.len()).any(|w| w == name.as_bytes());
+        assert!(has("mainCRTStartup"));
+        assert!(has("__imp_ReadFile"));
+        assert!(has("__imp_WriteFile"));
+        assert!(has("__imp_GetStdHandle"));
+    }
+
+    #[test]
+    fn macho_calls_libsystem() {
+        let opts = CompileOptions {
+            target: Some(Target::Amd64Apple),
+            ..Default::default()
+        };
+        let prog = crate::parse(",.").unwrap();
+        let mut gen = x86::X86Generator::with_options(&opts).unwrap();
+        let code = gen.encode(&prog);
+        let macho = object(&code, gen.entry(), gen.tape_size(), BinaryFormat::MachO).unwrap();
+        assert_eq!(macho[..4], [0xcf, 0xfa, 0xed, 0xfe]);
+        let has = |name: &str| macho.windows(name.len()).any(|w| w == name.as_bytes());
+        assert!(has("_main"));
+        assert!(has("_read"));
+        assert!(has("_write"));
+        assert!(has("___error"));
+    }
+}
@@ -0,0 +1,1730 @@
+//! Optimizations of the syntax tree. Each is a [`Pass`], registered by
+//! name in [`registry`], and a [`PassManager`] runs them in order: the
+//! default pipeline is every registered pass the `-O` level asks for, in
+//! the order listed there, `--passes` picks others.
+//!
+//! Every pass leaves loops marked [`ast::Attr::no_opt`], by a `{#pragma
+//! no-opt}` before them, as they are, along with everything in them.
+
+use std::{collections::HashMap, str::FromStr};
+
+use crate::{
+    ast::{self, Attr, NodeBlock, NodeStatement, Prog, Statement},
+    idiom,
+    interp::{self, Limits},
+    timing::Timings,
+    CompileOptions,
+};
+
+/// A transformation of the syntax tree that keeps what the program does.
+pub trait Pass {
+    /// The name the pass is registered and timed under.
+    fn name(&self) -> &'static str;
+
+    /// Rewrites `prog`, returning whether it changed anything.
+    fn run(&self, prog: &mut Prog, opts: &CompileOptions) -> bool;
+
+    /// The lowest [`OptLevel::rank`] the default pipeline runs the pass
+    /// at.
+    fn level(&self) -> u8 {
+        0
+    }
+}
+
+/// Every pass, in the order the default pipeline runs them. Runs are
+/// fused first, so the passes after it may count on every run of `+`, `-`,
+/// `<` or `>` being a single statement.
+pub fn registry() -> Vec<Box<dyn Pass>> {
+    vec![
+        Box::new(FuseRuns),
+        Box::new(CancelPairs),
+        Box::new(ClearLoops),
+        Box::new(DeadLoops),
+        Box::new(MultiplyLoops),
+        Box::new(ScanLoops),
+        Box::new(Idioms),
+        Box::new(ConstantOutput),
+        Box::new(PartialEval),
+        Box::new(UnrollLoops),
+        Box::new(DeadStores),
+        Box::new(IfLoops),
+        Box::new(ElideDeadAtExit),
+    ]
+}
+
+/// The registered pass called `name`.
+pub fn lookup(name: &str) -> Option<Box<dyn Pass>> {
+    registry().into_iter().find(|p| p.name() == name)
+}
+
+/// Passes by name in the order to run them, as `--passes` takes them:
+/// separated by commas, none at all when empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline(pub Vec<&'static str>);
+
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|name| !name.is_empty())
+            .map(registered)
+            .collect::<Result<_, _>>()
+            .map(Pipeline)
+    }
+}
+
+/// A pass switched on or off on top of the pipeline, as `-f` takes it:
+/// `-fNAME` runs it, `-fno-NAME` leaves it out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Toggle {
+    pub pass: &'static str,
+    pub on: bool,
+}
+
+impl FromStr for Toggle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, on) = match s.strip_prefix("no-") {
+            Some(name) => (name, false),
+            None => (s, true),
+        };
+        Ok(Toggle {
+            pass: registered(name)?,
+            on,
+        })
+    }
+}
+
+/// The name of the registered pass called `name`.
+fn registered(name: &str) -> Result<&'static str, String> {
+    match lookup(name) {
+        Some(pass) => Ok(pass.name()),
+        None => {
+            let known: Vec<_> = registry().iter().map(|p| p.name()).collect();
+            Err(format!(
+                "unknown pass `{}`, expected any of {}",
+                name,
+                known.join(", ")
+            ))
+        }
+    }
+}
+
+/// Runs passes over a program one after the other.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The pipeline `opts.passes` names, or the default one for
+    /// `opts.opt_level`, with `opts.toggles` applied in order.
+    pub fn for_options(opts: &CompileOptions) -> Self {
+        let mut manager = match &opts.passes {
+            Some(Pipeline(names)) => PassManager {
+                passes: names.iter().filter_map(|name| lookup(name)).collect(),
+            },
+            None => PassManager {
+                passes: registry()
+                    .into_iter()
+                    .filter(|pass| pass.level() <= opts.opt_level.rank())
+                    .collect(),
+            },
+        };
+        for toggle in &opts.toggles {
+            manager.toggle(*toggle);
+        }
+        manager
+    }
+
+    /// Drops every run of a pass switched off. One switched on that does
+    /// not run yet goes before the first pass the registry lists after it.
+    pub fn toggle(&mut self, toggle: Toggle) {
+        if !toggle.on {
+            self.passes.retain(|p| p.name() != toggle.pass);
+            return;
+        }
+        if self.names().contains(&toggle.pass) {
+            return;
+        }
+        let order: Vec<_> = registry().iter().map(|p| p.name()).collect();
+        let rank = |name| order.iter().position(|n| *n == name);
+        let at = self
+            .passes
+            .iter()
+            .position(|p| rank(p.name()) > rank(toggle.pass))
+            .unwrap_or(self.passes.len());
+        if let Some(pass) = lookup(toggle.pass) {
+            self.passes.insert(at, pass);
+        }
+    }
+
+    pub fn add(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|p| p.name()).collect()
+    }
+
+    /// Runs every pass over `prog` in order, timing each under its name,
+    /// and returns the names of those that changed it. The metrics of a
+    /// changed program are worked out again before the next pass.
+    pub fn run(
+        &self,
+        prog: &mut Prog,
+        opts: &CompileOptions,
+        timings: &mut Timings,
+    ) -> Vec<&'static str> {
+        self.passes
+            .iter()
+            .filter(|pass| {
+                timings.time(pass.name(), || {
+                    let changed = pass.run(prog, opts);
+                    if changed {
+                        ast::attach_metrics(prog);
+                    }
+                    changed
+                })
+            })
+            .map(|pass| pass.name())
+            .collect()
+    }
+}
+
+/// See [`fuse_runs`].
+pub struct FuseRuns;
+
+impl Pass for FuseRuns {
+    fn name(&self) -> &'static str {
+        "fuse-runs"
+    }
+
+    fn run(&self, prog: &mut Prog, _: &CompileOptions) -> bool {
+        fuse_runs(prog)
+    }
+
+    fn level(&self) -> u8 {
+        1
+    }
+}
+
+/// Merges statements of the same kind following each other into one, in
+/// every block of the tree: the lexer only counts runs of a character, so
+/// `++ comment ++` or a run put together by another pass is left as more
+/// than one statement. The merged statement keeps the span of the first
+/// and the comments of all. Returns whether anything was merged.
+pub fn fuse_runs(block: &mut NodeBlock) -> bool {
+    let mut changed = false;
+    let mut stats: Vec<NodeStatement> = Vec::with_capacity(block.stats.len());
+    for mut stat in block.stats.drain(..) {
+        match &mut stat.stat {
+            Statement::Loop(body) | Statement::If(body) if !stat.attr.no_opt => {
+                changed |= fuse_runs(body)
+            }
+            _ => {}
+        }
+        if let Some(prev) = stats.last_mut() {
+            let fused = match (&mut prev.stat, &stat.stat) {
+                (Statement::Add(a), Statement::Add(b)) | (Statement::Sub(a), Statement::Sub(b)) => {
+                    // cells wrap, so only the low bits of the count matter
+                    *a = a.wrapping_add(*b);
+                    true
+                }
+                (Statement::MoveL(a), Statement::MoveL(b))
+                | (Statement::MoveR(a), Statement::MoveR(b)) => {
+                    *a = a.saturating_add(*b);
+                    true
+                }
+                _ => false,
+            };
+            if fused {
+                let trivia = &mut prev.attr.trivia.trailing;
+                trivia.push_str(&stat.attr.trivia.leading);
+                trivia.push_str(&stat.attr.trivia.trailing);
+                changed = true;
+                continue;
+            }
+        }
+        stats.push(stat);
+    }
+    block.stats = stats;
+    changed
+}
+
+/// See [`cancel_pairs`].
+pub struct CancelPairs;
+
+impl Pass for CancelPairs {
+    fn name(&self) -> &'static str {
+        "cancel-pairs"
+    }
+
+    fn run(&self, prog: &mut Prog, opts: &CompileOptions) -> bool {
+        // on a shared tape the program starts wherever the caller says
+        let start = (!opts.shared_tape).then_some(0);
+        cancel_pairs(prog, start, opts.tape_len)
+    }
+
+    fn level(&self) -> u8 {
+        1
+    }
+}
+
+/// Folds arithmetic statements following each other into one adding what
+/// they add up to, and moves into one moving as far as they do, dropping
+/// either when it comes to nothing: `+++--` becomes `+` and `+-` nothing.
+/// Statements left next to each other by that are folded too, so this is
+/// also a [`fuse_runs`] of its own.
+///
+/// Moves stop on every cell along the way, and end the program with status
+/// 1 when that is off the tape, so two are only folded when the cell
+/// between them is known to be on it. `pos` is where the pointer is when
+/// the block starts, when known: a program starts on cell 0, and a
+/// balanced loop starts its body on the cell it was entered on.
+pub fn cancel_pairs(block: &mut NodeBlock, mut pos: Option<i64>, tape_len: usize) -> bool {
+    let on_tape = |pos: Option<i64>| pos.is_some_and(|p| (0..tape_len as i64).contains(&p));
+    let mut changed = false;
+    let mut stats: Vec<NodeStatement> = Vec::with_capacity(block.stats.len());
+    for mut stat in block.stats.drain(..) {
+        let before = pos;
+        match &mut stat.stat {
+            Statement::MoveL(_) | Statement::MoveR(_) => {
+                pos = pos
+                    .zip(shift(&stat.stat))
+                    .and_then(|(p, by)| p.checked_add(by));
+            }
+            Statement::Loop(body) | Statement::If(body) => {
+                let balanced = stat.attr.metrics.balanced;
+                if !stat.attr.no_opt {
+                    changed |= cancel_pairs(body, pos.filter(|_| balanced), tape_len);
+                }
+                pos = pos.filter(|_| balanced);
+            }
+            _ => {}
+        }
+
+        let Some(prev) = stats.last_mut() else {
+            stats.push(stat);
+            continue;
+        };
+        let folded = match (delta(&prev.stat), delta(&stat.stat)) {
+            (Some(a), Some(b)) => Some(match a.wrapping_add(b) {
+                0 => None,
+                d if d <= i32::MAX as u32 => Some(Statement::Add(d as usize)),
+                d => Some(Statement::Sub(d.wrapping_neg() as usize)),
+            }),
+            _ => match (shift(&prev.stat), shift(&stat.stat)) {
+                (Some(a), Some(b)) if on_tape(before) => a.checked_add(b).map(|by| match by {
+                    0 => None,
+                    by if by > 0 => Some(Statement::MoveR(by as usize)),
+                    by => Some(Statement::MoveL(by.unsigned_abs() as usize)),
+                }),
+                _ => None,
+            },
+        };
+        match folded {
+            Some(Some(folded)) => {
+                prev.stat = folded;
+                let trivia = &mut prev.attr.trivia.trailing;
+                trivia.push_str(&stat.attr.trivia.leading);
+                trivia.push_str(&stat.attr.trivia.trailing);
+            }
+            Some(None) => {
+                stats.pop();
+            }
+            None => {
+                stats.push(stat);
+                continue;
+            }
+        }
+        changed = true;
+    }
+    block.stats = stats;
+    changed
+}
+
+/// What an arithmetic statement adds to the cell, wrapping around.
+fn delta(stat: &Statement) -> Option<u32> {
+    match stat {
+        Statement::Add(n) => Some(*n as u32),
+        Statement::Sub(n) => Some((*n as u32).wrapping_neg()),
+        _ => None,
+    }
+}
+
+/// How far a move takes the pointer, right being positive.
+fn shift(stat: &Statement) -> Option<i64> {
+    match stat {
+        Statement::MoveL(n) => i64::try_from(*n).ok().map(|n| -n),
+        Statement::MoveR(n) => i64::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+/// See [`clear_loops`].
+pub struct ClearLoops;
+
+impl Pass for ClearLoops {
+    fn name(&self) -> &'static str {
+        "clear-loops"
+    }
+
+    fn run(&self, prog: &mut Prog, _: &CompileOptions) -> bool {
+        clear_loops(prog)
+    }
+
+    fn level(&self) -> u8 {
+        1
+    }
+}
+
+/// Replaces loops doing nothing but adding an odd number to the cell, such
+/// as `[-]` and `[+]`, with a [`Statement::Set`] of zero: adding an odd
+/// number over and over comes to every value a cell can hold, so they end
+/// once it is zero, whatever it was. Loops adding an even number may never
+/// end and are left alone. The `Set` takes the span and comments of the
+/// loop. Returns whether any loop was replaced.
+pub fn clear_loops(block: &mut NodeBlock) -> bool {
+    let mut changed = false;
+    for stat in &mut block.stats {
+        let Statement::Loop(body) = &mut stat.stat else {
+            continue;
+        };
+        if stat.attr.no_opt {
+            continue;
+        }
+        if clears(body) {
+            stat.stat = Statement::Set(0);
+            changed = true;
+        } else {
+            changed |= clear_loops(body);
+        }
+    }
+    changed
+}
+
+/// Whether a loop with `body` is one [`clear_loops`] replaces, ending with
+/// the cell at zero whatever it was.
+pub fn clears(body: &NodeBlock) -> bool {
+    matches!(body.stats.as_slice(), [only] if delta(&only.stat).is_some_and(|d| d % 2 == 1))
+}
+
+/// See [`dead_loops`].
+pub struct DeadLoops;
+
+impl Pass for DeadLoops {
+    fn name(&self) -> &'static str {
+        "dead-loops"
+    }
+
+    fn run(&self, prog: &mut Prog, opts: &CompileOptions) -> bool {
+        // on a shared tape the program starts on whatever was left there
+        dead_loops(prog, !opts.shared_tape)
+    }
+
+    fn level(&self) -> u8 {
+        1
+    }
+}
+
+/// Drops loops whose cell is known to be zero when they are reached, so
+/// that they would never go round, such as the comment loop many programs
+/// start with. Cells are known to be zero at the start of the program,
+/// when `blank` says the tape starts out that way, right after a loop and
+/// after a `Set` of zero, until something adds to them. Returns whether
+/// any loop was dropped.
+pub fn dead_loops(block: &mut NodeBlock, blank: bool) -> bool {
+    let mut zeros = Zeros {
+        rest: blank,
+        ..Default::default()
+    };
+    drop_dead_loops(block, &mut zeros)
+}
+
+/// Which cells are known to be zero, by their position relative to the
+/// cell the pointer was on when following it started.
+#[derive(Default)]
+struct Zeros {
+    pos: i64,
+    /// Whether each cell is known to be zero, overriding `rest`.
+    cells: HashMap<i64, bool>,
+    /// Whether the cells `cells` leaves out are known to be zero.
+    rest: bool,
+}
+
+impl Zeros {
+    fn is_zero(&self, at: i64) -> bool {
+        self.cells.get(&at).copied().unwrap_or(self.rest)
+    }
+
+    /// Forgets everything but the current cell being zero, as after a
+    /// loop.
+    fn after_loop(&mut self) {
+        *self = Zeros::default();
+        self.cells.insert(0, true);
+    }
+}
+
+fn drop_dead_loops(block: &mut NodeBlock, zeros: &mut Zeros) -> bool {
+    let before = block.stats.len();
+    let mut changed = false;
+    block.stats.retain_mut(|stat| {
+        let pos = zeros.pos;
+        match &mut stat.stat {
+            Statement::Loop(_) | Statement::If(_) if stat.attr.no_opt => zeros.after_loop(),
+            Statement::Loop(_) | Statement::If(_) if zeros.is_zero(pos) => return false,
+            Statement::Loop(body) | Statement::If(body) => {
+                // the body is entered on a cell that is not zero, and maybe
+                // after going round already
+                changed |= drop_dead_loops(body, &mut Zeros::default());
+                zeros.after_loop();
+            }
+            Statement::Scan(_) => zeros.after_loop(),
+            Statement::MoveL(_) | Statement::MoveR(_) => {
+                zeros.pos = pos.saturating_add(shift(&stat.stat).unwrap_or(i64::MAX));
+            }
+            Statement::Add(_) | Statement::Sub(_) | Statement::Read => {
+                if delta(&stat.stat) != Some(0) {
+                    zeros.cells.insert(pos, false);
+                }
+            }
+            Statement::Set(value) => {
+                zeros.cells.insert(pos, *value == 0);
+            }
+            Statement::Fill(values) => {
+                for (at, value) in (pos..).zip(values) {
+                    zeros.cells.insert(at, *value == 0);
+                }
+            }
+            Statement::Print(bytes) => {
+                zeros.cells.insert(pos, bytes.last() == Some(&0));
+            }
+            Statement::MulAdd { offset, .. } => {
+                if !zeros.is_zero(pos) {
+                    zeros
+                        .cells
+                        .insert(pos.saturating_add(*offset as i64), false);
+                }
+            }
+            Statement::Write => {}
+        }
+        true
+    });
+    changed || block.stats.len() != before
+}
+
+/// See [`multiply_loops`].
+pub struct MultiplyLoops;
+
+impl Pass for MultiplyLoops {
+    fn name(&self) -> &'static str {
+        "multiply-loops"
+    }
+
+    fn run(&self, prog: &mut Prog, _: &CompileOptions) -> bool {
+        multiply_loops(prog)
+    }
+
+    fn level(&self) -> u8 {
+        2
+    }
+}
+
+/// Turns the bodies of balanced loops counting their cell down or up by
+/// one while adding to others, such as `[->+>+++<<]`, into a
+/// [`Statement::MulAdd`] for each of the others followed by a `Set` of
+/// zero: the loop then goes round once, however large the count. Loops
+/// doing anything else, or stopping at a cell they do not add to on the
+/// way, which would go unchecked, are left alone. The new statements take
+/// the spans of the first statements adding to their cells, comments in the
+/// body are dropped. Returns whether any loop was turned.
+pub fn multiply_loops(block: &mut NodeBlock) -> bool {
+    let mut changed = false;
+    for stat in &mut block.stats {
+        let Statement::Loop(body) = &mut stat.stat else {
+            continue;
+        };
+        if stat.attr.no_opt {
+            continue;
+        }
+        match multiplied(body) {
+            Some(stats) => {
+                body.stats = stats;
+                changed = true;
+            }
+            None => changed |= multiply_loops(body),
+        }
+    }
+    changed
+}
+
+/// The statements of a multiply loop `body` as [`multiply_loops`] makes
+/// them, if it is one.
+fn multiplied(body: &NodeBlock) -> Option<Vec<NodeStatement>> {
+    // what is added to each cell, in the order they are first reached
+    let mut adds: Vec<(i64, u32, &NodeStatement)> = Vec::new();
+    let (mut pos, mut low, mut high) = (0i64, 0i64, 0i64);
+    for stat in &body.stats {
+        if let Some(by) = shift(&stat.stat) {
+            pos = pos.checked_add(by)?;
+            low = low.min(pos);
+            high = high.max(pos);
+        } else {
+            let d = delta(&stat.stat)?;
+            match adds.iter_mut().find(|(at, ..)| *at == pos) {
+                Some((_, sum, _)) => *sum = sum.wrapping_add(d),
+                None => adds.push((pos, d, stat)),
+            }
+        }
+    }
+    let counter = adds.iter().position(|(at, ..)| *at == 0)?;
+    let (_, step, counter) = adds.remove(counter);
+    adds.retain(|(_, d, _)| *d != 0);
+    let reached = |at: i64| at == 0 || adds.iter().any(|(a, ..)| *a == at);
+    if pos != 0 || !(step == 1 || step == u32::MAX) || adds.is_empty() {
+        return None;
+    }
+    if !reached(low) || !reached(high) {
+        return None;
+    }
+
+    // counting up goes round as many times as the negated cell
+    let sign = step.wrapping_neg();
+    let mut stats = adds
+        .into_iter()
+        .map(|(at, d, stat)| {
+            Some(NodeStatement {
+                attr: Attr {
+                    span: stat.attr.span,
+                    ..Default::default()
+                },
+                stat: Statement::MulAdd {
+                    offset: isize::try_from(at).ok()?,
+                    factor: d.wrapping_mul(sign),
+                },
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    stats.push(NodeStatement {
+        attr: Attr {
+            span: counter.attr.span,
+            ..Default::default()
+        },
+        stat: Statement::Set(0),
+    });
+    Some(stats)
+}
+
+/// See [`scan_loops`].
+pub struct ScanLoops;
+
+impl Pass for ScanLoops {
+    fn name(&self) -> &'static str {
+        "scan-loops"
+    }
+
+    fn run(&self, prog: &mut Prog, _: &CompileOptions) -> bool {
+        scan_loops(prog)
+    }
+
+    fn level(&self) -> u8 {
+        2
+    }
+}
+
+/// Replaces loops doing nothing but move, such as `[>]` and `[<<]`, with a
+/// [`Statement::Scan`] for the zero cell they look for, which backends
+/// where cells are bytes search for with `memchr`. The `Scan` takes the
+/// span and comments of the loop. Returns whether any loop was replaced.
+pub fn scan_loops(block: &mut NodeBlock) -> bool {
+    let mut changed = false;
+    for stat in &mut block.stats {
+        let Statement::Loop(body) = &mut stat.stat else {
+            continue;
+        };
+        if stat.attr.no_opt {
+            continue;
+        }
+        let stride = match body.stats.as_slice() {
+            [only] => shift(&only.stat).and_then(|by| isize::try_from(by).ok()),
+            _ => None,
+        };
+        match stride {
+            Some(stride) => {
+                stat.stat = Statement::Scan(stride);
+                changed = true;
+            }
+            None => changed |= scan_loops(body),
+        }
+    }
+    changed
+}
+
+/// See [`idiom::recognize`], for [`idiom::builtin`] and then
+/// [`CompileOptions::idioms`].
+pub struct Idioms;
+
+impl Pass for Idioms {
+    fn name(&self) -> &'static str {
+        "idioms"
+    }
+
+    fn run(&self, prog: &mut Prog, opts: &CompileOptions) -> bool {
+        let mut idioms = idiom::builtin();
+        idioms.extend(opts.idioms.iter().cloned());
+        idiom::recognize(prog, &idioms)
+    }
+
+    fn level(&self) -> u8 {
+        2
+    }
+}
+
+/// See [`constant_output`].
+pub struct ConstantOutput;
+
+impl Pass for ConstantOutput {
+    fn name(&self) -> &'static str {
+        "constant-output"
+    }
+
+    fn run(&self, prog: &mut Prog, opts: &CompileOptions) -> bool {
+        // a tape looked at once the program ends keeps every write, and a
+        // shared one starts out anything; the interpreter's cells are 32
+        // bits wide and a program keeping all its I/O keeps the time it
+        // takes to come up with its output
+        if opts.tape_seen_after_exit() || opts.backend.cell_bits() != 32 || opts.keep_all_io {
+            return false;
+        }
+        let limits = Limits {
+            steps: opts.eval_fuel,
+            cells: opts.tape_len,
+        };
+        constant_output(prog, limits)
+    }
+
+    fn level(&self) -> u8 {
+        2
+    }
+}
+
+/// Runs the whole program with the interpreter when it needs no input and
+/// ends within `limits`, and replaces it with a single
+/// [`Statement::Print`] of what it wrote, nothing if it wrote nothing,
+/// taking the span of its first statement. Programs marked no-opt
+/// anywhere in the outermost block are left alone. Returns whether the
+/// program was replaced.
+pub fn constant_output(prog: &mut Prog, limits: Limits) -> bool {
+    if prog.stats.iter().any(|stat| stat.attr.no_opt) {
+        return false;
+    }
+    let Some(first) = prog.stats.first() else {
+        return false;
+    };
+    // a program that is a `Print` already comes to itself
+    if let [NodeStatement {
+        stat: Statement::Print(_),
+        ..
+    }] = prog.stats.as_slice()
+    {
+        return false;
+    }
+    let attr = Attr {
+        span: first.attr.span,
+        ..Default::default()
+    };
+    let prefix = interp::run_prefix(&prog.stats, limits);
+    if prefix.len < prog.stats.len() {
+        return false;
+    }
+    prog.stats.clear();
+    if !prefix.output.is_empty() {
+        prog.stats.push(NodeStatement {
+            attr,
+            stat: Statement::Print(prefix.output),
+        });
+    }
+    true
+}
+
+/// See [`partial_eval`].
+pub struct PartialEval;
+
+impl Pass for PartialEval {
+    fn name(&self) -> &'static str {
+        "partial-eval"
+    }
+
+    fn run(&self, prog: &mut Prog, opts: &CompileOptions) -> bool {
+        // a tape looked at once the program ends keeps every write, and a
+        // shared one starts out anything; the interpreter's cells are 32
+        // bits wide and a program keeping all its I/O keeps the time it
+        // takes to come up with its output
+        if opts.tape_seen_after_exit() || opts.backend.cell_bits() != 32 || opts.keep_all_io {
+            return false;
+        }
+        let limits = Limits {
+            steps: opts.eval_fuel,
+            cells: opts.tape_len,
+        };
+        partial_eval(prog, limits)
+    }
+
+    fn level(&self) -> u8 {
+        3
+    }
+}
+
+/// Runs the statements of the outermost block that need no input with
+/// the interpreter, up to the first that reads or breaks `limits`, see
+/// [`interp::run_prefix`], or is marked no-opt, and replaces them with what they come to:
+/// writing what they wrote, the tape they left as a [`Statement::Fill`]
+/// and a move to where they left the pointer. That is done as long as it
+/// comes to no more statements than it replaces. The new statements take
+/// the span of the first statement run. Returns whether any were
+/// replaced.
+pub fn partial_eval(prog: &mut Prog, limits: Limits) -> bool {
+    let end = prog.stats.iter().position(|stat| stat.attr.no_opt);
+    let prefix = interp::run_prefix(&prog.stats[..end.unwrap_or(prog.stats.len())], limits);
+    let size: usize = prog.stats[..prefix.len]
+        .iter()
+        .map(|stat| stat.attr.metrics.size)
+        .sum();
+    let Some(first) = prog.stats.first() else {
+        return false;
+    };
+    let span = first.attr.span;
+    let stat = |stat| NodeStatement {
+        attr: Attr {
+            span,
+            ..Default::default()
+        },
+        stat,
+    };
+
+    // the output leaves its last byte in the first cell, which the tape
+    // then puts back
+    let mut stats = Vec::new();
+    if !prefix.output.is_empty() {
+        stats.push(stat(Statement::Print(prefix.output)));
+    }
+    let mut tape = prefix.tape;
+    while tape.last() == Some(&0) {
+        tape.pop();
+    }
+    if tape.is_empty() && !stats.is_empty() {
+        tape.push(0);
+    }
+    if !tape.is_empty() {
+        stats.push(stat(Statement::Fill(tape)));
+    }
+    if prefix.ptr > 0 {
+        stats.push(stat(Statement::MoveR(prefix.ptr)));
+    }
+    if prefix.len == 0 || stats.len() > size {
+        return false;
+    }
+    stats.extend(prog.stats.drain(prefix.len..));
+    prog.stats = stats;
+    true
+}
+
+/// See [`unroll_loops`].
+pub struct UnrollLoops;
+
+impl Pass for UnrollLoops {
+    fn name(&self) -> &'static str {
+        "unroll-loops"
+    }
+
+    fn run(&self, prog: &mut Prog, opts: &CompileOptions) -> bool {
+        // on a shared tape the program starts on whatever was left there
+        let blank = !opts.shared_tape;
+        unroll_loops(prog, blank, opts.unroll_budget, opts.backend.cell_bits())
+    }
+
+    fn level(&self) -> u8 {
+        3
+    }
+}
+
+/// Unrolls the loops of the outermost block whose trip count is known,
+/// those with a body of straight-line code that leaves the pointer where
+/// it was and adds the same to the loop's cell every time round, reached
+/// with that cell's value known. The body is repeated that many times, as
+/// long as that comes to no more than `budget` statements. Values are
+/// known by following the program from its start, on a tape that starts
+/// out zero when `blank` says so, through code the values going in decide,
+/// loops unrolled included; `cell_bits` is the width cells wrap at.
+/// Returns whether any loop was unrolled or, never going round, dropped.
+pub fn unroll_loops(block: &mut NodeBlock, blank: bool, budget: usize, cell_bits: u32) -> bool {
+    let mut known = Known {
+        rest: blank.then_some(0),
+        ..Default::default()
+    };
+    let mask = u32::MAX >> (32 - cell_bits);
+    let mut changed = false;
+    let mut stats = Vec::with_capacity(block.stats.len());
+    for stat in block.stats.drain(..) {
+        let unrolled = match &stat.stat {
+            Statement::Loop(body) if !stat.attr.no_opt => known
+                .value(known.pos)
+                .and_then(|value| unrolled(body, value, budget, mask)),
+            _ => None,
+        };
+        match unrolled {
+            Some(copies) => {
+                copies.iter().for_each(|copy| known.apply(&copy.stat));
+                stats.extend(copies);
+                changed = true;
+            }
+            // a loop on a zero cell never goes round, whatever is in it
+            None if matches!(stat.stat, Statement::Loop(_))
+                && known.value(known.pos).is_some_and(|v| v & mask == 0) =>
+            {
+                if stat.attr.no_opt {
+                    stats.push(stat);
+                } else {
+                    changed = true;
+                }
+            }
+            None => {
+                known.apply(&stat.stat);
+                stats.push(stat);
+            }
+        }
+    }
+    block.stats = stats;
+    changed
+}
+
+/// The statements of `body` as many times as the loop around it goes
+/// round when its cell starts out `value`, if that can be told and comes
+/// to no more than `budget` of them.
+fn unrolled(body: &NodeBlock, value: u32, budget: usize, mask: u32) -> Option<Vec<NodeStatement>> {
+    let (mut pos, mut step) = (0i64, 0u32);
+    for stat in &body.stats {
+        match &stat.stat {
+            Statement::MoveL(_) | Statement::MoveR(_) => {
+                pos = pos.checked_add(shift(&stat.stat)?)?
+            }
+            Statement::Add(_) | Statement::Sub(_) if pos == 0 => {
+                step = step.wrapping_add(delta(&stat.stat)?);
+            }
+            Statement::Set(_) | Statement::Read | Statement::Print(_) if pos == 0 => return None,
+            Statement::MulAdd { offset, .. } if pos.checked_add(*offset as i64)? == 0 => {
+                return None
+            }
+            Statement::Loop(_) | Statement::If(_) | Statement::Scan(_) | Statement::Fill(_) => {
+                return None
+            }
+            _ => {}
+        }
+    }
+    // a loop not adding to its cell never ends once entered
+    if pos != 0 || step & mask == 0 {
+        return None;
+    }
+    let (mut value, mut trips) = (value, 0usize);
+    while value & mask != 0 {
+        value = value.wrapping_add(step);
+        trips += 1;
+        if trips.checked_mul(body.stats.len())? > budget {
+            return None;
+        }
+    }
+    let copy = |stat: &NodeStatement| NodeStatement {
+        attr: Attr {
+            span: stat.attr.span,
+            ..Default::default()
+        },
+        stat: stat.stat.clone(),
+    };
+    Some(
+        (0..trips)
+            .flat_map(|_| body.stats.iter().map(copy))
+            .collect(),
+    )
+}
+
+/// The values of cells as far as they are known, by their position
+/// relative to the cell the pointer was on when following it started.
+#[derive(Default)]
+struct Known {
+    pos: i64,
+    /// The value of each cell, `None` when unknown, overriding `rest`.
+    cells: HashMap<i64, Option<u32>>,
+    /// The value of the cells `cells` leaves out, if known.
+    rest: Option<u32>,
+}
+
+impl Known {
+    fn value(&self, at: i64) -> Option<u32> {
+        self.cells.get(&at).copied().unwrap_or(self.rest)
+    }
+
+    /// Follows the pointer and the values through `stat`. Past a loop or
+    /// scan only the current cell is known, to be zero.
+    fn apply(&mut self, stat: &Statement) {
+        let pos = self.pos;
+        match stat {
+            Statement::MoveL(_) | Statement::MoveR(_) => {
+                self.pos = pos.saturating_add(shift(stat).unwrap_or(i64::MAX));
+            }
+            Statement::Add(_) | Statement::Sub(_) => {
+                let value = self.value(pos).zip(delta(stat));
+                self.cells
+                    .insert(pos, value.map(|(v, d)| v.wrapping_add(d)));
+            }
+            Statement::Set(value) => {
+                self.cells.insert(pos, Some(*value));
+            }
+            Statement::Fill(values) => {
+                for (at, value) in (pos..).zip(values) {
+                    self.cells.insert(at, Some(*value));
+                }
+            }
+            Statement::Print(bytes) => {
+                self.cells.insert(pos, bytes.last().map(|b| *b as u32));
+            }
+            Statement::Read => {
+                self.cells.insert(pos, None);
+            }
+            Statement::MulAdd { offset, factor } => {
+                let to = pos.saturating_add(*offset as i64);
+                let value = self.value(pos).zip(self.value(to));
+                let value = value.map(|(v, t)| t.wrapping_add(v.wrapping_mul(*factor)));
+                self.cells.insert(to, value);
+            }
+            Statement::Loop(_) | Statement::If(_) | Statement::Scan(_) => {
+                *self = Known::default();
+                self.cells.insert(0, Some(0));
+            }
+            Statement::Write => {}
+        }
+    }
+}
+
+/// See [`dead_stores`].
+pub struct DeadStores;
+
+impl Pass for DeadStores {
+    fn name(&self) -> &'static str {
+        "dead-stores"
+    }
+
+    fn run(&self, prog: &mut Prog, opts: &CompileOptions) -> bool {
+        // a move off the tape between a store and the `Set` overwriting it
+        // ends the run with the store still on the tape
+        if opts.tape_seen_after_exit() {
+            return false;
+        }
+        dead_stores(prog)
+    }
+
+    fn level(&self) -> u8 {
+        1
+    }
+}
+
+/// Drops arithmetic on a cell that a `Set` overwrites further on in the
+/// same block, with nothing looking at the cell in between: no `.` or `,`
+/// on it, no [`Statement::MulAdd`] reading or adding to it and no loop or
+/// scan at all, as those may look at any cell. A `Set` overwritten that
+/// way goes too, as does a `MulAdd` adding to such a cell. Cells are told
+/// apart by how far the pointer moved since the block started or its last
+/// loop. Returns whether anything was dropped.
+pub fn dead_stores(block: &mut NodeBlock) -> bool {
+    let mut changed = false;
+    // where the pointer is at each statement, from the last loop on
+    let mut at = Vec::with_capacity(block.stats.len());
+    let mut pos = 0i64;
+    for stat in &mut block.stats {
+        at.push(pos);
+        match &mut stat.stat {
+            Statement::Loop(body) | Statement::If(body) => {
+                if !stat.attr.no_opt {
+                    changed |= dead_stores(body);
+                }
+                pos = 0;
+            }
+            Statement::Scan(_) => pos = 0,
+            _ => pos = pos.saturating_add(shift(&stat.stat).unwrap_or(0)),
+        }
+    }
+
+    // cells overwritten before anything looks at them, going backwards
+    let mut overwritten: Vec<i64> = Vec::new();
+    let mut dead = vec![false; block.stats.len()];
+    for (i, stat) in block.stats.iter().enumerate().rev() {
+        let pos = at[i];
+        match &stat.stat {
+            Statement::Set(_) | Statement::Add(_) | Statement::Sub(_)
+                if overwritten.contains(&pos) =>
+            {
+                dead[i] = true;
+            }
+            // output from a data definition, storing its last byte
+            Statement::Set(_) | Statement::Print(_) => overwritten.push(pos),
+            Statement::MulAdd { offset, .. }
+                if overwritten.contains(&pos.saturating_add(*offset as i64)) =>
+            {
+                dead[i] = true;
+            }
+            Statement::MulAdd { offset, .. } => {
+                let to = pos.saturating_add(*offset as i64);
+                overwritten.retain(|p| *p != pos && *p != to);
+            }
+            Statement::Add(_) | Statement::Sub(_) | Statement::Read | Statement::Write => {
+                overwritten.retain(|p| *p != pos);
+            }
+            Statement::Loop(_) | Statement::If(_) | Statement::Scan(_) => overwritten.clear(),
+            // writes cells without looking at them
+            Statement::MoveL(_) | Statement::MoveR(_) | Statement::Fill(_) => {}
+        }
+    }
+    let mut dead = dead.into_iter();
+    block.stats.retain(|_| !dead.next().unwrap());
+    changed || at.len() != block.stats.len()
+}
+
+/// See [`if_loops`].
+pub struct IfLoops;
+
+impl Pass for IfLoops {
+    fn name(&self) -> &'static str {
+        "if-loops"
+    }
+
+    fn run(&self, prog: &mut Prog, _: &CompileOptions) -> bool {
+        if_loops(prog)
+    }
+
+    fn level(&self) -> u8 {
+        2
+    }
+}
+
+/// Turns loops whose body always leaves the pointer on their cell and the
+/// cell zero, such as `[>+<[-]]` or a multiply loop once
+/// [`multiply_loops`] is done with it, into a [`Statement::If`]: they go
+/// round at most once, so backends can leave out the test and branch back
+/// at the end of the body. Returns whether any loop was turned.
+pub fn if_loops(block: &mut NodeBlock) -> bool {
+    let mut changed = false;
+    for stat in &mut block.stats {
+        let (Statement::Loop(body) | Statement::If(body)) = &mut stat.stat else {
+            continue;
+        };
+        if stat.attr.no_opt {
+            continue;
+        }
+        changed |= if_loops(body);
+        if matches!(&stat.stat, Statement::Loop(body) if zeroes_its_cell(body)) {
+            if let Statement::Loop(body) = std::mem::replace(&mut stat.stat, Statement::Write) {
+                stat.stat = Statement::If(body);
+            }
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Whether `body` ends on the cell it started on with that cell zero,
+/// whatever it started with: the last thing to touch the cell is a `Set` of
+/// zero or a balanced loop on it, and the pointer moves by known amounts
+/// only, nested loops being balanced. Anything else a nested loop may do
+/// to the cell rules it out.
+fn zeroes_its_cell(body: &NodeBlock) -> bool {
+    let mut pos = 0i64;
+    let mut zero = false;
+    for stat in &body.stats {
+        match &stat.stat {
+            Statement::MoveL(_) | Statement::MoveR(_) => {
+                pos = match shift(&stat.stat).and_then(|by| pos.checked_add(by)) {
+                    Some(pos) => pos,
+                    None => return false,
+                };
+            }
+            Statement::Set(value) if pos == 0 => zero = *value == 0,
+            Statement::Print(bytes) if pos == 0 => zero = bytes.last() == Some(&0),
+            Statement::Fill(values) => {
+                let at = pos.checked_neg().and_then(|at| usize::try_from(at).ok());
+                if let Some(value) = at.and_then(|at| values.get(at)) {
+                    zero = *value == 0;
+                }
+            }
+            Statement::Add(_) | Statement::Sub(_) | Statement::Read if pos == 0 => {
+                zero &= delta(&stat.stat) == Some(0);
+            }
+            Statement::MulAdd { offset, .. } if pos.checked_add(*offset as i64) == Some(0) => {
+                zero = false;
+            }
+            Statement::Loop(_) | Statement::If(_) if !stat.attr.metrics.balanced => return false,
+            // a loop ends on a zero cell, an `If` on one it left zero
+            Statement::Loop(_) | Statement::If(_) => zero = pos == 0,
+            Statement::Scan(_) => return false,
+            _ => {}
+        }
+    }
+    pos == 0 && zero
+}
+
+/// See [`elide_dead_at_exit`].
+pub struct ElideDeadAtExit;
+
+impl Pass for ElideDeadAtExit {
+    fn name(&self) -> &'static str {
+        "elide-dead-at-exit"
+    }
+
+    fn run(&self, prog: &mut Prog, opts: &CompileOptions) -> bool {
+        if opts.tape_seen_after_exit() {
+            return false;
+        }
+        let before = prog.stats.len();
+        elide_dead_at_exit(prog, opts.keep_all_io);
+        prog.stats.len() != before
+    }
+}
+
+/// Drops the statements at the end of the program whose only effect is on
+/// the tape, which nobody can observe once the program exits. Moves may
+/// still trip the bounds check and loops may not terminate, so anything
+/// other than plain arithmetic stops the elision, and so does I/O.
+///
+/// With `keep_all_io` programs doing any I/O are left untouched, for those
+/// relying on the time spent after their last output.
+pub fn elide_dead_at_exit(prog: &mut Prog, keep_all_io: bool) {
+    if keep_all_io && contains_io(prog) {
+        return;
+    }
+
+    while let Some(last) = prog.stats.last() {
+        match last.stat {
+            Statement::Add(_) | Statement::Sub(_) | Statement::Set(_) => {
+                prog.stats.pop();
+            }
+            _ => break,
+        }
+    }
+}
+
+fn contains_io(block: &NodeBlock) -> bool {
+    block.stats.iter().any(|stat| match &stat.stat {
+        Statement::Read | Statement::Write | Statement::Print(_) => true,
+        Statement::Loop(b) | Statement::If(b) => contains_io(b),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ast, lex, AtExit, OptLevel};
+
+    fn parse(src: &str) -> Prog {
+        ast::parse(&lex::lex(src.to_string())).unwrap()
+    }
+
+    #[test]
+    fn elide_trailing_arithmetic() {
+        let mut prog = parse("+.>+++--");
+        elide_dead_at_exit(&mut prog, false);
+        assert_eq!(prog.stats.len(), 3);
+        assert_eq!(prog.stats[2].stat, Statement::MoveR(1));
+    }
+
+    #[test]
+    fn shared_tapes_keep_trailing_stores() {
+        // whatever the program leaves on the tape its caller sees
+        for src in ["+++>++", "+<>[-]"] {
+            let expected = interp::run_prefix(&parse(src).stats, Limits::default());
+            for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+                let opts = CompileOptions {
+                    shared_tape: true,
+                    opt_level,
+                    ..Default::default()
+                };
+                let prog = crate::optimize(src, &opts).unwrap();
+                let found = interp::run_prefix(&prog.stats, Limits::default());
+                assert_eq!(found.tape, expected.tape, "{} at {:?}", src, opt_level);
+            }
+        }
+    }
+
+    #[test]
+    fn dumped_tapes_keep_trailing_arithmetic() {
+        let src = "+>+++<++";
+        let ran = interp::run_prefix(&parse(src).stats, Limits::default());
+        let cells: String = ran.tape.iter().map(|c| format!(" {}", c)).collect();
+        let expected = format!("bfc: tape:{}\nbfc: pointer at cell {}\n", cells, ran.ptr);
+        for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            // the dump walks back from the end of the tape
+            let opts = CompileOptions {
+                tape_len: 16,
+                opt_level,
+                atexit: AtExit {
+                    dump_tape: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let il = crate::compile_with(src.to_string(), &opts).unwrap();
+            let (_, dumped) = crate::validate::run_il_with_stderr(&il, b"", 100_000).unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&dumped),
+                expected,
+                "{:?}",
+                opt_level
+            );
+        }
+    }
+
+    #[test]
+    fn passes_leave_tapes_seen_after_exit_alone() {
+        // each of them drops or folds the stores to cell 0 otherwise
+        let passes: [(&dyn Pass, &str); 4] = [
+            (&ConstantOutput, "+>++<[-]>+"),
+            (&PartialEval, "+>++<[-]>+,"),
+            (&DeadStores, "+>++<[-]>+,"),
+            (&ElideDeadAtExit, ",>+<+"),
+        ];
+        for (pass, src) in passes {
+            let prog = || {
+                let mut prog = crate::parse(src).unwrap();
+                clear_loops(&mut prog);
+                prog
+            };
+            assert!(
+                pass.run(&mut prog(), &CompileOptions::default()),
+                "{}",
+                pass.name()
+            );
+            for atexit in ["dump-tape", "histogram"] {
+                let opts = CompileOptions {
+                    atexit: atexit.parse().unwrap(),
+                    ..Default::default()
+                };
+                assert!(
+                    !pass.run(&mut prog(), &opts),
+                    "{} with {}",
+                    pass.name(),
+                    atexit
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fuse_across_comments() {
+        let mut prog = crate::parse_with_trivia("++ two ++\n[>> more >-]--").unwrap();
+        assert_eq!(prog.stats.len(), 4);
+        assert!(fuse_runs(&mut prog));
+        assert_eq!(prog.stats.len(), 3);
+        assert_eq!(prog.stats[0].stat, Statement::Add(4));
+        assert_eq!(prog.stats[0].attr.trivia.trailing, " two ");
+        let Statement::Loop(body) = &prog.stats[1].stat else {
+            panic!("expected a loop");
+        };
+        assert_eq!(body.stats[0].stat, Statement::MoveR(3));
+        assert_eq!(body.stats[0].attr.span.col, 2);
+        assert!(!fuse_runs(&mut prog));
+    }
+
+    #[test]
+    fn cancel_opposing() {
+        // `><<` takes the pointer off the tape, so the `>` after it stays
+        let mut prog = crate::parse("+++--.>+-<<>[>><<-+]").unwrap();
+        assert!(cancel_pairs(&mut prog, Some(0), 30_000));
+        assert_eq!(
+            prog.to_string(),
+            "Block\n  Add(1)\n  Write\n  MoveL(1)\n  MoveR(1)\n  Loop\n    Block\n"
+        );
+
+        // `<` off the left end of the tape exits, whatever comes after
+        let mut prog = crate::parse("<>+").unwrap();
+        assert!(!cancel_pairs(&mut prog, Some(0), 30_000));
+        let mut prog = crate::parse("><").unwrap();
+        assert!(!cancel_pairs(&mut prog, Some(0), 1));
+        assert!(!cancel_pairs(&mut prog, None, 30_000));
+        assert!(cancel_pairs(&mut prog, Some(0), 2));
+        assert!(prog.stats.is_empty());
+    }
+
+    #[test]
+    fn clear_odd_loops() {
+        let mut prog = crate::parse("[-][+++]>[--][>[+]<]").unwrap();
+        assert!(clear_loops(&mut prog));
+        assert_eq!(
+            prog.to_string(),
+            "Block\n  Set(0)\n  Set(0)\n  MoveR(1)\n  Loop\n    Block\n      Sub(2)\n  \
+             Loop\n    Block\n      MoveR(1)\n      Set(0)\n      MoveL(1)\n"
+        );
+        assert!(!clear_loops(&mut prog));
+    }
+
+    #[test]
+    fn multiply_balanced_loops() {
+        let src = "+++[->++>+++<<]>>[<+>+]<[>>+<<+]>>>+[<<-<+>>>-]<<<<.>.>.>.";
+        let mut prog = crate::parse(src).unwrap();
+        assert!(multiply_loops(&mut prog));
+        let bodies: Vec<_> = prog
+            .stats
+            .iter()
+            .filter_map(|stat| match &stat.stat {
+                Statement::Loop(body) => Some(body.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            bodies,
+            [
+                "Block\n  MulAdd(1, 2)\n  MulAdd(2, 3)\n  Set(0)\n",
+                "Block\n  MulAdd(-1, 4294967295)\n  Set(0)\n",
+                "Block\n  MulAdd(2, 4294967295)\n  Set(0)\n",
+                "Block\n  MulAdd(-2, 4294967295)\n  MulAdd(-3, 1)\n  Set(0)\n",
+            ]
+        );
+        // counting up takes too long to run before
+        let src = "+++[->++>+++<<]>>>++[<<-<+>>>-]<<<.>.>.>.";
+        let mut prog = crate::parse(src).unwrap();
+        assert!(multiply_loops(&mut prog));
+        let limits = crate::interp::Limits::default();
+        let before = crate::interp::run(&crate::parse(src).unwrap(), b"", limits);
+        let after = crate::interp::run(&prog, b"", limits);
+        assert_eq!(after.output, [2, 4, 9, 0]);
+        assert_eq!(after.output, before.output);
+        assert!(after.steps < before.steps);
+
+        // stops at a cell it leaves alone, or adds two to its own
+        for src in ["[->>><+<<]", "[-->+<]", "[->+<.]", "[>+<]"] {
+            assert!(!multiply_loops(&mut crate::parse(src).unwrap()), "{}", src);
+        }
+    }
+
+    #[test]
+    fn scan_moving_loops() {
+        let src = "+>+>+>>+<<<<[>]>[<<]+[>[-]<[<]]";
+        let mut prog = crate::parse(src).unwrap();
+        assert!(scan_loops(&mut prog));
+        let scans: Vec<_> = prog
+            .stats
+            .iter()
+            .map(|stat| stat.stat.to_string())
+            .filter(|stat| stat.starts_with("Scan"))
+            .collect();
+        assert_eq!(scans, ["Scan(1)", "Scan(-2)"]);
+        let Statement::Loop(body) = &prog.stats.last().unwrap().stat else {
+            panic!("expected a loop");
+        };
+        assert_eq!(body.stats[3].stat, Statement::Scan(-1));
+        assert!(!scan_loops(&mut prog));
+
+        let limits = crate::interp::Limits::default();
+        let before = crate::interp::run(&crate::parse("+>+[>]<[<<]<.").unwrap(), b"", limits);
+        let mut prog = crate::parse("+>+[>]<[<<]<.").unwrap();
+        scan_loops(&mut prog);
+        let after = crate::interp::run(&prog, b"", limits);
+        assert_eq!((after.output, after.status), (before.output, before.status));
+    }
+
+    #[test]
+    fn drop_loops_on_zero_cells() {
+        let src = "[a comment, with commands.]>[+]<+[-][.]>[[-][.]].";
+        let mut prog = parse(src);
+        assert!(dead_loops(&mut prog, true));
+        assert_eq!(prog.to_string(), parse("><+[-]>[[-]].").to_string());
+        let limits = crate::interp::Limits::default();
+        let before = crate::interp::run(&parse(src), b"", limits);
+        let after = crate::interp::run(&prog, b"", limits);
+        assert_eq!((after.output, after.status), (before.output, before.status));
+
+        // a shared tape may start out anything
+        let mut prog = parse(src);
+        assert!(dead_loops(&mut prog, false));
+        assert!(matches!(prog.stats[0].stat, Statement::Loop(_)));
+    }
+
+    #[test]
+    fn print_constant_output() {
+        let limits = Limits {
+            steps: 10_000,
+            cells: 100,
+        };
+        let hello = "++++++++[>+++++++++<-]>.<+++[>++++++++++<-]>-.+++++++..+++.[-]++++++++++.";
+        let mut prog = crate::parse(hello).unwrap();
+        assert!(constant_output(&mut prog, limits));
+        let stats: Vec<_> = prog.stats.iter().map(|s| s.stat.to_string()).collect();
+        assert_eq!(stats, ["Print(\"Hello\\n\")"]);
+        assert!(!constant_output(&mut prog, limits));
+
+        let mut prog = crate::parse("+++>+<").unwrap();
+        assert!(constant_output(&mut prog, limits));
+        assert!(prog.stats.is_empty());
+        // reading, looping for good or moving off the tape
+        for src in [",.", "+[]", "+.<"] {
+            assert!(!constant_output(&mut crate::parse(src).unwrap(), limits));
+        }
+
+        let opts = CompileOptions {
+            opt_level: OptLevel::O2,
+            validate: true,
+            ..Default::default()
+        };
+        let il = crate::compile_with(hello.to_string(), &opts).unwrap();
+        assert_eq!(il.matches("call $write(").count(), 1);
+        let histogram = CompileOptions {
+            atexit: AtExit {
+                histogram: true,
+                ..Default::default()
+            },
+            ..opts
+        };
+        assert!(!ConstantOutput.run(&mut crate::parse(hello).unwrap(), &histogram));
+    }
+
+    #[test]
+    fn evaluate_what_needs_no_input() {
+        let src = "++++++[>++++++++<-]>.+.<+++[>>+<<-],[.[-],]";
+        let mut prog = crate::parse(src).unwrap();
+        let limits = Limits {
+            steps: 1000,
+            cells: 100,
+        };
+        assert!(partial_eval(&mut prog, limits));
+        let stats: Vec<_> = prog.stats.iter().map(|s| s.stat.to_string()).collect();
+        assert_eq!(stats, ["Print(\"01\")", "Fill(0, 49, 3)", "Read", "Loop"]);
+        for input in [&b""[..], b"ab"] {
+            let before = crate::interp::run(&crate::parse(src).unwrap(), input, limits);
+            let after = crate::interp::run(&prog, input, limits);
+            assert_eq!((after.output, after.status), (before.output, before.status));
+        }
+
+        // out of fuel after the first statement, or off the tape at once
+        let mut prog = crate::parse(src).unwrap();
+        assert!(partial_eval(
+            &mut prog,
+            Limits {
+                steps: 10,
+                ..limits
+            }
+        ));
+        assert_eq!(prog.stats[0].stat, Statement::Fill(vec![6]));
+        assert!(!partial_eval(&mut crate::parse("<+").unwrap(), limits));
+
+        let opts = CompileOptions {
+            opt_level: OptLevel::O3,
+            validate: true,
+            ..Default::default()
+        };
+        let il = crate::compile_with(src.to_string(), &opts).unwrap();
+        assert!(il.contains("call $memcpy("));
+        let keep_all_io = CompileOptions {
+            keep_all_io: true,
+            ..opts
+        };
+        assert!(!PartialEval.run(&mut crate::parse(src).unwrap(), &keep_all_io));
+    }
+
+    #[test]
+    fn unroll_known_trip_counts() {
+        let src = "+++[>++.<-]>[-]<++++++++[>+<-]>.<,[>.<-]";
+        let mut prog = crate::parse(src).unwrap();
+        assert!(unroll_loops(&mut prog, true, 16, 32));
+        let stats: Vec<_> = prog.stats.iter().map(|s| s.stat.to_string()).collect();
+        assert_eq!(stats.len(), 31);
+        assert_eq!(
+            stats[1..6],
+            ["MoveR(1)", "Add(2)", "Write", "MoveL(1)", "Sub(1)"]
+        );
+        assert_eq!(stats.iter().filter(|s| *s == "Loop").count(), 2);
+        let limits = crate::interp::Limits::default();
+        for input in [&b""[..], b"\x02"] {
+            let before = crate::interp::run(&crate::parse(src).unwrap(), input, limits);
+            let after = crate::interp::run(&prog, input, limits);
+            assert_eq!((after.output, after.status), (before.output, before.status));
+        }
+
+        // cells wrap at their width, a shared tape starts out anything
+        let src = "-[>+<-]";
+        let mut prog = crate::parse(src).unwrap();
+        assert!(!unroll_loops(&mut prog, true, 1024, 32));
+        assert!(!unroll_loops(&mut prog, false, 1024, 8));
+        assert!(unroll_loops(&mut prog, true, 1024, 8));
+        assert_eq!(prog.stats.len(), 1 + 255 * 4);
+
+        // a loop never going round goes, unless it is marked no-opt
+        let mut prog = crate::parse("[.>][.>]+").unwrap();
+        prog.stats[1].attr.no_opt = true;
+        assert!(unroll_loops(&mut prog, true, 16, 32));
+        let stats: Vec<_> = prog.stats.iter().map(|s| s.stat.to_string()).collect();
+        assert_eq!(stats, ["Loop", "Add(1)"]);
+        assert!(prog.stats[0].attr.no_opt);
+    }
+
+    #[test]
+    fn drop_overwritten_stores() {
+        let src = "+++[-]>++<[-].>[-]+>,[-]<<[->+<]++[-]";
+        let mut prog = parse(src);
+        clear_loops(&mut prog);
+        assert!(dead_stores(&mut prog));
+        // `++` goes too, the `.` in between writes another cell
+        let mut expected = parse("><[-].>[-]+>,[-]<<[->+<][-]");
+        clear_loops(&mut expected);
+        assert_eq!(prog.to_string(), expected.to_string());
+        let limits = crate::interp::Limits::default();
+        for input in [&b""[..], b"a"] {
+            let before = crate::interp::run(&parse(src), input, limits);
+            let after = crate::interp::run(&prog, input, limits);
+            assert_eq!((after.output, after.status), (before.output, before.status));
+        }
+        let dump_tape = CompileOptions {
+            atexit: AtExit {
+                dump_tape: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // on a tape of one cell `>` ends the run with `+` on the dumped tape
+        let mut prog = parse("+><[-]");
+        clear_loops(&mut prog);
+        assert!(!DeadStores.run(&mut prog.clone(), &dump_tape));
+        assert!(DeadStores.run(&mut prog, &CompileOptions::default()));
+    }
+
+    #[test]
+    fn turn_loops_going_round_once_into_ifs() {
+        let src = "[>[-]<],[>+<[-]]>.<++[->+++<]>.[-.]";
+        let mut prog = crate::parse(src).unwrap();
+        multiply_loops(&mut prog);
+        assert!(if_loops(&mut prog));
+        let stats: Vec<_> = prog.stats.iter().map(|s| s.stat.to_string()).collect();
+        assert_eq!(
+            stats,
+            [
+                "Loop", "Read", "If", "MoveR(1)", "Write", "MoveL(1)", "Add(2)", "If", "MoveR(1)",
+                "Write", "Loop"
+            ]
+        );
+        let limits = crate::interp::Limits::default();
+        for input in [&b""[..], b"a"] {
+            let before = crate::interp::run(&crate::parse(src).unwrap(), input, limits);
+            let after = crate::interp::run(&prog, input, limits);
+            assert_eq!((after.output, after.status), (before.output, before.status));
+        }
+
+        let opts = CompileOptions {
+            backend: crate::Backend::C,
+            opt_level: OptLevel::O2,
+            ..Default::default()
+        };
+        let c = crate::compile_with(src.to_string(), &opts).unwrap();
+        assert_eq!(c.matches("if (tape[p]) {").count(), 2);
+    }
+
+    #[test]
+    fn leave_no_opt_loops() {
+        let src = "{#pragma no-opt}\n[-[->+<]++ ++]>{#pragma no-opt} [>]<[-].";
+        let mut prog = crate::parse(src).unwrap();
+        assert!(prog.stats[0].attr.no_opt && prog.stats[2].attr.no_opt);
+        let before = prog.to_string();
+        let opts = CompileOptions {
+            opt_level: OptLevel::O3,
+            ..Default::default()
+        };
+        PassManager::for_options(&opts).run(&mut prog, &opts, &mut Timings::default());
+        // the last loop is the only one cleared
+        let kept = before
+            .strip_suffix("  Loop\n    Block\n      Sub(1)\n  Write\n")
+            .unwrap();
+        assert_eq!(prog.to_string(), format!("{}  Set(0)\n  Write\n", kept));
+        assert!(prog.stats[0].attr.no_opt);
+    }
+
+    #[test]
+    fn io_is_a_barrier() {
+        let mut prog = parse("+.+");
+        elide_dead_at_exit(&mut prog, false);
+        assert_eq!(prog.stats.len(), 2);
+        assert_eq!(prog.stats[1].stat, Statement::Write);
+    }
+
+    #[test]
+    fn pipeline_by_name() {
+        assert_eq!("".parse(), Ok(Pipeline(Vec::new())));
+        let pipeline: Pipeline = "elide-dead-at-exit,elide-dead-at-exit".parse().unwrap();
+        assert_eq!(pipeline.0.len(), 2);
+        assert!("fuse"
+            .parse::<Pipeline>()
+            .unwrap_err()
+            .contains("elide-dead-at-exit"));
+
+        let opts = CompileOptions {
+            passes: Some(pipeline),
+            ..Default::default()
+        };
+        let manager = PassManager::for_options(&opts);
+        let mut prog = parse("+.+");
+        let mut timings = Timings::default();
+        let changed = manager.run(&mut prog, &opts, &mut timings);
+        assert_eq!(changed, ["elide-dead-at-exit"]);
+        assert_eq!(timings.phases()[0].0, "elide-dead-at-exit");
+        assert!(PassManager::new().names().is_empty());
+    }
+
+    #[test]
+    fn default_pipeline_by_level() {
+        for level in OptLevel::ALL {
+            let opts = CompileOptions {
+                opt_level: level,
+                ..Default::default()
+            };
+            let names = PassManager::for_options(&opts).names();
+            let expected: Vec<_> = registry()
+                .iter()
+                .filter(|p| p.level() <= level.rank())
+                .map(|p| p.name())
+                .collect();
+            assert_eq!(names, expected);
+            assert!(names.contains(&"elide-dead-at-exit"));
+        }
+        assert!(OptLevel::O3.rank() > OptLevel::Size.rank());
+    }
+
+    #[test]
+    fn toggle_passes() {
+        let off: Toggle = "no-elide-dead-at-exit".parse().unwrap();
+        assert!(!off.on);
+        assert!("no-fuse".parse::<Toggle>().is_err());
+
+        let mut opts = CompileOptions {
+            toggles: vec![off],
+            ..Default::default()
+        };
+        assert!(PassManager::for_options(&opts).names().is_empty());
+        opts.toggles.push("elide-dead-at-exit".parse().unwrap());
+        assert_eq!(
+            PassManager::for_options(&opts).names(),
+            ["elide-dead-at-exit"]
+        );
+    }
+
+    #[test]
+    fn keep_all_io() {
+        let mut prog = parse("[.]+");
+        elide_dead_at_exit(&mut prog, true);
+        assert_eq!(prog.stats.len(), 2);
+
+        let mut prog = parse("[>]+");
+        elide_dead_at_exit(&mut prog, true);
+        assert_eq!(prog.stats.len(), 1);
+    }
+}
@@ -0,0 +1,127 @@
+//! A static page for trying programs out in the browser, written by
+//! `bfc playground --export`. It works offline and needs no server beyond
+//! one handing out files: the page carries an interpreter in JavaScript
+//! that runs programs like the compiled executables do, 32 bit cells, `,`
+//! at end of input leaving the cell alone and moving off the tape ending
+//! the run with status 1.
+
+use crate::CompileOptions;
+
+/// Steps a run may take before the page gives up on it, so that a program
+/// that never stops does not hang the tab.
+const MAX_STEPS: u64 = 10_000_000;
+
+/// The files making up the playground, by name, for programs run with
+/// `opts`.
+pub fn files(opts: &CompileOptions) -> Vec<(&'static str, String)> {
+    vec![
+        ("index.html", INDEX.to_string()),
+        (
+            "playground.js",
+            SCRIPT
+                .replace("@TAPE_LEN@", &opts.tape_len.to_string())
+                .replace("@MAX_STEPS@", &MAX_STEPS.to_string()),
+        ),
+    ]
+}
+
+const INDEX: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>bfc playground</title>
+<style>
+  body { font-family: sans-serif; max-width: 60em; margin: 2em auto; }
+  textarea, pre { width: 100%; box-sizing: border-box; font-family: monospace; }
+  pre { background: #f4f4f4; min-height: 4em; padding: 0.5em; white-space: pre-wrap; }
+</style>
+</head>
+<body>
+<h1>bfc playground</h1>
+<label for="source">Program</label>
+<textarea id="source" rows="12" spellcheck="false">++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.</textarea>
+<label for="input">Input</label>
+<textarea id="input" rows="3" spellcheck="false"></textarea>
+<p><button id="run">Run</button> <span id="status"></span></p>
+<pre id="output"></pre>
+<script src="playground.js"></script>
+</body>
+</html>
+"#;
+
+const SCRIPT: &str = r#""use strict";
+
+const TAPE_LEN = @TAPE_LEN@;
+const MAX_STEPS = @MAX_STEPS@;
+
+// Runs `src` on the bytes of `input`, returning what it wrote and how the
+// run ended.
+function run(src, input) {
+  const code = [];
+  const jumps = [];
+  const open = [];
+  for (let i = 0; i < src.length; i++) {
+    const c = src[i];
+    if (!"+-<>,.[]".includes(c)) continue;
+    if (c === "[") open.push(code.length);
+    if (c === "]") {
+      if (open.length === 0) return { output: [], status: "unmatched ] at offset " + i };
+      const start = open.pop();
+      jumps[start] = code.length;
+      jumps[code.length] = start;
+    }
+    code.push(c);
+  }
+  if (open.length > 0) return { output: [], status: "unmatched [" };
+
+  const tape = new Uint32Array(TAPE_LEN);
+  const output = [];
+  let ptr = 0;
+  let read = 0;
+  let steps = 0;
+  for (let pc = 0; pc < code.length; pc++) {
+    if (++steps > MAX_STEPS) return { output, status: "stopped after " + MAX_STEPS + " steps" };
+    switch (code[pc]) {
+      case "+": tape[ptr]++; break;
+      case "-": tape[ptr]--; break;
+      case ">": if (++ptr >= TAPE_LEN) return { output, status: "moved off the tape, exit status 1" }; break;
+      case "<": if (--ptr < 0) return { output, status: "moved off the tape, exit status 1" }; break;
+      case ",": if (read < input.length) tape[ptr] = input[read++]; break;
+      case ".": output.push(tape[ptr] & 0xff); break;
+      case "[": if (tape[ptr] === 0) pc = jumps[pc]; break;
+      case "]": if (tape[ptr] !== 0) pc = jumps[pc]; break;
+    }
+  }
+  return { output, status: "exit status 0" };
+}
+
+if (typeof document !== "undefined") {
+  document.getElementById("run").addEventListener("click", () => {
+    const input = new TextEncoder().encode(document.getElementById("input").value);
+    const result = run(document.getElementById("source").value, input);
+    document.getElementById("output").textContent =
+      new TextDecoder().decode(new Uint8Array(result.output));
+    document.getElementById("status").textContent = result.status;
+  });
+} else {
+  module.exports = { run };
+}
+"#;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn export_fills_in_options() {
+        let opts = CompileOptions {
+            tape_len: 64,
+            ..Default::default()
+        };
+        let files = files(&opts);
+        assert_eq!(files[0].0, "index.html");
+        assert!(files[0].1.contains("<script src=\"playground.js\">"));
+        assert!(files[1].1.contains("const TAPE_LEN = 64;\n"));
+        assert!(!files[1].1.contains('@'));
+    }
+}
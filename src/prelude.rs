@@ -0,0 +1,38 @@
+//! The part of the library meant to be built on: compiling a source with
+//! [`CompileOptions`] for a [`Backend`], checking it for [`Diagnostic`]s,
+//! and running it under the reference interpreter, [`interp::run`]. These
+//! keep their names and signatures across releases, where the modules
+//! hidden from the documentation, the code generators' internals, the
+//! lexer and the machinery of `bfc`'s own subcommands, may change with any
+//! of them.
+//!
+//! [`Error`], [`Backend`], [`AtExit`] and [`CompileOptions`] grow new
+//! variants and fields as the compiler learns new tricks, so they are
+//! `#[non_exhaustive]`: match them with a wildcard arm, and start options
+//! from [`CompileOptions::default`] or [`CompileOptions::host`] before
+//! setting the fields that matter.
+//!
+//! ```
+//! use brainfuck_compiler::prelude::*;
+//!
+//! let mut opts = CompileOptions::default();
+//! opts.backend = Backend::C;
+//! opts.opt_level = OptLevel::O2;
+//! let c = compile_with("++[->+<]>.".to_string(), &opts).unwrap();
+//! assert!(c.contains("main"));
+//! assert!(check("[").len() == 1);
+//!
+//! let prog = parse(",+.").unwrap();
+//! let outcome = interp::run(&prog, b"a", Limits::default());
+//! assert_eq!(outcome.output, b"b");
+//! ```
+
+pub use crate::{
+    ast::Diagnostic,
+    check, compile, compile_bytecode, compile_executable, compile_object, compile_with,
+    idiom::Idiom,
+    interp::{self, Limits, Outcome, Status},
+    optimize, parse,
+    target::Target,
+    AtExit, Backend, Checkpoint, CompileOptions, Entry, Error, Linkage, Newline, OptLevel,
+};
@@ -0,0 +1,309 @@
+//! Python 3, for teaching: the script reads like the program it came from,
+//! one line per statement and a `while tape[p]:` per loop, and runs with
+//! nothing but the interpreter. Behaves like the other backends: 32 bit
+//! cells, `,` at end of input leaves the cell alone, and moving off the tape
+//! exits with status 1.
+//!
+//! CPython refuses more than 20 nested blocks in a function, so loops
+//! nested deeper than [`MAX_NESTING`] get a function of their own.
+
+use std::fmt::Write;
+
+use crate::{ast, backend::CodeGen, CompileOptions, Error, Newline};
+
+/// Deepest loop nesting kept in one function.
+pub const MAX_NESTING: usize = 16;
+
+const READ_ERR: &str = "bfc: read error\\n";
+const WRITE_ERR: &str = "bfc: write error\\n";
+
+pub struct PythonGenerator {
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    debug_file: Option<String>,
+    loop_counter: usize,
+    indent: usize,
+    depth: usize,
+    body: String,
+    /// Loops moved out of the way of the nesting limit, in order.
+    functions: Vec<String>,
+}
+
+impl Default for PythonGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for PythonGenerator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl PythonGenerator {
+    pub fn new() -> Self {
+        PythonGenerator {
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            debug_file: None,
+            loop_counter: 0,
+            indent: 1,
+            depth: 0,
+            body: String::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    pub fn with_options(opts: &CompileOptions) -> Self {
+        PythonGenerator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            debug_file: opts.debug_file.clone(),
+            ..Self::new()
+        }
+    }
+
+    pub fn gen(&mut self, prog: &ast::Prog) -> String {
+        self.body.push_str("def main():\n    global p\n");
+        self.generate_block(prog);
+        self.line("flush()");
+        let main = std::mem::take(&mut self.body);
+
+        let mut out = String::new();
+        out.push_str("#!/usr/bin/env python3\nimport sys\n\n");
+        writeln!(out, "TAPE_LEN = {}", self.tape_len).unwrap();
+        out.push_str("tape = [0] * TAPE_LEN\np = 0\n\n\n");
+
+        out.push_str("def putc(c):\n");
+        let write = if self.crlf {
+            "sys.stdout.buffer.write(b\"\\r\\n\" if c & 0xFF == 10 else bytes([c & 0xFF]))"
+        } else {
+            "sys.stdout.buffer.write(bytes([c & 0xFF]))"
+        };
+        writeln!(out, "    try:\n        {}\n    except OSError:", write).unwrap();
+        if self.io_strict {
+            writeln!(out, "        fail(\"{}\")\n\n", WRITE_ERR).unwrap();
+        } else {
+            // carry on like the native programs do
+            out.push_str("        pass\n\n\n");
+        }
+
+        out.push_str("def getc():\n    flush()\n");
+        let read = if self.crlf {
+            "c = sys.stdin.buffer.read(1)\n        while c == b\"\\r\":\n            c = sys.stdin.buffer.read(1)"
+        } else {
+            "c = sys.stdin.buffer.read(1)"
+        };
+        if self.io_strict {
+            writeln!(out, "    try:\n        {}", read).unwrap();
+            out.push_str("    except OSError:\n        fail(\"");
+            out.push_str(READ_ERR);
+            out.push_str("\")\n");
+        } else {
+            writeln!(out, "    {}", read.replace("\n    ", "\n")).unwrap();
+        }
+        out.push_str("    return c[0] if c else -1\n\n\n");
+
+        out.push_str("def flush():\n");
+        if self.io_strict {
+            out.push_str(
+                "    try:\n        sys.stdout.flush()\n    except OSError:\n        fail(\"",
+            );
+            out.push_str(WRITE_ERR);
+            out.push_str("\")\n\n\n");
+            out.push_str("def fail(msg):\n    sys.stderr.write(msg)\n    sys.exit(1)\n\n\n");
+        } else {
+            out.push_str(
+                "    try:\n        sys.stdout.flush()\n    except OSError:\n        pass\n\n\n",
+            );
+        }
+
+        for func in std::mem::take(&mut self.functions) {
+            out.push_str(&func);
+            out.push_str("\n\n");
+        }
+        out.push_str(&main);
+        out.push_str("\n\nif __name__ == \"__main__\":\n    main()\n");
+        out
+    }
+
+    fn generate_block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.generate_statement(stat)
+        }
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) {
+        if let Some(file) = &self.debug_file {
+            let span = stat.attr.span;
+            let line = format!("# {}:{}:{}", file, span.line, span.col);
+            self.line(&line);
+        }
+
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move("-=", *n),
+            ast::Statement::MoveR(n) => self.generate_move("+=", *n),
+            ast::Statement::Add(n) => {
+                self.line(&format!("tape[p] = (tape[p] + {}) & 0xFFFFFFFF", *n as u32))
+            }
+            ast::Statement::Sub(n) => {
+                self.line(&format!("tape[p] = (tape[p] - {}) & 0xFFFFFFFF", *n as u32))
+            }
+            ast::Statement::Set(n) => self.line(&format!("tape[p] = {}", n)),
+            ast::Statement::MulAdd { offset, factor } => {
+                let (there, back) = if *offset < 0 {
+                    ("-=", "+")
+                } else {
+                    ("+=", "-")
+                };
+                let n = offset.unsigned_abs();
+                self.generate_move(there, n);
+                self.line(&format!(
+                    "tape[p] = (tape[p] + tape[p {} {}] * {}) & 0xFFFFFFFF",
+                    back, n, factor
+                ));
+                self.generate_move(if *offset < 0 { "+=" } else { "-=" }, n);
+            }
+            ast::Statement::Read => {
+                self.line("c = getc()");
+                self.line("if c >= 0:");
+                self.line("    tape[p] = c");
+            }
+            ast::Statement::Write => self.line("putc(tape[p])"),
+            // a loop, but one never holding another
+            ast::Statement::Scan(stride) => {
+                self.line("while tape[p]:");
+                self.indent += 1;
+                let op = if *stride < 0 { "-=" } else { "+=" };
+                self.generate_move(op, stride.unsigned_abs());
+                self.indent -= 1;
+            }
+            // an `if` goes round at most once as the loop it came from
+            ast::Statement::Loop(body) | ast::Statement::If(body) if self.depth == MAX_NESTING => {
+                self.generate_function(body)
+            }
+            ast::Statement::If(body) => {
+                self.line("if tape[p]:");
+                self.indent += 1;
+                self.depth += 1;
+                if body.stats.is_empty() {
+                    self.line("pass");
+                }
+                self.generate_block(body);
+                self.depth -= 1;
+                self.indent -= 1;
+            }
+            ast::Statement::Fill(values) => {
+                let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+                self.line(&format!(
+                    "tape[p:p + {}] = [{}]",
+                    values.len(),
+                    values.join(", ")
+                ));
+            }
+            ast::Statement::Print(bytes) => {
+                let chars: Vec<_> = bytes.iter().map(|b| b.to_string()).collect();
+                self.line(&format!("for c in ({},):", chars.join(", ")));
+                self.indent += 1;
+                self.line("putc(c)");
+                self.indent -= 1;
+                self.line(&format!("tape[p] = {}", bytes[bytes.len() - 1]));
+            }
+            ast::Statement::Loop(body) => {
+                self.line("while tape[p]:");
+                self.indent += 1;
+                self.depth += 1;
+                if body.stats.is_empty() {
+                    self.line("pass");
+                }
+                self.generate_block(body);
+                self.depth -= 1;
+                self.indent -= 1;
+            }
+        }
+    }
+
+    /// Moves the loop `body` into a function of its own and calls it.
+    fn generate_function(&mut self, body: &ast::NodeBlock) {
+        let name = format!("loop_{}", self.loop_counter);
+        self.loop_counter += 1;
+        self.line(&format!("{}()", name));
+
+        let outer = std::mem::replace(&mut self.body, format!("def {}():\n    global p\n", name));
+        let (indent, depth) = (self.indent, self.depth);
+        self.indent = 1;
+        self.depth = 0;
+        self.line("while tape[p]:");
+        self.indent += 1;
+        self.depth += 1;
+        if body.stats.is_empty() {
+            self.line("pass");
+        }
+        self.generate_block(body);
+        (self.indent, self.depth) = (indent, depth);
+        let func = std::mem::replace(&mut self.body, outer);
+        self.functions.push(func);
+    }
+
+    /// Moves the pointer by `n` cells, `op` being `+=` or `-=`, and exits
+    /// with status 1 when it ends up off the tape.
+    fn generate_move(&mut self, op: &str, n: usize) {
+        if n >= self.tape_len {
+            self.line("sys.exit(1)");
+            return;
+        }
+        self.line(&format!("p {} {}", op, n));
+        self.line("if not 0 <= p < TAPE_LEN:");
+        self.line("    sys.exit(1)");
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.body.push_str("    ");
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn empty_loops_pass() {
+        let py = PythonGenerator::new().gen(&crate::parse("+[]").unwrap());
+        assert!(py.contains("    while tape[p]:\n        pass\n"));
+    }
+
+    #[test]
+    fn products_are_masked() {
+        let opts = CompileOptions {
+            opt_level: crate::OptLevel::O2,
+            ..Default::default()
+        };
+        let prog = crate::optimize(",[->-<]>.", &opts).unwrap();
+        let py = PythonGenerator::with_options(&opts).gen(&prog);
+        assert!(
+            py.contains("        tape[p] = (tape[p] + tape[p - 1] * 4294967295) & 0xFFFFFFFF\n")
+        );
+    }
+
+    #[test]
+    fn deep_loops_get_functions() {
+        let src = format!(
+            "{}{}",
+            "[".repeat(MAX_NESTING + 2),
+            "]".repeat(MAX_NESTING + 2)
+        );
+        let py = PythonGenerator::new().gen(&crate::parse(&src).unwrap());
+        assert!(py.contains("def loop_0():\n    global p\n    while tape[p]:\n        while tape[p]:\n            pass\n"));
+        assert!(py.contains(&format!("{}loop_0()\n", "    ".repeat(MAX_NESTING + 1))));
+    }
+}
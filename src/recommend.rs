@@ -0,0 +1,167 @@
+//! Working out what a program needs from the compiler by running it under
+//! [`interp`] on sample inputs: how far the pointer goes, what range its
+//! cells hold and whether it reads past the end of its input. The compiled
+//! code's semantics are fixed, 32 bit cells and `,` at end of input leaving
+//! the cell alone, so only the tape length ends up as an option; the rest
+//! tells whether a program written for other semantics can run on them.
+
+use std::fmt;
+
+use crate::{
+    ast::NodeBlock,
+    interp::{self, Limits, Status},
+    lex::PRAGMA,
+};
+
+/// Cells a run may touch, well past any tape a program is written for so
+/// that the furthest cell it reaches is measured rather than cut off.
+pub const MAX_CELLS: usize = 1 << 24;
+
+/// What runs of a program on a set of inputs used.
+#[derive(Debug, PartialEq)]
+pub struct Recommendation {
+    pub runs: usize,
+    /// Runs that did not finish, whatever they used is a lower bound.
+    pub unfinished: Vec<Status>,
+    /// Cells up to the furthest one the pointer reached in any run.
+    pub tape_len: usize,
+    /// Smallest and largest cell values, see [`interp::Usage`].
+    pub low: i32,
+    pub high: i32,
+    /// Whether some run read past the end of its input.
+    pub reads_eof: bool,
+}
+
+impl Recommendation {
+    /// Narrowest of 8, 16 and 32 bit cells holding every value the runs
+    /// produced, ignoring values below zero.
+    pub fn cell_bits(&self) -> u32 {
+        if self.high <= 0xff {
+            8
+        } else if self.high <= 0xffff {
+            16
+        } else {
+            32
+        }
+    }
+
+    /// Whether the program takes cells below zero, so that what it does
+    /// next depends on where cells wrap around.
+    pub fn wraps(&self) -> bool {
+        self.low < 0
+    }
+}
+
+impl fmt::Display for Recommendation {
+    /// Written as a `bfc.toml`, the findings left as comments.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.unfinished.first() {
+            None => writeln!(f, "# {} runs, all finished", self.runs)?,
+            Some(status) => writeln!(
+                f,
+                "# {} of {} runs did not finish ({}), the figures are lower bounds",
+                self.unfinished.len(),
+                self.runs,
+                status
+            )?,
+        }
+        writeln!(f, "tape-len = {}", self.tape_len)?;
+        let bits = self.cell_bits();
+        if self.wraps() {
+            writeln!(
+                f,
+                "# cells: takes cells below zero, relying on them wrapping around; \
+                 compiled cells are 32 bits, programs like this are often written for 8"
+            )?;
+        } else {
+            writeln!(
+                f,
+                "# cells: {} bits, values stay within 0..={}",
+                bits, self.high
+            )?;
+        }
+        if self.reads_eof {
+            writeln!(
+                f,
+                "# end of input: read past, relying on `,` leaving the cell alone there"
+            )
+        } else {
+            writeln!(f, "# end of input: never read past, any convention works")
+        }
+    }
+}
+
+/// Runs `prog` on each of `inputs`, at most `steps` statements each.
+pub fn recommend(prog: &NodeBlock, inputs: &[Vec<u8>], steps: u64) -> Recommendation {
+    let limits = Limits {
+        steps,
+        cells: MAX_CELLS,
+    };
+    let mut rec = Recommendation {
+        runs: inputs.len(),
+        unfinished: Vec::new(),
+        tape_len: 1,
+        low: 0,
+        high: 0,
+        reads_eof: false,
+    };
+    for input in inputs {
+        let outcome = interp::run(prog, input, limits);
+        if outcome.status != Status::Finished {
+            rec.unfinished.push(outcome.status);
+        }
+        rec.tape_len = rec.tape_len.max(outcome.usage.cells);
+        rec.low = rec.low.min(outcome.usage.low);
+        rec.high = rec.high.max(outcome.usage.high);
+        rec.reads_eof |= outcome.eof_at.is_some();
+    }
+    rec
+}
+
+/// `src` with a `tape-len` pragma for `rec`, replacing any it had. The
+/// pragma goes after those the program starts with, or first.
+pub fn with_pragmas(src: &str, rec: &Recommendation) -> String {
+    let pragma = format!("{}tape-len = {}", PRAGMA, rec.tape_len);
+    let mut lines: Vec<&str> = src
+        .lines()
+        .filter(|line| {
+            line.strip_prefix(PRAGMA)
+                .is_none_or(|p| !p.trim_start().starts_with("tape-len"))
+        })
+        .collect();
+    let at = lines
+        .iter()
+        .take_while(|line| line.starts_with(PRAGMA))
+        .count();
+    lines.insert(at, &pragma);
+
+    let mut out = lines.join("\n");
+    if src.ends_with('\n') || src.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn measure_runs() {
+        let prog = crate::parse(">>,[-<+>]<<-.").unwrap();
+        let rec = recommend(&prog, &[b"\x05".to_vec(), Vec::new()], 1000);
+        assert_eq!(rec.tape_len, 3);
+        assert_eq!((rec.low, rec.high), (-1, 5));
+        assert!(rec.wraps() && rec.reads_eof);
+        assert_eq!(rec.cell_bits(), 8);
+
+        let wide = crate::parse(&"+".repeat(300)).unwrap();
+        assert_eq!(recommend(&wide, &[Vec::new()], 1000).cell_bits(), 16);
+
+        let src = "#bfc newline = \"crlf\"\n#bfc tape-len = 9\n+.\n";
+        assert_eq!(
+            with_pragmas(src, &rec),
+            "#bfc newline = \"crlf\"\n#bfc tape-len = 3\n+.\n"
+        );
+    }
+}
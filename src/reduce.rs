@@ -0,0 +1,313 @@
+//! Test case reduction: [`reduce`] cuts a program down to a smaller one that
+//! is still interesting, by delta debugging over its tree. Runs of
+//! statements are taken out, halves first, loops are replaced by their
+//! bodies and counts are lowered, for as long as one of those keeps the
+//! program interesting. What interesting means is up to the caller, for
+//! `bfc reduce` one of the tests [`Interesting`] names, such as the
+//! compiled code and the interpreter disagreeing, see [`diverges`].
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    ast::{NodeBlock, Prog, Statement},
+    interp::{self, Limits},
+    validate::Run,
+};
+
+/// What a program has to do to be kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interesting {
+    /// Its compiled code writes something else or exits with another
+    /// status than the interpreter on some input, see [`diverges`].
+    DiffOutputs,
+}
+
+impl Interesting {
+    pub const ALL: [Interesting; 1] = [Interesting::DiffOutputs];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Interesting::DiffOutputs => "diff-outputs",
+        }
+    }
+}
+
+impl fmt::Display for Interesting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Interesting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Interesting::ALL
+            .into_iter()
+            .find(|i| i.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Interesting::ALL.iter().map(|i| i.name()).collect();
+                format!("unknown test `{}`, expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// Whether the code `run` makes of `src` behaves differently from the
+/// interpreter, running the tree as parsed, on any of `inputs`. `run` is
+/// given the program and the inputs the interpreter finished on within
+/// `limits`, the others are left out, and returns how each run went in the
+/// same order, as for [`crate::enumerate::cross_check`].
+pub fn diverges<E>(
+    src: &str,
+    inputs: &[&[u8]],
+    limits: Limits,
+    run: impl FnOnce(&str, &[&[u8]]) -> Result<Vec<Run>, E>,
+) -> Result<bool, E> {
+    let prog = match crate::parse(src) {
+        Ok(prog) => prog,
+        Err(_) => return Ok(false),
+    };
+    let (inputs, expected): (Vec<_>, Vec<_>) = inputs
+        .iter()
+        .filter_map(|&input| Some((input, Run::of(interp::run(&prog, input, limits))?)))
+        .unzip();
+    if inputs.is_empty() {
+        return Ok(false);
+    }
+    Ok(run(src, &inputs)? != expected)
+}
+
+/// A statement of the program being reduced, close enough to the source to
+/// print it back.
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    /// A command repeated.
+    Run(char, usize),
+    Loop(Vec<Node>),
+}
+
+fn tree(block: &NodeBlock) -> Vec<Node> {
+    block
+        .stats
+        .iter()
+        .map(|stat| match &stat.stat {
+            Statement::MoveL(n) => Node::Run('<', *n),
+            Statement::MoveR(n) => Node::Run('>', *n),
+            Statement::Add(n) => Node::Run('+', *n),
+            Statement::Sub(n) => Node::Run('-', *n),
+            Statement::Read => Node::Run(',', 1),
+            Statement::Write => Node::Run('.', 1),
+            Statement::Loop(body) => Node::Loop(tree(body)),
+            Statement::Set(_)
+            | Statement::MulAdd { .. }
+            | Statement::Scan(_)
+            | Statement::If(_)
+            | Statement::Fill(_)
+            | Statement::Print(_) => {
+                unreachable!("programs are reduced as parsed, before the passes run")
+            }
+        })
+        .collect()
+}
+
+fn source(nodes: &[Node], out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Run(c, n) => out.extend(std::iter::repeat_n(*c, *n)),
+            Node::Loop(body) => {
+                out.push('[');
+                source(body, out);
+                out.push(']');
+            }
+        }
+    }
+}
+
+/// A change to try, on the block found by following `path`, indices of
+/// loops from the outermost block in.
+#[derive(Debug)]
+enum Edit {
+    /// Take out `len` statements from `at`.
+    Remove {
+        path: Vec<usize>,
+        at: usize,
+        len: usize,
+    },
+    /// Replace the loop at `at` by its body.
+    Unwrap { path: Vec<usize>, at: usize },
+    /// Lower the count of the run at `at` to `to`.
+    Lower {
+        path: Vec<usize>,
+        at: usize,
+        to: usize,
+    },
+}
+
+/// Every block of `nodes` with the path to it, outermost first.
+fn blocks<'a>(nodes: &'a [Node], path: &mut Vec<usize>, out: &mut Vec<(Vec<usize>, &'a [Node])>) {
+    out.push((path.clone(), nodes));
+    for (i, node) in nodes.iter().enumerate() {
+        if let Node::Loop(body) = node {
+            path.push(i);
+            blocks(body, path, out);
+            path.pop();
+        }
+    }
+}
+
+/// The edits to try on `nodes`, those making it the smallest first.
+fn edits(nodes: &[Node]) -> Vec<Edit> {
+    let mut all = Vec::new();
+    blocks(nodes, &mut Vec::new(), &mut all);
+    // whole blocks, then halves of them, quarters and so on, the longest
+    // runs of statements first
+    let mut removals = Vec::new();
+    for (path, block) in &all {
+        let mut len = block.len();
+        while len > 0 {
+            removals.extend((0..=block.len() - len).step_by(len).map(|at| {
+                let path = path.clone();
+                (len, Edit::Remove { path, at, len })
+            }));
+            len /= 2;
+        }
+    }
+    removals.sort_by_key(|(len, _)| std::cmp::Reverse(*len));
+    let mut out: Vec<_> = removals.into_iter().map(|(_, edit)| edit).collect();
+    for (path, block) in &all {
+        for (at, node) in block.iter().enumerate() {
+            if let Node::Loop(_) = node {
+                out.push(Edit::Unwrap {
+                    path: path.clone(),
+                    at,
+                });
+            }
+        }
+    }
+    for (path, block) in &all {
+        for (at, node) in block.iter().enumerate() {
+            if let Node::Run(_, n) = node {
+                let lower = [1, n / 2, n - 1];
+                let mut lower: Vec<_> = lower
+                    .into_iter()
+                    .filter(|to| (1..*n).contains(to))
+                    .collect();
+                lower.dedup();
+                out.extend(lower.into_iter().map(|to| Edit::Lower {
+                    path: path.clone(),
+                    at,
+                    to,
+                }));
+            }
+        }
+    }
+    out
+}
+
+fn block_at<'a>(mut nodes: &'a mut Vec<Node>, path: &[usize]) -> &'a mut Vec<Node> {
+    for &i in path {
+        nodes = match &mut nodes[i] {
+            Node::Loop(body) => body,
+            Node::Run(..) => unreachable!("paths lead through loops"),
+        };
+    }
+    nodes
+}
+
+fn apply(nodes: &[Node], edit: &Edit) -> Vec<Node> {
+    let mut nodes = nodes.to_vec();
+    match edit {
+        Edit::Remove { path, at, len } => {
+            block_at(&mut nodes, path).drain(*at..*at + *len);
+        }
+        Edit::Unwrap { path, at } => {
+            let block = block_at(&mut nodes, path);
+            if let Node::Loop(body) = block.remove(*at) {
+                block.splice(*at..*at, body);
+            }
+        }
+        Edit::Lower { path, at, to } => {
+            if let Node::Run(_, n) = &mut block_at(&mut nodes, path)[*at] {
+                *n = *to;
+            }
+        }
+    }
+    nodes
+}
+
+/// Cuts `prog` down as far as edits keeping it `interesting` go, returning
+/// the source of the smallest program found, or `None` when `prog` is not
+/// interesting to begin with. Each edit kept starts the search over, so it
+/// ends on a program no single edit leaves interesting. Errors of
+/// `interesting`, such as a toolchain that is missing, stop it.
+pub fn reduce<E>(
+    prog: &Prog,
+    mut interesting: impl FnMut(&str) -> Result<bool, E>,
+) -> Result<Option<String>, E> {
+    let print = |nodes: &[Node]| {
+        let mut out = String::new();
+        source(nodes, &mut out);
+        out
+    };
+    let mut nodes = tree(prog);
+    if !interesting(&print(&nodes))? {
+        return Ok(None);
+    }
+    'search: loop {
+        for edit in edits(&nodes) {
+            let candidate = apply(&nodes, &edit);
+            if interesting(&print(&candidate))? {
+                nodes = candidate;
+                continue 'search;
+            }
+        }
+        return Ok(Some(print(&nodes)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{validate, CompileOptions, OptLevel};
+
+    #[test]
+    fn reduce_to_what_is_miscompiled() {
+        let prog = crate::parse("++>+++++[<+>-]<.>,[-]<[->>+<<]>>.").unwrap();
+        let found = reduce(&prog, |src| {
+            Ok::<_, ()>(src.contains("[-]") && src.contains('.'))
+        });
+        assert_eq!(found, Ok(Some("[-].".to_string())));
+        assert_eq!(reduce(&prog, |_| Ok::<_, ()>(false)), Ok(None));
+
+        // QBE IL that subtracts where it should add moves the pointer left
+        // on `>`, off the tape, once a `,` keeps the program from being run
+        // while compiling
+        let opts = CompileOptions {
+            opt_level: OptLevel::O2,
+            tape_len: 8,
+            ..Default::default()
+        };
+        let limits = Limits {
+            steps: 1000,
+            cells: opts.tape_len,
+        };
+        let prog = crate::parse("+++>++[<+>-]<.>,.").unwrap();
+        let found = reduce(&prog, |src| {
+            diverges(src, &crate::enumerate::INPUTS, limits, |src, inputs| {
+                let prog = crate::optimize(src, &opts).map_err(|e| e.to_string())?;
+                let il = crate::gen::QbeGenerator::with_options(&opts)
+                    .unwrap()
+                    .gen(&prog);
+                let il = il.map_err(|e| e.to_string())?.replace("add", "sub");
+                inputs
+                    .iter()
+                    .map(|input| validate::run_il(&il, input, 1_000_000))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| e.to_string())
+            })
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(found, ">,");
+    }
+}
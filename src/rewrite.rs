@@ -0,0 +1,180 @@
+//! Edits to the original source text, addressed by [`Span`]s from the
+//! lexer or the tree. Bytes no edit touches come out exactly as they went
+//! in, comments and odd whitespace included, so tools that change a few
+//! statements never reformat the rest of a file.
+
+use std::{fmt, ops::Range};
+
+use crate::{
+    ast::{NodeStatement, Statement},
+    lex::Span,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The span does not point into the source.
+    OutOfRange(Span),
+    /// Two edits touch the same bytes, the later one starting at the span.
+    Overlap(Span),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OutOfRange(s) => write!(f, "{}:{} is outside the source", s.line, s.col),
+            Error::Overlap(s) => write!(f, "edits overlap at {}:{}", s.line, s.col),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug)]
+struct Edit {
+    range: Range<usize>,
+    start: Span,
+    text: String,
+}
+
+/// Collects edits against `src` and applies them all at once, so every span
+/// refers to the source as it was parsed no matter the order of the edits.
+pub struct Rewriter<'a> {
+    src: &'a str,
+    // byte offset of every line start
+    lines: Vec<usize>,
+    edits: Vec<Edit>,
+}
+
+impl<'a> Rewriter<'a> {
+    pub fn new(src: &'a str) -> Self {
+        let lines = [0]
+            .into_iter()
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Rewriter {
+            src,
+            lines,
+            edits: Vec::new(),
+        }
+    }
+
+    /// Byte offset of `span`, which may point just past the end of a line.
+    pub fn offset(&self, span: Span) -> Result<usize, Error> {
+        let start = *span
+            .line
+            .checked_sub(1)
+            .and_then(|i| self.lines.get(i))
+            .ok_or(Error::OutOfRange(span))?;
+        let line = &self.src[start..];
+        let line = &line[..line.find('\n').unwrap_or(line.len())];
+        let col = span.col.checked_sub(1).ok_or(Error::OutOfRange(span))?;
+        line.char_indices()
+            .map(|(i, _)| i)
+            .chain([line.len()])
+            .nth(col)
+            .map(|i| start + i)
+            .ok_or(Error::OutOfRange(span))
+    }
+
+    /// Replaces the text from `start` up to, not including, `end`.
+    pub fn replace(&mut self, start: Span, end: Span, text: &str) -> Result<(), Error> {
+        let range = self.offset(start)?..self.offset(end)?;
+        if range.start > range.end {
+            return Err(Error::OutOfRange(end));
+        }
+        self.edits.push(Edit {
+            range,
+            start,
+            text: text.to_string(),
+        });
+        Ok(())
+    }
+
+    pub fn insert(&mut self, at: Span, text: &str) -> Result<(), Error> {
+        self.replace(at, at, text)
+    }
+
+    pub fn delete(&mut self, start: Span, end: Span) -> Result<(), Error> {
+        self.replace(start, end, "")
+    }
+
+    /// Replaces the source of a whole statement, a loop from its `[` to its
+    /// `]`. The tree must carry spans, see [`crate::parse`].
+    pub fn replace_statement(&mut self, stat: &NodeStatement, text: &str) -> Result<(), Error> {
+        let (start, end) = statement_range(stat);
+        self.replace(start, end, text)
+    }
+
+    /// The source with every edit applied. Insertions at the same place
+    /// come out in the order they were made.
+    pub fn finish(mut self) -> Result<String, Error> {
+        // stable, so insertions keep their order
+        self.edits.sort_by_key(|e| (e.range.start, e.range.end));
+        let mut out = String::with_capacity(self.src.len());
+        let mut pos = 0;
+        for edit in &self.edits {
+            if edit.range.start < pos {
+                return Err(Error::Overlap(edit.start));
+            }
+            out.push_str(&self.src[pos..edit.range.start]);
+            out.push_str(&edit.text);
+            pos = edit.range.end;
+        }
+        out.push_str(&self.src[pos..]);
+        Ok(out)
+    }
+}
+
+/// Where a statement starts and where the text after it starts. Runs of
+/// `+-<>` are always contiguous, so their count is their width.
+pub fn statement_range(stat: &NodeStatement) -> (Span, Span) {
+    let start = stat.attr.span;
+    let (line, col) = match &stat.stat {
+        Statement::MoveL(n) | Statement::MoveR(n) | Statement::Add(n) | Statement::Sub(n) => {
+            (start.line, start.col + n)
+        }
+        Statement::Read | Statement::Write => (start.line, start.col + 1),
+        // put in by a pass for the `[-]` it starts at
+        Statement::Set(_) => (start.line, start.col + 3),
+        // and for the `+` or `-` of a multiply loop it stands for
+        Statement::MulAdd { .. } => (start.line, start.col + 1),
+        // and for the `[>]` or `[<<]` it starts at
+        Statement::Scan(stride) => (start.line, start.col + stride.unsigned_abs() + 2),
+        // and for code that ran while compiling, ending where it starts
+        Statement::Fill(_) | Statement::Print(_) => (start.line, start.col),
+        Statement::Loop(body) | Statement::If(body) => {
+            (body.attr.span.line, body.attr.span.col + 1)
+        }
+    };
+    (start, Span { line, col })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn edit_around_comments() {
+        let src = "é +++ add\n[-] clear\n.";
+        let ast = crate::parse(src).unwrap();
+        let mut rw = Rewriter::new(src);
+        rw.replace_statement(&ast.stats[0], "++").unwrap();
+        rw.replace_statement(&ast.stats[1], "[-]>").unwrap();
+        rw.insert(Span { line: 3, col: 1 }, ">").unwrap();
+        rw.insert(Span { line: 3, col: 1 }, "<").unwrap();
+        rw.delete(Span { line: 3, col: 1 }, Span { line: 3, col: 2 })
+            .unwrap();
+        assert_eq!(rw.finish().unwrap(), "é ++ add\n[-]> clear\n><");
+    }
+
+    #[test]
+    fn reject_bad_edits() {
+        let mut rw = Rewriter::new("+++\n");
+        let at = |line, col| Span { line, col };
+        assert_eq!(rw.insert(at(1, 6), "x"), Err(Error::OutOfRange(at(1, 6))));
+        assert_eq!(rw.insert(at(3, 1), "x"), Err(Error::OutOfRange(at(3, 1))));
+        rw.delete(at(1, 1), at(1, 3)).unwrap();
+        rw.replace(at(1, 2), at(1, 4), "-").unwrap();
+        assert_eq!(rw.finish(), Err(Error::Overlap(at(1, 2))));
+    }
+}
@@ -0,0 +1,363 @@
+//! RV64 assembly for Linux, making system calls itself like [`crate::x86`]
+//! so that building needs only an assembler and a linker. Behaves like
+//! [`crate::gen::QbeGenerator`]'s output: 32 bit cells, `,` at end of input
+//! leaves the cell alone, I/O retried on `EINTR`, and moving off the tape
+//! exits with status 1.
+//!
+//! `s1` holds the cell pointer, `s2` the start of the tape and `s3` its size
+//! in bytes, all kept across the runtime routines. Conditional branches only
+//! reach 4 KiB, so each one skips over a `j` or `tail` that does the long
+//! jump.
+
+use std::fmt::Write;
+
+use crate::{ast, backend::CodeGen, target::Target, CompileOptions, Error, Newline};
+
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_EXIT: u32 = 93;
+const EINTR: i32 = 4;
+
+// keeps the tape within reach of `auipc`
+const MAX_TAPE_LEN: usize = 1 << 28;
+
+const READ_ERR: &[u8] = b"bfc: read error\n";
+const WRITE_ERR: &[u8] = b"bfc: write error\n";
+
+pub struct Rv64Generator {
+    label_counter: usize,
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    debug_file: Option<String>,
+    text: String,
+}
+
+impl Default for Rv64Generator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for Rv64Generator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl Rv64Generator {
+    pub fn new() -> Self {
+        Rv64Generator {
+            label_counter: 0,
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            debug_file: None,
+            text: String::new(),
+        }
+    }
+
+    /// Fails for targets other than Linux on RV64, the system calls are its
+    /// own, and for tapes too large to address relative to the code.
+    pub fn with_options(opts: &CompileOptions) -> Result<Self, Error> {
+        if opts.target.is_some_and(|t| t != Target::Rv64) {
+            return Err(Error::Unsupported("RV64 code is only generated for rv64"));
+        }
+        if opts.tape_len > MAX_TAPE_LEN {
+            return Err(Error::Unsupported(
+                "RV64 code supports tapes of up to 2^28 cells",
+            ));
+        }
+        Ok(Rv64Generator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            debug_file: opts.debug_file.clone(),
+            ..Self::new()
+        })
+    }
+
+    pub fn gen(&mut self, prog: &ast::Prog) -> String {
+        if let Some(file) = &self.debug_file {
+            let file = file.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(self.text, "\t.file 1 \"{}\"", file).unwrap();
+        }
+        // nothing sets up `gp`, so the linker must not rewrite addresses
+        // relative to it
+        self.text
+            .push_str("\t.option norelax\n\t.text\n\t.globl _start\n_start:\n");
+        self.instr("lla s2, bf_tape");
+        self.instr("mv s1, s2");
+        self.instr(&format!("li s3, {}", self.tape_len * 4));
+        self.generate_block(prog);
+        self.instr("li a0, 0");
+        self.instr("tail bf_exit");
+
+        self.generate_runtime();
+        writeln!(
+            self.text,
+            "\n\t.bss\n\t.balign 16\nbf_tape:\n\t.zero {}\nbf_buf:\n\t.zero 1",
+            self.tape_len * 4
+        )
+        .unwrap();
+        if self.io_strict {
+            writeln!(
+                self.text,
+                "\n\t.section .rodata\nbf_read_err:\n\t.ascii \"{}\"\nbf_write_err:\n\t.ascii \"{}\"",
+                READ_ERR.escape_ascii(),
+                WRITE_ERR.escape_ascii()
+            )
+            .unwrap();
+        }
+        std::mem::take(&mut self.text)
+    }
+
+    fn generate_block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.generate_statement(stat)
+        }
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) {
+        if self.debug_file.is_some() {
+            let span = stat.attr.span;
+            self.instr(&format!(".loc 1 {} {}", span.line, span.col));
+        }
+
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move("sub", *n as u64),
+            ast::Statement::MoveR(n) => self.generate_move("add", *n as u64),
+            ast::Statement::Add(n) => self.generate_add("add", *n as u32),
+            ast::Statement::Sub(n) => self.generate_add("sub", *n as u32),
+            ast::Statement::Set(0) => self.instr("sw zero, 0(s1)"),
+            ast::Statement::Set(n) => {
+                self.instr(&format!("li t0, {}", *n as i32));
+                self.instr("sw t0, 0(s1)");
+            }
+            ast::Statement::MulAdd { offset, factor } => {
+                // moves only touch t0 and t1, the product waits in t2
+                self.instr("lw t2, 0(s1)");
+                self.instr(&format!("li t3, {}", *factor as i32));
+                self.instr("mulw t2, t2, t3");
+                let n = offset.unsigned_abs() as u64;
+                let (there, back) = if *offset < 0 {
+                    ("sub", "add")
+                } else {
+                    ("add", "sub")
+                };
+                self.generate_move(there, n);
+                self.instr("lw t0, 0(s1)");
+                self.instr("addw t0, t0, t2");
+                self.instr("sw t0, 0(s1)");
+                self.generate_move(back, n);
+            }
+            ast::Statement::Read => {
+                let skip = self.generate_label("eof");
+                self.instr("call bf_getc");
+                self.instr(&format!("bltz a0, {}", skip));
+                self.instr("sw a0, 0(s1)");
+                self.label(&skip);
+            }
+            ast::Statement::Write => {
+                self.instr("lw a0, 0(s1)");
+                if self.crlf {
+                    let plain = self.generate_label("char");
+                    self.instr("andi t0, a0, 0xff");
+                    self.instr("li t1, 10");
+                    self.instr(&format!("bne t0, t1, {}", plain));
+                    self.instr("li a0, 13");
+                    self.instr("call bf_putc");
+                    self.instr("li a0, 10");
+                    self.label(&plain);
+                }
+                self.instr("call bf_putc");
+            }
+            ast::Statement::Scan(stride) => {
+                let start = self.generate_label("scan");
+                let end = self.generate_label("scanned");
+                self.instr("lw t0, 0(s1)");
+                self.instr(&format!("bnez t0, {}", start));
+                self.instr(&format!("j {}", end));
+                self.label(&start);
+                let op = if *stride < 0 { "sub" } else { "add" };
+                self.generate_move(op, stride.unsigned_abs() as u64);
+                self.instr("lw t0, 0(s1)");
+                self.instr(&format!("beqz t0, {}", end));
+                self.instr(&format!("j {}", start));
+                self.label(&end);
+            }
+            ast::Statement::Loop(body) => {
+                let start = self.generate_label("loop");
+                let end = self.generate_label("end");
+                self.instr("lw t0, 0(s1)");
+                self.instr(&format!("bnez t0, {}", start));
+                self.instr(&format!("j {}", end));
+                self.label(&start);
+                self.generate_block(body);
+                self.instr("lw t0, 0(s1)");
+                self.instr(&format!("beqz t0, {}", end));
+                self.instr(&format!("j {}", start));
+                self.label(&end);
+            }
+            ast::Statement::If(body) => {
+                let then = self.generate_label("then");
+                let end = self.generate_label("fi");
+                self.instr("lw t0, 0(s1)");
+                self.instr(&format!("bnez t0, {}", then));
+                self.instr(&format!("j {}", end));
+                self.label(&then);
+                self.generate_block(body);
+                self.label(&end);
+            }
+            // there is no data to copy from, see `spell_out_fill`
+            ast::Statement::Fill(values) => self.generate_block(&ast::spell_out_fill(values)),
+            // nor any to write from, see `spell_out_print`
+            ast::Statement::Print(bytes) => self.generate_block(&ast::spell_out_print(bytes)),
+        }
+    }
+
+    /// `op` is `add` or `sub`.
+    fn generate_add(&mut self, op: &str, n: u32) {
+        self.instr("lw t0, 0(s1)");
+        match op {
+            "add" if n < 2048 => self.instr(&format!("addiw t0, t0, {}", n)),
+            "sub" if n <= 2048 => self.instr(&format!("addiw t0, t0, -{}", n)),
+            _ => {
+                self.instr(&format!("li t1, {}", n));
+                self.instr(&format!("{}w t0, t0, t1", op));
+            }
+        }
+        self.instr("sw t0, 0(s1)");
+    }
+
+    /// Moves the pointer by `n` cells, `op` being `add` or `sub`, and exits
+    /// with status 1 when it ends up off the tape.
+    fn generate_move(&mut self, op: &str, n: u64) {
+        let bytes = n.wrapping_mul(4);
+        match op {
+            "add" if bytes < 2048 => self.instr(&format!("addi s1, s1, {}", bytes)),
+            "sub" if bytes <= 2048 => self.instr(&format!("addi s1, s1, -{}", bytes)),
+            _ => {
+                self.instr(&format!("li t1, {}", bytes));
+                self.instr(&format!("{} s1, s1, t1", op));
+            }
+        }
+        let ok = self.generate_label("ok");
+        self.instr("sub t0, s1, s2");
+        self.instr(&format!("bltu t0, s3, {}", ok));
+        self.instr("tail bf_halt");
+        self.label(&ok);
+    }
+
+    /// `bf_putc` writes the byte in `a0`, `bf_getc` returns the next byte of
+    /// input in `a0`, or -1 at its end.
+    fn generate_runtime(&mut self) {
+        self.label("bf_halt");
+        self.instr("li a0, 1");
+        self.label("bf_exit");
+        self.instr(&format!("li a7, {}", SYS_EXIT));
+        self.instr("ecall");
+
+        self.label("bf_putc");
+        self.instr("lla a1, bf_buf");
+        self.instr("sb a0, 0(a1)");
+        self.label(".Lputc_retry");
+        self.generate_syscall(SYS_WRITE, 1);
+        self.instr("li t0, 1");
+        self.instr("beq a0, t0, .Lputc_done");
+        // nothing written, try again
+        self.instr("beqz a0, .Lputc_retry");
+        self.instr(&format!("li t0, {}", -EINTR));
+        self.instr("beq a0, t0, .Lputc_retry");
+        if self.io_strict {
+            self.generate_io_error("bf_write_err", WRITE_ERR);
+        }
+        self.label(".Lputc_done");
+        self.instr("ret");
+
+        self.label("bf_getc");
+        self.instr("lla a1, bf_buf");
+        self.label(".Lgetc_retry");
+        self.generate_syscall(SYS_READ, 0);
+        self.instr("li t0, 1");
+        self.instr("bne a0, t0, .Lgetc_short");
+        self.instr("lbu a0, 0(a1)");
+        if self.crlf {
+            self.instr("li t0, 13");
+            self.instr("beq a0, t0, .Lgetc_retry");
+        }
+        self.instr("ret");
+        self.label(".Lgetc_short");
+        self.instr("beqz a0, .Lgetc_eof");
+        self.instr(&format!("li t0, {}", -EINTR));
+        self.instr("beq a0, t0, .Lgetc_retry");
+        if self.io_strict {
+            self.generate_io_error("bf_read_err", READ_ERR);
+        }
+        self.label(".Lgetc_eof");
+        self.instr("li a0, -1");
+        self.instr("ret");
+    }
+
+    /// One byte through the buffer `a1` points at, the result left in `a0`.
+    fn generate_syscall(&mut self, nr: u32, fd: u32) {
+        self.instr(&format!("li a0, {}", fd));
+        self.instr("li a2, 1");
+        self.instr(&format!("li a7, {}", nr));
+        self.instr("ecall");
+    }
+
+    fn generate_io_error(&mut self, name: &str, msg: &[u8]) {
+        self.instr("li a0, 2");
+        self.instr(&format!("lla a1, {}", name));
+        self.instr(&format!("li a2, {}", msg.len()));
+        self.instr(&format!("li a7, {}", SYS_WRITE));
+        self.instr("ecall");
+        self.instr("j bf_halt");
+    }
+
+    fn instr(&mut self, instr: &str) {
+        writeln!(self.text, "\t{}", instr).unwrap();
+    }
+
+    fn label(&mut self, label: &str) {
+        writeln!(self.text, "{}:", label).unwrap();
+    }
+
+    fn generate_label(&mut self, prefix: &str) -> String {
+        let c = self.label_counter;
+        self.label_counter += 1;
+        format!(".L{}{}", prefix, c)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn multiplies_sign_extend_the_factor() {
+        let opts = CompileOptions {
+            opt_level: crate::OptLevel::O2,
+            ..Default::default()
+        };
+        let prog = crate::optimize(",[->-<]>.", &opts).unwrap();
+        let asm = Rv64Generator::new().gen(&prog);
+        assert!(asm.contains("\tcall bf_getc\n\tbltz a0, .Leof0\n\tsw a0, 0(s1)\n.Leof0:\n"));
+        assert!(asm.contains("\tlw t2, 0(s1)\n\tli t3, -1\n\tmulw t2, t2, t3\n"));
+        assert!(asm.contains("\tlw t0, 0(s1)\n\taddw t0, t0, t2\n\tsw t0, 0(s1)\n"));
+        assert!(asm.contains("\tsw zero, 0(s1)\n"));
+    }
+
+    #[test]
+    fn large_immediates() {
+        let mut gen = Rv64Generator::new();
+        gen.generate_add("sub", 0x1_0005);
+        assert_eq!(
+            gen.text,
+            "\tlw t0, 0(s1)\n\tli t1, 65541\n\tsubw t0, t0, t1\n\tsw t0, 0(s1)\n"
+        );
+    }
+}
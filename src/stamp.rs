@@ -0,0 +1,294 @@
+//! Provenance stamps, embedded with `--stamp` and read back by `bfc
+//! inspect`: which version of bfc built an artifact, a fingerprint of the
+//! options it was built with, the ones that matter most spelled out, and a
+//! hash of the source, so that binaries that got separated from their
+//! sources can still be traced back.
+//!
+//! Stamps also carry the [`compat`] string of the settings that change what
+//! a program does, so that two builds can be told apart on what matters
+//! for their runs, and the qbe backend writes a hash of it into checkpoint
+//! snapshots, so that an executable refuses to resume from one written
+//! under other settings.
+//!
+//! A stamp is one line of text, [`MARKER`] followed by `key=value` pairs.
+//! Textual outputs end with it in a comment, and where the output becomes
+//! a binary it also goes in a string constant, so that the same scan for
+//! the marker finds it in either.
+
+use std::{fmt, str::FromStr};
+
+use crate::{Backend, CompileOptions};
+
+pub const MARKER: &str = "bfc-stamp:";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamp {
+    /// The version of bfc.
+    pub version: String,
+    /// A hash of every option the output depends on, equal for equal
+    /// options within a version of bfc.
+    pub options: u128,
+    /// A hash of the source.
+    pub source: u128,
+    /// The [`compat`] string of the build, `None` in stamps of versions of
+    /// bfc from before it.
+    pub compat: Option<String>,
+    /// The backend, target, tape and optimization level, by the names of
+    /// their `bfc.toml` keys. Keys a stamp carries that this version does
+    /// not know end up here too.
+    pub settings: Vec<(String, String)>,
+}
+
+impl Stamp {
+    pub fn new(src: &str, opts: &CompileOptions) -> Stamp {
+        Stamp {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            options: hash(&format!("{:?}", opts)),
+            source: hash(src),
+            compat: Some(compat(opts)),
+            settings: [
+                ("backend", Some(opts.backend.to_string())),
+                ("target", opts.target.map(|t| t.to_string())),
+                ("tape-len", Some(opts.tape_len.to_string())),
+                ("opt-level", Some(opts.opt_level.to_string())),
+            ]
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value?)))
+            .collect(),
+        }
+    }
+
+    /// The setting called `key`.
+    pub fn setting(&self, key: &str) -> Option<&str> {
+        self.settings
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The first stamp in `bytes`, an artifact of any kind.
+    pub fn find(bytes: &[u8]) -> Option<Stamp> {
+        let marker = MARKER.as_bytes();
+        let at = bytes.windows(marker.len()).position(|w| w == marker)?;
+        let len = bytes[at..]
+            .iter()
+            .position(|b| !(b' '..=b'~').contains(b) || *b == b'"')
+            .unwrap_or(bytes.len() - at);
+        std::str::from_utf8(&bytes[at..at + len]).ok()?.parse().ok()
+    }
+}
+
+fn hash(text: &str) -> u128 {
+    u128::from_be_bytes(md5::compute(text).0)
+}
+
+/// The settings that change what a program does on a given input, rather
+/// than how fast it does it, as `key:value` pairs separated by commas: how
+/// wide cells are, what `,` does at end of input, how long the tape is, how
+/// line endings are translated and whether moves are checked. Builds whose
+/// compat strings differ may behave differently, and a snapshot one of them
+/// checkpoints is no good to the other.
+pub fn compat(opts: &CompileOptions) -> String {
+    let bounds = if opts.unchecked {
+        "unchecked"
+    } else {
+        "checked"
+    };
+    format!(
+        "cell-bits:{},eof:unchanged,tape-len:{},newline:{},bounds:{}",
+        opts.backend.cell_bits(),
+        opts.tape_len,
+        opts.newline.resolve(),
+        bounds
+    )
+}
+
+/// A hash of the [`compat`] string of `opts`, small enough to be a
+/// constant in any backend.
+pub fn compat_hash(opts: &CompileOptions) -> u64 {
+    (hash(&compat(opts)) >> 65) as u64
+}
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} version={} options={:032x} source={:032x}",
+            MARKER, self.version, self.options, self.source
+        )?;
+        if let Some(compat) = &self.compat {
+            write!(f, " compat={}", compat)?;
+        }
+        for (key, value) in &self.settings {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Stamp {
+    type Err = String;
+
+    /// Reads a stamp, keeping keys it does not know as settings so that
+    /// newer ones can add some.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s
+            .strip_prefix(MARKER)
+            .ok_or_else(|| format!("expected `{}`", MARKER))?;
+        let (mut version, mut options, mut source) = (None, None, None);
+        let mut compat = None;
+        let mut settings = Vec::new();
+        for field in fields.split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("expected `key=value`, found `{}`", field))?;
+            let hex = || u128::from_str_radix(value, 16).map_err(|e| format!("{}: {}", key, e));
+            match key {
+                "version" => version = Some(value.to_string()),
+                "options" => options = Some(hex()?),
+                "source" => source = Some(hex()?),
+                "compat" => compat = Some(value.to_string()),
+                _ => settings.push((key.to_string(), value.to_string())),
+            }
+        }
+        match (version, options, source) {
+            (Some(version), Some(options), Some(source)) => Ok(Stamp {
+                version,
+                options,
+                source,
+                compat,
+                settings,
+            }),
+            _ => Err("missing version, options or source".to_string()),
+        }
+    }
+}
+
+/// What kind of artifact `bytes` are, as far as the first few of them
+/// tell.
+pub fn kind(bytes: &[u8]) -> &'static str {
+    match bytes {
+        [0x7f, b'E', b'L', b'F', ..] => match bytes.get(16..18) {
+            Some([1, 0] | [0, 1]) => "ELF object",
+            Some([3, 0] | [0, 3]) => "ELF shared object or position independent executable",
+            _ => "ELF executable",
+        },
+        [0xcf, 0xfa, 0xed, 0xfe, ..] => "Mach-O binary",
+        [0xca, 0xfe, 0xba, 0xbe, ..] => "JVM class file",
+        [0x64, 0x86, ..] | [0xaa, 0x64, ..] => "COFF object",
+        _ if crate::bytecode::is_bytecode(bytes) => "bfc bytecode",
+        _ => match std::str::from_utf8(bytes) {
+            Ok(text) if is_qbe(text) => "QBE IL",
+            Ok(_) => "text",
+            Err(_) => "binary",
+        },
+    }
+}
+
+fn is_qbe(text: &str) -> bool {
+    text.lines().any(|line| {
+        ["function ", "export function ", "data $"]
+            .iter()
+            .any(|start| line.starts_with(start))
+    })
+}
+
+/// The source file the line table of QBE IL, generated with `-g`, points
+/// at.
+pub fn source_map(text: &str) -> Option<&str> {
+    text.lines()
+        .find_map(|line| line.strip_prefix("dbgfile \""))
+        .and_then(|rest| rest.strip_suffix('"'))
+}
+
+/// `out`, generated by `opts.backend`, with `stamp` added at the end.
+pub fn embed(mut out: String, stamp: &Stamp, opts: &CompileOptions) -> String {
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    let apple = opts.target.is_some_and(|t| t.is_apple());
+    let text = match opts.backend {
+        Backend::Qbe => format!("# {}\ndata $bf.stamp = {{ b \"{}\", b 0 }}\n", stamp, stamp),
+        Backend::C => format!(
+            "/* {} */\n__attribute__((used)) static const char bf_stamp[] = \"{}\";\n",
+            stamp, stamp
+        ),
+        Backend::Llvm => format!(
+            "; {}\n@bf.stamp = private constant [{} x i8] c\"{}\\00\"\n\
+             @llvm.used = appending global [1 x ptr] [ptr @bf.stamp], section \"llvm.metadata\"\n",
+            stamp,
+            stamp.to_string().len() + 1,
+            stamp
+        ),
+        // Mach-O has no `.comment`, and a few bytes of data hurt nobody
+        Backend::X86_64Asm | Backend::Aarch64Asm | Backend::Rv64Asm | Backend::AvrAsm if apple => {
+            format!("\t.data\n\t.asciz \"{}\"\n", stamp)
+        }
+        Backend::X86_64Asm | Backend::Aarch64Asm | Backend::Rv64Asm | Backend::AvrAsm => {
+            format!("\t.section .comment\n\t.asciz \"{}\"\n", stamp)
+        }
+        Backend::Js => format!("// {}\n", stamp),
+        Backend::Python => format!("# {}\n", stamp),
+        Backend::Lua => format!("-- {}\n", stamp),
+    };
+    out.push_str(&text);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn find_embedded_stamp() {
+        let opts = CompileOptions::default();
+        let stamp = Stamp::new("+.", &opts);
+        assert_eq!(stamp.source, Stamp::new("+.", &opts).source);
+        assert_ne!(stamp.source, Stamp::new("-.", &opts).source);
+        let other = CompileOptions {
+            tape_len: 10,
+            ..Default::default()
+        };
+        assert_ne!(stamp.options, Stamp::new("+.", &other).options);
+
+        let il = embed("function w $main() {\n}".to_string(), &stamp, &opts);
+        assert!(il.contains(&format!("}}\n# {}\ndata $bf.stamp", stamp)));
+        let mut binary = b"\x7fELF\0\0".to_vec();
+        binary.extend_from_slice(stamp.to_string().as_bytes());
+        binary.push(0);
+        assert_eq!(Stamp::find(&binary), Some(stamp.clone()));
+        assert_eq!(Stamp::find(il.as_bytes()), Some(stamp));
+        assert_eq!(Stamp::find(b"no stamp here"), None);
+        let newer: Stamp = "bfc-stamp: version=1 future=x options=0 source=ff"
+            .parse()
+            .unwrap();
+        assert_eq!(newer.setting("future"), Some("x"));
+    }
+
+    #[test]
+    fn stamp_spells_out_settings() {
+        let opts = CompileOptions {
+            tape_len: 100,
+            ..Default::default()
+        };
+        let stamp = Stamp::new("", &opts);
+        assert!(stamp
+            .to_string()
+            .ends_with(" backend=qbe tape-len=100 opt-level=0"));
+        assert_eq!(stamp.to_string().parse(), Ok(stamp.clone()));
+        assert_eq!(
+            stamp.compat.as_deref(),
+            Some("cell-bits:32,eof:unchanged,tape-len:100,newline:raw,bounds:checked")
+        );
+        let older: Stamp = "bfc-stamp: version=1 options=0 source=ff".parse().unwrap();
+        assert_eq!(older.compat, None);
+
+        assert_eq!(
+            kind(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x01\0"),
+            "ELF object"
+        );
+        let il = "dbgfile \"hello.b\"\nexport function w $main() {\n}\n";
+        assert_eq!(kind(il.as_bytes()), "QBE IL");
+        assert_eq!(source_map(il), Some("hello.b"));
+    }
+}
@@ -0,0 +1,120 @@
+use std::{fmt, str::FromStr};
+
+/// Machines `bfc` can generate code for, named as QBE's `-t` flag expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Amd64Sysv,
+    Amd64Apple,
+    Arm64,
+    Arm64Apple,
+    Rv64,
+    /// 64 bit Windows, which QBE does not support: only the x86-64 and c
+    /// backends generate code for it.
+    Amd64Windows,
+}
+
+impl Target {
+    pub const ALL: [Target; 6] = [
+        Target::Amd64Sysv,
+        Target::Amd64Apple,
+        Target::Arm64,
+        Target::Arm64Apple,
+        Target::Rv64,
+        Target::Amd64Windows,
+    ];
+
+    pub fn qbe_name(self) -> &'static str {
+        match self {
+            Target::Amd64Sysv => "amd64_sysv",
+            Target::Amd64Apple => "amd64_apple",
+            Target::Arm64 => "arm64",
+            Target::Arm64Apple => "arm64_apple",
+            Target::Rv64 => "rv64",
+            Target::Amd64Windows => "amd64_windows",
+        }
+    }
+
+    /// The machine running the compiler, if `bfc` can generate code for it.
+    pub fn host() -> Option<Target> {
+        match (std::env::consts::ARCH, std::env::consts::OS) {
+            ("x86_64", "windows") => Some(Target::Amd64Windows),
+            (_, "windows") => None,
+            ("x86_64", "macos") => Some(Target::Amd64Apple),
+            ("x86_64", _) => Some(Target::Amd64Sysv),
+            ("aarch64", "macos") => Some(Target::Arm64Apple),
+            ("aarch64", _) => Some(Target::Arm64),
+            ("riscv64", _) => Some(Target::Rv64),
+            _ => None,
+        }
+    }
+
+    /// GNU style triple, as cross toolchains are named after.
+    pub fn triple(self) -> &'static str {
+        match self {
+            Target::Amd64Sysv => "x86_64-linux-gnu",
+            Target::Amd64Apple => "x86_64-apple-darwin",
+            Target::Arm64 => "aarch64-linux-gnu",
+            Target::Arm64Apple => "arm64-apple-darwin",
+            Target::Rv64 => "riscv64-linux-gnu",
+            Target::Amd64Windows => "x86_64-w64-mingw32",
+        }
+    }
+
+    pub fn is_apple(self) -> bool {
+        matches!(self, Target::Amd64Apple | Target::Arm64Apple)
+    }
+
+    pub fn is_windows(self) -> bool {
+        self == Target::Amd64Windows
+    }
+
+    /// Libc function returning the address of the calling thread's `errno`.
+    pub fn errno_location(self) -> &'static str {
+        if self.is_apple() {
+            "__error"
+        } else {
+            "__errno_location"
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Target::ALL
+            .into_iter()
+            .find(|t| t.qbe_name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Target::ALL.iter().map(|t| t.qbe_name()).collect();
+                format!("expected one of {}, found `{}`", names.join(", "), s)
+            })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.qbe_name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip_names() {
+        for t in Target::ALL {
+            assert_eq!(t.qbe_name().parse::<Target>(), Ok(t));
+        }
+        assert!("x86".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn host_is_supported() {
+        if cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+            assert_eq!(Target::host(), Some(Target::Amd64Sysv));
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    fmt::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::OptLevel;
+
+/// Wall clock time spent in each compilation phase, in the order the phases
+/// ran. A phase timed more than once accumulates.
+#[derive(Debug, Default)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.add(phase, start.elapsed());
+        res
+    }
+
+    pub fn add(&mut self, phase: &'static str, elapsed: Duration) {
+        match self.phases.iter_mut().find(|(p, _)| *p == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, d)| *d).sum()
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        // pass names can be longer than the usual phases
+        let width = self
+            .phases
+            .iter()
+            .map(|(p, _)| p.len())
+            .max()
+            .unwrap_or(0)
+            .max(10);
+        let rows = self
+            .phases
+            .iter()
+            .copied()
+            .chain(std::iter::once(("total", total)));
+        for (phase, elapsed) in rows {
+            let share = if total.is_zero() {
+                0.0
+            } else {
+                elapsed.as_secs_f64() / total.as_secs_f64() * 100.0
+            };
+            writeln!(
+                f,
+                "{:<width$} {:>10.3}ms {:>5.1}%",
+                phase,
+                elapsed.as_secs_f64() * 1000.0,
+                share
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How long compiling each program of a corpus took at each optimization
+/// level, phase by phase, for `bfc bench-passes`.
+#[derive(Debug, Default)]
+pub struct CorpusTimings {
+    /// Compilations each entry is the fastest of.
+    pub runs: usize,
+    pub entries: Vec<(String, OptLevel, Timings)>,
+}
+
+impl CorpusTimings {
+    /// The phases summed over the corpus at `level`.
+    pub fn totals(&self, level: OptLevel) -> Timings {
+        let mut totals = Timings::default();
+        for (_, _, timings) in self.entries.iter().filter(|(_, l, _)| *l == level) {
+            for &(phase, elapsed) in timings.phases() {
+                totals.add(phase, elapsed);
+            }
+        }
+        totals
+    }
+
+    /// The report as JSON, times in milliseconds: the totals per level
+    /// under `"totals"` and every compilation under `"programs"`.
+    pub fn to_json(&self) -> String {
+        fn phases(out: &mut String, timings: &Timings) {
+            out.push('{');
+            for &(phase, elapsed) in timings.phases() {
+                write!(out, "\"{}\":{:.3},", phase, elapsed.as_secs_f64() * 1e3).unwrap();
+            }
+            write!(
+                out,
+                "\"total\":{:.3}}}",
+                timings.total().as_secs_f64() * 1e3
+            )
+            .unwrap();
+        }
+
+        let mut out = format!("{{\"runs\":{},\"totals\":{{", self.runs);
+        for (i, level) in OptLevel::ALL.into_iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, "\"-O{}\":", level).unwrap();
+            phases(&mut out, &self.totals(level));
+        }
+        out.push_str("},\"programs\":[");
+        for (i, (file, level, timings)) in self.entries.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let file = file.replace('\\', "\\\\").replace('"', "\\\"");
+            write!(
+                out,
+                "{{\"file\":\"{}\",\"level\":\"-O{}\",\"phases\":",
+                file, level
+            )
+            .unwrap();
+            phases(&mut out, timings);
+            out.push('}');
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accumulate_phases() {
+        let mut t = Timings::default();
+        t.add("lex", Duration::from_millis(1));
+        t.add("parse", Duration::from_millis(2));
+        t.add("lex", Duration::from_millis(3));
+        assert_eq!(t.phases().len(), 2);
+        assert_eq!(t.total(), Duration::from_millis(6));
+
+        let report = t.to_string();
+        assert!(report.starts_with("lex             4.000ms  66.7%\n"));
+        assert!(report.ends_with("total           6.000ms 100.0%\n"));
+    }
+
+    #[test]
+    fn corpus_report() {
+        let mut t = Timings::default();
+        t.add("parse", Duration::from_micros(1500));
+        t.add("codegen", Duration::from_millis(2));
+        let mut corpus = CorpusTimings {
+            runs: 1,
+            entries: vec![("a.bf".to_string(), OptLevel::O0, t)],
+        };
+        let mut t = Timings::default();
+        t.add("parse", Duration::from_millis(1));
+        corpus
+            .entries
+            .push(("dir/\"b\".bf".to_string(), OptLevel::O0, t));
+        assert_eq!(
+            corpus.totals(OptLevel::O0).total(),
+            Duration::from_micros(4500)
+        );
+        assert_eq!(
+            corpus.to_json(),
+            "{\"runs\":1,\"totals\":{\"-O0\":{\"parse\":2.500,\"codegen\":2.000,\"total\":4.500},\
+             \"-O1\":{\"total\":0.000},\"-O2\":{\"total\":0.000},\"-O3\":{\"total\":0.000},\
+             \"-Os\":{\"total\":0.000}},\"programs\":[\
+             {\"file\":\"a.bf\",\"level\":\"-O0\",\"phases\":{\"parse\":1.500,\"codegen\":2.000,\"total\":3.500}},\
+             {\"file\":\"dir/\\\"b\\\".bf\",\"level\":\"-O0\",\"phases\":{\"parse\":1.000,\"total\":1.000}}]}"
+        );
+    }
+}
@@ -0,0 +1,495 @@
+//! Translation validation: runs a program through [`interp`] on its
+//! optimized tree and through a small interpreter for the QBE IL
+//! [`crate::gen::QbeGenerator`] emitted for it, on a handful of inputs, and
+//! reports the first input the two disagree on. Both runs are bounded, so
+//! this is only meant for small programs; inputs a program does not finish
+//! on in time are skipped.
+//!
+//! The IL interpreter knows the subset of QBE the generator emits and the
+//! few C library functions the runtime calls, nothing more.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{ast, il, interp};
+
+/// Inputs every program is run on.
+const INPUTS: [&[u8]; 4] = [b"", b"a", b"Hello, World!\n", b"\x00\xff\r\n9z"];
+
+/// Statements the tree interpreter may execute for one input.
+const STEPS: u64 = 100_000;
+/// IL instructions allowed per statement the tree interpreter executed,
+/// on top of [`BASE_IL_STEPS`] for the runtime's own work.
+const IL_STEPS_PER_STEP: u64 = 64;
+const BASE_IL_STEPS: u64 = 1_000_000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The IL uses something the interpreter does not know.
+    Il(String),
+    Mismatch {
+        input: Vec<u8>,
+        expected: Run,
+        found: Run,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Il(e) => write!(f, "cannot interpret the IL: {}", e),
+            Error::Mismatch {
+                input,
+                expected,
+                found,
+            } => write!(
+                f,
+                "on input \"{}\" the program {} but the IL {}",
+                input.escape_ascii(),
+                expected,
+                found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// How a run ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    /// Exit status, `None` when the run was cut short.
+    pub status: Option<i32>,
+    /// Everything written to stdout.
+    pub output: Vec<u8>,
+}
+
+impl Run {
+    /// The run compiled code would have made, going by one under the
+    /// interpreter; `None` when that was cut short.
+    pub fn of(outcome: interp::Outcome) -> Option<Run> {
+        let status = match outcome.status {
+            interp::Status::Finished => 0,
+            interp::Status::OutOfBounds | interp::Status::CellLimit => 1,
+            _ => return None,
+        };
+        Some(Run {
+            status: Some(status),
+            output: outcome.output,
+        })
+    }
+}
+
+impl fmt::Display for Run {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "exited with {}", status)?,
+            None => write!(f, "did not finish")?,
+        }
+        write!(f, " after writing \"{}\"", self.output.escape_ascii())
+    }
+}
+
+/// Checks that `il`, generated for `prog` with a tape of `tape_len` cells,
+/// behaves like `prog` does under the interpreter.
+pub fn validate(prog: &ast::Prog, il: &str, tape_len: usize) -> Result<(), Error> {
+    let module = il::parse(il).map_err(|e| Error::Il(e.to_string()))?;
+    let program = Program::new(&module);
+    for input in INPUTS {
+        let limits = interp::Limits {
+            steps: STEPS,
+            cells: tape_len,
+        };
+        let outcome = interp::run(prog, input, limits);
+        let steps = outcome.steps;
+        let Some(expected) = Run::of(outcome) else {
+            continue;
+        };
+        let (found, _) = program.run(input, BASE_IL_STEPS + steps * IL_STEPS_PER_STEP)?;
+        if found != expected {
+            return Err(Error::Mismatch {
+                input: input.to_vec(),
+                expected,
+                found,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Runs `il` on `input`, executing at most `steps` IL instructions.
+pub fn run_il(il: &str, input: &[u8], steps: u64) -> Result<Run, Error> {
+    run_il_with_stderr(il, input, steps).map(|(run, _)| run)
+}
+
+/// Runs `il` like [`run_il`], also returning everything written to stderr,
+/// such as what the `--atexit` hooks report.
+pub fn run_il_with_stderr(il: &str, input: &[u8], steps: u64) -> Result<(Run, Vec<u8>), Error> {
+    let module = il::parse(il).map_err(|e| Error::Il(e.to_string()))?;
+    Program::new(&module).run(input, steps)
+}
+
+/// A module with the blocks of every function found by label.
+struct Program<'a> {
+    module: &'a il::Module,
+    labels: HashMap<&'a str, HashMap<&'a str, usize>>,
+}
+
+impl<'a> Program<'a> {
+    fn new(module: &'a il::Module) -> Program<'a> {
+        let labels = module
+            .functions
+            .iter()
+            .map(|func| {
+                let blocks = func.blocks.iter().enumerate();
+                let labels = blocks.map(|(i, block)| (block.label.as_str(), i)).collect();
+                (func.name.as_str(), labels)
+            })
+            .collect();
+        Program { module, labels }
+    }
+
+    /// The run and what it wrote to stderr.
+    fn run(&self, input: &[u8], steps: u64) -> Result<(Run, Vec<u8>), Error> {
+        let mut machine = Machine {
+            program: self,
+            mem: vec![0; 16],
+            globals: HashMap::new(),
+            input,
+            output: Vec::new(),
+            errors: Vec::new(),
+            steps,
+        };
+        for data in &self.module.data {
+            let bytes = data.bytes();
+            let addr = machine.alloc(bytes.len() as u64, data.align.unwrap_or(8));
+            machine.mem[addr as usize..][..bytes.len()].copy_from_slice(&bytes);
+            machine.globals.insert(data.name.clone(), addr);
+        }
+        let errno = machine.alloc(8, 8);
+        machine.globals.insert("errno".to_string(), errno);
+
+        let status = match machine.call("main", Vec::new()) {
+            Ok(status) => Some(status.unwrap_or(0) as i32),
+            Err(Stop::Exit(status)) => Some(status),
+            Err(Stop::Steps) => None,
+            Err(Stop::Fault(e)) => return Err(Error::Il(e)),
+        };
+        let run = Run {
+            status,
+            output: machine.output,
+        };
+        Ok((run, machine.errors))
+    }
+}
+
+/// Why a run stopped before `main` returned.
+enum Stop {
+    Exit(i32),
+    Steps,
+    Fault(String),
+}
+
+struct Machine<'a> {
+    program: &'a Program<'a>,
+    mem: Vec<u8>,
+    globals: HashMap<String, u64>,
+    input: &'a [u8],
+    output: Vec<u8>,
+    errors: Vec<u8>,
+    /// Instructions left to execute.
+    steps: u64,
+}
+
+impl Machine<'_> {
+    fn alloc(&mut self, size: u64, align: u64) -> u64 {
+        let addr = (self.mem.len() as u64).next_multiple_of(align.max(1));
+        self.mem.resize((addr + size) as usize, 0);
+        addr
+    }
+
+    fn mem(&mut self, addr: u64, len: u64) -> Result<&mut [u8], Stop> {
+        let fault = || Stop::Fault(format!("access to {} bytes at {:#x}", len, addr));
+        let end = addr.checked_add(len).ok_or_else(fault)?;
+        if addr < 16 || end > self.mem.len() as u64 {
+            return Err(fault());
+        }
+        Ok(&mut self.mem[addr as usize..end as usize])
+    }
+
+    fn load(&mut self, addr: u64, len: u64) -> Result<u64, Stop> {
+        let mut bytes = [0; 8];
+        bytes[..len as usize].copy_from_slice(self.mem(addr, len)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn store(&mut self, addr: u64, len: u64, val: u64) -> Result<(), Stop> {
+        self.mem(addr, len)?
+            .copy_from_slice(&val.to_le_bytes()[..len as usize]);
+        Ok(())
+    }
+
+    fn call(&mut self, name: &str, args: Vec<u64>) -> Result<Option<u64>, Stop> {
+        let program = self.program;
+        let Some(func) = program.module.function(name) else {
+            return self.call_libc(name, &args).map(Some);
+        };
+        let labels = &program.labels[name];
+        let target = |label: &String| {
+            labels
+                .get(label.as_str())
+                .copied()
+                .ok_or_else(|| Stop::Fault(format!("no block @{}", label)))
+        };
+        let mut temps: HashMap<&str, u64> = func
+            .params
+            .iter()
+            .map(|(_, name)| name.as_str())
+            .zip(args)
+            .collect();
+        let set = |temps: &mut HashMap<_, _>, name, ty, val: u64| {
+            temps.insert(
+                name,
+                if ty == il::Ty::Long {
+                    val
+                } else {
+                    val as u32 as u64
+                },
+            );
+        };
+
+        let mut block = 0;
+        loop {
+            let insts = &func
+                .blocks
+                .get(block)
+                .ok_or_else(|| Stop::Fault(format!("fell off the end of ${}", name)))?
+                .insts;
+            let mut next = block + 1;
+            for inst in insts {
+                if let il::Inst::Dbgloc(_) = inst {
+                    continue;
+                }
+                self.steps = self.steps.checked_sub(1).ok_or(Stop::Steps)?;
+                let arg = |temps: &HashMap<&str, u64>, arg: &il::Value| match arg {
+                    il::Value::Temp(t) => temps
+                        .get(t.as_str())
+                        .copied()
+                        .ok_or_else(|| Stop::Fault(format!("%{} is undefined", t))),
+                    il::Value::Global(g) => self
+                        .globals
+                        .get(g)
+                        .copied()
+                        .ok_or_else(|| Stop::Fault(format!("${} is undefined", g))),
+                    il::Value::Const(c) => Ok(*c),
+                };
+                match inst {
+                    il::Inst::Assign { dest, ty, op, args } => {
+                        let args = args
+                            .iter()
+                            .map(|a| arg(&temps, a))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        if let Some(val) = self.op(op, &args, *ty == il::Ty::Long)? {
+                            set(&mut temps, dest.as_str(), *ty, val);
+                        }
+                    }
+                    il::Inst::Volatile { op, args } => {
+                        let args = args
+                            .iter()
+                            .map(|a| arg(&temps, a))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        self.op(op, &args, false)?;
+                    }
+                    il::Inst::Call { dest, func, args } => {
+                        let args = args
+                            .iter()
+                            .map(|(_, a)| arg(&temps, a))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        let val = self.call(func, args)?;
+                        if let Some((name, ty)) = dest {
+                            set(&mut temps, name.as_str(), *ty, val.unwrap_or(0));
+                        }
+                    }
+                    il::Inst::Jmp(label) => {
+                        next = target(label)?;
+                        break;
+                    }
+                    il::Inst::Jnz(cond, then, els) => {
+                        next = if arg(&temps, cond)? as u32 != 0 {
+                            target(then)?
+                        } else {
+                            target(els)?
+                        };
+                        break;
+                    }
+                    il::Inst::Ret(val) => return val.as_ref().map(|v| arg(&temps, v)).transpose(),
+                    il::Inst::Dbgloc(_) => {}
+                }
+            }
+            block = next;
+        }
+    }
+
+    /// Runs `op`, returning `None` for stores. `long` tells whether the
+    /// result is a long.
+    fn op(&mut self, op: &str, args: &[u64], long: bool) -> Result<Option<u64>, Stop> {
+        let unknown = || Stop::Fault(format!("unknown instruction `{}`", op));
+        let (a, b) = match *args {
+            [a] => (a, 0),
+            [a, b] => (a, b),
+            _ => return Err(unknown()),
+        };
+        let signed = |v: u64| if long { v as i64 } else { v as i32 as i64 };
+        let val = match op {
+            "copy" => a,
+            "add" => a.wrapping_add(b),
+            "sub" => a.wrapping_sub(b),
+            "mul" => a.wrapping_mul(b),
+            "and" => a & b,
+            "or" => a | b,
+            "xor" => a ^ b,
+            "shl" => a.wrapping_shl(b as u32),
+            "div" | "rem" if signed(b) == 0 => {
+                return Err(Stop::Fault("division by zero".to_string()))
+            }
+            "div" => signed(a).wrapping_div(signed(b)) as u64,
+            "rem" => signed(a).wrapping_rem(signed(b)) as u64,
+            "extsw" => a as i32 as i64 as u64,
+            "extuw" => a as u32 as u64,
+            "alloc4" | "alloc8" | "alloc16" => self.alloc(a, op[5..].parse().unwrap()),
+            "loadl" => self.load(a, 8)?,
+            "loadw" | "loadsw" => self.load(a, 4)? as i32 as i64 as u64,
+            "loaduw" => self.load(a, 4)?,
+            "loadub" => self.load(a, 1)?,
+            "loadsb" => self.load(a, 1)? as i8 as i64 as u64,
+            "storel" | "storew" | "storeh" | "storeb" => {
+                let len = match op {
+                    "storel" => 8,
+                    "storew" => 4,
+                    "storeh" => 2,
+                    _ => 1,
+                };
+                self.store(b, len, a)?;
+                return Ok(None);
+            }
+            _ => return compare(op, a, b).map(Some).ok_or_else(unknown),
+        };
+        Ok(Some(val))
+    }
+
+    /// The C library functions the runtime calls.
+    fn call_libc(&mut self, name: &str, args: &[u64]) -> Result<u64, Stop> {
+        match (name, args) {
+            ("read", &[fd, buf, len]) => {
+                if fd as u32 != 0 {
+                    return Ok(u64::MAX);
+                }
+                let (read, rest) = self.input.split_at(self.input.len().min(len as usize));
+                self.mem(buf, read.len() as u64)?.copy_from_slice(read);
+                self.input = rest;
+                let n = read.len();
+                Ok(n as u64)
+            }
+            ("write", &[fd, buf, len]) => {
+                let bytes = self.mem(buf, len)?.to_vec();
+                // stderr is for diagnostics, not part of what is compared
+                match fd as u32 {
+                    1 => self.output.extend(bytes),
+                    2 => self.errors.extend(bytes),
+                    _ => {}
+                }
+                Ok(len)
+            }
+            ("__errno_location" | "__error", []) => Ok(self.globals["errno"]),
+            ("exit", &[status]) => Err(Stop::Exit(status as i32)),
+            ("calloc", &[n, size]) => Ok(self.alloc(n.wrapping_mul(size), 16)),
+            ("memcpy", &[dst, src, len]) => {
+                let bytes = self.mem(src, len)?.to_vec();
+                self.mem(dst, len)?.copy_from_slice(&bytes);
+                Ok(dst)
+            }
+            ("fflush", _) => Ok(0),
+            _ => Err(Stop::Fault(format!("call to unknown function ${}", name))),
+        }
+    }
+}
+
+/// `c<cond><type>` comparisons, `None` for anything else.
+fn compare(op: &str, a: u64, b: u64) -> Option<u64> {
+    let op = op.strip_prefix('c')?;
+    let (cond, ty) = op.split_at(op.len().checked_sub(1)?);
+    let (sa, sb, ua, ub) = match ty {
+        "l" => (a as i64, b as i64, a, b),
+        "w" => (
+            a as i32 as i64,
+            b as i32 as i64,
+            a as u32 as u64,
+            b as u32 as u64,
+        ),
+        _ => return None,
+    };
+    let res = match cond {
+        "eq" => ua == ub,
+        "ne" => ua != ub,
+        "slt" => sa < sb,
+        "sle" => sa <= sb,
+        "sgt" => sa > sb,
+        "sge" => sa >= sb,
+        "ult" => ua < ub,
+        "ule" => ua <= ub,
+        "ugt" => ua > ub,
+        "uge" => ua >= ub,
+        _ => return None,
+    };
+    Some(res as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CompileOptions;
+
+    const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.";
+
+    fn il(src: &str, opts: &CompileOptions) -> (ast::Prog, String) {
+        let prog = crate::optimize(src, opts).unwrap();
+        let il = crate::gen::QbeGenerator::with_options(opts)
+            .unwrap()
+            .gen(&prog)
+            .unwrap();
+        (prog, il)
+    }
+
+    #[test]
+    fn generated_il_validates() {
+        let variants = [
+            CompileOptions::default(),
+            CompileOptions {
+                split_loops: true,
+                io_strict: true,
+                ..Default::default()
+            },
+            CompileOptions {
+                max_stack: Some(0),
+                atexit: "flush,dump-tape,stats,histogram".parse().unwrap(),
+                ..Default::default()
+            },
+        ];
+        for opts in &variants {
+            for src in [HELLO, ",[.,]", "+[>+]", "<"] {
+                let (prog, il) = il(src, opts);
+                validate(&prog, &il, opts.tape_len).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn catch_lowering_bugs() {
+        let opts = CompileOptions::default();
+        let (prog, il) = il("+++.", &opts);
+        let broken = il.replace("add", "sub");
+        match validate(&prog, &broken, opts.tape_len) {
+            Err(Error::Mismatch { input, .. }) => assert_eq!(input, b""),
+            res => panic!("{:?}", res),
+        }
+    }
+}
@@ -0,0 +1,967 @@
+//! x86-64 code for Linux, making system calls itself so that building needs
+//! neither `qbe` nor libc: AT&T syntax assembly for an assembler and a
+//! linker, or machine code for [`crate::elf`] to wrap up with nothing else
+//! installed. Behaves like [`crate::gen::QbeGenerator`]'s output: 32 bit
+//! cells, `,` at end of input leaves the cell alone, I/O retried on `EINTR`,
+//! and moving off the tape exits with status 1.
+//!
+//! On Windows the runtime goes through `kernel32.dll` instead, imported the
+//! way `link.exe` expects, and the entry point is `mainCRTStartup`. macOS has
+//! no stable system call interface, there it calls `read`, `write` and `exit`
+//! from `libSystem` under their underscored Mach-O names and starts at
+//! `_main`.
+//!
+//! `%rbx` holds the cell pointer and `%r12` the start of the tape, both kept
+//! across the runtime routines. On Windows `%r13` and `%r14` hold the stdin
+//! and stdout handles.
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{ast, backend::CodeGen, target::Target, CompileOptions, Error, Newline};
+
+const SYS_READ: i32 = 0;
+const SYS_WRITE: i32 = 1;
+const SYS_EXIT: i32 = 60;
+const EINTR: i8 = 4;
+// what `_main` is entered with on top of a 16 byte aligned stack
+const RET_ADDR: i32 = 8;
+
+const STD_INPUT_HANDLE: i32 = -10;
+const STD_OUTPUT_HANDLE: i32 = -11;
+const STD_ERROR_HANDLE: i32 = -12;
+// what reading a pipe whose writer is gone fails with, the end of input
+const ERROR_BROKEN_PIPE: i8 = 109;
+// shadow space for four register arguments, a fifth on the stack and the
+// byte count `ReadFile`/`WriteFile` report, keeping `%rsp` 16 byte aligned
+// after a call pushed the return address
+const IO_FRAME: i32 = 56;
+const IO_ARG5: i8 = 32;
+const IO_COUNT: i8 = 40;
+
+// keeps everything within reach of 32 bit displacements
+const MAX_TAPE_LEN: usize = 1 << 28;
+
+pub const READ_ERR: &[u8] = b"bfc: read error\n";
+pub const WRITE_ERR: &[u8] = b"bfc: write error\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rsi = 6,
+    Rdi = 7,
+    R8 = 8,
+    R9 = 9,
+    R12 = 12,
+    R13 = 13,
+    R14 = 14,
+}
+
+impl Reg {
+    fn name(self) -> &'static str {
+        match self {
+            Reg::Rax => "rax",
+            Reg::Rcx => "rcx",
+            Reg::Rdx => "rdx",
+            Reg::Rbx => "rbx",
+            Reg::Rsp => "rsp",
+            Reg::Rsi => "rsi",
+            Reg::Rdi => "rdi",
+            Reg::R8 => "r8",
+            Reg::R9 => "r9",
+            Reg::R12 => "r12",
+            Reg::R13 => "r13",
+            Reg::R14 => "r14",
+        }
+    }
+
+    fn name32(self) -> &'static str {
+        match self {
+            Reg::Rax => "eax",
+            Reg::Rcx => "ecx",
+            Reg::Rdx => "edx",
+            Reg::Rbx => "ebx",
+            Reg::Rsp => "esp",
+            Reg::Rsi => "esi",
+            Reg::Rdi => "edi",
+            Reg::R8 => "r8d",
+            Reg::R9 => "r9d",
+            Reg::R12 => "r12d",
+            Reg::R13 => "r13d",
+            Reg::R14 => "r14d",
+        }
+    }
+
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn ext(self) -> bool {
+        self as u8 > 7
+    }
+}
+
+/// Data the code refers to, relative to the instruction pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sym {
+    Tape,
+    /// The byte `read` and `write` go through.
+    Buf,
+    ReadErr,
+    WriteErr,
+    /// Import address table entry of a `kernel32.dll` function, as an import
+    /// library defines it.
+    Import(&'static str),
+    /// Function called directly, left for the linker to find.
+    Func(&'static str),
+}
+
+impl Sym {
+    pub fn name(self) -> &'static str {
+        match self {
+            Sym::Tape => "bf_tape",
+            Sym::Buf => "bf_buf",
+            Sym::ReadErr => "bf_read_err",
+            Sym::WriteErr => "bf_write_err",
+            Sym::Import(name) | Sym::Func(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Cond {
+    E,
+    Ne,
+    Ae,
+    S,
+}
+
+impl Cond {
+    fn name(self) -> &'static str {
+        match self {
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::Ae => "ae",
+            Cond::S => "s",
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Cond::E => 0x84,
+            Cond::Ne => 0x85,
+            Cond::Ae => 0x83,
+            Cond::S => 0x88,
+        }
+    }
+}
+
+/// The handful of instruction forms the generator needs, `(%rbx)` being the
+/// current cell.
+#[derive(Debug, Clone)]
+enum Inst {
+    Lea(Reg, Sym),
+    Mov(Reg, Reg),
+    MovImm(Reg, i32),
+    MovAbs(Reg, i64),
+    Add(Reg, Reg),
+    AddImm(Reg, i32),
+    Sub(Reg, Reg),
+    Cmp(Reg, Reg),
+    CmpImm(Reg, i8),
+    /// 32 bit compare against a sign extended byte.
+    CmpImm32(Reg, i8),
+    CmpDil(u8),
+    Test(Reg),
+    Test32(Reg),
+    AddCell(i32),
+    /// `addl %reg, (%rbx)`
+    AddCellReg(Reg),
+    SubCell(i32),
+    /// `imull $imm, %reg, %reg`
+    Imul32(Reg, i32),
+    CmpCell0,
+    LoadCell(Reg),
+    StoreCell(Reg),
+    StoreBuf,
+    LoadBuf,
+    Jcc(Cond, String),
+    Jmp(String),
+    Call(String),
+    /// Call through an import address table entry.
+    CallImport(&'static str),
+    /// Call of a function in another object.
+    CallFunc(&'static str),
+    /// `cmpl $imm, (reg)`
+    CmpMem32(Reg, i8),
+    /// `lea disp(%rsp), reg`
+    LeaStack(Reg, i8),
+    /// `movl disp(%rsp), reg`
+    LoadStack(Reg, i8),
+    /// `movq $0, disp(%rsp)`
+    ClearStack(i8),
+    Syscall,
+    Ret,
+}
+
+enum Item {
+    Label(String),
+    Loc(usize, usize),
+    Inst(Inst),
+}
+
+/// Machine code for [`crate::elf`], with the places that still need the
+/// address of some data.
+#[derive(Debug, Default)]
+pub struct Code {
+    pub text: Vec<u8>,
+    pub entry: usize,
+    /// 32 bit fields holding `sym` minus the address right after the field.
+    pub relocs: Vec<(usize, Sym)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Os {
+    Linux,
+    Windows,
+    Apple,
+}
+
+pub struct X86Generator {
+    label_counter: usize,
+    tape_len: usize,
+    io_strict: bool,
+    crlf: bool,
+    os: Os,
+    debug_file: Option<String>,
+    items: Vec<Item>,
+}
+
+impl Default for X86Generator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen for X86Generator {
+    type Output = String;
+
+    fn generate(&mut self, prog: &ast::Prog) -> Result<String, Error> {
+        Ok(self.gen(prog))
+    }
+}
+
+impl X86Generator {
+    pub fn new() -> Self {
+        X86Generator {
+            label_counter: 0,
+            tape_len: 30_000,
+            io_strict: false,
+            crlf: false,
+            os: Os::Linux,
+            debug_file: None,
+            items: Vec::new(),
+        }
+    }
+
+    /// Fails for targets other than x86-64, and for tapes too large to
+    /// address relative to the code.
+    pub fn with_options(opts: &CompileOptions) -> Result<Self, Error> {
+        let os =
+            match opts.target {
+                None | Some(Target::Amd64Sysv) => Os::Linux,
+                Some(Target::Amd64Windows) => Os::Windows,
+                Some(Target::Amd64Apple) => Os::Apple,
+                Some(_) => return Err(Error::Unsupported(
+                    "x86-64 code is only generated for amd64_sysv, amd64_apple and amd64_windows",
+                )),
+            };
+        if opts.tape_len > MAX_TAPE_LEN {
+            return Err(Error::Unsupported(
+                "x86-64 code supports tapes of up to 2^28 cells",
+            ));
+        }
+        Ok(X86Generator {
+            tape_len: opts.tape_len,
+            io_strict: opts.io_strict,
+            crlf: opts.newline.resolve() == Newline::Crlf,
+            os,
+            debug_file: opts.debug_file.clone(),
+            ..Self::new()
+        })
+    }
+
+    /// Symbol execution starts at.
+    pub fn entry(&self) -> &'static str {
+        match self.os {
+            Os::Linux => "_start",
+            Os::Windows => "mainCRTStartup",
+            Os::Apple => "_main",
+        }
+    }
+
+    pub fn gen(&mut self, prog: &ast::Prog) -> String {
+        self.generate_items(prog);
+
+        let mut out = String::new();
+        if let Some(file) = &self.debug_file {
+            let file = file.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(out, "\t.file 1 \"{}\"", file).unwrap();
+        }
+        writeln!(out, "\t.text\n\t.globl {}", self.entry()).unwrap();
+        for item in &self.items {
+            match item {
+                Item::Label(label) => writeln!(out, "{}:", label),
+                Item::Loc(line, col) => writeln!(out, "\t.loc 1 {} {}", line, col),
+                Item::Inst(inst) => writeln!(out, "\t{}", render(inst)),
+            }
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "\n\t.bss\n\t.balign 16\n{}:\n\t.zero {}\n{}:\n\t.zero 1",
+            Sym::Tape.name(),
+            self.tape_len * 4,
+            Sym::Buf.name(),
+        )
+        .unwrap();
+        if self.io_strict {
+            writeln!(
+                out,
+                "\n\t.section {}\n{}:\n\t.ascii \"{}\"\n{}:\n\t.ascii \"{}\"",
+                match self.os {
+                    Os::Linux => ".rodata",
+                    Os::Windows => ".rdata",
+                    Os::Apple => "__TEXT,__const",
+                },
+                Sym::ReadErr.name(),
+                READ_ERR.escape_ascii(),
+                Sym::WriteErr.name(),
+                WRITE_ERR.escape_ascii()
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Machine code for `prog`, as [`X86Generator::gen`] would have it
+    /// assembled. Source locations are dropped.
+    pub fn encode(&mut self, prog: &ast::Prog) -> Code {
+        self.generate_items(prog);
+
+        let mut code = Code::default();
+        let mut labels = HashMap::new();
+        let mut jumps = Vec::new();
+        for item in &self.items {
+            match item {
+                Item::Label(label) => {
+                    labels.insert(label.as_str(), code.text.len());
+                }
+                Item::Loc(..) => {}
+                Item::Inst(inst) => encode(inst, &mut code, &mut jumps),
+            }
+        }
+        for (at, label) in jumps {
+            let target = labels[label.as_str()] as i64;
+            let rel = (target - (at as i64 + 4)) as i32;
+            code.text[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        code.entry = labels[self.entry()];
+        code
+    }
+
+    /// Bytes of tape, zeroed at startup.
+    pub fn tape_size(&self) -> usize {
+        self.tape_len * 4
+    }
+
+    /// Fills `items` with the instructions running `prog`.
+    fn generate_items(&mut self, prog: &ast::Prog) {
+        self.items.clear();
+        self.label(self.entry());
+        match self.os {
+            Os::Linux => {}
+            Os::Windows => {
+                // aligns the stack again after the call that got here
+                self.inst(Inst::AddImm(Reg::Rsp, -40));
+                self.generate_std_handle(Reg::R13, STD_INPUT_HANDLE);
+                self.generate_std_handle(Reg::R14, STD_OUTPUT_HANDLE);
+            }
+            Os::Apple => self.inst(Inst::AddImm(Reg::Rsp, -RET_ADDR)),
+        }
+        self.inst(Inst::Lea(Reg::R12, Sym::Tape));
+        self.inst(Inst::Mov(Reg::Rbx, Reg::R12));
+        self.generate_block(prog);
+        self.inst(Inst::MovImm(Reg::Rdi, 0));
+        self.inst(Inst::Jmp("bf_exit".to_string()));
+        self.generate_runtime();
+    }
+
+    fn generate_block(&mut self, block: &ast::NodeBlock) {
+        for stat in &block.stats {
+            self.generate_statement(stat)
+        }
+    }
+
+    fn generate_statement(&mut self, stat: &ast::NodeStatement) {
+        if self.debug_file.is_some() {
+            let span = stat.attr.span;
+            self.items.push(Item::Loc(span.line, span.col));
+        }
+
+        match &stat.stat {
+            ast::Statement::MoveL(n) => self.generate_move((*n as u64).wrapping_neg() as i64),
+            ast::Statement::MoveR(n) => self.generate_move(*n as u64 as i64),
+            ast::Statement::Add(n) => self.inst(Inst::AddCell(*n as u32 as i32)),
+            ast::Statement::Sub(n) => self.inst(Inst::SubCell(*n as u32 as i32)),
+            ast::Statement::Set(n) => {
+                self.inst(Inst::MovImm(Reg::Rax, *n as i32));
+                self.inst(Inst::StoreCell(Reg::Rax));
+            }
+            ast::Statement::MulAdd { offset, factor } => {
+                // moves only touch %rax and %rcx, the product waits in %edx
+                self.inst(Inst::LoadCell(Reg::Rdx));
+                self.inst(Inst::Imul32(Reg::Rdx, *factor as i32));
+                self.generate_move(*offset as i64);
+                self.inst(Inst::AddCellReg(Reg::Rdx));
+                self.generate_move(-(*offset as i64));
+            }
+            ast::Statement::Read => {
+                let skip = self.generate_label("eof");
+                self.inst(Inst::Call("bf_getc".to_string()));
+                self.inst(Inst::Test32(Reg::Rax));
+                self.inst(Inst::Jcc(Cond::S, skip.clone()));
+                self.inst(Inst::StoreCell(Reg::Rax));
+                self.label(&skip);
+            }
+            ast::Statement::Write => {
+                self.inst(Inst::LoadCell(Reg::Rdi));
+                if self.crlf {
+                    let plain = self.generate_label("char");
+                    self.inst(Inst::CmpDil(b'\n'));
+                    self.inst(Inst::Jcc(Cond::Ne, plain.clone()));
+                    self.inst(Inst::MovImm(Reg::Rdi, b'\r' as i32));
+                    self.inst(Inst::Call("bf_putc".to_string()));
+                    self.inst(Inst::MovImm(Reg::Rdi, b'\n' as i32));
+                    self.label(&plain);
+                }
+                self.inst(Inst::Call("bf_putc".to_string()));
+            }
+            ast::Statement::Scan(stride) => {
+                let start = self.generate_label("scan");
+                let end = self.generate_label("scanned");
+                self.inst(Inst::CmpCell0);
+                self.inst(Inst::Jcc(Cond::E, end.clone()));
+                self.label(&start);
+                self.generate_move(*stride as i64);
+                self.inst(Inst::CmpCell0);
+                self.inst(Inst::Jcc(Cond::Ne, start));
+                self.label(&end);
+            }
+            ast::Statement::Loop(body) => {
+                let start = self.generate_label("loop");
+                let end = self.generate_label("end");
+                self.inst(Inst::CmpCell0);
+                self.inst(Inst::Jcc(Cond::E, end.clone()));
+                self.label(&start);
+                self.generate_block(body);
+                self.inst(Inst::CmpCell0);
+                self.inst(Inst::Jcc(Cond::Ne, start));
+                self.label(&end);
+            }
+            ast::Statement::If(body) => {
+                let end = self.generate_label("fi");
+                self.inst(Inst::CmpCell0);
+                self.inst(Inst::Jcc(Cond::E, end.clone()));
+                self.generate_block(body);
+                self.label(&end);
+            }
+            // there is no data to copy from, see `spell_out_fill`
+            ast::Statement::Fill(values) => self.generate_block(&ast::spell_out_fill(values)),
+            // nor any to write from, see `spell_out_print`
+            ast::Statement::Print(bytes) => self.generate_block(&ast::spell_out_print(bytes)),
+        }
+    }
+
+    /// Moves the pointer by `n` cells and exits with status 1 when it ends up
+    /// off the tape.
+    fn generate_move(&mut self, n: i64) {
+        match i32::try_from(n.wrapping_mul(4)) {
+            Ok(bytes) => self.inst(Inst::AddImm(Reg::Rbx, bytes)),
+            Err(_) => {
+                self.inst(Inst::MovAbs(Reg::Rax, n.wrapping_mul(4)));
+                self.inst(Inst::Add(Reg::Rbx, Reg::Rax));
+            }
+        }
+        self.inst(Inst::Mov(Reg::Rax, Reg::Rbx));
+        self.inst(Inst::Sub(Reg::Rax, Reg::R12));
+        self.inst(Inst::MovAbs(Reg::Rcx, self.tape_size() as i64));
+        self.inst(Inst::Cmp(Reg::Rax, Reg::Rcx));
+        self.inst(Inst::Jcc(Cond::Ae, "bf_halt".to_string()));
+    }
+
+    /// `bf_putc` writes the byte in `%dil`, `bf_getc` returns the next byte
+    /// of input in `%eax`, or -1 at its end. `bf_exit` ends the process with
+    /// the status in `%edi`.
+    fn generate_runtime(&mut self) {
+        match self.os {
+            Os::Linux => {}
+            Os::Windows => return self.generate_windows_runtime(),
+            Os::Apple => return self.generate_macos_runtime(),
+        }
+
+        self.label("bf_halt");
+        self.inst(Inst::MovImm(Reg::Rdi, 1));
+        self.label("bf_exit");
+        self.inst(Inst::MovImm(Reg::Rax, SYS_EXIT));
+        self.inst(Inst::Syscall);
+
+        self.label("bf_putc");
+        self.inst(Inst::StoreBuf);
+        self.label(".Lputc_retry");
+        self.generate_syscall(SYS_WRITE, 1);
+        self.inst(Inst::CmpImm(Reg::Rax, 1));
+        self.inst(Inst::Jcc(Cond::E, ".Lputc_done".to_string()));
+        // nothing written, try again
+        self.inst(Inst::Test(Reg::Rax));
+        self.inst(Inst::Jcc(Cond::E, ".Lputc_retry".to_string()));
+        self.inst(Inst::CmpImm(Reg::Rax, -EINTR));
+        self.inst(Inst::Jcc(Cond::E, ".Lputc_retry".to_string()));
+        if self.io_strict {
+            self.generate_io_error(Sym::WriteErr, WRITE_ERR);
+        }
+        self.label(".Lputc_done");
+        self.inst(Inst::Ret);
+
+        self.label("bf_getc");
+        self.label(".Lgetc_retry");
+        self.generate_syscall(SYS_READ, 0);
+        self.inst(Inst::CmpImm(Reg::Rax, 1));
+        self.inst(Inst::Jcc(Cond::Ne, ".Lgetc_short".to_string()));
+        self.inst(Inst::LoadBuf);
+        if self.crlf {
+            self.inst(Inst::CmpImm32(Reg::Rax, b'\r' as i8));
+            self.inst(Inst::Jcc(Cond::E, ".Lgetc_retry".to_string()));
+        }
+        self.inst(Inst::Ret);
+        self.label(".Lgetc_short");
+        self.inst(Inst::Test(Reg::Rax));
+        self.inst(Inst::Jcc(Cond::E, ".Lgetc_eof".to_string()));
+        self.inst(Inst::CmpImm(Reg::Rax, -EINTR));
+        self.inst(Inst::Jcc(Cond::E, ".Lgetc_retry".to_string()));
+        if self.io_strict {
+            self.generate_io_error(Sym::ReadErr, READ_ERR);
+        }
+        self.label(".Lgetc_eof");
+        self.inst(Inst::MovImm(Reg::Rax, -1));
+        self.inst(Inst::Ret);
+    }
+
+    fn generate_windows_runtime(&mut self) {
+        self.label("bf_halt");
+        self.inst(Inst::MovImm(Reg::Rdi, 1));
+        self.label("bf_exit");
+        self.inst(Inst::Mov(Reg::Rcx, Reg::Rdi));
+        self.inst(Inst::CallImport("__imp_ExitProcess"));
+
+        self.label("bf_putc");
+        self.inst(Inst::StoreBuf);
+        self.inst(Inst::AddImm(Reg::Rsp, -IO_FRAME));
+        self.label(".Lputc_retry");
+        self.generate_file_io(Reg::R14, "__imp_WriteFile");
+        self.inst(Inst::Test32(Reg::Rax));
+        self.inst(Inst::Jcc(Cond::Ne, ".Lputc_ok".to_string()));
+        if self.io_strict {
+            self.generate_windows_io_error(Sym::WriteErr, WRITE_ERR);
+        } else {
+            self.inst(Inst::Jmp(".Lputc_done".to_string()));
+        }
+        self.label(".Lputc_ok");
+        // nothing written, try again
+        self.inst(Inst::LoadStack(Reg::Rax, IO_COUNT));
+        self.inst(Inst::Test32(Reg::Rax));
+        self.inst(Inst::Jcc(Cond::E, ".Lputc_retry".to_string()));
+        self.label(".Lputc_done");
+        self.inst(Inst::AddImm(Reg::Rsp, IO_FRAME));
+        self.inst(Inst::Ret);
+
+        self.label("bf_getc");
+        self.inst(Inst::AddImm(Reg::Rsp, -IO_FRAME));
+        self.label(".Lgetc_retry");
+        self.generate_file_io(Reg::R13, "__imp_ReadFile");
+        self.inst(Inst::Test32(Reg::Rax));
+        self.inst(Inst::Jcc(Cond::E, ".Lgetc_fail".to_string()));
+        self.inst(Inst::LoadStack(Reg::Rax, IO_COUNT));
+        self.inst(Inst::Test32(Reg::Rax));
+        self.inst(Inst::Jcc(Cond::E, ".Lgetc_eof".to_string()));
+        self.inst(Inst::LoadBuf);
+        if self.crlf {
+            self.inst(Inst::CmpImm32(Reg::Rax, b'\r' as i8));
+            self.inst(Inst::Jcc(Cond::E, ".Lgetc_retry".to_string()));
+        }
+        self.inst(Inst::Jmp(".Lgetc_done".to_string()));
+        self.label(".Lgetc_fail");
+        self.inst(Inst::CallImport("__imp_GetLastError"));
+        self.inst(Inst::CmpImm32(Reg::Rax, ERROR_BROKEN_PIPE));
+        self.inst(Inst::Jcc(Cond::E, ".Lgetc_eof".to_string()));
+        if self.io_strict {
+            self.generate_windows_io_error(Sym::ReadErr, READ_ERR);
+        }
+        self.label(".Lgetc_eof");
+        self.inst(Inst::MovImm(Reg::Rax, -1));
+        self.label(".Lgetc_done");
+        self.inst(Inst::AddImm(Reg::Rsp, IO_FRAME));
+        self.inst(Inst::Ret);
+    }
+
+    /// Like the Linux runtime, with `errno` read through `__error` and the
+    /// stack kept aligned for the calls into `libSystem`.
+    fn generate_macos_runtime(&mut self) {
+        self.label("bf_halt");
+        self.inst(Inst::MovImm(Reg::Rdi, 1));
+        self.label("bf_exit");
+        self.inst(Inst::CallFunc("_exit"));
+
+        self.label("bf_putc");
+        self.inst(Inst::StoreBuf);
+        self.inst(Inst::AddImm(Reg::Rsp, -RET_ADDR));
+        self.label(".Lputc_retry");
+        self.generate_libc_io("_write", 1);
+        self.inst(Inst::CmpImm(Reg::Rax, 1));
+        self.inst(Inst::Jcc(Cond::E, ".Lputc_done".to_string()));
+        // nothing written, try again
+        self.inst(Inst::Test(Reg::Rax));
+        self.inst(Inst::Jcc(Cond::E, ".Lputc_retry".to_string()));
+        self.generate_errno_check(".Lputc_retry");
+        if self.io_strict {
+            self.generate_macos_io_error(Sym::WriteErr, WRITE_ERR);
+        }
+        self.label(".Lputc_done");
+        self.inst(Inst::AddImm(Reg::Rsp, RET_ADDR));
+        self.inst(Inst::Ret);
+
+        self.label("bf_getc");
+        self.inst(Inst::AddImm(Reg::Rsp, -RET_ADDR));
+        self.label(".Lgetc_retry");
+        self.generate_libc_io("_read", 0);
+        self.inst(Inst::CmpImm(Reg::Rax, 1));
+        self.inst(Inst::Jcc(Cond::Ne, ".Lgetc_short".to_string()));
+        self.inst(Inst::LoadBuf);
+        if self.crlf {
+            self.inst(Inst::CmpImm32(Reg::Rax, b'\r' as i8));
+            self.inst(Inst::Jcc(Cond::E, ".Lgetc_retry".to_string()));
+        }
+        self.inst(Inst::Jmp(".Lgetc_done".to_string()));
+        self.label(".Lgetc_short");
+        self.inst(Inst::Test(Reg::Rax));
+        self.inst(Inst::Jcc(Cond::E, ".Lgetc_eof".to_string()));
+        self.generate_errno_check(".Lgetc_retry");
+        if self.io_strict {
+            self.generate_macos_io_error(Sym::ReadErr, READ_ERR);
+        }
+        self.label(".Lgetc_eof");
+        self.inst(Inst::MovImm(Reg::Rax, -1));
+        self.label(".Lgetc_done");
+        self.inst(Inst::AddImm(Reg::Rsp, RET_ADDR));
+        self.inst(Inst::Ret);
+    }
+
+    /// `read` or `write` of one byte through `bf_buf` on `fd`, the result
+    /// left in `%rax`.
+    fn generate_libc_io(&mut self, func: &'static str, fd: i32) {
+        self.inst(Inst::MovImm(Reg::Rdi, fd));
+        self.inst(Inst::Lea(Reg::Rsi, Sym::Buf));
+        self.inst(Inst::MovImm(Reg::Rdx, 1));
+        self.inst(Inst::CallFunc(func));
+    }
+
+    /// Jumps to `retry` when the call that just failed was interrupted.
+    fn generate_errno_check(&mut self, retry: &str) {
+        self.inst(Inst::CallFunc("___error"));
+        self.inst(Inst::CmpMem32(Reg::Rax, EINTR));
+        self.inst(Inst::Jcc(Cond::E, retry.to_string()));
+    }
+
+    fn generate_macos_io_error(&mut self, sym: Sym, msg: &[u8]) {
+        self.inst(Inst::MovImm(Reg::Rdi, 2));
+        self.inst(Inst::Lea(Reg::Rsi, sym));
+        self.inst(Inst::MovImm(Reg::Rdx, msg.len() as i32));
+        self.inst(Inst::CallFunc("_write"));
+        self.inst(Inst::Jmp("bf_halt".to_string()));
+    }
+
+    /// `ReadFile` or `WriteFile` of one byte through `bf_buf` on `handle`,
+    /// from within a frame of `IO_FRAME` bytes.
+    fn generate_file_io(&mut self, handle: Reg, func: &'static str) {
+        self.inst(Inst::Mov(Reg::Rcx, handle));
+        self.inst(Inst::Lea(Reg::Rdx, Sym::Buf));
+        self.inst(Inst::MovImm(Reg::R8, 1));
+        self.inst(Inst::LeaStack(Reg::R9, IO_COUNT));
+        self.inst(Inst::ClearStack(IO_ARG5));
+        self.inst(Inst::CallImport(func));
+    }
+
+    fn generate_windows_io_error(&mut self, sym: Sym, msg: &[u8]) {
+        self.generate_std_handle(Reg::Rcx, STD_ERROR_HANDLE);
+        self.inst(Inst::Lea(Reg::Rdx, sym));
+        self.inst(Inst::MovImm(Reg::R8, msg.len() as i32));
+        self.inst(Inst::LeaStack(Reg::R9, IO_COUNT));
+        self.inst(Inst::ClearStack(IO_ARG5));
+        self.inst(Inst::CallImport("__imp_WriteFile"));
+        self.inst(Inst::Jmp("bf_halt".to_string()));
+    }
+
+    fn generate_std_handle(&mut self, dst: Reg, which: i32) {
+        self.inst(Inst::MovImm(Reg::Rcx, which));
+        self.inst(Inst::CallImport("__imp_GetStdHandle"));
+        self.inst(Inst::Mov(dst, Reg::Rax));
+    }
+
+    /// One byte through `bf_buf`, the result left in `%rax`.
+    fn generate_syscall(&mut self, nr: i32, fd: i32) {
+        self.inst(Inst::MovImm(Reg::Rax, nr));
+        self.inst(Inst::MovImm(Reg::Rdi, fd));
+        self.inst(Inst::Lea(Reg::Rsi, Sym::Buf));
+        self.inst(Inst::MovImm(Reg::Rdx, 1));
+        self.inst(Inst::Syscall);
+    }
+
+    fn generate_io_error(&mut self, sym: Sym, msg: &[u8]) {
+        self.inst(Inst::MovImm(Reg::Rax, SYS_WRITE));
+        self.inst(Inst::MovImm(Reg::Rdi, 2));
+        self.inst(Inst::Lea(Reg::Rsi, sym));
+        self.inst(Inst::MovImm(Reg::Rdx, msg.len() as i32));
+        self.inst(Inst::Syscall);
+        self.inst(Inst::Jmp("bf_halt".to_string()));
+    }
+
+    fn inst(&mut self, inst: Inst) {
+        self.items.push(Item::Inst(inst));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.items.push(Item::Label(label.to_string()));
+    }
+
+    fn generate_label(&mut self, prefix: &str) -> String {
+        let c = self.label_counter;
+        self.label_counter += 1;
+        format!(".L{}{}", prefix, c)
+    }
+}
+
+fn render(inst: &Inst) -> String {
+    match inst {
+        Inst::Lea(r, sym) => format!("leaq {}(%rip), %{}", sym.name(), r.name()),
+        Inst::Mov(dst, src) => format!("movq %{}, %{}", src.name(), dst.name()),
+        Inst::MovImm(r, imm) => format!("movl ${}, %{}", imm, r.name32()),
+        Inst::MovAbs(r, imm) => format!("movabsq ${}, %{}", imm, r.name()),
+        Inst::Add(dst, src) => format!("addq %{}, %{}", src.name(), dst.name()),
+        Inst::AddImm(r, imm) => format!("addq ${}, %{}", imm, r.name()),
+        Inst::Sub(dst, src) => format!("subq %{}, %{}", src.name(), dst.name()),
+        Inst::Cmp(a, b) => format!("cmpq %{}, %{}", b.name(), a.name()),
+        Inst::CmpImm(r, imm) => format!("cmpq ${}, %{}", imm, r.name()),
+        Inst::CmpImm32(r, imm) => format!("cmpl ${}, %{}", imm, r.name32()),
+        Inst::CmpDil(imm) => format!("cmpb ${}, %dil", imm),
+        Inst::Test(r) => format!("testq %{0}, %{0}", r.name()),
+        Inst::Test32(r) => format!("testl %{0}, %{0}", r.name32()),
+        Inst::AddCell(imm) => format!("addl ${}, (%rbx)", imm),
+        Inst::AddCellReg(r) => format!("addl %{}, (%rbx)", r.name32()),
+        Inst::SubCell(imm) => format!("subl ${}, (%rbx)", imm),
+        Inst::Imul32(r, imm) => format!("imull ${}, %{1}, %{1}", imm, r.name32()),
+        Inst::CmpCell0 => "cmpl $0, (%rbx)".to_string(),
+        Inst::LoadCell(r) => format!("movl (%rbx), %{}", r.name32()),
+        Inst::StoreCell(r) => format!("movl %{}, (%rbx)", r.name32()),
+        Inst::StoreBuf => format!("movb %dil, {}(%rip)", Sym::Buf.name()),
+        Inst::LoadBuf => format!("movzbl {}(%rip), %eax", Sym::Buf.name()),
+        Inst::Jcc(cond, label) => format!("j{} {}", cond.name(), label),
+        Inst::Jmp(label) => format!("jmp {}", label),
+        Inst::Call(label) => format!("call {}", label),
+        Inst::CallImport(name) => format!("call *{}(%rip)", name),
+        Inst::CallFunc(name) => format!("call {}", name),
+        Inst::CmpMem32(r, imm) => format!("cmpl ${}, (%{})", imm, r.name()),
+        Inst::LeaStack(r, disp) => format!("leaq {}(%rsp), %{}", disp, r.name()),
+        Inst::LoadStack(r, disp) => format!("movl {}(%rsp), %{}", disp, r.name32()),
+        Inst::ClearStack(disp) => format!("movq $0, {}(%rsp)", disp),
+        Inst::Syscall => "syscall".to_string(),
+        Inst::Ret => "ret".to_string(),
+    }
+}
+
+/// REX prefix for a 64 bit operation with `reg` in the ModRM reg field and
+/// `rm` in its r/m field.
+fn rex_w(reg: Reg, rm: Reg) -> u8 {
+    0x48 | (reg.ext() as u8) << 2 | rm.ext() as u8
+}
+
+fn modrm_reg(reg: u8, rm: u8) -> u8 {
+    0xc0 | (reg & 7) << 3 | rm & 7
+}
+
+// `(%rbx)` as ModRM r/m, with the reg field still to be or'ed in
+const MEM_RBX: u8 = 0x03;
+// `disp32(%rip)`
+const MEM_RIP: u8 = 0x05;
+// a SIB byte follows the ModRM one, here always the one for plain `(%rsp)`
+const SIB: u8 = 0x04;
+const SIB_RSP: u8 = 0x24;
+
+fn encode(inst: &Inst, code: &mut Code, jumps: &mut Vec<(usize, String)>) {
+    let out = &mut code.text;
+    match inst {
+        Inst::Lea(r, sym) => {
+            out.extend([0x48 | (r.ext() as u8) << 2, 0x8d, r.low() << 3 | MEM_RIP]);
+            code.relocs.push((out.len(), *sym));
+            out.extend([0; 4]);
+        }
+        Inst::Mov(dst, src) => {
+            out.extend([rex_w(*src, *dst), 0x89, modrm_reg(src.low(), dst.low())])
+        }
+        Inst::MovImm(r, imm) => {
+            if r.ext() {
+                out.push(0x41);
+            }
+            out.push(0xb8 + r.low());
+            out.extend(imm.to_le_bytes());
+        }
+        Inst::MovAbs(r, imm) => {
+            out.extend([0x48 | r.ext() as u8, 0xb8 + r.low()]);
+            out.extend(imm.to_le_bytes());
+        }
+        Inst::Add(dst, src) => {
+            out.extend([rex_w(*src, *dst), 0x01, modrm_reg(src.low(), dst.low())])
+        }
+        Inst::AddImm(r, imm) => {
+            out.extend([0x48 | r.ext() as u8, 0x81, modrm_reg(0, r.low())]);
+            out.extend(imm.to_le_bytes());
+        }
+        Inst::Sub(dst, src) => {
+            out.extend([rex_w(*src, *dst), 0x29, modrm_reg(src.low(), dst.low())])
+        }
+        Inst::Cmp(a, b) => out.extend([rex_w(*b, *a), 0x39, modrm_reg(b.low(), a.low())]),
+        Inst::CmpImm(r, imm) => out.extend([
+            0x48 | r.ext() as u8,
+            0x83,
+            modrm_reg(7, r.low()),
+            *imm as u8,
+        ]),
+        Inst::CmpImm32(r, imm) => out.extend([0x83, modrm_reg(7, r.low()), *imm as u8]),
+        // the bare REX prefix selects %dil over %bh
+        Inst::CmpDil(imm) => out.extend([0x40, 0x80, modrm_reg(7, Reg::Rdi as u8), *imm]),
+        Inst::Test(r) => out.extend([rex_w(*r, *r), 0x85, modrm_reg(r.low(), r.low())]),
+        Inst::Test32(r) => out.extend([0x85, modrm_reg(r.low(), r.low())]),
+        Inst::AddCell(imm) => {
+            out.extend([0x81, MEM_RBX]);
+            out.extend(imm.to_le_bytes());
+        }
+        Inst::AddCellReg(r) => out.extend([0x01, r.low() << 3 | MEM_RBX]),
+        Inst::SubCell(imm) => {
+            out.extend([0x81, 5 << 3 | MEM_RBX]);
+            out.extend(imm.to_le_bytes());
+        }
+        Inst::Imul32(r, imm) => {
+            out.extend([0x69, modrm_reg(r.low(), r.low())]);
+            out.extend(imm.to_le_bytes());
+        }
+        Inst::CmpCell0 => out.extend([0x83, 7 << 3 | MEM_RBX, 0]),
+        Inst::LoadCell(r) => out.extend([0x8b, r.low() << 3 | MEM_RBX]),
+        Inst::StoreCell(r) => out.extend([0x89, r.low() << 3 | MEM_RBX]),
+        Inst::StoreBuf => {
+            out.extend([0x40, 0x88, (Reg::Rdi as u8) << 3 | MEM_RIP]);
+            code.relocs.push((out.len(), Sym::Buf));
+            out.extend([0; 4]);
+        }
+        Inst::LoadBuf => {
+            out.extend([0x0f, 0xb6, MEM_RIP]);
+            code.relocs.push((out.len(), Sym::Buf));
+            out.extend([0; 4]);
+        }
+        Inst::Jcc(cond, label) => {
+            out.extend([0x0f, cond.code()]);
+            jumps.push((out.len(), label.clone()));
+            out.extend([0; 4]);
+        }
+        Inst::Jmp(label) | Inst::Call(label) => {
+            out.push(if let Inst::Jmp(_) = inst { 0xe9 } else { 0xe8 });
+            jumps.push((out.len(), label.clone()));
+            out.extend([0; 4]);
+        }
+        Inst::CallImport(name) => {
+            out.extend([0xff, 2 << 3 | MEM_RIP]);
+            code.relocs.push((out.len(), Sym::Import(name)));
+            out.extend([0; 4]);
+        }
+        Inst::CallFunc(name) => {
+            out.push(0xe8);
+            code.relocs.push((out.len(), Sym::Func(name)));
+            out.extend([0; 4]);
+        }
+        // registers that would need a SIB byte or a displacement never hold
+        // the address
+        Inst::CmpMem32(r, imm) => out.extend([0x83, 7 << 3 | r.low(), *imm as u8]),
+        Inst::LeaStack(r, disp) => out.extend([
+            0x48 | (r.ext() as u8) << 2,
+            0x8d,
+            0x40 | r.low() << 3 | SIB,
+            SIB_RSP,
+            *disp as u8,
+        ]),
+        Inst::LoadStack(r, disp) => {
+            out.extend([0x8b, 0x40 | r.low() << 3 | SIB, SIB_RSP, *disp as u8])
+        }
+        Inst::ClearStack(disp) => {
+            out.extend([0x48, 0xc7, 0x40 | SIB, SIB_RSP, *disp as u8]);
+            out.extend([0; 4]);
+        }
+        Inst::Syscall => out.extend([0x0f, 0x05]),
+        Inst::Ret => out.push(0xc3),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn moves_are_bounds_checked() {
+        let prog = crate::parse("+>>[-<]").unwrap();
+        let asm = X86Generator::new().gen(&prog);
+        assert!(asm.contains("\taddl $1, (%rbx)\n\taddq $8, %rbx\n"));
+        assert!(asm.contains("\taddq $-4, %rbx\n"));
+        assert_eq!(asm.matches("jae bf_halt").count(), 2);
+        assert!(asm.contains("bf_tape:\n\t.zero 120000\n"));
+    }
+
+    #[test]
+    fn linux_x86_64_only() {
+        let opts = CompileOptions {
+            target: Some(Target::Arm64),
+            ..Default::default()
+        };
+        assert!(X86Generator::with_options(&opts).is_err());
+        let opts = CompileOptions {
+            tape_len: MAX_TAPE_LEN + 1,
+            ..Default::default()
+        };
+        assert!(X86Generator::with_options(&opts).is_err());
+    }
+
+    #[test]
+    fn encode_instructions() {
+        let prog = crate::parse("+<").unwrap();
+        let code = X86Generator::new().encode(&prog);
+        assert_eq!(code.entry, 0);
+        assert_eq!(
+            code.text[..16],
+            [
+                0x4c, 0x8d, 0x25, 0, 0, 0, 0, // leaq bf_tape(%rip), %r12
+                0x4c, 0x89, 0xe3, // movq %r12, %rbx
+                0x81, 0x03, 1, 0, 0, 0, // addl $1, (%rbx)
+            ]
+        );
+        assert_eq!(code.relocs[0], (3, Sym::Tape));
+    }
+}